@@ -0,0 +1,132 @@
+//! A read-only view of an EPUB archive as stored, for the checks that look
+//! at the book without fixing it, and how to read archives that hold several
+//! entries with the same name.
+
+use crate::epub::{get_opf_filename, has_xhtml_extension};
+use crate::error::FixError;
+use crate::href;
+use crate::opf::Package;
+use crate::salvage;
+use std::collections::BTreeSet;
+use std::io::{Cursor, Read};
+use zip::{CompressionMethod, ZipArchive};
+
+/// Which entry to keep when the archive holds several with the same name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum DuplicateMode {
+    /// Keep the last one, which most ZIP tools extract.
+    #[default]
+    Last,
+    /// Keep the largest one.
+    Largest,
+}
+
+/// An archive entry as stored, with what the rules need to know about it.
+pub(crate) struct Entry {
+    pub(crate) name: String,
+    pub(crate) data: Vec<u8>,
+    pub(crate) compression: CompressionMethod,
+}
+
+/// The parts of a book the rules look at.
+pub(crate) struct Book {
+    pub(crate) size: u64,
+    pub(crate) entries: Vec<Entry>,
+    /// Names the archive holds more than one entry for; `entries` has one.
+    pub(crate) duplicates: BTreeSet<String>,
+    pub(crate) opf_path: Option<String>,
+    /// The parsed package document, or why it could not be read.
+    pub(crate) package: Result<Package, FixError>,
+}
+
+impl Book {
+    pub(crate) fn read(filename: &str) -> Result<Book, FixError> {
+        let data = std::fs::read(filename)?;
+        let size = data.len() as u64;
+        let duplicates = salvage::duplicate_names(&data);
+        let mut archive = ZipArchive::new(Cursor::new(data.as_slice()))?;
+        let mut entries = Vec::with_capacity(archive.len());
+        for i in 0..archive.len() {
+            let mut file = archive.by_index(i)?;
+            let mut data = Vec::new();
+            file.read_to_end(&mut data)?;
+            entries.push(Entry {
+                name: file.name().to_string(),
+                data,
+                compression: file.compression(),
+            });
+        }
+
+        let opf_path = entries
+            .iter()
+            .find(|entry| entry.name == "META-INF/container.xml")
+            .and_then(|entry| get_opf_filename(&entry.data));
+        let package = match &opf_path {
+            None => Err(FixError::InvalidPackage(
+                "META-INF/container.xml names no package document".to_string(),
+            )),
+            Some(path) => match entries.iter().find(|entry| &entry.name == path) {
+                None => Err(FixError::InvalidPackage(format!(
+                    "{path} is not in the archive"
+                ))),
+                Some(entry) => Package::parse(&entry.data),
+            },
+        };
+        Ok(Book {
+            size,
+            entries,
+            duplicates,
+            opf_path,
+            package,
+        })
+    }
+
+    pub(crate) fn opf_path(&self) -> &str {
+        self.opf_path.as_deref().unwrap_or_default()
+    }
+
+    pub(crate) fn entry(&self, path: &str) -> Option<&Entry> {
+        self.entries.iter().find(|entry| entry.name == path)
+    }
+
+    pub(crate) fn contains(&self, path: &str) -> bool {
+        self.entry(path).is_some()
+    }
+
+    /// The XHTML content documents: those the manifest declares, or every
+    /// file with an XHTML extension when the package cannot be read.
+    pub(crate) fn content_documents(&self) -> impl Iterator<Item = &Entry> {
+        let declared: Option<BTreeSet<String>> = self.package.as_ref().ok().map(|package| {
+            package
+                .manifest
+                .iter()
+                .filter(|item| item.is_xhtml())
+                .map(|item| href::resolve(self.opf_path(), &item.href))
+                .collect()
+        });
+        self.entries.iter().filter(move |entry| match &declared {
+            Some(declared) => declared.contains(&entry.name),
+            None => has_xhtml_extension(&entry.name),
+        })
+    }
+}
+
+/// Writes a book with `files` in order to `path`, storing `mimetype`
+/// uncompressed and deflating everything else.
+#[cfg(test)]
+pub(crate) fn write_test_book(path: &std::path::Path, files: &[(&str, &[u8])]) {
+    use std::io::Write;
+    use zip::write::SimpleFileOptions;
+
+    let mut zip = zip::ZipWriter::new(std::fs::File::create(path).unwrap());
+    for (name, data) in files {
+        let method = match *name {
+            "mimetype" => CompressionMethod::Stored,
+            _ => CompressionMethod::Deflated,
+        };
+        let options = SimpleFileOptions::default().compression_method(method);
+        zip.start_file(*name, options).unwrap();
+        zip.write_all(data).unwrap();
+    }
+    zip.finish().unwrap();
+}
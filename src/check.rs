@@ -0,0 +1,421 @@
+//! `fixepub check`: runs the rules a delivery target rejects books for and
+//! reports which pass, without modifying the book.
+
+use crate::archive::Book;
+use crate::epub::{collect_body_id, is_valid_language};
+use crate::error::FixError;
+use crate::{encoding_matcher, href, images, links, manifest, media_type};
+use std::collections::BTreeSet;
+use std::fmt;
+use zip::CompressionMethod;
+
+/// Send to Kindle accepts documents up to 200 MB.
+const MAX_BOOK_BYTES: u64 = 200 * 1024 * 1024;
+/// The Kindle Publishing Guidelines limit each content document to 30 MB.
+const MAX_CONTENT_BYTES: usize = 30 * 1024 * 1024;
+/// The Kindle Publishing Guidelines limit each image to 5 MB.
+const MAX_IMAGE_BYTES: usize = 5 * 1024 * 1024;
+/// Longer archive paths are rejected by some ZIP tools along the way.
+const MAX_NAME_BYTES: usize = 255;
+
+/// The reading system whose rules to check a book against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Target {
+    /// Send to Kindle and the Kindle conversion service.
+    #[default]
+    Kindle,
+}
+
+/// The outcome of one rule: it passed when there are no problems.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuleResult {
+    pub rule: &'static str,
+    pub problems: Vec<String>,
+}
+
+impl RuleResult {
+    pub fn passed(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// The results of all rules for one book.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Report {
+    pub results: Vec<RuleResult>,
+}
+
+impl Report {
+    /// The number of rules that failed.
+    pub fn failures(&self) -> usize {
+        self.results
+            .iter()
+            .filter(|result| !result.passed())
+            .count()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for result in &self.results {
+            let status = if result.passed() { "PASS" } else { "FAIL" };
+            writeln!(f, "{status}  {}", result.rule)?;
+            for problem in &result.problems {
+                writeln!(f, "        {problem}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A rule by name, and the check listing the problems that break it.
+type Rule = (&'static str, fn(&Book) -> Vec<String>);
+
+/// Checks the book `filename` against the rules of `target`.
+pub fn check(filename: &str, target: Target) -> Result<Report, FixError> {
+    let book = Book::read(filename)?;
+    let rules: &[Rule] = match target {
+        Target::Kindle => &[
+            ("mimetype is the first, uncompressed entry", check_mimetype),
+            ("Package document is readable", check_package),
+            ("dc:language is a valid language tag", check_language),
+            ("Content documents declare their encoding", check_encoding),
+            ("No links to <body> ids", check_body_id_links),
+            ("Book and file sizes are within limits", check_sizes),
+            ("No unsupported media", check_media),
+            ("File names are short, ASCII and unique", check_file_names),
+            ("Manifest matches the archive", check_manifest),
+        ],
+    };
+    Ok(Report {
+        results: rules
+            .iter()
+            .map(|(rule, check)| RuleResult {
+                rule,
+                problems: check(&book),
+            })
+            .collect(),
+    })
+}
+
+fn check_mimetype(book: &Book) -> Vec<String> {
+    let Some(first) = book.entries.first() else {
+        return vec!["the archive is empty".to_string()];
+    };
+    if first.name != "mimetype" {
+        if book.contains("mimetype") {
+            return vec![format!("the first entry is {}, not mimetype", first.name)];
+        }
+        return vec!["the archive has no mimetype entry".to_string()];
+    }
+    let mut problems = Vec::new();
+    if first.compression != CompressionMethod::Stored {
+        problems.push("mimetype is compressed".to_string());
+    }
+    if first.data != b"application/epub+zip" {
+        problems.push(format!(
+            "mimetype contains {:?}, not \"application/epub+zip\"",
+            String::from_utf8_lossy(&first.data)
+        ));
+    }
+    problems
+}
+
+fn check_package(book: &Book) -> Vec<String> {
+    match &book.package {
+        Ok(_) => Vec::new(),
+        Err(err) => match &book.opf_path {
+            Some(path) if book.contains(path) => vec![format!("{path}: {err}")],
+            _ => vec![err.to_string()],
+        },
+    }
+}
+
+fn check_language(book: &Book) -> Vec<String> {
+    let Ok(package) = &book.package else {
+        return Vec::new();
+    };
+    match package.metadata.first("language") {
+        None => vec!["the package has no dc:language".to_string()],
+        Some(language) if !is_valid_language(language) => {
+            vec![format!("{language:?} is not a valid language tag")]
+        }
+        Some(_) => Vec::new(),
+    }
+}
+
+fn check_encoding(book: &Book) -> Vec<String> {
+    book.content_documents()
+        .filter(|entry| {
+            let content = String::from_utf8_lossy(&entry.data);
+            !matches!(
+                encoding_matcher::is_xml_declaration(content.trim_start()),
+                Ok((_, true))
+            )
+        })
+        .map(|entry| format!("{} has no XML declaration with an encoding", entry.name))
+        .collect()
+}
+
+fn check_body_id_links(book: &Book) -> Vec<String> {
+    let body_ids: Vec<(String, String)> = book
+        .content_documents()
+        .filter_map(|entry| collect_body_id(&entry.name, &entry.data))
+        .collect();
+    let mut problems = Vec::new();
+    for entry in book.content_documents() {
+        let content = String::from_utf8_lossy(&entry.data);
+        for (link, _) in &body_ids {
+            if content.contains(link.as_str()) {
+                problems.push(format!("{} links to {link}", entry.name));
+            }
+        }
+    }
+    problems
+}
+
+fn check_sizes(book: &Book) -> Vec<String> {
+    let mut problems = Vec::new();
+    if book.size > MAX_BOOK_BYTES {
+        problems.push(format!(
+            "the book is {} bytes, more than {MAX_BOOK_BYTES}",
+            book.size
+        ));
+    }
+    let documents: BTreeSet<&str> = book
+        .content_documents()
+        .map(|entry| entry.name.as_str())
+        .collect();
+    for entry in &book.entries {
+        let size = entry.data.len();
+        if documents.contains(entry.name.as_str()) && size > MAX_CONTENT_BYTES {
+            problems.push(format!(
+                "{} is {size} bytes, more than {MAX_CONTENT_BYTES}",
+                entry.name
+            ));
+        }
+        let is_image = media_type::sniff(&entry.name, &entry.data)
+            .is_some_and(|media_type| media_type.starts_with("image/"));
+        if is_image && size > MAX_IMAGE_BYTES {
+            problems.push(format!(
+                "{} is {size} bytes, more than {MAX_IMAGE_BYTES}",
+                entry.name
+            ));
+        }
+    }
+    problems
+}
+
+fn check_media(book: &Book) -> Vec<String> {
+    book.entries
+        .iter()
+        .filter_map(|entry| {
+            let media_type = media_type::sniff(&entry.name, &entry.data)?;
+            let unsupported = images::UNSUPPORTED.contains(&media_type)
+                || media_type.starts_with("audio/")
+                || media_type.starts_with("video/");
+            unsupported.then(|| format!("{} is {media_type}", entry.name))
+        })
+        .collect()
+}
+
+fn check_file_names(book: &Book) -> Vec<String> {
+    let mut problems = Vec::new();
+    for entry in &book.entries {
+        if !entry.name.is_ascii() {
+            problems.push(format!("{} is not ASCII", entry.name));
+        }
+        if entry.name.len() > MAX_NAME_BYTES {
+            problems.push(format!(
+                "{} is longer than {MAX_NAME_BYTES} bytes",
+                entry.name
+            ));
+        }
+    }
+    problems.extend(
+        book.duplicates
+            .iter()
+            .map(|name| format!("{name} appears more than once")),
+    );
+    problems
+}
+
+fn check_manifest(book: &Book) -> Vec<String> {
+    let Ok(package) = &book.package else {
+        return Vec::new();
+    };
+    let opf_path = book.opf_path();
+    let declared: BTreeSet<String> = package
+        .manifest
+        .iter()
+        .map(|item| href::resolve(opf_path, &item.href))
+        .collect();
+
+    let mut problems: Vec<String> = package
+        .manifest
+        .iter()
+        .filter(|item| !href::is_external(&item.href))
+        .filter(|item| !book.contains(&href::resolve(opf_path, &item.href)))
+        .map(|item| format!("manifest item {} points at missing {}", item.id, item.href))
+        .collect();
+
+    let documents = book
+        .content_documents()
+        .map(|entry| (entry, links::xhtml_links(&entry.data)));
+    let style_sheets = book
+        .entries
+        .iter()
+        // Classified like the fixer does, by sniffed media type.
+        .filter(|entry| media_type::sniff(&entry.name, &entry.data) == Some(media_type::CSS))
+        .map(|entry| {
+            (
+                entry,
+                links::css_urls(&String::from_utf8_lossy(&entry.data)),
+            )
+        });
+    let mut referenced = BTreeSet::new();
+    for (entry, entry_links) in documents.chain(style_sheets) {
+        referenced.extend(
+            entry_links
+                .iter()
+                .filter(|link| !href::is_external(link))
+                .map(|link| href::resolve(&entry.name, link)),
+        );
+    }
+    problems.extend(
+        book.entries
+            .iter()
+            .filter(|entry| !manifest::is_container_file(&entry.name, opf_path))
+            .filter(|entry| !declared.contains(&entry.name))
+            .map(|entry| {
+                if referenced.contains(&entry.name) {
+                    format!("{} is used but not in the manifest", entry.name)
+                } else {
+                    format!("{} is not in the manifest", entry.name)
+                }
+            }),
+    );
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::write_test_book;
+
+    const CONTAINER: &[u8] =
+        b"<container><rootfiles><rootfile full-path='OEBPS/content.opf'/></rootfiles></container>";
+
+    #[test]
+    fn check_passes_a_clean_book() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("book.epub");
+        write_test_book(
+            &path,
+            &[
+                ("mimetype", b"application/epub+zip"),
+                ("META-INF/container.xml", CONTAINER),
+                (
+                    "OEBPS/content.opf",
+                    b"<package version='3.0'><metadata xmlns:dc='http://purl.org/dc/elements/1.1/'>\
+                      <dc:language>en</dc:language></metadata><manifest>\
+                      <item id='c1' href='c1.xhtml' media-type='application/xhtml+xml'/>\
+                      </manifest></package>",
+                ),
+                (
+                    "OEBPS/c1.xhtml",
+                    b"<?xml version='1.0' encoding='utf-8'?><html><body><p>Hi</p></body></html>",
+                ),
+            ],
+        );
+        let report = check(path.to_str().unwrap(), Target::Kindle).unwrap();
+        assert_eq!(report.failures(), 0, "{report}");
+    }
+
+    #[test]
+    fn check_reports_each_broken_rule() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("book.epub");
+        write_test_book(
+            &path,
+            &[
+                ("META-INF/container.xml", CONTAINER),
+                ("mimetype", b"application/epub+zip"),
+                (
+                    "OEBPS/content.opf",
+                    b"<package version='3.0'><metadata xmlns:dc='http://purl.org/dc/elements/1.1/'>\
+                      <dc:language>english</dc:language></metadata><manifest>\
+                      <item id='c1' href='c1.xhtml' media-type='application/xhtml+xml'/>\
+                      <item id='c2' href='c2.xhtml' media-type='application/xhtml+xml'/>\
+                      <item id='gone' href='gone.css' media-type='text/css'/>\
+                      </manifest></package>",
+                ),
+                (
+                    "OEBPS/c1.xhtml",
+                    b"<html><body id='top'><img src='caf\xc3\xa9.webp'/></body></html>",
+                ),
+                (
+                    "OEBPS/c2.xhtml",
+                    b"<?xml version='1.0' encoding='utf-8'?><html><body><a href='c1.xhtml#top'>1</a></body></html>",
+                ),
+                ("OEBPS/caf\u{e9}.webp", b"RIFF\0\0\0\0WEBPVP8 "),
+            ],
+        );
+        let report = check(path.to_str().unwrap(), Target::Kindle).unwrap();
+        let failed: Vec<&str> = report
+            .results
+            .iter()
+            .filter(|result| !result.passed())
+            .map(|result| result.rule)
+            .collect();
+        assert_eq!(
+            failed,
+            [
+                "mimetype is the first, uncompressed entry",
+                "dc:language is a valid language tag",
+                "Content documents declare their encoding",
+                "No links to <body> ids",
+                "No unsupported media",
+                "File names are short, ASCII and unique",
+                "Manifest matches the archive",
+            ]
+        );
+        let manifest = &report.results.last().unwrap().problems;
+        assert_eq!(
+            manifest,
+            &[
+                "manifest item gone points at missing gone.css",
+                "OEBPS/caf\u{e9}.webp is used but not in the manifest",
+            ]
+        );
+    }
+
+    #[test]
+    fn check_manifest_counts_links_from_declared_content_documents() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("book.epub");
+        write_test_book(
+            &path,
+            &[
+                ("mimetype", b"application/epub+zip"),
+                ("META-INF/container.xml", CONTAINER),
+                (
+                    "OEBPS/content.opf",
+                    b"<package version='3.0'><metadata/><manifest>\
+                      <item id='c1' href='c1.htm' media-type='application/xhtml+xml'/>\
+                      </manifest></package>",
+                ),
+                (
+                    "OEBPS/c1.htm",
+                    b"<html><body><img src='a.png'/></body></html>",
+                ),
+                ("OEBPS/a.png", b"\x89PNG"),
+            ],
+        );
+        let book = Book::read(path.to_str().unwrap()).unwrap();
+        assert_eq!(
+            check_manifest(&book),
+            ["OEBPS/a.png is used but not in the manifest"]
+        );
+    }
+}
@@ -1,8 +1,249 @@
-use clap::Parser;
+use crate::archive::DuplicateMode;
+use crate::check::Target;
+use crate::css::CssMode;
+use crate::images::ImageMode;
+use crate::metadata::{Creator, MetadataUpdate, ModifiedMode, Series};
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Remove manifest items that point at files missing from the archive.
+    #[arg(long)]
+    pub remove_missing_items: bool,
+
+    /// Append chapters missing from the spine as non-linear instead of
+    /// inserting them in table-of-contents order.
+    #[arg(long)]
+    pub missing_chapters_nonlinear: bool,
+
+    /// Do not generate a dc:identifier for books that lack one.
+    #[arg(long)]
+    pub no_identifier: bool,
+
+    /// Do not add a dc:title from the first heading or file name.
+    #[arg(long)]
+    pub no_title: bool,
+
+    /// When to write dcterms:modified on EPUB 3 books.
+    #[arg(long, value_enum, default_value_t)]
+    pub modified: ModifiedMode,
+
+    /// Image to add to the book and use as its cover.
+    #[arg(long, value_name = "IMAGE")]
+    pub cover: Option<PathBuf>,
+
+    /// Mark the first image of the first chapter as the cover when nothing
+    /// else identifies one, even if that chapter is not a cover page.
+    #[arg(long)]
+    pub guess_cover: bool,
+
+    /// Replace an <svg> wrapping the cover image on the cover page with a
+    /// plain <img>, which Kindle renders more reliably.
+    #[arg(long)]
+    pub unwrap_svg_cover: bool,
+
+    /// Upgrade EPUB 2 books to EPUB 3, generating a navigation document from
+    /// the NCX and keeping the NCX for older readers.
+    #[arg(long)]
+    pub upgrade: bool,
+
+    /// Do not generate an NCX for books that lack one.
+    #[arg(long)]
+    pub no_ncx: bool,
+
+    /// Which entry to keep when the archive holds several with the same
+    /// name. Directory entries are always dropped.
+    #[arg(long, value_enum, default_value_t)]
+    pub duplicates: DuplicateMode,
+
+    /// Recover what is intact of archives that cannot be read, such as
+    /// truncated downloads or archives with a broken central directory.
+    #[arg(long)]
+    pub salvage: bool,
+
+    /// Write the same bytes every time the same book is fixed: entries in
+    /// name order with mimetype first, uniform permissions and compression,
+    /// and timestamps from SOURCE_DATE_EPOCH, or 1980-01-01 when it is unset.
+    #[arg(long)]
+    pub reproducible: bool,
+
+    /// Do not re-read the fixed book to check it is intact and that fixing
+    /// it again changes nothing.
+    #[arg(long)]
+    pub no_verify: bool,
+
+    /// Validate the fixed book, as the `validate` subcommand does, and fail
+    /// when it has errors.
+    #[arg(long)]
+    pub validate: bool,
+
+    /// What to do with CSS that Kindle renders poorly: `position: fixed`,
+    /// negative body margins, viewport units, remote @imports, huge body
+    /// font sizes and @font-face rules for missing fonts.
+    #[arg(long, value_enum, default_value_t)]
+    pub css: CssMode,
+
+    /// What to do with images that are missing from the archive, remote,
+    /// embedded as data: URIs or in a format Kindle does not support (WebP,
+    /// AVIF, TIFF).
+    #[arg(long, value_enum, default_value_t)]
+    pub images: ImageMode,
+
+    /// Move data: URI images into files of their own and declare them in the
+    /// manifest.
+    #[arg(long)]
+    pub extract_data_images: bool,
+
+    #[cfg(feature = "transcode")]
+    #[command(flatten)]
+    pub transcode: TranscodeArgs,
+
+    /// Do not rename files whose names Kindle and other readers choke on:
+    /// spaces, non-ASCII characters, `#`, `%`, backslashes and names that
+    /// differ only in case.
+    #[arg(long)]
+    pub keep_file_names: bool,
+
+    /// Deobfuscate embedded fonts, which Kindle cannot read, and remove them
+    /// from META-INF/encryption.xml.
+    #[arg(long)]
+    pub deobfuscate_fonts: bool,
+
+    /// Do not build a table of contents from the chapter headings of books
+    /// that have none.
+    #[arg(long)]
+    pub no_toc: bool,
+
+    /// The deepest heading level, 1 to 6, to include in a table of contents
+    /// built from the chapter headings.
+    #[arg(long, value_name = "LEVEL", default_value_t = 3,
+          value_parser = clap::value_parser!(u8).range(1..=6))]
+    pub toc_depth: u8,
+
     #[arg(last = true)]
     pub filenames: Vec<String>,
 }
+
+#[cfg(feature = "transcode")]
+#[derive(clap::Args, Debug)]
+pub struct TranscodeArgs {
+    /// Convert WebP, TIFF and BMP images to JPEG or PNG and downscale images
+    /// beyond --max-image-pixels or --max-image-bytes.
+    #[arg(long)]
+    pub transcode_images: bool,
+
+    /// The largest image, in pixels, before it is downscaled.
+    #[arg(long, value_name = "PIXELS", default_value_t = crate::transcode::Limits::default().max_pixels)]
+    pub max_image_pixels: u64,
+
+    /// The largest image file, in bytes, before it is downscaled.
+    #[arg(long, value_name = "BYTES", default_value_t = crate::transcode::Limits::default().max_bytes)]
+    pub max_image_bytes: usize,
+}
+
+#[cfg(feature = "transcode")]
+impl TranscodeArgs {
+    /// The limits to apply, if image transcoding is enabled.
+    pub fn limits(&self) -> Option<crate::transcode::Limits> {
+        self.transcode_images.then_some(crate::transcode::Limits {
+            max_pixels: self.max_image_pixels,
+            max_bytes: self.max_image_bytes,
+        })
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Show or edit the title, creators, series and other book metadata.
+    Meta(Box<MetaArgs>),
+    /// Check books against the rules a reading system rejects them for,
+    /// without modifying them. Fails if any rule fails.
+    Check(CheckArgs),
+    /// Validate the structure of books: the OCF container, the package
+    /// document, XHTML well-formedness and references. Messages use
+    /// epubcheck's IDs. Fails if there are errors.
+    Validate(ValidateArgs),
+}
+
+#[derive(clap::Args, Debug)]
+pub struct CheckArgs {
+    /// The reading system to check for.
+    #[arg(long, value_enum, default_value_t)]
+    pub target: Target,
+
+    /// The EPUBs to check.
+    #[arg(required = true)]
+    pub filenames: Vec<String>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ValidateArgs {
+    /// The EPUBs to validate.
+    #[arg(required = true)]
+    pub filenames: Vec<String>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct MetaArgs {
+    /// The EPUB to read. Without any field options its metadata is printed.
+    pub filename: String,
+
+    /// Where to write the edited book; defaults to `<name>-fixed.epub`.
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+
+    /// Set the title.
+    #[arg(long)]
+    pub title: Option<String>,
+
+    /// Replace the creators; repeat for several. ROLE is a MARC relator
+    /// code such as `aut` or `edt`.
+    #[arg(long = "creator", value_name = "NAME[|FILE-AS[|ROLE]]")]
+    pub creators: Vec<Creator>,
+
+    /// Set the series name; an empty name removes the series.
+    #[arg(long)]
+    pub series: Option<String>,
+
+    /// Set the position in the series.
+    #[arg(long, requires = "series")]
+    pub series_index: Option<String>,
+
+    /// Set the publisher.
+    #[arg(long)]
+    pub publisher: Option<String>,
+
+    /// Set the publication date, e.g. `2024-05-01`.
+    #[arg(long)]
+    pub date: Option<String>,
+
+    /// Replace the subjects; repeat for several.
+    #[arg(long = "subject", value_name = "SUBJECT")]
+    pub subjects: Vec<String>,
+
+    /// Set the description.
+    #[arg(long)]
+    pub description: Option<String>,
+}
+
+impl MetaArgs {
+    pub fn update(&self) -> MetadataUpdate {
+        MetadataUpdate {
+            title: self.title.clone(),
+            creators: (!self.creators.is_empty()).then(|| self.creators.clone()),
+            series: self.series.as_ref().map(|name| Series {
+                name: name.clone(),
+                index: self.series_index.clone(),
+            }),
+            publisher: self.publisher.clone(),
+            date: self.date.clone(),
+            subjects: (!self.subjects.is_empty()).then(|| self.subjects.clone()),
+            description: self.description.clone(),
+        }
+    }
+}
@@ -0,0 +1,368 @@
+use crate::opf::{self, Package};
+use crate::xml_doc::XmlElement;
+use crate::{href, images, manifest, svg};
+use scraper::{Html, Selector};
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+
+/// A cover image found by [`detect`], as an archive path.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Detected {
+    /// An image marked as the cover, named after it or shown alone on the
+    /// first page of the book.
+    Cover(String),
+    /// The first image of a first chapter that is not a cover page, which
+    /// may well not be a cover.
+    Guess(String),
+}
+
+/// Locates the cover image of `package`.
+///
+/// Tries, in order: the EPUB 3 `cover-image` property, the EPUB 2
+/// `<meta name="cover">`, the guide's cover reference, image file names
+/// containing "cover", and the first image of the first spine item, which
+/// is only a guess unless that item is a cover page. `media_types` maps
+/// archive entries to their sniffed media type and `read` returns the
+/// content of an entry.
+pub(crate) fn detect<'a>(
+    package: &Package,
+    opf_path: &str,
+    media_types: &BTreeMap<String, Option<&'static str>>,
+    read: impl Fn(&str) -> Option<&'a [u8]>,
+) -> Option<Detected> {
+    let is_image = |path: &str| {
+        media_types
+            .get(path)
+            .is_some_and(|media_type| media_type.is_some_and(|t| t.starts_with("image/")))
+    };
+    let resolve = |item_href: &str| href::resolve(opf_path, item_href);
+
+    let from_property = package
+        .items_with_property("cover-image")
+        .map(|item| resolve(&item.href))
+        .find(|path| is_image(path));
+    if let Some(path) = from_property {
+        return Some(Detected::Cover(path));
+    }
+
+    if let Some(content) = package.metadata.meta_content("cover") {
+        let path = match package.item(content) {
+            Some(item) => resolve(&item.href),
+            None => resolve(content),
+        };
+        if is_image(&path) {
+            return Some(Detected::Cover(path));
+        }
+    }
+
+    let first_image_in = |path: String| -> Option<String> {
+        if is_image(&path) {
+            return Some(path);
+        }
+        first_image(&path, read(&path)?, is_image)
+    };
+
+    let from_guide = package
+        .guide
+        .iter()
+        .filter(|reference| reference.kind.eq_ignore_ascii_case("cover"))
+        .find_map(|reference| first_image_in(resolve(&reference.href)));
+    if let Some(path) = from_guide {
+        return Some(Detected::Cover(path));
+    }
+
+    let mut named: Vec<String> = package
+        .manifest
+        .iter()
+        .map(|item| resolve(&item.href))
+        .filter(|path| is_image(path) && file_stem(path).contains("cover"))
+        .collect();
+    named.sort_by_key(|path| file_stem(path) != "cover");
+    if let Some(path) = named.into_iter().next() {
+        return Some(Detected::Cover(path));
+    }
+
+    let first = resolve(&package.spine_items().next()?.href);
+    if is_image(&first) {
+        return Some(Detected::Cover(first));
+    }
+    let content = read(&first)?;
+    if is_cover_page(content) {
+        return first_image(&first, content, is_image).map(Detected::Cover);
+    }
+    // Only suggest images Kindle can display.
+    let is_supported = |path: &str| {
+        is_image(path)
+            && !media_types.get(path).is_some_and(|media_type| {
+                media_type.is_some_and(|t| images::UNSUPPORTED.contains(&t))
+            })
+    };
+    first_image(&first, content, is_supported).map(Detected::Guess)
+}
+
+/// Whether `content` is a page showing a single image and no text.
+fn is_cover_page(content: &[u8]) -> bool {
+    let content = String::from_utf8_lossy(content);
+    if svg::wrapped_image(&content).is_some() {
+        return true;
+    }
+    let document = Html::parse_document(&content);
+    let Some(body) = document.select(&Selector::parse("body").unwrap()).next() else {
+        return false;
+    };
+    body.select(&Selector::parse("img, svg").unwrap()).count() == 1
+        && body.text().all(|text| text.trim().is_empty())
+}
+
+fn file_stem(path: &str) -> String {
+    Path::new(path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default()
+}
+
+/// The archive path of the first `<img>` or SVG `<image>` in a document
+/// that links to an image of the archive.
+fn first_image(path: &str, content: &[u8], is_image: impl Fn(&str) -> bool) -> Option<String> {
+    let document = Html::parse_document(&String::from_utf8_lossy(content));
+    document.tree.nodes().find_map(|node| {
+        let element = node.value().as_element()?;
+        let src = match element.name() {
+            "img" => element.attr("src")?,
+            "image" => svg::image_href(element)?,
+            _ => return None,
+        };
+        if href::is_external(src) {
+            return None;
+        }
+        Some(href::resolve(path, src)).filter(|image| is_image(image))
+    })
+}
+
+/// Marks the archive entry `cover` as the cover image of `package`.
+///
+/// Declares the image in the manifest if needed, points
+/// `<meta name="cover">` at it and, on EPUB 3 packages, moves the
+/// `cover-image` property to it. Returns true if the package was modified.
+pub(crate) fn repair(
+    package: &mut XmlElement,
+    opf_path: &str,
+    cover: &str,
+    media_type: &str,
+) -> bool {
+    let is_epub3 = opf::is_epub3(package);
+    let Some(manifest) = package.child_mut("manifest") else {
+        return false;
+    };
+
+    let mut changed = false;
+    let existing = manifest
+        .elements()
+        .filter(|item| item.local_name() == "item")
+        .find(|item| href::resolve(opf_path, item.attr("href").unwrap_or_default()) == cover)
+        .and_then(|item| item.attr("id"))
+        .map(String::from);
+    let id = match existing {
+        Some(id) => id,
+        None => {
+            let ids: HashSet<String> = manifest
+                .elements()
+                .filter_map(|item| item.attr("id"))
+                .map(String::from)
+                .collect();
+            let id = manifest::unique_id(cover, &ids);
+            report!("Cover: adding {cover} to the manifest as {id}");
+            let name = match manifest.elements().find(|e| e.local_name() == "item") {
+                Some(item) => item.name.clone(),
+                None => "item".to_string(),
+            };
+            manifest.append_child(
+                XmlElement::new(&name)
+                    .with_attr("id", &id)
+                    .with_attr("href", &href::relative(opf_path, cover))
+                    .with_attr("media-type", media_type),
+            );
+            changed = true;
+            id
+        }
+    };
+
+    if is_epub3 {
+        for item in manifest
+            .elements_mut()
+            .filter(|item| item.local_name() == "item")
+        {
+            let properties = item.attr("properties").unwrap_or_default().to_string();
+            let mut list: Vec<&str> = properties.split_whitespace().collect();
+            let has_cover = list.contains(&"cover-image");
+            let is_cover = item.attr("id") == Some(id.as_str());
+            if has_cover == is_cover {
+                continue;
+            }
+            if is_cover {
+                report!("Cover: adding the cover-image property to {id}");
+                list.push("cover-image");
+            } else {
+                let other = item.attr("id").unwrap_or_default();
+                report!("Cover: removing the cover-image property from {other}");
+                list.retain(|p| *p != "cover-image");
+            }
+            if list.is_empty() {
+                item.remove_attr("properties");
+            } else {
+                item.set_attr("properties", &list.join(" "));
+            }
+            changed = true;
+        }
+    }
+
+    let Some(metadata) = package.child_mut("metadata") else {
+        return changed;
+    };
+    let meta = metadata
+        .elements_mut()
+        .find(|e| e.local_name() == "meta" && e.attr("name") == Some("cover"));
+    match meta {
+        Some(meta) if meta.attr("content") == Some(id.as_str()) => {}
+        Some(meta) => {
+            report!("Cover: pointing <meta name=\"cover\"> at {id}");
+            meta.set_attr("content", &id);
+            changed = true;
+        }
+        None => {
+            report!("Cover: adding <meta name=\"cover\"> for {id}");
+            let name = match metadata.elements().find(|e| e.local_name() == "meta") {
+                Some(meta) => meta.name.clone(),
+                None => "meta".to_string(),
+            };
+            metadata.append_child(
+                XmlElement::new(&name)
+                    .with_attr("name", "cover")
+                    .with_attr("content", &id),
+            );
+            changed = true;
+        }
+    }
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::xml_doc::XmlDocument;
+
+    fn media_types(paths: &[&str]) -> BTreeMap<String, Option<&'static str>> {
+        paths
+            .iter()
+            .map(|path| (path.to_string(), crate::media_type::from_extension(path)))
+            .collect()
+    }
+
+    #[test]
+    fn detect_prefers_markers_then_falls_back_to_heuristics() {
+        let opf =
+            br#"<package version="2.0"><metadata><meta name="cover" content="img"/></metadata>
+            <manifest><item id="img" href="Images/front.jpg" media-type="image/jpeg"/>
+            <item id="c" href="Images/my-cover.png" media-type="image/png"/>
+            <item id="t" href="Text/title.xhtml" media-type="application/xhtml+xml"/></manifest>
+            <spine><itemref idref="t"/></spine></package>"#;
+        let archive = media_types(&[
+            "OEBPS/Images/front.jpg",
+            "OEBPS/Images/my-cover.png",
+            "OEBPS/Text/title.xhtml",
+        ]);
+        let package = Package::parse(opf).unwrap();
+        let cover = detect(&package, "OEBPS/content.opf", &archive, |_| None);
+        assert_eq!(
+            cover,
+            Some(Detected::Cover("OEBPS/Images/front.jpg".to_string()))
+        );
+
+        let opf = String::from_utf8_lossy(opf).replace(r#"<meta name="cover" content="img"/>"#, "");
+        let package = Package::parse(opf.as_bytes()).unwrap();
+        let cover = detect(&package, "OEBPS/content.opf", &archive, |_| None);
+        assert_eq!(
+            cover,
+            Some(Detected::Cover("OEBPS/Images/my-cover.png".to_string()))
+        );
+    }
+
+    #[test]
+    fn detect_only_guesses_the_first_image_of_a_chapter() {
+        let opf = br#"<package version="3.0"><metadata/>
+            <manifest><item id="img" href="a.jpg" media-type="image/jpeg"/>
+            <item id="t" href="t.xhtml" media-type="application/xhtml+xml"/></manifest>
+            <spine><itemref idref="t"/></spine></package>"#;
+        let archive = media_types(&["a.jpg", "b.webp", "t.xhtml"]);
+        let package = Package::parse(opf).unwrap();
+        let detect_with = |page: &'static [u8]| {
+            detect(&package, "content.opf", &archive, |path| {
+                (path == "t.xhtml").then_some(page)
+            })
+        };
+
+        let cover_page = br#"<html><body><p><img src="a.jpg"/></p></body></html>"#;
+        assert_eq!(
+            detect_with(cover_page),
+            Some(Detected::Cover("a.jpg".to_string()))
+        );
+        let chapter = br#"<html><body><h1>One</h1><img src="a.jpg"/><p>Text</p></body></html>"#;
+        assert_eq!(
+            detect_with(chapter),
+            Some(Detected::Guess("a.jpg".to_string()))
+        );
+        let chapter =
+            br#"<html><body><h1>One</h1><img src="b.webp"/><img src="a.jpg"/></body></html>"#;
+        assert_eq!(
+            detect_with(chapter),
+            Some(Detected::Guess("a.jpg".to_string()))
+        );
+    }
+
+    #[test]
+    fn repair_adds_both_cover_markers() {
+        let mut package = XmlDocument::parse(
+            br#"<package version="3.0"><metadata><meta property="dcterms:modified">x</meta></metadata><manifest>
+            <item id="old" href="old.jpg" media-type="image/jpeg" properties="cover-image"/>
+            <item id="img" href="a.jpg" media-type="image/jpeg"/></manifest></package>"#
+                .as_slice(),
+        )
+        .unwrap()
+        .root;
+        assert!(repair(&mut package, "content.opf", "a.jpg", "image/jpeg"));
+        let manifest = package.child("manifest").unwrap();
+        let props: Vec<_> = manifest
+            .elements()
+            .map(|item| item.attr("properties"))
+            .collect();
+        assert_eq!(props, [None, Some("cover-image")]);
+        let meta = package
+            .child("metadata")
+            .unwrap()
+            .elements()
+            .nth(1)
+            .unwrap();
+        assert_eq!(meta.attr("content"), Some("img"));
+        assert!(!repair(&mut package, "content.opf", "a.jpg", "image/jpeg"));
+    }
+
+    #[test]
+    fn repair_declares_new_cover_on_epub2() {
+        let mut package = XmlDocument::parse(
+            br#"<package version="2.0"><metadata/><manifest/></package>"#.as_slice(),
+        )
+        .unwrap()
+        .root;
+        assert!(repair(
+            &mut package,
+            "OEBPS/content.opf",
+            "OEBPS/cover.png",
+            "image/png"
+        ));
+        let item = package.child("manifest").unwrap().child("item").unwrap();
+        assert_eq!(item.attr("href"), Some("cover.png"));
+        assert_eq!(item.attr("properties"), None);
+        let meta = package.child("metadata").unwrap().child("meta").unwrap();
+        assert_eq!(meta.attr("content"), item.attr("id"));
+    }
+}
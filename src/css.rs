@@ -0,0 +1,649 @@
+//! Sanitation of CSS that Kindle's KF8 renderer handles poorly.
+//!
+//! The stylesheet is scanned statement by statement and copied through
+//! verbatim; only the declarations and rules that are reported get touched.
+
+use crate::{href, links};
+
+/// What to do with CSS rules that break Kindle rendering.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum CssMode {
+    /// Only report the rules.
+    #[default]
+    Report,
+    /// Replace values with safe equivalents, removing what cannot be kept.
+    Rewrite,
+    /// Remove the offending declarations and rules.
+    Remove,
+}
+
+/// Viewport-relative length units.
+const VIEWPORT_UNITS: &[&str] = &[
+    "vw", "vh", "vmin", "vmax", "svw", "svh", "lvw", "lvh", "dvw", "dvh", "vi", "vb",
+];
+
+/// Margin properties checked for negative values on `body`.
+const MARGINS: &[&str] = &[
+    "margin",
+    "margin-top",
+    "margin-right",
+    "margin-bottom",
+    "margin-left",
+];
+
+/// At-rules whose block holds further statements.
+const GROUPING_RULES: &[&str] = &["media", "supports", "document", "-moz-document", "layer"];
+
+/// Checks the stylesheet `css` of the archive entry `path` and, unless
+/// `mode` is [`CssMode::Report`], returns it with the offending rules
+/// rewritten or removed. `exists` tells whether an archive entry exists.
+pub(crate) fn sanitize(
+    css: &str,
+    path: &str,
+    exists: &impl Fn(&str) -> bool,
+    mode: CssMode,
+) -> Option<String> {
+    let mut sanitizer = Sanitizer {
+        path,
+        exists,
+        mode,
+        changed: false,
+    };
+    let out = sanitizer.statements(css);
+    sanitizer.changed.then_some(out)
+}
+
+/// Applies [`sanitize`] to the `<style>` elements of an XHTML document.
+pub(crate) fn sanitize_inline(
+    html: &str,
+    path: &str,
+    exists: &impl Fn(&str) -> bool,
+    mode: CssMode,
+) -> Option<String> {
+    let mut out = String::with_capacity(html.len());
+    let mut changed = false;
+    let mut rest = html;
+    while let Some(open) = find_ignore_case(rest, "<style") {
+        let Some(start) = rest[open..].find('>').map(|i| open + i + 1) else {
+            break;
+        };
+        let end = find_ignore_case(&rest[start..], "</style")
+            .map(|i| start + i)
+            .unwrap_or(rest.len());
+        out.push_str(&rest[..start]);
+        let css = &rest[start..end];
+        match sanitize(css, path, exists, mode) {
+            Some(fixed) => {
+                out.push_str(&fixed);
+                changed = true;
+            }
+            None => out.push_str(css),
+        }
+        rest = &rest[end..];
+    }
+    out.push_str(rest);
+    changed.then_some(out)
+}
+
+fn find_ignore_case(haystack: &str, needle: &str) -> Option<usize> {
+    haystack
+        .as_bytes()
+        .windows(needle.len())
+        .position(|window| window.eq_ignore_ascii_case(needle.as_bytes()))
+}
+
+struct Sanitizer<'a, F> {
+    path: &'a str,
+    exists: &'a F,
+    mode: CssMode,
+    changed: bool,
+}
+
+/// What to do with a declaration or rule.
+enum Action {
+    Keep,
+    Replace(String),
+    Remove,
+}
+
+impl<F: Fn(&str) -> bool> Sanitizer<'_, F> {
+    /// Copies a list of statements, sanitizing each.
+    fn statements(&mut self, css: &str) -> String {
+        let mut out = String::with_capacity(css.len());
+        let mut i = 0;
+        while i < css.len() {
+            let start = skip_trivia(css, i);
+            out.push_str(&css[i..start]);
+            if start >= css.len() {
+                break;
+            }
+            if css[start..].starts_with('}') {
+                out.push('}');
+                i = start + 1;
+                continue;
+            }
+
+            let Some(stop) = find_top_level(css, start, b"{;") else {
+                out.push_str(&css[start..]);
+                break;
+            };
+            if css.as_bytes()[stop] == b';' {
+                let statement = &css[start..=stop];
+                match self.at_statement(statement) {
+                    Action::Remove => self.changed = true,
+                    _ => out.push_str(statement),
+                }
+                i = stop + 1;
+                continue;
+            }
+
+            let end = block_end(css, stop);
+            let prelude = &css[start..stop];
+            let close = if css[..end].ends_with('}') {
+                end - 1
+            } else {
+                end
+            };
+            let body = &css[stop + 1..close];
+            match at_rule_name(prelude) {
+                Some(name) if GROUPING_RULES.contains(&name.as_str()) => {
+                    let inner = self.statements(body);
+                    if is_emptied(body, &inner) {
+                        i = remove_line(&mut out, css, end);
+                        continue;
+                    }
+                    out.push_str(&css[start..=stop]);
+                    out.push_str(&inner);
+                    out.push_str(&css[close..end]);
+                }
+                Some(name) if name == "font-face" => match self.font_face(body) {
+                    Action::Keep => out.push_str(&css[start..end]),
+                    Action::Replace(body) => {
+                        out.push_str(&css[start..=stop]);
+                        out.push_str(&body);
+                        out.push_str(&css[close..end]);
+                    }
+                    Action::Remove => {}
+                },
+                Some(_) => out.push_str(&css[start..end]),
+                None => {
+                    let declarations = self.declarations(prelude.trim(), body);
+                    if is_emptied(body, &declarations) {
+                        i = remove_line(&mut out, css, end);
+                        continue;
+                    }
+                    out.push_str(&css[start..=stop]);
+                    out.push_str(&declarations);
+                    out.push_str(&css[close..end]);
+                }
+            }
+            i = end;
+        }
+        out
+    }
+
+    fn report(&mut self, message: &str, action: &Action) {
+        let path = self.path;
+        match action {
+            Action::Keep => report!("CSS: {path}: {message}"),
+            Action::Replace(value) => report!("CSS: {path}: {message}, rewriting to {value}"),
+            Action::Remove => report!("CSS: {path}: {message}, removing it"),
+        }
+        if !matches!(action, Action::Keep) {
+            self.changed = true;
+        }
+    }
+
+    /// The action for `replacement` under the current mode.
+    fn action(&self, replacement: Option<String>) -> Action {
+        match (self.mode, replacement) {
+            (CssMode::Report, _) => Action::Keep,
+            (CssMode::Rewrite, Some(value)) => Action::Replace(value),
+            _ => Action::Remove,
+        }
+    }
+
+    fn at_statement(&mut self, statement: &str) -> Action {
+        if at_rule_name(statement).as_deref() != Some("import") {
+            return Action::Keep;
+        }
+        let Some(url) = links::css_urls(statement).into_iter().next() else {
+            return Action::Keep;
+        };
+        if !href::is_external(&url) || url.starts_with("data:") {
+            return Action::Keep;
+        }
+        let action = self.action(None);
+        self.report(&format!("@import of remote {url}"), &action);
+        action
+    }
+
+    fn font_face(&mut self, body: &str) -> Action {
+        let Some(src) = split_declarations(body)
+            .into_iter()
+            .find(|declaration| declaration.property().as_deref() == Some("src"))
+        else {
+            return Action::Keep;
+        };
+        let value = src.value();
+        let sources: Vec<&str> = split_top_level(value, b',');
+        let missing: Vec<String> = sources
+            .iter()
+            .flat_map(|source| links::css_urls(source))
+            .filter(|url| !href::is_external(url) && !(self.exists)(&href::resolve(self.path, url)))
+            .collect();
+        if missing.is_empty() {
+            return Action::Keep;
+        }
+
+        let kept: Vec<&str> = sources
+            .into_iter()
+            .filter(|source| {
+                links::css_urls(source)
+                    .iter()
+                    .all(|url| !missing.contains(url))
+            })
+            .map(str::trim)
+            .collect();
+        let message = format!(
+            "@font-face source {} is not in the archive",
+            missing.join(", ")
+        );
+        if kept.is_empty() || self.mode != CssMode::Rewrite {
+            let action = self.action(None);
+            self.report(&message, &action);
+            return action;
+        }
+        let kept = kept.join(", ");
+        self.report(&message, &Action::Replace(format!("src: {kept}")));
+        Action::Replace(body.replacen(value.trim(), &kept, 1))
+    }
+
+    fn declarations(&mut self, selector: &str, body: &str) -> String {
+        let is_body = selector
+            .split(',')
+            .any(|selector| last_type_selector(selector) == "body");
+        let mut out = String::with_capacity(body.len());
+        for declaration in split_declarations(body) {
+            let Some(property) = declaration.property() else {
+                out.push_str(declaration.text);
+                continue;
+            };
+            let value = declaration.value().trim();
+            let (value, important) = match value.to_ascii_lowercase().find("!important") {
+                Some(index) => (value[..index].trim(), &value[index..]),
+                None => (value, ""),
+            };
+
+            let Some((message, replacement)) = check(is_body, &property, value) else {
+                out.push_str(declaration.text);
+                continue;
+            };
+            let action = self.action(replacement);
+            self.report(&format!("{message} in `{selector}`"), &action);
+            match action {
+                Action::Keep => out.push_str(declaration.text),
+                Action::Replace(new_value) => {
+                    let colon = declaration.text.find(':').unwrap_or_default();
+                    let important = match important {
+                        "" => String::new(),
+                        important => format!(" {important}"),
+                    };
+                    let terminator = match declaration.text.strip_suffix(';') {
+                        Some(_) => ";",
+                        None => &declaration.text[declaration.text.trim_end().len()..],
+                    };
+                    out.push_str(&format!(
+                        "{}: {new_value}{important}{terminator}",
+                        &declaration.text[..colon]
+                    ));
+                }
+                Action::Remove => {
+                    // Keep the space before a closing brace.
+                    if !declaration.text.ends_with(';') {
+                        let text = declaration.text.trim_end();
+                        out.push_str(&declaration.text[text.len()..]);
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Checks one declaration; returns the problem and, when there is one, a
+/// safe replacement value.
+fn check(is_body: bool, property: &str, value: &str) -> Option<(String, Option<String>)> {
+    if property == "position" && value.eq_ignore_ascii_case("fixed") {
+        return Some(("position: fixed".into(), Some("static".into())));
+    }
+    if is_body && MARGINS.contains(&property) && value.split_whitespace().any(is_negative) {
+        let fixed: Vec<&str> = value
+            .split_whitespace()
+            .map(|part| if is_negative(part) { "0" } else { part })
+            .collect();
+        return Some((
+            format!("negative {property} {value} on body"),
+            Some(fixed.join(" ")),
+        ));
+    }
+    if is_body && property == "font-size" && is_huge_font_size(value) {
+        return Some((format!("font-size {value} on body"), Some("1em".into())));
+    }
+    if has_viewport_units(value) {
+        // Percentages resolve against the containing block, not the
+        // viewport, so there is no safe equivalent.
+        return Some((format!("viewport units in {property}: {value}"), None));
+    }
+    None
+}
+
+/// Whether sanitizing left nothing of a block that had content, in which
+/// case its rule is dropped too.
+fn is_emptied(body: &str, sanitized: &str) -> bool {
+    sanitized.trim().is_empty() && !body.trim().is_empty()
+}
+
+/// Drops a rule that ends at `end` of `css` from `out`, along with its line
+/// if it had one to itself. Returns where to continue in `css`.
+fn remove_line(out: &mut String, css: &str, end: usize) -> usize {
+    let indented = out.trim_end_matches([' ', '\t']).len();
+    if !out[..indented].is_empty() && !out[..indented].ends_with('\n') {
+        return end;
+    }
+    let rest = css[end..].trim_start_matches([' ', '\t']);
+    match rest
+        .strip_prefix("\r\n")
+        .or_else(|| rest.strip_prefix('\n'))
+    {
+        Some(after) => {
+            out.truncate(indented);
+            css.len() - after.len()
+        }
+        None => end,
+    }
+}
+
+fn is_negative(value: &str) -> bool {
+    value
+        .strip_prefix('-')
+        .is_some_and(|rest| rest.starts_with(|c: char| c.is_ascii_digit() || c == '.'))
+        && value
+            .trim_start_matches(['-', '0', '.'])
+            .chars()
+            .any(|c| c.is_ascii_digit())
+}
+
+/// Splits a CSS length such as `2.5em` into its number and lowercase unit.
+fn split_length(value: &str) -> Option<(f64, String)> {
+    let end = value
+        .find(|c: char| !(c.is_ascii_digit() || matches!(c, '.' | '-' | '+')))
+        .unwrap_or(value.len());
+    let number = value[..end].parse().ok()?;
+    Some((number, value[end..].to_ascii_lowercase()))
+}
+
+fn is_huge_font_size(value: &str) -> bool {
+    let value = value.trim().to_ascii_lowercase();
+    if matches!(value.as_str(), "xx-large" | "xxx-large") {
+        return true;
+    }
+    match split_length(&value) {
+        Some((size, unit)) => match unit.as_str() {
+            "em" | "rem" => size > 2.0,
+            "%" => size > 200.0,
+            "px" => size > 32.0,
+            "pt" => size > 24.0,
+            _ => false,
+        },
+        None => false,
+    }
+}
+
+/// The length tokens of a value, e.g. `calc(100vh - 2em)` gives `100vh` and
+/// `2em`.
+fn length_tokens(value: &str) -> impl Iterator<Item = &str> {
+    value
+        .split(|c: char| c.is_whitespace() || matches!(c, '(' | ')' | ',' | '/' | '*'))
+        .filter(|token| {
+            token.starts_with(|c: char| c.is_ascii_digit() || matches!(c, '.' | '-' | '+'))
+        })
+}
+
+fn has_viewport_units(value: &str) -> bool {
+    length_tokens(value).any(|token| {
+        split_length(token).is_some_and(|(_, unit)| VIEWPORT_UNITS.contains(&unit.as_str()))
+    })
+}
+
+/// The element name of the last compound selector, e.g. `body` for
+/// `html > body.chapter`.
+fn last_type_selector(selector: &str) -> String {
+    let last = selector
+        .rsplit(|c: char| c.is_whitespace() || matches!(c, '>' | '+' | '~'))
+        .find(|part| !part.is_empty())
+        .unwrap_or_default();
+    last.split(['.', '#', '[', ':'])
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase()
+}
+
+/// A declaration with its surrounding whitespace and terminating `;`.
+struct Declaration<'a> {
+    text: &'a str,
+}
+
+impl<'a> Declaration<'a> {
+    fn property(&self) -> Option<String> {
+        let colon = find_top_level(self.text, 0, b":")?;
+        let property = strip_comments(&self.text[..colon]);
+        let property = property.trim();
+        (!property.is_empty()).then(|| property.to_ascii_lowercase())
+    }
+
+    fn value(&self) -> &'a str {
+        let colon = find_top_level(self.text, 0, b":").map_or(self.text.len(), |i| i + 1);
+        self.text[colon..].trim_end_matches(';')
+    }
+}
+
+fn split_declarations(body: &str) -> Vec<Declaration<'_>> {
+    let mut declarations = Vec::new();
+    let mut start = 0;
+    while start < body.len() {
+        let end = find_top_level(body, start, b";").map_or(body.len(), |i| i + 1);
+        declarations.push(Declaration {
+            text: &body[start..end],
+        });
+        start = end;
+    }
+    declarations
+}
+
+fn split_top_level(value: &str, separator: u8) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    while let Some(index) = find_top_level(value, start, &[separator]) {
+        parts.push(&value[start..index]);
+        start = index + 1;
+    }
+    parts.push(&value[start..]);
+    parts
+}
+
+fn strip_comments(text: &str) -> String {
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("/*") {
+        out.push_str(&rest[..start]);
+        rest = rest[start..]
+            .find("*/")
+            .map_or("", |end| &rest[start + end + 2..]);
+    }
+    out.push_str(rest);
+    out
+}
+
+/// The lowercase name of an at-rule, e.g. `media` for `@media print`.
+fn at_rule_name(prelude: &str) -> Option<String> {
+    let name = prelude.trim_start().strip_prefix('@')?;
+    let end = name
+        .find(|c: char| !(c.is_alphanumeric() || c == '-'))
+        .unwrap_or(name.len());
+    Some(name[..end].to_ascii_lowercase())
+}
+
+/// Skips whitespace, comments and the `<!--`/`-->` markers of inline styles.
+fn skip_trivia(css: &str, mut i: usize) -> usize {
+    loop {
+        let rest = &css[i..];
+        let trimmed = rest.trim_start();
+        i += rest.len() - trimmed.len();
+        if trimmed.starts_with("/*") {
+            i += trimmed.find("*/").map_or(trimmed.len(), |end| end + 2);
+        } else if trimmed.starts_with("<!--") {
+            i += 4;
+        } else if trimmed.starts_with("-->") {
+            i += 3;
+        } else {
+            return i;
+        }
+    }
+}
+
+/// The index of the first of `targets` at nesting depth zero from `start`,
+/// skipping strings, comments and parenthesized or bracketed text.
+fn find_top_level(css: &str, start: usize, targets: &[u8]) -> Option<usize> {
+    let bytes = css.as_bytes();
+    let mut depth = 0usize;
+    let mut i = start;
+    while i < bytes.len() {
+        let byte = bytes[i];
+        match byte {
+            b'"' | b'\'' => {
+                i += 1;
+                while i < bytes.len() && bytes[i] != byte {
+                    i += if bytes[i] == b'\\' { 2 } else { 1 };
+                }
+            }
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                i = css[i + 2..]
+                    .find("*/")
+                    .map_or(bytes.len(), |end| i + 2 + end + 1);
+            }
+            b'\\' => i += 1,
+            _ if depth == 0 && targets.contains(&byte) => return Some(i),
+            b'(' | b'[' => depth += 1,
+            b')' | b']' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+/// The index just past the `}` matching the `{` at `open`.
+fn block_end(css: &str, open: usize) -> usize {
+    let mut depth = 0usize;
+    let mut i = open;
+    while let Some(index) = find_top_level(css, i, b"{}") {
+        if css.as_bytes()[index] == b'{' {
+            depth += 1;
+        } else {
+            depth -= 1;
+            if depth == 0 {
+                return index + 1;
+            }
+        }
+        i = index + 1;
+    }
+    css.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CSS: &str = r#"@import url("https://example.com/fonts.css");
+@import "base.css";
+body { margin: 0 -1em; font-size: 3em; }
+.header { position: fixed; top: 0 }
+@media screen {
+  .hero { height: 100vh !important; width: calc(50vw - 1em); color: blue }
+  .banner { width: 100vw }
+}
+@font-face { font-family: "A"; src: url(../Fonts/missing.otf), url(../Fonts/a.otf); }
+@font-face { font-family: "B"; src: url(../Fonts/gone.ttf); }
+p { color: red }
+"#;
+
+    fn exists(path: &str) -> bool {
+        path == "OEBPS/Fonts/a.otf"
+    }
+
+    #[test]
+    fn report_mode_leaves_css_untouched() {
+        assert_eq!(
+            sanitize(CSS, "OEBPS/Styles/style.css", &exists, CssMode::Report),
+            None
+        );
+    }
+
+    #[test]
+    fn rewrite_mode_replaces_values() {
+        let css = sanitize(CSS, "OEBPS/Styles/style.css", &exists, CssMode::Rewrite).unwrap();
+        assert_eq!(
+            css,
+            r#"
+@import "base.css";
+body { margin: 0 0; font-size: 1em; }
+.header { position: static; top: 0 }
+@media screen {
+  .hero { color: blue }
+}
+@font-face { font-family: "A"; src: url(../Fonts/a.otf); }
+
+p { color: red }
+"#
+        );
+        assert_eq!(
+            sanitize(&css, "OEBPS/Styles/style.css", &exists, CssMode::Rewrite),
+            None
+        );
+    }
+
+    #[test]
+    fn remove_mode_drops_declarations_and_rules() {
+        let css = sanitize(CSS, "OEBPS/Styles/style.css", &exists, CssMode::Remove).unwrap();
+        assert_eq!(
+            css,
+            r#"
+@import "base.css";
+.header { top: 0 }
+@media screen {
+  .hero { color: blue }
+}
+
+
+p { color: red }
+"#
+        );
+
+        let css = "p { color: red }\n@media print {\n  .page { height: 100vh; }\n}\n";
+        assert_eq!(
+            sanitize(css, "OEBPS/Styles/style.css", &exists, CssMode::Rewrite).as_deref(),
+            Some("p { color: red }\n")
+        );
+    }
+
+    #[test]
+    fn sanitize_inline_rewrites_style_elements() {
+        let html = "<html><head><STYLE type=\"text/css\"><!-- .x { position: fixed } --></STYLE></head></html>";
+        let fixed = sanitize_inline(html, "OEBPS/ch1.xhtml", &exists, CssMode::Rewrite).unwrap();
+        assert_eq!(
+            fixed,
+            "<html><head><STYLE type=\"text/css\"><!-- .x { position: static } --></STYLE></head></html>"
+        );
+    }
+}
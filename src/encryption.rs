@@ -0,0 +1,255 @@
+//! Reading of `META-INF/encryption.xml`: font obfuscation and DRM.
+
+use crate::opf::Package;
+use crate::xml_doc::XmlElement;
+use crate::{href, media_type};
+use sha1_smol::Sha1;
+use std::collections::{BTreeMap, BTreeSet};
+use uuid::Uuid;
+use xmltree::Element;
+
+pub(crate) const ENCRYPTION_PATH: &str = "META-INF/encryption.xml";
+
+/// The IDPF font obfuscation algorithm of the EPUB OCF specification.
+pub(crate) const IDPF_ALGORITHM: &str = "http://www.idpf.org/2008/embedding";
+/// Adobe's font obfuscation algorithm, found in many EPUB 2 books.
+pub(crate) const ADOBE_ALGORITHM: &str = "http://ns.adobe.com/pdf/enc#RC";
+
+/// How an embedded font is obfuscated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Obfuscation {
+    Idpf,
+    Adobe,
+}
+
+/// The resources `META-INF/encryption.xml` declares as encrypted.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub(crate) struct Encryption {
+    /// Obfuscated fonts by archive path.
+    pub(crate) obfuscated: BTreeMap<String, Obfuscation>,
+    /// Resources encrypted with any other algorithm, i.e. DRM, with the
+    /// algorithm URI.
+    pub(crate) encrypted: BTreeMap<String, String>,
+}
+
+impl Encryption {
+    pub(crate) fn parse(content: &[u8]) -> Option<Encryption> {
+        let root = Element::parse(content).ok()?;
+        let mut encryption = Encryption::default();
+        for data in root
+            .children
+            .iter()
+            .filter_map(|node| node.as_element())
+            .filter(|element| element.name == "EncryptedData")
+        {
+            let algorithm = data
+                .get_child("EncryptionMethod")
+                .and_then(|method| method.attributes.get("Algorithm"))
+                .map(|algorithm| algorithm.trim())
+                .unwrap_or_default();
+            let Some(uri) = data
+                .get_child("CipherData")
+                .and_then(|cipher| cipher.get_child("CipherReference"))
+                .and_then(|reference| reference.attributes.get("URI"))
+            else {
+                continue;
+            };
+
+            let path = href::resolve("", uri);
+            match algorithm {
+                IDPF_ALGORITHM => {
+                    encryption.obfuscated.insert(path, Obfuscation::Idpf);
+                }
+                ADOBE_ALGORITHM => {
+                    encryption.obfuscated.insert(path, Obfuscation::Adobe);
+                }
+                _ => {
+                    encryption.encrypted.insert(path, algorithm.to_string());
+                }
+            }
+        }
+        Some(encryption)
+    }
+
+    /// True when resources are encrypted with something other than font
+    /// obfuscation.
+    pub(crate) fn is_drm(&self) -> bool {
+        !self.encrypted.is_empty()
+    }
+}
+
+impl Obfuscation {
+    /// The number of leading bytes the algorithm scrambles.
+    fn length(self) -> usize {
+        match self {
+            Obfuscation::Idpf => 1040,
+            Obfuscation::Adobe => 1024,
+        }
+    }
+
+    /// The key derived from the identifiers of `package`: the SHA-1 of the
+    /// unique identifier without whitespace for IDPF, the bytes of the first
+    /// UUID identifier for Adobe.
+    fn key(self, package: &Package) -> Option<Vec<u8>> {
+        match self {
+            Obfuscation::Idpf => {
+                let uid: String = package
+                    .unique_identifier_value()?
+                    .chars()
+                    .filter(|c| !matches!(c, ' ' | '\t' | '\r' | '\n'))
+                    .collect();
+                Some(Sha1::from(uid).digest().bytes().to_vec())
+            }
+            Obfuscation::Adobe => {
+                let uid = package.unique_identifier_value();
+                let identifiers = package.metadata.dc("identifier").map(|e| e.value.as_str());
+                uid.into_iter().chain(identifiers).find_map(|identifier| {
+                    let identifier = identifier.trim();
+                    let uuid = identifier.strip_prefix("urn:uuid:").unwrap_or(identifier);
+                    Some(Uuid::parse_str(uuid).ok()?.as_bytes().to_vec())
+                })
+            }
+        }
+    }
+}
+
+/// Reverses the obfuscation of a font with the key from `package`.
+///
+/// Returns `None` when no key can be derived or the result does not look
+/// like a font, e.g. because the identifier changed since the book was made.
+pub(crate) fn deobfuscate(
+    data: &[u8],
+    obfuscation: Obfuscation,
+    package: &Package,
+) -> Option<Vec<u8>> {
+    let key = obfuscation.key(package)?;
+    let mut font = data.to_vec();
+    for (byte, key) in font
+        .iter_mut()
+        .take(obfuscation.length())
+        .zip(key.iter().cycle())
+    {
+        *byte ^= key;
+    }
+    media_type::sniff("", &font)
+        .is_some_and(|media_type| media_type.starts_with("font/"))
+        .then_some(font)
+}
+
+/// Removes the `EncryptedData` entries for `paths` from an
+/// `encryption.xml` root. Returns true if any were removed.
+pub(crate) fn remove_entries(encryption: &mut XmlElement, paths: &BTreeSet<String>) -> bool {
+    let mut changed = false;
+    encryption.retain_elements(|data| {
+        let uri = data
+            .child("CipherData")
+            .and_then(|cipher| cipher.child("CipherReference"))
+            .and_then(|reference| reference.attr("URI"));
+        let remove = data.local_name() == "EncryptedData"
+            && uri.is_some_and(|uri| paths.contains(&href::resolve("", uri)));
+        changed |= remove;
+        !remove
+    });
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::xml_doc::XmlDocument;
+
+    #[test]
+    fn parse_separates_obfuscated_fonts_from_drm() {
+        let xml = br#"<encryption xmlns="urn:oasis:names:tc:opendocument:xmlns:container"
+            xmlns:enc="http://www.w3.org/2001/04/xmlenc#">
+  <enc:EncryptedData>
+    <enc:EncryptionMethod Algorithm="http://www.idpf.org/2008/embedding"/>
+    <enc:CipherData><enc:CipherReference URI="OEBPS/Fonts/My%20Font.otf"/></enc:CipherData>
+  </enc:EncryptedData>
+  <enc:EncryptedData>
+    <enc:EncryptionMethod Algorithm="http://ns.adobe.com/pdf/enc#RC"/>
+    <enc:CipherData><enc:CipherReference URI="/OEBPS/Fonts/b.ttf"/></enc:CipherData>
+  </enc:EncryptedData>
+</encryption>"#;
+        let encryption = Encryption::parse(xml).unwrap();
+        assert!(!encryption.is_drm());
+        assert_eq!(
+            encryption.obfuscated,
+            BTreeMap::from([
+                ("OEBPS/Fonts/My Font.otf".to_string(), Obfuscation::Idpf),
+                ("OEBPS/Fonts/b.ttf".to_string(), Obfuscation::Adobe),
+            ])
+        );
+
+        let xml = String::from_utf8_lossy(xml).replace(
+            "http://ns.adobe.com/pdf/enc#RC",
+            "http://www.w3.org/2001/04/xmlenc#aes128-cbc",
+        );
+        let encryption = Encryption::parse(xml.as_bytes()).unwrap();
+        assert!(encryption.is_drm());
+        assert_eq!(encryption.obfuscated.len(), 1);
+    }
+
+    fn package(identifier: &str) -> Package {
+        let opf = format!(
+            r#"<package version="3.0" unique-identifier="uid"><metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+            <dc:identifier id="uid">{identifier}</dc:identifier></metadata><manifest/></package>"#
+        );
+        Package::parse(opf.as_bytes()).unwrap()
+    }
+
+    fn obfuscate(font: &[u8], key: &[u8], length: usize) -> Vec<u8> {
+        font.iter()
+            .enumerate()
+            .map(|(i, byte)| {
+                if i < length {
+                    byte ^ key[i % key.len()]
+                } else {
+                    *byte
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn deobfuscate_reverses_idpf_and_adobe_obfuscation() {
+        let mut font = b"OTTO".to_vec();
+        font.extend((0..2000u32).map(|i| (i % 251) as u8));
+
+        let book = package(" urn:uuid:6ba7b810-9dad-11d1-80b4-00c04fd430c8\n");
+        let key = Sha1::from("urn:uuid:6ba7b810-9dad-11d1-80b4-00c04fd430c8")
+            .digest()
+            .bytes();
+        let obfuscated = obfuscate(&font, &key, 1040);
+        assert_eq!(
+            deobfuscate(&obfuscated, Obfuscation::Idpf, &book).as_deref(),
+            Some(font.as_slice())
+        );
+
+        let key = Uuid::parse_str("6ba7b810-9dad-11d1-80b4-00c04fd430c8").unwrap();
+        let obfuscated = obfuscate(&font, key.as_bytes(), 1024);
+        assert_eq!(
+            deobfuscate(&obfuscated, Obfuscation::Adobe, &book).as_deref(),
+            Some(font.as_slice())
+        );
+
+        let other = package("isbn:9780000000000");
+        assert_eq!(deobfuscate(&obfuscated, Obfuscation::Adobe, &other), None);
+    }
+
+    #[test]
+    fn remove_entries_drops_deobfuscated_fonts() {
+        let mut document = XmlDocument::parse(
+            br#"<encryption xmlns:enc="http://www.w3.org/2001/04/xmlenc#">
+  <enc:EncryptedData><enc:CipherData><enc:CipherReference URI="a.otf"/></enc:CipherData></enc:EncryptedData>
+  <enc:EncryptedData><enc:CipherData><enc:CipherReference URI="b.otf"/></enc:CipherData></enc:EncryptedData>
+</encryption>"#
+                .as_slice(),
+        )
+        .unwrap();
+        let paths = BTreeSet::from(["a.otf".to_string()]);
+        assert!(remove_entries(&mut document.root, &paths));
+        assert!(!remove_entries(&mut document.root, &paths));
+        assert_eq!(document.root.elements().count(), 1);
+    }
+}
@@ -1,13 +1,35 @@
-use crate::{encoding_matcher, error::FixError};
+#[cfg(feature = "transcode")]
+use crate::transcode;
+use crate::{
+    archive::DuplicateMode,
+    cover::{self, Detected},
+    css::{self, CssMode},
+    encoding_matcher,
+    encryption::{self, Encryption, Obfuscation},
+    error::FixError,
+    filenames, headings, href,
+    images::{self, ImageMode},
+    links, manifest, media_type,
+    metadata::{self, BookMetadata, MetadataUpdate, ModifiedMode},
+    ncx,
+    opf::{self, Package},
+    output, salvage, spine, svg,
+    toc::{self, TocEntry},
+    upgrade::{self, Upgrade},
+    validate, xhtml,
+    xml_doc::{XmlDocument, XmlElement},
+};
 use indicatif::{ProgressBar, ProgressStyle};
 use language_tags::LanguageTag;
-use scraper::{Html, Selector};
+use scraper::{node::Text, Html, Node, Selector};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
 use std::fs::File;
-use std::io::{BufWriter, Read, Write};
+use std::io::{Cursor, Read, Write};
 use std::path::{Path, PathBuf};
-use xmltree::{Element, EmitterConfig, XMLNode};
-use zip::{write::SimpleFileOptions, ZipArchive, ZipWriter};
+use xmltree::Element;
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipArchive, ZipWriter};
 
+#[derive(Clone)]
 struct ArchiveEntry {
     name: String,
     data: Vec<u8>,
@@ -30,45 +52,576 @@ pub(crate) fn change_file_stem(original_path: &Path, new_stem: &str) -> PathBuf
     new_path
 }
 
-pub(crate) fn fix(filename: &str, output_filename: &Path) -> Result<(), FixError> {
-    let file = File::open(filename)?;
-    let mut archive = ZipArchive::new(file)?;
+#[derive(Debug, Clone)]
+pub struct FixOptions {
+    /// Remove manifest items whose file is missing instead of only reporting them.
+    pub remove_missing_items: bool,
+    /// Append chapters missing from the spine with `linear="no"` instead of
+    /// inserting them in table-of-contents order.
+    pub missing_chapters_nonlinear: bool,
+    /// Generate a `dc:identifier` when the package has none.
+    pub generate_identifier: bool,
+    /// Add a `dc:title` from the first heading or the file name when missing.
+    pub fill_title: bool,
+    /// When to write `dcterms:modified` on EPUB 3 books.
+    pub modified: ModifiedMode,
+    /// An image to add to the book and mark as its cover.
+    pub cover: Option<PathBuf>,
+    /// Mark the first image of the first chapter as the cover when nothing
+    /// else identifies one, even if that chapter is not a cover page.
+    pub guess_cover: bool,
+    /// Replace an `<svg>` wrapping the cover image on the cover page with a
+    /// plain `<img>`.
+    pub unwrap_svg_cover: bool,
+    /// Upgrade EPUB 2 books to EPUB 3.
+    pub upgrade: bool,
+    /// Generate an NCX for books that lack one.
+    pub generate_ncx: bool,
+    /// What to do with CSS that Kindle renders poorly.
+    pub css: CssMode,
+    /// What to do with images that are missing, remote, `data:` URIs or in
+    /// a format Kindle does not support.
+    pub images: ImageMode,
+    /// Move `data:` URI images into files of their own.
+    pub extract_data_images: bool,
+    /// Convert unsupported images and downscale the ones beyond these limits.
+    #[cfg(feature = "transcode")]
+    pub transcode: Option<transcode::Limits>,
+    /// Rename archive entries with spaces, non-ASCII characters, `#`, `%`,
+    /// backslashes or case-only collisions, updating every reference.
+    pub sanitize_file_names: bool,
+    /// Deobfuscate the fonts listed in `META-INF/encryption.xml` and remove
+    /// their encryption entries.
+    pub deobfuscate_fonts: bool,
+    /// Build a table of contents from the chapter headings of books without
+    /// a usable one.
+    pub generate_toc: bool,
+    /// The deepest heading level, 1 to 6, in a generated table of contents.
+    pub toc_depth: usize,
+    /// Which entry to keep when the archive holds several with the same name.
+    pub duplicates: DuplicateMode,
+    /// Recover the intact entries of archives that cannot be read, scanning
+    /// for local file headers instead of using the central directory.
+    pub salvage: bool,
+    /// Write a reproducible archive: entries sorted with `mimetype` first,
+    /// with uniform permissions and compression, and every timestamp set to
+    /// these seconds since the Unix epoch.
+    pub reproducible: Option<u64>,
+    /// Re-read the written book and check that it is intact and that fixing
+    /// it again changes nothing.
+    pub verify: bool,
+    /// Validate the written book and fail when it has errors.
+    pub validate: bool,
+}
 
-    let output_file = File::create(output_filename)?;
-    let mut output_zip = ZipWriter::new(output_file);
+impl Default for FixOptions {
+    fn default() -> Self {
+        FixOptions {
+            remove_missing_items: false,
+            missing_chapters_nonlinear: false,
+            generate_identifier: true,
+            fill_title: true,
+            modified: ModifiedMode::default(),
+            cover: None,
+            guess_cover: false,
+            unwrap_svg_cover: false,
+            upgrade: false,
+            generate_ncx: true,
+            css: CssMode::default(),
+            images: ImageMode::default(),
+            extract_data_images: false,
+            #[cfg(feature = "transcode")]
+            transcode: None,
+            sanitize_file_names: true,
+            deobfuscate_fonts: false,
+            generate_toc: true,
+            toc_depth: 3,
+            duplicates: DuplicateMode::default(),
+            salvage: false,
+            reproducible: None,
+            verify: true,
+            validate: false,
+        }
+    }
+}
 
-    let mut entries = Vec::with_capacity(archive.len());
-    let mut body_id_list: Vec<(String, String)> = Vec::new();
-    let mut opf_path = String::new();
+pub(crate) fn fix(
+    filename: &str,
+    output_filename: &Path,
+    options: &FixOptions,
+) -> Result<(), FixError> {
+    let entries = match read_entries(filename) {
+        Err(err) if options.salvage => salvage_entries(filename, err)?,
+        result => result?,
+    };
+    let (entries, book) = fix_archive(entries, filename, options)?;
 
-    for i in 0..archive.len() {
-        let mut file = archive.by_index(i)?;
-        let mut content = Vec::new();
-        file.read_to_end(&mut content)?;
+    let mut written = Vec::with_capacity(entries.len());
+    write_entries(output_filename, entries, |entry| {
+        let content = process_file(entry.name.as_str(), &entry.data, &book, options);
+        written.push((entry.name.clone(), content.len()));
+        content
+    })?;
 
-        let file_name = file.name().to_string();
-        if is_xhtml(&file_name) {
-            if let Some(entry) = collect_body_id(&file_name, &content) {
-                body_id_list.push(entry);
-            }
+    if options.verify {
+        if let Err(reason) = verify_output(output_filename, &written, filename, &book, options) {
+            // The output is unusable; do not leave it behind.
+            let _ = std::fs::remove_file(output_filename);
+            return Err(FixError::VerificationFailed(
+                output_filename.to_string_lossy().to_string(),
+                reason,
+            ));
         }
+        report!(
+            "Verify: {} entries re-read, fixing again changes nothing",
+            written.len()
+        );
+    }
+    if options.validate {
+        validate_output(output_filename)?;
+    }
+    Ok(())
+}
+
+/// Runs the fixes that add, remove or rename archive entries of the book
+/// `filename`, and gathers the context `process_file` then rewrites each
+/// entry with.
+fn fix_archive(
+    mut entries: Vec<ArchiveEntry>,
+    filename: &str,
+    options: &FixOptions,
+) -> Result<(Vec<ArchiveEntry>, BookContext), FixError> {
+    resolve_duplicates(&mut entries, options.duplicates);
+    drop_directory_entries(&mut entries);
+    let encryption = read_encryption(&entries);
+    if encryption.is_drm() {
+        return Err(FixError::DrmProtected(format!(
+            "{filename} has {} encrypted files",
+            encryption.encrypted.len()
+        )));
+    }
+    let mut opf = PackageDocument::read(&entries).ok();
+    if options.deobfuscate_fonts && !encryption.obfuscated.is_empty() {
+        deobfuscate_fonts(&mut entries, &encryption, opf.as_ref());
+    }
+    if options.extract_data_images {
+        extract_data_images(&mut entries, opf.as_ref());
+    }
+    #[cfg(feature = "transcode")]
+    if let Some(limits) = &options.transcode {
+        transcode_images(&mut entries, limits, opf.as_mut());
+    }
+    if options.sanitize_file_names {
+        sanitize_file_names(&mut entries, opf.as_mut());
+    }
 
-        if file_name == "META-INF/container.xml" {
-            if let Some(path) = get_opf_filename(&content) {
-                opf_path = path;
+    let new_cover = match &options.cover {
+        Some(path) => Some(add_cover(&mut entries, path)?),
+        None => None,
+    };
+
+    let upgrade = match &opf {
+        Some(opf) if options.upgrade => plan_upgrade(&mut entries, opf),
+        _ => None,
+    };
+
+    if options.generate_identifier {
+        // Before the NCX is generated, which carries the identifier.
+        if let Some(opf) = &mut opf {
+            let seed = options.reproducible.map(|_| opf.document.to_bytes());
+            opf.edit(&mut entries, |package| {
+                metadata::ensure_identifier(package, seed.as_deref())
+            });
+        }
+    }
+    let mut book = BookContext::new(&entries, opf, options);
+    if let Some(new_cover) = new_cover {
+        let old_cover = book.cover.replace(new_cover.clone());
+        replace_cover_image(&mut entries, &book, old_cover.as_deref(), &new_cover);
+        book.svg_cover_page = book.find_svg_cover_page(&entries);
+    }
+    if let Some(nav_path) = upgrade
+        .as_ref()
+        .and_then(|upgrade| upgrade.nav_path.as_ref())
+    {
+        book.add_content_document(nav_path);
+    }
+    book.upgrade = upgrade.map(|upgrade| Upgrade {
+        properties: book.content_properties(&entries),
+        ..upgrade
+    });
+    if let Some(page) = book.svg_cover_page.clone() {
+        if options.unwrap_svg_cover {
+            unwrap_svg_cover(&mut entries, &mut book, &page);
+        } else {
+            report!(
+                "Cover: {page} wraps the cover image in an <svg>, which Kindle may render blank \
+                 (--unwrap-svg-cover replaces it with an <img>)"
+            );
+        }
+    }
+    if options.generate_toc && book.generated_toc.is_empty() && !book.has_ncx_toc(&entries) {
+        synthesize_toc(&mut entries, &mut book, options.toc_depth);
+    }
+    if book
+        .upgrade
+        .as_ref()
+        .is_some_and(|upgrade| upgrade.nav_path.is_none())
+    {
+        // An EPUB 3 package must have a navigation document.
+        report!("Upgrade: no table of contents for a navigation document, keeping EPUB 2");
+        book.upgrade = None;
+    }
+    if book.ncx_path.is_none() && options.generate_ncx && book.package.is_some() {
+        let ncx_path = unused_entry_name(&entries, &book.opf_path, "toc", "ncx");
+        match book.generate_ncx(&ncx_path) {
+            Some(ncx) => {
+                report!("NCX: generating {ncx_path}");
+                entries.push(ArchiveEntry {
+                    name: ncx_path.clone(),
+                    data: ncx,
+                    options: SimpleFileOptions::default(),
+                });
+                book.ncx_path = Some(ncx_path);
             }
+            None => report!("NCX: no table of contents to generate an NCX from"),
         }
+    }
+    book.timestamp = match options.reproducible {
+        Some(seconds) => metadata::format_timestamp(seconds),
+        None => metadata::now_timestamp(),
+    };
+    if book.fallback_title.is_empty() {
+        book.fallback_title = Path::new(filename)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().replace(['_', '-'], " "))
+            .unwrap_or_default();
+    }
+
+    if let Some(seconds) = options.reproducible {
+        make_reproducible(&mut entries, seconds);
+    }
+    Ok((entries, book))
+}
+
+/// Validates the book written to `output_filename`, reporting every message,
+/// and fails when any is an error. The output is kept for inspection.
+fn validate_output(output_filename: &Path) -> Result<(), FixError> {
+    let output_filename = output_filename.to_string_lossy();
+    let messages = validate::validate(&output_filename)?;
+    for message in &messages {
+        report!("Validate: {message}");
+    }
+    let errors = validate::error_count(&messages);
+    if errors > 0 {
+        return Err(FixError::ValidationFailed(
+            output_filename.to_string(),
+            errors,
+        ));
+    }
+    report!("Validate: no errors found");
+    Ok(())
+}
+
+/// Re-reads the book written to `output_filename` and checks that it holds
+/// exactly the `written` entries and sizes, that its package and content
+/// documents parse, and that running the whole pipeline on it again, as the
+/// book `filename`, changes nothing. `book` is the context of the first run:
+/// a package document is only required if it had one, and its timestamp and
+/// fallback title are reused.
+fn verify_output(
+    output_filename: &Path,
+    written: &[(String, usize)],
+    filename: &str,
+    book: &BookContext,
+    options: &FixOptions,
+) -> Result<(), String> {
+    let file = File::open(output_filename).map_err(|err| err.to_string())?;
+    let mut archive = ZipArchive::new(file).map_err(|err| err.to_string())?;
+    if archive.len() != written.len() {
+        return Err(format!(
+            "{} entries were written but {} were read back",
+            written.len(),
+            archive.len()
+        ));
+    }
+    for (name, size) in written {
+        let entry = archive
+            .by_name(name)
+            .map_err(|err| format!("{name}: {err}"))?;
+        if entry.size() != *size as u64 {
+            return Err(format!(
+                "{name} was written with {size} bytes but has {}",
+                entry.size()
+            ));
+        }
+    }
+
+    let entries =
+        read_entries(&output_filename.to_string_lossy()).map_err(|err| err.to_string())?;
+    if book.package.is_some() {
+        let opf = PackageDocument::read(&entries).map_err(|err| err.to_string())?;
+        if let Err(err) = &opf.package {
+            return Err(format!("{}: {err}", opf.path));
+        }
+    }
+
+    // The cover given on the command line is already in the book.
+    let options = FixOptions {
+        cover: None,
+        ..options.clone()
+    };
+    let (fixed, mut again) = output::muted(|| fix_archive(entries.clone(), filename, &options))
+        .map_err(|err| format!("fixing again fails: {err}"))?;
+    for entry in entries.iter().filter(|entry| again.is_xhtml(&entry.name)) {
+        if !xhtml::is_well_formed(&entry.data, again.is_epub3()) {
+            return Err(format!("{} is not well-formed XHTML", entry.name));
+        }
+    }
+
+    again.timestamp = book.timestamp.clone();
+    again.fallback_title = book.fallback_title.clone();
+    let mut changed: BTreeSet<&str> = output::muted(|| {
+        fixed
+            .iter()
+            .filter(|entry| {
+                entries
+                    .iter()
+                    .find(|written| written.name == entry.name)
+                    .is_none_or(|written| {
+                        process_file(&entry.name, &entry.data, &again, &options) != written.data
+                    })
+            })
+            .map(|entry| entry.name.as_str())
+            .collect()
+    });
+    changed.extend(
+        entries
+            .iter()
+            .filter(|entry| !fixed.iter().any(|fixed| fixed.name == entry.name))
+            .map(|entry| entry.name.as_str()),
+    );
+    if !changed.is_empty() {
+        let changed: Vec<&str> = changed.into_iter().collect();
+        return Err(format!("fixing again changes {}", changed.join(", ")));
+    }
+    Ok(())
+}
+
+/// Writes `update` into the package metadata of `filename`, saving the book
+/// as `output_filename`. Nothing else in the archive is changed.
+pub fn update_metadata(
+    filename: &str,
+    output_filename: &Path,
+    update: &MetadataUpdate,
+) -> Result<(), FixError> {
+    let entries = read_entries(filename)?;
+    let opf_path = container_opf_path(&entries)
+        .ok_or_else(|| FixError::InvalidPackage("no rootfile in container.xml".to_string()))?;
+    let content = entries
+        .iter()
+        .find(|entry| entry.name == opf_path)
+        .map(|entry| entry.data.as_slice())
+        .ok_or_else(|| FixError::InvalidPackage(format!("{opf_path} is not in the archive")))?;
+
+    let mut opf = XmlDocument::parse(content)?;
+    let current = BookMetadata::from_package(&Package::from_element(&opf.root)?);
+    let opf_content = if update.apply(&mut opf.root, &current) {
+        metadata::ensure_modified(
+            &mut opf.root,
+            &metadata::now_timestamp(),
+            ModifiedMode::Always,
+        );
+        opf.to_bytes()
+    } else {
+        report!("Metadata: nothing to change");
+        content.to_vec()
+    };
+
+    write_entries(output_filename, entries, |entry| {
+        if entry.name == opf_path {
+            opf_content.clone()
+        } else {
+            entry.data.clone()
+        }
+    })
+}
+
+fn read_entries(filename: &str) -> Result<Vec<ArchiveEntry>, FixError> {
+    let data = std::fs::read(filename)?;
+    let mut archive = ZipArchive::new(Cursor::new(data.as_slice()))?;
+
+    // ZipArchive collapses entries with the same name into one, so read
+    // every copy from the local headers for `resolve_duplicates` to choose.
+    let duplicates = salvage::duplicate_names(&data);
+    if !duplicates.is_empty() {
+        let salvaged = salvage::salvage(&data);
+        if salvaged.lost.is_empty() {
+            return Ok(recovered_entries(salvaged.entries));
+        }
+        let names = duplicates.into_iter().collect::<Vec<_>>().join(", ");
+        report!("Duplicates: not every copy of {names} can be read, keeping the indexed one");
+    }
+
+    let mut entries = Vec::with_capacity(archive.len());
+
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        let mut content = Vec::new();
+        file.read_to_end(&mut content)?;
 
         let options = SimpleFileOptions::default()
             .compression_method(file.compression())
             .unix_permissions(file.unix_mode().unwrap_or(0o755));
 
         entries.push(ArchiveEntry {
-            name: file_name,
+            name: file.name().to_string(),
             data: content,
             options,
         });
     }
+    Ok(entries)
+}
+
+/// Recovers the intact entries of `filename`, an archive that could not be
+/// read because of `err`, putting back a `mimetype` entry if it was lost.
+fn salvage_entries(filename: &str, err: FixError) -> Result<Vec<ArchiveEntry>, FixError> {
+    let data = std::fs::read(filename)?;
+    report!("Salvage: {err}, scanning the archive for intact entries");
+    let salvaged = salvage::salvage(&data);
+    for (name, reason) in &salvaged.lost {
+        report!("Salvage: lost {name}: {reason}");
+    }
+    if salvaged.entries.is_empty() {
+        return Err(err);
+    }
+    report!("Salvage: recovered {} entries", salvaged.entries.len());
+
+    let mut entries = recovered_entries(salvaged.entries);
+    if !entries.iter().any(|entry| entry.name == "mimetype") {
+        report!("Salvage: adding the lost mimetype entry");
+        entries.insert(
+            0,
+            ArchiveEntry {
+                name: "mimetype".to_string(),
+                data: b"application/epub+zip".to_vec(),
+                options: SimpleFileOptions::default().compression_method(CompressionMethod::Stored),
+            },
+        );
+    }
+    Ok(entries)
+}
+
+fn recovered_entries(recovered: Vec<salvage::Recovered>) -> Vec<ArchiveEntry> {
+    recovered
+        .into_iter()
+        .map(|entry| ArchiveEntry {
+            name: entry.name,
+            data: entry.data,
+            options: SimpleFileOptions::default().compression_method(entry.compression),
+        })
+        .collect()
+}
+
+/// Keeps one of the entries that share a name, chosen by `mode`, in the
+/// place of the first of them.
+fn resolve_duplicates(entries: &mut Vec<ArchiveEntry>, mode: DuplicateMode) {
+    let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+    for entry in entries.iter() {
+        *counts.entry(entry.name.clone()).or_default() += 1;
+    }
+    if counts.values().all(|&count| count == 1) {
+        return;
+    }
+
+    let mut positions: BTreeMap<String, usize> = BTreeMap::new();
+    let mut kept: Vec<ArchiveEntry> = Vec::with_capacity(counts.len());
+    for entry in entries.drain(..) {
+        let Some(&index) = positions.get(&entry.name) else {
+            positions.insert(entry.name.clone(), kept.len());
+            kept.push(entry);
+            continue;
+        };
+        let replace = match mode {
+            DuplicateMode::Last => true,
+            DuplicateMode::Largest => entry.data.len() > kept[index].data.len(),
+        };
+        if replace {
+            kept[index] = entry;
+        }
+    }
+    for (name, count) in counts.into_iter().filter(|(_, count)| *count > 1) {
+        let size = kept[positions[&name]].data.len();
+        let which = match mode {
+            DuplicateMode::Last => "last",
+            DuplicateMode::Largest => "largest",
+        };
+        report!("Duplicates: {name} appears {count} times, keeping the {which} ({size} bytes)");
+    }
+    *entries = kept;
+}
+
+/// Drops directory entries, which EPUB readers do not need and some tools
+/// write as empty files.
+fn drop_directory_entries(entries: &mut Vec<ArchiveEntry>) {
+    entries.retain(|entry| {
+        let is_directory = entry.name.ends_with('/');
+        if is_directory {
+            report!("Directories: dropping directory entry {}", entry.name);
+        }
+        !is_directory
+    });
+}
+
+/// Orders `entries` by name with `mimetype` first and gives them all the same
+/// timestamp, `seconds` since the Unix epoch, permissions and compression, so
+/// that the written archive only depends on their names and content.
+fn make_reproducible(entries: &mut [ArchiveEntry], seconds: u64) {
+    entries.sort_by(|a, b| (a.name != "mimetype", &a.name).cmp(&(b.name != "mimetype", &b.name)));
+    let options = SimpleFileOptions::default()
+        .last_modified_time(zip_date_time(seconds))
+        .unix_permissions(0o644);
+    for entry in entries {
+        entry.options = if entry.name == "mimetype" {
+            options.compression_method(CompressionMethod::Stored)
+        } else {
+            options
+                .compression_method(CompressionMethod::Deflated)
+                .compression_level(Some(6))
+        };
+    }
+}
+
+/// The ZIP timestamp of `seconds` since the Unix epoch. ZIP timestamps run
+/// from 1980 to 2107; times outside that range become 1980-01-01.
+fn zip_date_time(seconds: u64) -> zip::DateTime {
+    let (year, month, day) = metadata::civil_from_days((seconds / 86_400) as i64);
+    let time = seconds % 86_400;
+    u16::try_from(year)
+        .ok()
+        .and_then(|year| {
+            zip::DateTime::from_date_and_time(
+                year,
+                month as u8,
+                day as u8,
+                (time / 3600) as u8,
+                (time / 60 % 60) as u8,
+                (time % 60) as u8,
+            )
+            .ok()
+        })
+        .unwrap_or_default()
+}
+
+/// Writes `entries` to a new archive at `output_filename`, taking the content
+/// of each entry from `process`.
+fn write_entries(
+    output_filename: &Path,
+    entries: Vec<ArchiveEntry>,
+    mut process: impl FnMut(&ArchiveEntry) -> Vec<u8>,
+) -> Result<(), FixError> {
+    let output_file = File::create(output_filename)?;
+    let mut output_zip = ZipWriter::new(output_file);
 
     let pb = ProgressBar::new(entries.len() as u64);
     let style =
@@ -76,8 +629,7 @@ pub(crate) fn fix(filename: &str, output_filename: &Path) -> Result<(), FixError
     pb.set_style(style);
 
     for entry in entries {
-        let modified_content =
-            process_file(entry.name.as_str(), &entry.data, &body_id_list, &opf_path);
+        let modified_content = process(&entry);
         output_zip.start_file(entry.name, entry.options)?;
         output_zip.write_all(&modified_content)?;
         pb.inc(1);
@@ -88,38 +640,852 @@ pub(crate) fn fix(filename: &str, output_filename: &Path) -> Result<(), FixError
     Ok(())
 }
 
+/// Adds the image at `path` to the archive next to the package document and
+/// returns its entry name.
+fn add_cover(entries: &mut Vec<ArchiveEntry>, path: &Path) -> Result<String, FixError> {
+    let data = std::fs::read(path)?;
+    let extension = match media_type::sniff(&path.to_string_lossy(), &data) {
+        Some("image/jpeg") => "jpg",
+        Some("image/png") => "png",
+        Some("image/gif") => "gif",
+        _ => {
+            return Err(FixError::InvalidCover(format!(
+                "{} is not a JPEG, PNG or GIF image",
+                path.display()
+            )))
+        }
+    };
+
+    let opf_path = container_opf_path(entries).unwrap_or_default();
+    let name = unused_entry_name(entries, &opf_path, "cover", extension);
+
+    report!("Cover: adding {} as {name}", path.display());
+    entries.push(ArchiveEntry {
+        name: name.clone(),
+        data,
+        options: SimpleFileOptions::default().compression_method(CompressionMethod::Stored),
+    });
+    Ok(name)
+}
+
+/// Points the cover pages, the guide's cover reference and the first spine
+/// item, from the `old` cover image at the `new` one given with `--cover`.
+fn replace_cover_image(
+    entries: &mut [ArchiveEntry],
+    book: &BookContext,
+    old: Option<&str>,
+    new: &str,
+) {
+    let Some(package) = &book.package else {
+        return;
+    };
+    let guide_pages: BTreeSet<String> = package
+        .guide
+        .iter()
+        .filter(|reference| reference.kind.eq_ignore_ascii_case("cover"))
+        .map(|reference| href::resolve(&book.opf_path, &reference.href))
+        .collect();
+    let mut pages = guide_pages.clone();
+    pages.extend(
+        package
+            .spine_items()
+            .next()
+            .map(|item| href::resolve(&book.opf_path, &item.href)),
+    );
+    let renamed: BTreeMap<String, String> = old
+        .map(|old| (old.to_string(), new.to_string()))
+        .into_iter()
+        .collect();
+
+    for page in &pages {
+        let Some(entry) = entries.iter_mut().find(|entry| entry.name == *page) else {
+            continue;
+        };
+        let content = String::from_utf8_lossy(&entry.data);
+        match links::rewrite_xhtml(&content, page, page, &renamed) {
+            Some(content) => {
+                report!("Cover: pointing {page} at {new}");
+                entry.data = content.into_bytes();
+            }
+            None if guide_pages.contains(page.as_str()) => {
+                report!("Cover: {page} does not show the old cover image, leaving it unchanged");
+            }
+            None => {}
+        }
+    }
+}
+
+/// Prepares the EPUB 3 upgrade of an EPUB 2 book: finds the manifest
+/// properties its content documents need and adds a navigation document
+/// built from the NCX to the archive.
+fn plan_upgrade(entries: &mut Vec<ArchiveEntry>, opf: &PackageDocument) -> Option<Upgrade> {
+    let opf_path = &opf.path;
+    let find = |path: &str| entries.iter().find(|entry| entry.name == path);
+    let package = opf.package.as_ref().ok()?;
+    if package.is_epub3() {
+        return None;
+    }
+
+    // Content document properties are computed from the repaired manifest.
+    let mut upgrade = Upgrade::default();
+
+    // The NCX is repaired before the navigation document is built from it, so
+    // that the nav does not link entries missing from the archive.
+    let ncx = package
+        .ncx()
+        .map(|item| href::resolve(opf_path, &item.href))
+        .and_then(|path| {
+            let mut ncx = XmlDocument::parse(&find(&path)?.data).ok()?;
+            let exists = |target: &str| find(target).is_some();
+            output::muted(|| ncx::repair(&mut ncx.root, &path, exists, None));
+            Some((path, toc::parse_ncx(&ncx.to_bytes())?))
+        })
+        .filter(|(_, toc)| !toc.is_empty());
+    let Some((ncx_path, entries_toc)) = ncx else {
+        report!("Upgrade: no NCX to build a navigation document from");
+        return Some(upgrade);
+    };
+
+    let nav_path = unused_entry_name(entries, &ncx_path, "nav", "xhtml");
+    let nav = toc::nav_document(
+        "Contents",
+        Some(book_language(package)),
+        &toc::rebase(&entries_toc, &ncx_path, &nav_path),
+        &upgrade::landmarks(&package.guide, opf_path, &nav_path),
+    );
+    entries.push(ArchiveEntry {
+        name: nav_path.clone(),
+        data: nav.into_bytes(),
+        options: SimpleFileOptions::default(),
+    });
+    upgrade.nav_path = Some(nav_path);
+    Some(upgrade)
+}
+
+/// Builds a table of contents from the `h1` to `h<depth>` headings of the
+/// spine documents, adding ids to the headings that lack one. EPUB 3 books,
+/// and books being upgraded, also get a navigation document.
+fn synthesize_toc(entries: &mut Vec<ArchiveEntry>, book: &mut BookContext, depth: usize) {
+    let Some(package) = &book.package else {
+        return;
+    };
+
+    let mut found = Vec::new();
+    for item in package.spine_items() {
+        let path = href::resolve(&book.opf_path, &item.href);
+        let Some(entry) = entries.iter_mut().find(|entry| entry.name == path) else {
+            continue;
+        };
+        let (document_headings, content) = headings::collect(&path, &entry.data, depth);
+        if let Some(content) = content {
+            entry.data = content;
+        }
+        found.extend(document_headings);
+    }
+    if found.is_empty() {
+        report!("TOC: no headings to build a table of contents from");
+        return;
+    }
+    report!(
+        "TOC: building a table of contents from {} headings",
+        found.len()
+    );
+    book.generated_toc = headings::nest(&found);
+
+    let existing_nav = package
+        .items_with_property("nav")
+        .next()
+        .map(|item| href::resolve(&book.opf_path, &item.href));
+    if !package.is_epub3() && book.upgrade.is_none() {
+        return;
+    }
+    let nav_path = match &existing_nav {
+        Some(path) => path.clone(),
+        None => unused_entry_name(entries, &book.opf_path, "nav", "xhtml"),
+    };
+    let nav = toc::nav_document(
+        "Contents",
+        Some(book_language(package)),
+        &toc::rebase(&book.generated_toc, "", &nav_path),
+        &upgrade::landmarks(&package.guide, &book.opf_path, &nav_path),
+    )
+    .into_bytes();
+
+    if let Some(entry) = entries.iter_mut().find(|entry| entry.name == nav_path) {
+        report!("TOC: replacing the empty navigation document {nav_path}");
+        entry.data = nav;
+        return;
+    }
+    report!("TOC: generating navigation document {nav_path}");
+    entries.push(ArchiveEntry {
+        name: nav_path.clone(),
+        data: nav,
+        options: SimpleFileOptions::default(),
+    });
+    book.media_types
+        .insert(nav_path.clone(), Some(media_type::XHTML));
+    book.add_content_document(&nav_path);
+    match &mut book.upgrade {
+        Some(upgrade) => upgrade.nav_path = Some(nav_path),
+        None => book.generated_nav = Some(nav_path),
+    }
+}
+
+fn read_encryption(entries: &[ArchiveEntry]) -> Encryption {
+    let Some(entry) = entries
+        .iter()
+        .find(|entry| entry.name == encryption::ENCRYPTION_PATH)
+    else {
+        return Encryption::default();
+    };
+    Encryption::parse(&entry.data).unwrap_or_else(|| {
+        report!("Encryption: cannot read {}", encryption::ENCRYPTION_PATH);
+        Encryption::default()
+    })
+}
+
+/// Replaces the obfuscated fonts of `encryption` with plain ones and removes
+/// them from `META-INF/encryption.xml`, or the whole file once it is empty.
+/// The font keys come from the identifiers of `opf`.
+fn deobfuscate_fonts(
+    entries: &mut Vec<ArchiveEntry>,
+    encryption: &Encryption,
+    opf: Option<&PackageDocument>,
+) {
+    let Some(package) = opf.and_then(|opf| opf.package.as_ref().ok()) else {
+        report!("Encryption: no package document to derive the font keys from");
+        return;
+    };
+
+    let mut plain = BTreeSet::new();
+    for (path, &obfuscation) in &encryption.obfuscated {
+        let Some(entry) = entries.iter_mut().find(|entry| &entry.name == path) else {
+            continue;
+        };
+        match encryption::deobfuscate(&entry.data, obfuscation, package) {
+            Some(font) => {
+                report!("Encryption: deobfuscating {path}");
+                entry.data = font;
+                plain.insert(path.clone());
+            }
+            None => report!("Encryption: cannot deobfuscate {path}, the key does not match"),
+        }
+    }
+    if plain.is_empty() {
+        return;
+    }
+
+    let Some(index) = entries
+        .iter()
+        .position(|entry| entry.name == encryption::ENCRYPTION_PATH)
+    else {
+        return;
+    };
+    let Ok(mut document) = XmlDocument::parse(&entries[index].data) else {
+        return;
+    };
+    encryption::remove_entries(&mut document.root, &plain);
+    if document.root.elements().next().is_none() {
+        report!("Encryption: removing {}", encryption::ENCRYPTION_PATH);
+        entries.remove(index);
+    } else {
+        entries[index].data = document.to_bytes();
+    }
+}
+
+/// Moves the `data:` URI images of content documents into archive files next
+/// to the documents and points the `<img>`s at them. The manifest pass then
+/// declares the new files, since they are referenced.
+fn extract_data_images(entries: &mut Vec<ArchiveEntry>, opf: Option<&PackageDocument>) {
+    let selector = Selector::parse("img[src^='data:']").unwrap();
+    let documents = opf.and_then(PackageDocument::content_documents);
+    for index in 0..entries.len() {
+        let path = entries[index].name.clone();
+        if !is_content_document(documents.as_ref(), &path) {
+            continue;
+        }
+        let mut html = String::from_utf8_lossy(&entries[index].data).to_string();
+        let uris: Vec<String> = Html::parse_document(&html)
+            .select(&selector)
+            .filter_map(|img| img.value().attr("src"))
+            .map(String::from)
+            .collect();
+        if uris.is_empty() {
+            continue;
+        }
+
+        let stem = Path::new(&path)
+            .file_stem()
+            .map(|stem| format!("{}-image", stem.to_string_lossy()))
+            .unwrap_or_else(|| "image".to_string());
+        for uri in uris {
+            let Some((data, extension)) = images::decode_data_uri(&uri) else {
+                report!("Images: {path}: cannot decode a data: URI image");
+                continue;
+            };
+            if !html.contains(&uri) {
+                continue;
+            }
+            let name = unused_entry_name(entries, &path, &stem, extension);
+            report!("Images: {path}: extracting a data: URI image to {name}");
+            html = html.replacen(&uri, &href::relative(&path, &name), 1);
+            entries.push(ArchiveEntry {
+                name,
+                data,
+                options: SimpleFileOptions::default(),
+            });
+        }
+        entries[index].data = html.into_bytes();
+    }
+}
+
+/// Converts and downscales the images of the book according to `limits`,
+/// renaming the ones whose format changes and updating every reference.
+#[cfg(feature = "transcode")]
+fn transcode_images(
+    entries: &mut [ArchiveEntry],
+    limits: &transcode::Limits,
+    opf: Option<&mut PackageDocument>,
+) {
+    let mut renamed = BTreeMap::new();
+    for index in 0..entries.len() {
+        let path = entries[index].name.clone();
+        let Some(media_type) = media_type::sniff(&path, &entries[index].data) else {
+            continue;
+        };
+        let Some(image) = transcode::transcode(&entries[index].data, media_type, limits) else {
+            continue;
+        };
+        report!("Images: {path}: {}", image.reason);
+        entries[index].data = image.data;
+        if media_type::from_extension(&path) == Some(image.media_type) {
+            continue;
+        }
+        let stem = Path::new(&path)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let name = unused_entry_name(entries, &path, &stem, image.extension);
+        report!("Images: renaming {path} to {name}");
+        entries[index].name = name.clone();
+        renamed.insert(path, name);
+    }
+    if !renamed.is_empty() {
+        rename_references(entries, &renamed, opf);
+    }
+}
+
+/// Renames the archive entries whose names readers choke on and updates every
+/// reference to them. Container files and obfuscated fonts keep their names,
+/// which other files of the container refer to.
+fn sanitize_file_names(entries: &mut [ArchiveEntry], opf: Option<&mut PackageDocument>) {
+    let opf_path = container_opf_path(entries).unwrap_or_default();
+    let obfuscated = read_encryption(entries).obfuscated;
+    let renamed = filenames::safe_names(entries.iter().map(|entry| entry.name.as_str()), |name| {
+        manifest::is_container_file(name, &opf_path) || obfuscated.contains_key(name)
+    });
+    if renamed.is_empty() {
+        return;
+    }
+    for entry in entries.iter_mut() {
+        if let Some(name) = renamed.get(&entry.name) {
+            report!("File names: renaming {} to {name}", entry.name);
+            entry.name = name.clone();
+        }
+    }
+    rename_references(entries, &renamed, opf);
+}
+
+/// Points every reference to the archive entries renamed in `renamed` — in
+/// content documents, stylesheets, the NCX and the package document `opf` —
+/// at their new names, updating the manifest media types to match. The
+/// entries already carry their new names.
+fn rename_references(
+    entries: &mut [ArchiveEntry],
+    renamed: &BTreeMap<String, String>,
+    opf: Option<&mut PackageDocument>,
+) {
+    let old_names: BTreeMap<&str, &str> = renamed
+        .iter()
+        .map(|(old, new)| (new.as_str(), old.as_str()))
+        .collect();
+    let (opf_path, content_documents) = match opf {
+        Some(opf) => {
+            let path = opf.path.clone();
+            opf.edit(entries, |root| manifest::rename(root, &path, renamed));
+            (path, opf.content_documents())
+        }
+        None => (container_opf_path(entries).unwrap_or_default(), None),
+    };
+    for entry in entries.iter_mut() {
+        if entry.name == opf_path {
+            continue;
+        }
+
+        let rewrite = match media_type::from_extension(&entry.name) {
+            _ if is_content_document(content_documents.as_ref(), &entry.name) => {
+                links::rewrite_xhtml
+            }
+            // The `<content src>` of NCX navPoints are link attributes too.
+            Some(media_type::NCX) => links::rewrite_xhtml,
+            Some(media_type::CSS) => links::rewrite_css,
+            _ => continue,
+        };
+        let content = String::from_utf8_lossy(&entry.data);
+        let old_name = old_names.get(entry.name.as_str()).copied();
+        let old_base = old_name.unwrap_or(&entry.name);
+        if let Some(content) = rewrite(&content, old_base, &entry.name, renamed) {
+            entry.data = content.into_bytes();
+        }
+    }
+}
+
+/// Replaces the `<svg>` wrapping the cover image on `page` with an `<img>`.
+fn unwrap_svg_cover(entries: &mut [ArchiveEntry], book: &mut BookContext, page: &str) {
+    let Some(entry) = entries.iter_mut().find(|entry| entry.name == page) else {
+        return;
+    };
+    let content = String::from_utf8_lossy(&entry.data).to_string();
+    let Some(wrapped) = svg::wrapped_image(&content) else {
+        return;
+    };
+    report!("Cover: replacing the <svg> wrapper on {page} with an <img>");
+    entry.data = svg::unwrap(&content, &wrapped).into_bytes();
+    book.svg_cover_unwrapped = true;
+    if let Some(properties) = book
+        .upgrade
+        .as_mut()
+        .and_then(|upgrade| upgrade.properties.get_mut(page))
+    {
+        properties.retain(|property| *property != "svg");
+    }
+}
+
+fn container_opf_path(entries: &[ArchiveEntry]) -> Option<String> {
+    entries
+        .iter()
+        .find(|entry| entry.name == "META-INF/container.xml")
+        .and_then(|entry| get_opf_filename(&entry.data))
+}
+
+/// An archive entry name for `<stem>.<extension>` next to `sibling` that is
+/// not taken yet, adding `-2`, `-3`, ... to the stem as needed.
+fn unused_entry_name(
+    entries: &[ArchiveEntry],
+    sibling: &str,
+    stem: &str,
+    extension: &str,
+) -> String {
+    let mut name = href::resolve(sibling, &format!("{stem}.{extension}"));
+    let mut n = 2;
+    while entries.iter().any(|entry| entry.name == name) {
+        name = href::resolve(sibling, &format!("{stem}-{n}.{extension}"));
+        n += 1;
+    }
+    name
+}
+
+/// The package document of a book, parsed once: the tree the package fixes
+/// edit and the typed model read from it.
+struct PackageDocument {
+    path: String,
+    document: XmlDocument,
+    package: Result<Package, FixError>,
+}
+
+impl PackageDocument {
+    /// Parses the package document `META-INF/container.xml` names.
+    fn read(entries: &[ArchiveEntry]) -> Result<PackageDocument, FixError> {
+        let path = container_opf_path(entries)
+            .ok_or_else(|| FixError::InvalidPackage("no rootfile in container.xml".to_string()))?;
+        let entry = entries
+            .iter()
+            .find(|entry| entry.name == path)
+            .ok_or_else(|| FixError::InvalidPackage(format!("{path} is not in the archive")))?;
+        let document = XmlDocument::parse(&entry.data)?;
+        let package = Package::from_element(&document.root);
+        Ok(PackageDocument {
+            path,
+            document,
+            package,
+        })
+    }
+
+    /// Applies `edit` to the package element. When it reports a change, the
+    /// document is written back to its entry and the model re-read from it.
+    fn edit(&mut self, entries: &mut [ArchiveEntry], edit: impl FnOnce(&mut XmlElement) -> bool) {
+        if !edit(&mut self.document.root) {
+            return;
+        }
+        self.package = Package::from_element(&self.document.root);
+        if let Some(entry) = entries.iter_mut().find(|entry| entry.name == self.path) {
+            entry.data = self.document.to_bytes();
+        }
+    }
+
+    fn content_documents(&self) -> Option<HashSet<String>> {
+        let package = self.package.as_ref().ok()?;
+        Some(declared_content_documents(package, &self.path))
+    }
+}
+
+/// Book-wide information gathered before any entry is rewritten.
+#[derive(Default)]
+struct BookContext {
+    opf_path: String,
+    /// The package document the package fixes start from.
+    opf: Option<XmlDocument>,
+    package: Option<Package>,
+    /// Entries the OPF manifest declares as XHTML content documents, or
+    /// `None` when the manifest could not be read.
+    content_documents: Option<HashSet<String>>,
+    body_id_list: Vec<(String, String)>,
+    /// Every archive entry with the media type sniffed from its content.
+    media_types: BTreeMap<String, Option<&'static str>>,
+    /// Entries linked from content documents and stylesheets.
+    references: BTreeSet<String>,
+    /// Entries linked from the table of contents, in reading order.
+    toc_order: Vec<String>,
+    /// Title to use when the package has none: the first heading in the
+    /// spine, or the input file name.
+    fallback_title: String,
+    /// The `dcterms:modified` value for this run.
+    timestamp: String,
+    /// Archive path of the cover image, if one was found or given.
+    cover: Option<String>,
+    /// Archive path of the first image of the first chapter, reported as a
+    /// possible cover when nothing else identifies one.
+    cover_guess: Option<String>,
+    /// The planned EPUB 3 upgrade, when requested for an EPUB 2 book.
+    upgrade: Option<Upgrade>,
+    /// Archive path of the NCX, if the book has or gets one.
+    ncx_path: Option<String>,
+    /// Table of contents to build a missing NCX from, with archive paths as
+    /// hrefs.
+    generated_toc: Vec<TocEntry>,
+    /// Archive path of a navigation document generated from the headings,
+    /// to declare in the manifest.
+    generated_nav: Option<String>,
+    /// Fonts obfuscated according to `META-INF/encryption.xml`, which are
+    /// passed through untouched.
+    obfuscated: BTreeMap<String, Obfuscation>,
+    /// The content document wrapping the cover image in an `<svg>`.
+    svg_cover_page: Option<String>,
+    /// Whether that `<svg>` was replaced with an `<img>`, so the page no
+    /// longer needs the `svg` manifest property.
+    svg_cover_unwrapped: bool,
+}
+
+impl BookContext {
+    /// Gathers the context of the book in `entries`, whose package document
+    /// `opf` has already been parsed.
+    fn new(entries: &[ArchiveEntry], opf: Option<PackageDocument>, options: &FixOptions) -> Self {
+        let mut book = BookContext::default();
+
+        match opf {
+            Some(opf) => {
+                book.opf_path = opf.path;
+                book.opf = Some(opf.document);
+                book.package = opf.package.ok();
+            }
+            None => book.opf_path = container_opf_path(entries).unwrap_or_default(),
+        }
+        book.content_documents = book
+            .package
+            .as_ref()
+            .map(|package| declared_content_documents(package, &book.opf_path));
+
+        book.obfuscated = read_encryption(entries).obfuscated;
+        for entry in entries {
+            // The first bytes of obfuscated fonts are scrambled.
+            let media_type = if book.obfuscated.contains_key(&entry.name) {
+                media_type::from_extension(&entry.name)
+            } else {
+                media_type::sniff(&entry.name, &entry.data)
+            };
+            book.media_types.insert(entry.name.clone(), media_type);
+        }
+
+        book.ncx_path = book
+            .package
+            .as_ref()
+            .and_then(|package| package.ncx())
+            .map(|item| href::resolve(&book.opf_path, &item.href))
+            .filter(|path| book.media_types.contains_key(path));
+        book.toc_order = book.read_toc_order(entries);
+        book.collect_references(entries);
+        // Repairing the manifest can declare more content documents, whose
+        // links can declare more still. Classify entries by the manifest as
+        // it will be written, so that fixing the book again treats them the
+        // same way.
+        for _ in 0..entries.len() {
+            let repaired = book.repaired_content_documents(options);
+            if repaired.is_none() || repaired == book.content_documents {
+                break;
+            }
+            book.content_documents = repaired;
+            book.collect_references(entries);
+        }
+        book.generated_toc = match book.nav_toc(entries) {
+            Some((nav_path, toc)) => toc::rebase(&toc, &nav_path, ""),
+            None => Vec::new(),
+        };
+        book.fallback_title = book.first_heading(entries).unwrap_or_default();
+        match book.detect_cover(entries, options) {
+            Some(Detected::Cover(path)) => book.cover = Some(path),
+            Some(Detected::Guess(path)) if options.guess_cover => book.cover = Some(path),
+            Some(Detected::Guess(path)) => book.cover_guess = Some(path),
+            None => {}
+        }
+        book.svg_cover_page = book.find_svg_cover_page(entries);
+        book
+    }
+
+    /// Gathers the body ids and the entries linked from the content
+    /// documents, the stylesheets and the table of contents.
+    fn collect_references(&mut self, entries: &[ArchiveEntry]) {
+        self.body_id_list.clear();
+        self.references.clear();
+        for entry in entries {
+            let entry_links = if self.is_xhtml(&entry.name) {
+                if let Some(body_id) = collect_body_id(&entry.name, &entry.data) {
+                    self.body_id_list.push(body_id);
+                }
+                links::xhtml_links(&entry.data)
+            } else if self.is_css(&entry.name) {
+                links::css_urls(&String::from_utf8_lossy(&entry.data))
+            } else {
+                continue;
+            };
+            self.references.extend(
+                entry_links
+                    .iter()
+                    .filter(|link| !href::is_external(link))
+                    .map(|link| href::resolve(&entry.name, link)),
+            );
+        }
+        self.references.extend(self.toc_order.iter().cloned());
+    }
+
+    /// The content documents the manifest declares once `fix_manifest` has
+    /// repaired it, or `None` when there is no package document.
+    fn repaired_content_documents(&self, options: &FixOptions) -> Option<HashSet<String>> {
+        let mut opf = self.opf.clone()?;
+        output::muted(|| fix_manifest(&mut opf.root, self, options));
+        let package = Package::from_element(&opf.root).ok()?;
+        Some(declared_content_documents(&package, &self.opf_path))
+    }
+
+    /// Detects the cover on the package as it will be written: with its
+    /// spine repaired, the first chapter is the same one the fixed book has.
+    fn detect_cover(&self, entries: &[ArchiveEntry], options: &FixOptions) -> Option<Detected> {
+        let package = self.package.as_ref()?;
+        let repaired = self.opf.clone().and_then(|mut opf| {
+            let changed = output::muted(|| {
+                spine::repair(
+                    &mut opf.root,
+                    &self.opf_path,
+                    &self.toc_order,
+                    options.missing_chapters_nonlinear,
+                )
+            });
+            changed.then(|| Package::from_element(&opf.root).ok())?
+        });
+        cover::detect(
+            repaired.as_ref().unwrap_or(package),
+            &self.opf_path,
+            &self.media_types,
+            |path| {
+                entries
+                    .iter()
+                    .find(|entry| entry.name == path)
+                    .map(|entry| entry.data.as_slice())
+            },
+        )
+    }
+
+    /// Treats `path`, a navigation document added while fixing, as a content
+    /// document of the book.
+    fn add_content_document(&mut self, path: &str) {
+        if let Some(documents) = &mut self.content_documents {
+            documents.insert(path.to_string());
+        }
+    }
+
+    fn find_svg_cover_page(&self, entries: &[ArchiveEntry]) -> Option<String> {
+        let cover = self.cover.as_ref()?;
+        entries
+            .iter()
+            .filter(|entry| self.is_xhtml(&entry.name))
+            .find(|entry| {
+                svg::wrapped_image(&String::from_utf8_lossy(&entry.data))
+                    .is_some_and(|wrapped| href::resolve(&entry.name, &wrapped.href) == *cover)
+            })
+            .map(|entry| entry.name.clone())
+    }
+
+    fn first_heading(&self, entries: &[ArchiveEntry]) -> Option<String> {
+        let package = self.package.as_ref()?;
+        package.spine_items().find_map(|item| {
+            let path = href::resolve(&self.opf_path, &item.href);
+            let entry = entries.iter().find(|entry| entry.name == path)?;
+            first_heading_text(&entry.data)
+        })
+    }
+
+    fn nav_toc(&self, entries: &[ArchiveEntry]) -> Option<(String, Vec<TocEntry>)> {
+        let package = self.package.as_ref()?;
+        let item = package.items_with_property("nav").next()?;
+        let path = href::resolve(&self.opf_path, &item.href);
+        let entry = entries.iter().find(|entry| entry.name == path)?;
+        let toc = toc::parse_nav(&entry.data)?;
+        (!toc.is_empty()).then_some((path, toc))
+    }
+
+    fn ncx_toc(&self, entries: &[ArchiveEntry]) -> Option<(String, Vec<TocEntry>)> {
+        let path = self.ncx_path.as_ref()?;
+        let entry = entries.iter().find(|entry| &entry.name == path)?;
+        Some((path.clone(), toc::parse_ncx(&entry.data)?))
+    }
+
+    fn has_ncx_toc(&self, entries: &[ArchiveEntry]) -> bool {
+        self.ncx_toc(entries)
+            .is_some_and(|(_, toc)| !toc.is_empty())
+    }
+
+    fn read_toc_order(&self, entries: &[ArchiveEntry]) -> Vec<String> {
+        let Some((toc_path, toc)) = self.nav_toc(entries).or_else(|| self.ncx_toc(entries)) else {
+            return Vec::new();
+        };
+        let mut order: Vec<String> = Vec::new();
+        for entry in toc::flatten(&toc) {
+            if entry.href.is_empty() || href::is_external(&entry.href) {
+                continue;
+            }
+            let path = href::resolve(&toc_path, &entry.href);
+            if !order.contains(&path) {
+                order.push(path);
+            }
+        }
+        order
+    }
+
+    /// The NCX for this book, built from the navigation document or from the
+    /// table of contents generated from the headings.
+    fn generate_ncx(&self, ncx_path: &str) -> Option<Vec<u8>> {
+        if self.generated_toc.is_empty() {
+            return None;
+        }
+        let package = self.package.as_ref()?;
+        let title = package
+            .metadata
+            .first("title")
+            .unwrap_or(&self.fallback_title);
+        let ncx = toc::ncx_document(
+            package.unique_identifier_value().unwrap_or_default(),
+            title,
+            &toc::rebase(&self.generated_toc, "", ncx_path),
+        );
+        Some(ncx.into_bytes())
+    }
+
+    /// The EPUB 3 properties of every content document of the repaired
+    /// manifest, including the ones the repair declares.
+    fn content_properties(&self, entries: &[ArchiveEntry]) -> BTreeMap<String, Vec<&'static str>> {
+        entries
+            .iter()
+            .filter(|entry| self.is_xhtml(&entry.name))
+            .map(|entry| (entry.name.clone(), upgrade::content_properties(&entry.data)))
+            .filter(|(_, properties)| !properties.is_empty())
+            .collect()
+    }
+
+    /// True when the book is written as EPUB 3, which does not declare the
+    /// XHTML entities.
+    fn is_epub3(&self) -> bool {
+        self.upgrade.is_some() || self.package.as_ref().is_some_and(Package::is_epub3)
+    }
+
+    fn is_xhtml(&self, file_path: &str) -> bool {
+        is_content_document(self.content_documents.as_ref(), file_path)
+    }
+
+    /// Classifies stylesheets like the manifest repair does, by their
+    /// sniffed media type, so that one added to the manifest while fixing is
+    /// treated the same way when the fixed book is read back.
+    fn is_css(&self, file_path: &str) -> bool {
+        self.media_types.get(file_path) == Some(&Some(media_type::CSS))
+    }
+}
+
 fn process_file(
     file_path: &str,
     content: &[u8],
-    body_id_list: &[(String, String)],
-    opf_path: &str,
+    book: &BookContext,
+    options: &FixOptions,
 ) -> Vec<u8> {
-    fix_encoding(
+    if book.obfuscated.contains_key(file_path) {
+        report!("Encryption: passing obfuscated font {file_path} through untouched");
+        return content.to_vec();
+    }
+    if !book.opf_path.is_empty() && file_path == book.opf_path {
+        return fix_package(content, book, options);
+    }
+    if book.ncx_path.as_deref() == Some(file_path) {
+        return fix_ncx(file_path, content, book);
+    }
+    if book.is_css(file_path) {
+        return fix_css(file_path, content, book, options);
+    }
+    if !book.is_xhtml(file_path) {
+        return content.to_vec();
+    }
+
+    let content = fix_malformed_xhtml(
         file_path,
+        book,
         &fix_stray_img(
             file_path,
-            &fix_book_language(
-                file_path,
-                &fix_body_id_link(file_path, content, body_id_list),
-                opf_path,
-            ),
+            &fix_body_id_link(content, &book.body_id_list),
+            book,
+            options,
         ),
-    )
+    );
+    fix_encoding(&fix_inline_css(file_path, &content, book, options))
 }
 
-fn is_xhtml(file_path: &str) -> bool {
-    matches!(
-        Path::new(file_path).extension().and_then(|s| s.to_str()),
-        Some("html" | "xhtml")
-    )
+/// The archive paths of the XHTML content documents `package` declares.
+fn declared_content_documents(package: &Package, opf_path: &str) -> HashSet<String> {
+    package
+        .manifest
+        .iter()
+        .filter(|item| item.is_xhtml())
+        .map(|item| href::resolve(opf_path, &item.href))
+        .collect()
 }
 
-fn fix_body_id_link(file_path: &str, content: &[u8], body_id_list: &[(String, String)]) -> Vec<u8> {
-    if !is_xhtml(file_path) {
-        return content.to_vec();
+/// Whether `file_path` is an XHTML content document: one of the `declared`
+/// ones, or any file with an XHTML extension when there is no manifest.
+fn is_content_document(declared: Option<&HashSet<String>>, file_path: &str) -> bool {
+    match declared {
+        Some(documents) => documents.contains(file_path),
+        None => has_xhtml_extension(file_path),
     }
+}
+
+pub(crate) fn has_xhtml_extension(file_path: &str) -> bool {
+    Path::new(file_path)
+        .extension()
+        .and_then(|s| s.to_str())
+        .is_some_and(|ext| {
+            ["html", "xhtml", "htm"]
+                .iter()
+                .any(|known| ext.eq_ignore_ascii_case(known))
+        })
+}
 
-    let mut html = String::from_utf8_lossy(&content).to_string();
+fn fix_body_id_link(content: &[u8], body_id_list: &[(String, String)]) -> Vec<u8> {
+    let mut html = String::from_utf8_lossy(content).to_string();
     for (src, target) in body_id_list.iter() {
         if html.contains(src) {
             html = html.replace(src, target);
@@ -128,48 +1494,137 @@ fn fix_body_id_link(file_path: &str, content: &[u8], body_id_list: &[(String, St
     html.into_bytes()
 }
 
-fn fix_encoding(file_path: &str, content: &[u8]) -> Vec<u8> {
-    if is_xhtml(file_path) {
-        let encoding = r#"<?xml version="1.0" encoding="utf-8"?>"#;
-        let content_str = String::from_utf8_lossy(content);
-        let trimmed_html = content_str.trim_start();
+fn fix_encoding(content: &[u8]) -> Vec<u8> {
+    let encoding = r#"<?xml version="1.0" encoding="utf-8"?>"#;
+    let content_str = String::from_utf8_lossy(content);
+    let trimmed_html = content_str.trim_start();
 
-        // Check if the beginning of the file content starts with a partial XML declaration
-        match encoding_matcher::is_xml_declaration(trimmed_html) {
-            Ok((_, true)) => (),
-            _ => {
-                return format!("{}\n{}", encoding, trimmed_html).into_bytes();
-            }
+    // Check if the beginning of the file content starts with a partial XML declaration
+    match encoding_matcher::is_xml_declaration(trimmed_html) {
+        Ok((_, true)) => content.to_vec(),
+        _ => {
+            // A declaration without an encoding is replaced, not followed by
+            // a second one.
+            let body = trimmed_html
+                .strip_prefix("<?xml")
+                .filter(|rest| rest.starts_with(|c: char| c.is_ascii_whitespace() || c == '?'))
+                .and_then(|rest| rest.find("?>").map(|end| rest[end + 2..].trim_start()))
+                .unwrap_or(trimmed_html);
+            format!("{}\n{}", encoding, body).into_bytes()
         }
     }
-    content.to_vec()
 }
 
-fn fix_stray_img(file_path: &str, content: &[u8]) -> Vec<u8> {
-    if !is_xhtml(file_path) {
+/// Removes `<img>` and SVG `<image>` elements without a link and handles the
+/// ones whose image is missing, remote, a `data:` URI or unsupported
+/// according to `options.images`. An `<svg>` holding nothing but a broken
+/// `<image>` is handled as a whole.
+fn fix_stray_img(
+    file_path: &str,
+    content: &[u8],
+    book: &BookContext,
+    options: &FixOptions,
+) -> Vec<u8> {
+    let html = String::from_utf8_lossy(content).to_string();
+    let mut document = Html::parse_document(&html);
+    let selector = Selector::parse("img, image").unwrap();
+
+    let mut stray_imgs = Vec::new();
+    let mut placeholders = Vec::new();
+    for img in document.select(&selector) {
+        let (src, target) = if img.value().name() == "image" {
+            let target = svg::wrapper(img).unwrap_or(img);
+            (svg::image_href(img.value()), target.id())
+        } else {
+            (img.value().attr("src"), img.id())
+        };
+        let Some(src) = src else {
+            stray_imgs.push(target);
+            continue;
+        };
+        let Some(problem) = images::problem(file_path, src, &book.media_types) else {
+            continue;
+        };
+        let shown = match src.strip_prefix("data:") {
+            Some(_) => "image",
+            None => src,
+        };
+        match options.images {
+            ImageMode::Report => report!("Images: {file_path}: {shown} {problem}"),
+            ImageMode::Remove => {
+                report!("Images: {file_path}: removing {shown}, it {problem}");
+                stray_imgs.push(target);
+            }
+            ImageMode::Placeholder => {
+                report!("Images: {file_path}: replacing {shown} with a placeholder, it {problem}");
+                let alt = img.value().attr("alt").unwrap_or_default().trim();
+                let label = if alt.is_empty() { "image" } else { alt };
+                placeholders.push((target, format!("[{label}]")));
+            }
+        }
+    }
+
+    if stray_imgs.is_empty() && placeholders.is_empty() {
         return content.to_vec();
     }
+    for (img, text) in placeholders {
+        let mut node = document.tree.get_mut(img).unwrap();
+        node.insert_before(Node::Text(Text { text: text.into() }));
+        node.detach();
+    }
+    for img in stray_imgs {
+        document.tree.get_mut(img).unwrap().detach();
+    }
+    xhtml::write(file_path, &document).into_bytes()
+}
 
-    let html = String::from_utf8_lossy(&content).to_string();
-    let mut document = Html::parse_document(&html);
-    let selector = Selector::parse("img").unwrap();
+fn fix_css(file_path: &str, content: &[u8], book: &BookContext, options: &FixOptions) -> Vec<u8> {
+    let exists = |path: &str| book.media_types.contains_key(path);
+    match css::sanitize(
+        &String::from_utf8_lossy(content),
+        file_path,
+        &exists,
+        options.css,
+    ) {
+        Some(fixed) => fixed.into_bytes(),
+        None => content.to_vec(),
+    }
+}
 
-    let stray_imgs: Vec<_> = document
-        .select(&selector)
-        .filter(|img| img.value().attr("src").is_none())
-        .map(|img| img.id())
-        .collect();
+fn fix_inline_css(
+    file_path: &str,
+    content: &[u8],
+    book: &BookContext,
+    options: &FixOptions,
+) -> Vec<u8> {
+    let exists = |path: &str| book.media_types.contains_key(path);
+    let html = String::from_utf8_lossy(content);
+    match css::sanitize_inline(&html, file_path, &exists, options.css) {
+        Some(fixed) => fixed.into_bytes(),
+        None => content.to_vec(),
+    }
+}
 
-    if !stray_imgs.is_empty() {
-        for img in stray_imgs {
-            document.tree.get_mut(img).unwrap().detach();
-        }
-        return document.html().into_bytes();
+fn fix_malformed_xhtml(file_path: &str, book: &BookContext, content: &[u8]) -> Vec<u8> {
+    if xhtml::is_well_formed(content, book.is_epub3()) {
+        return content.to_vec();
     }
-    content.to_vec()
+
+    report!("Repairing malformed XHTML in {}", file_path);
+    xhtml::repair(file_path, &String::from_utf8_lossy(content)).into_bytes()
+}
+
+/// The text of the first `h1`–`h6` of a document, whitespace collapsed.
+fn first_heading_text(content: &[u8]) -> Option<String> {
+    let selector = Selector::parse("h1, h2, h3, h4, h5, h6").unwrap();
+    let document = Html::parse_document(&String::from_utf8_lossy(content));
+    let heading = document.select(&selector).next()?;
+    let text = heading.text().collect::<Vec<_>>().join(" ");
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    (!text.is_empty()).then_some(text)
 }
 
-fn get_opf_filename(content: &[u8]) -> Option<String> {
+pub(crate) fn get_opf_filename(content: &[u8]) -> Option<String> {
     let container_xml = Element::parse(content).ok()?;
     container_xml
         .get_child("rootfiles")
@@ -178,81 +1633,202 @@ fn get_opf_filename(content: &[u8]) -> Option<String> {
         .map(|path| path.to_string())
 }
 
-fn fix_book_language(file_path: &str, content: &[u8], opf_path: &str) -> Vec<u8> {
-    if opf_path.is_empty() || file_path != opf_path {
+/// Runs the package document fixes, in order, on the package document parsed
+/// for the book, serializing it once if any of them changed it. `content` is
+/// that document as it is in the archive.
+fn fix_package(content: &[u8], book: &BookContext, options: &FixOptions) -> Vec<u8> {
+    let Some(mut opf) = book.opf.clone() else {
         return content.to_vec();
-    }
-    let mut opf = match Element::parse(content) {
-        Ok(opf) => opf,
-        Err(_) => return content.to_vec(),
     };
+    let package = &mut opf.root;
 
-    let Some(metadata) = opf.get_mut_child("metadata") else {
+    let mut changed = fix_book_language(package);
+    changed |= fix_upgrade(package, book);
+    changed |= fix_manifest(package, book, options);
+    changed |= fix_content_properties(package, book);
+    changed |= fix_spine(package, book, options);
+    changed |= fix_ncx_reference(package, book);
+    changed |= fix_nav_reference(package, book);
+    changed |= fix_cover(package, book);
+    changed |= fix_svg_cover_property(package, book);
+    changed |= fix_metadata(package, book, options);
+
+    if !changed {
         return content.to_vec();
-    };
+    }
+    opf.to_bytes()
+}
+
+fn fix_book_language(package: &mut XmlElement) -> bool {
+    match package.child_mut("metadata") {
+        Some(metadata) => fix_language(metadata),
+        None => false,
+    }
+}
 
-    let changed = fix_language(metadata);
+fn fix_manifest(package: &mut XmlElement, book: &BookContext, options: &FixOptions) -> bool {
+    manifest::reconcile(
+        package,
+        &book.opf_path,
+        &book.media_types,
+        &book.references,
+        options.remove_missing_items,
+    )
+}
 
+fn fix_spine(package: &mut XmlElement, book: &BookContext, options: &FixOptions) -> bool {
+    spine::repair(
+        package,
+        &book.opf_path,
+        &book.toc_order,
+        options.missing_chapters_nonlinear,
+    )
+}
+
+fn fix_ncx(file_path: &str, content: &[u8], book: &BookContext) -> Vec<u8> {
+    let regenerate = |reason: &str| match book.generate_ncx(file_path) {
+        Some(ncx) => {
+            report!("NCX: {file_path} {reason}, regenerating it");
+            ncx
+        }
+        None => {
+            report!("NCX: {file_path} {reason}");
+            content.to_vec()
+        }
+    };
+
+    let Ok(mut ncx) = XmlDocument::parse(content) else {
+        return regenerate("is malformed");
+    };
+    let uid = book
+        .package
+        .as_ref()
+        .and_then(|package| package.unique_identifier_value());
+    let exists = |path: &str| book.media_types.contains_key(path);
+    let changed = ncx::repair(&mut ncx.root, file_path, exists, uid);
+    if !ncx::has_nav_points(&ncx.root) {
+        return regenerate("has no usable entries");
+    }
     if !changed {
         return content.to_vec();
     }
+    ncx.to_bytes()
+}
 
-    let config = EmitterConfig::new()
-        .perform_indent(true)
-        .normalize_empty_elements(false);
+fn fix_ncx_reference(package: &mut XmlElement, book: &BookContext) -> bool {
+    match &book.ncx_path {
+        Some(ncx_path) => ncx::link(package, &book.opf_path, ncx_path),
+        None => false,
+    }
+}
 
-    let mut buf = BufWriter::new(Vec::new());
-    if opf.write_with_config(&mut buf, config).is_err() {
-        return content.to_vec();
+fn fix_nav_reference(package: &mut XmlElement, book: &BookContext) -> bool {
+    match &book.generated_nav {
+        Some(nav_path) => headings::link_nav(package, &book.opf_path, nav_path),
+        None => false,
     }
+}
 
-    match buf.into_inner() {
-        Ok(bytes) => bytes,
-        Err(_) => content.to_vec(),
+fn fix_upgrade(package: &mut XmlElement, book: &BookContext) -> bool {
+    match &book.upgrade {
+        Some(upgrade) => upgrade::repair(package, &book.opf_path, upgrade, &book.timestamp),
+        None => false,
     }
 }
 
-fn fix_language(metadata: &mut Element) -> bool {
-    // Check if 'dc:language' exists and extract the language, if present
-    let mut language_tag = metadata.get_mut_child("language");
+fn fix_content_properties(package: &mut XmlElement, book: &BookContext) -> bool {
+    match &book.upgrade {
+        Some(upgrade) => upgrade::add_properties(package, &book.opf_path, upgrade),
+        None => false,
+    }
+}
 
-    let mut language = language_tag
-        .as_mut()
-        .and_then(|lt| lt.get_text().map(String::from))
-        .unwrap_or_default();
+fn fix_cover(package: &mut XmlElement, book: &BookContext) -> bool {
+    let Some(cover) = &book.cover else {
+        match &book.cover_guess {
+            Some(guess) => report!(
+                "Cover: no cover image found; {guess}, the first image of the first chapter, \
+                 may be one (--guess-cover marks it as the cover)"
+            ),
+            None => report!("Cover: no cover image found"),
+        }
+        return false;
+    };
+
+    let media_type = book
+        .media_types
+        .get(cover)
+        .copied()
+        .flatten()
+        .unwrap_or("image/jpeg");
+    cover::repair(package, &book.opf_path, cover, media_type)
+}
 
-    let is_valid = match LanguageTag::parse(language.as_str()) {
+fn fix_svg_cover_property(package: &mut XmlElement, book: &BookContext) -> bool {
+    match book.svg_cover_page.as_deref() {
+        Some(page) if book.svg_cover_unwrapped => {
+            manifest::remove_property(package, &book.opf_path, page, "svg")
+        }
+        _ => false,
+    }
+}
+
+/// Ensures the title and modification date. The identifier is ensured by
+/// `fix_archive`, before the NCX is generated.
+fn fix_metadata(package: &mut XmlElement, book: &BookContext, options: &FixOptions) -> bool {
+    let mut changed = false;
+    if options.fill_title {
+        changed |= metadata::ensure_title(package, &book.fallback_title);
+    }
+    changed |= metadata::ensure_modified(package, &book.timestamp, options.modified);
+    changed
+}
+
+/// True when `language` is a well-formed and valid BCP 47 language tag.
+pub(crate) fn is_valid_language(language: &str) -> bool {
+    match LanguageTag::parse(language.trim()) {
         Ok(tag) => tag.validate().is_ok(),
         Err(_) => false,
-    };
+    }
+}
 
-    if !is_valid {
-        println!(
-            "Language {} is not supported. Asking for a valid language.",
-            language
-        );
-        language = "en".to_string(); // TODO: replace with flag.
-    } else {
+/// The language `fix_language` leaves in the package, for documents generated
+/// before it runs.
+fn book_language(package: &Package) -> &str {
+    package
+        .metadata
+        .first("language")
+        .filter(|language| is_valid_language(language))
+        .unwrap_or("en")
+}
+
+fn fix_language(metadata: &mut XmlElement) -> bool {
+    // Check if 'dc:language' exists and extract the language, if present
+    let language = metadata
+        .child("language")
+        .map(XmlElement::text)
+        .unwrap_or_default();
+
+    if is_valid_language(&language) {
         return false;
     }
+    report!(
+        "Language {} is not supported. Asking for a valid language.",
+        language
+    );
+    let language = "en"; // TODO: replace with flag.
 
-    if language_tag.is_none() {
-        println!("Language tag is missing. {:?}", metadata);
-        let mut new_language_tag = Element::new("dc:language");
-        new_language_tag.children.clear();
-        new_language_tag
-            .children
-            .push(XMLNode::Text(language.clone()));
-        metadata.children.push(XMLNode::Element(new_language_tag));
+    if let Some(t) = metadata.child_mut("language") {
+        t.set_text(language);
     } else {
-        let t = language_tag.unwrap();
-        t.children.clear();
-        t.children.push(XMLNode::Text(language.clone()));
+        report!("Language tag is missing.");
+        let name = opf::dc_element_name(metadata, "language");
+        metadata.append_child(XmlElement::new(&name).with_text(language));
     }
     true
 }
 
-fn collect_body_id(file_name: &str, content: &[u8]) -> Option<(String, String)> {
+pub(crate) fn collect_body_id(file_name: &str, content: &[u8]) -> Option<(String, String)> {
     let html = String::from_utf8_lossy(content);
     let document = Html::parse_document(&html);
     let body_selector = Selector::parse("body").unwrap();
@@ -278,7 +1854,11 @@ mod tests {
     #[test]
     fn process_file_works() {
         let content = "b";
-        let result = process_file("a", content.as_bytes(), &[], "other_path");
+        let book = BookContext {
+            opf_path: "other_path".to_string(),
+            ..Default::default()
+        };
+        let result = process_file("a", content.as_bytes(), &book, &FixOptions::default());
         assert_eq!(String::from_utf8_lossy(&result), "b");
     }
 
@@ -294,7 +1874,7 @@ mod tests {
     fn fix_body_id_link_replaces_links_correctly() {
         let content = b"<html><body><a href='page1#id1'>Link</a></body></html>";
         let body_id_list = vec![("page1#id1".to_string(), "new_page1.xhtml".to_string())];
-        let result = fix_body_id_link("file.xhtml", content, &body_id_list);
+        let result = fix_body_id_link(content, &body_id_list);
         assert_eq!(
             String::from_utf8_lossy(&result),
             "<html><body><a href='new_page1.xhtml'>Link</a></body></html>"
@@ -304,7 +1884,7 @@ mod tests {
     #[test]
     fn fix_encoding_adds_xml_declaration() {
         let content = b"<html><body>Test</body></html>";
-        let result = fix_encoding("file.xhtml", content);
+        let result = fix_encoding(content);
         let expected = "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<html><body>Test</body></html>";
         assert_eq!(String::from_utf8_lossy(&result), expected);
     }
@@ -312,27 +1892,693 @@ mod tests {
     #[test]
     fn fix_encoding_does_not_duplicate_xml_declaration() {
         let content = b"<?xml version=\"1.0\" encoding=\"utf-8\"?><html><body>Test</body></html>";
-        let result = fix_encoding("file.xhtml", content);
+        let result = fix_encoding(content);
         assert_eq!(
             String::from_utf8_lossy(&result),
             String::from_utf8_lossy(content)
         );
     }
 
+    #[test]
+    fn fix_encoding_replaces_a_declaration_without_encoding() {
+        for declaration in [
+            r#"<?xml version="1.0"?>"#,
+            "<?xml version='1.0' standalone='yes' ?>",
+        ] {
+            let content = format!("{declaration}\n<html><body>Test</body></html>");
+            let result = fix_encoding(content.as_bytes());
+            assert_eq!(
+                String::from_utf8_lossy(&result),
+                "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<html><body>Test</body></html>"
+            );
+        }
+    }
+
     #[test]
     fn fix_stray_img_removes_stray_images() {
         let content = b"<html><body><img/><img src='valid.png'/></body></html>";
-        let result = fix_stray_img("file.xhtml", content);
+        let mut book = BookContext::default();
+        book.media_types
+            .insert("valid.png".into(), Some("image/png"));
+        let result = fix_stray_img("a.xhtml", content, &book, &FixOptions::default());
 
         let result_str = String::from_utf8_lossy(&result);
 
-        let expected = "<html><head></head><body><img src=\"valid.png\"></body></html>";
+        let expected = "<html xmlns=\"http://www.w3.org/1999/xhtml\"><head></head><body><img src=\"valid.png\"/></body></html>";
         assert_eq!(
             result_str, expected,
             "Unexpected output structure after removing stray images."
         );
     }
 
+    #[test]
+    fn fix_stray_img_handles_broken_images() {
+        let content = br#"<html><body><img src="gone.png" alt="Map"/><img src="https://example.com/a.png"/></body></html>"#;
+        let book = BookContext::default();
+        let options = FixOptions {
+            images: ImageMode::Placeholder,
+            ..FixOptions::default()
+        };
+        let result = fix_stray_img("a.xhtml", content, &book, &options);
+        assert_eq!(
+            String::from_utf8_lossy(&result),
+            "<html xmlns=\"http://www.w3.org/1999/xhtml\"><head></head><body>[Map][image]</body></html>"
+        );
+
+        let options = FixOptions {
+            images: ImageMode::Report,
+            ..FixOptions::default()
+        };
+        let result = fix_stray_img("a.xhtml", content, &book, &options);
+        assert_eq!(result, content);
+    }
+
+    #[test]
+    fn fix_stray_img_checks_svg_images() {
+        let content =
+            br#"<html><body><svg><image xlink:href="gone.jpg"/></svg><p>Text</p></body></html>"#;
+        let options = FixOptions {
+            images: ImageMode::Remove,
+            ..FixOptions::default()
+        };
+        let result = fix_stray_img("a.xhtml", content, &BookContext::default(), &options);
+        assert_eq!(
+            String::from_utf8_lossy(&result),
+            "<html xmlns=\"http://www.w3.org/1999/xhtml\"><head></head><body><p>Text</p></body></html>"
+        );
+
+        let mut book = BookContext::default();
+        book.media_types
+            .insert("gone.jpg".into(), Some("image/jpeg"));
+        let result = fix_stray_img("a.xhtml", content, &book, &options);
+        assert_eq!(result, content);
+    }
+
+    #[test]
+    fn book_context_uses_manifest_media_types() {
+        let entry = |name: &str, data: &[u8]| ArchiveEntry {
+            name: name.to_string(),
+            data: data.to_vec(),
+            options: SimpleFileOptions::default(),
+        };
+        let entries = vec![
+            entry(
+                "META-INF/container.xml",
+                b"<container><rootfiles><rootfile full-path='OEBPS/content.opf'/></rootfiles></container>",
+            ),
+            entry(
+                "OEBPS/content.opf",
+                b"<package><metadata/><manifest>\
+                  <item id='c1' href='Text/ch1.htm' media-type='application/xhtml+xml'/>\
+                  <item id='c2' href='Text/CH2.XHTML' media-type='application/xhtml+xml'/>\
+                  <item id='ad' href='ad.html' media-type='application/octet-stream'/>\
+                  </manifest></package>",
+            ),
+        ];
+        let opf = PackageDocument::read(&entries).ok();
+        let book = BookContext::new(&entries, opf, &FixOptions::default());
+        assert!(book.is_xhtml("OEBPS/Text/ch1.htm"));
+        assert!(book.is_xhtml("OEBPS/Text/CH2.XHTML"));
+        assert!(!book.is_xhtml("OEBPS/ad.html"));
+    }
+
+    #[test]
+    fn resolve_duplicates_keeps_one_entry_per_name() {
+        let entry = |name: &str, data: &[u8]| ArchiveEntry {
+            name: name.to_string(),
+            data: data.to_vec(),
+            options: SimpleFileOptions::default(),
+        };
+        let entries = || {
+            vec![
+                entry("a.css", b"long rule"),
+                entry("dir/", b""),
+                entry("b.css", b"b"),
+                entry("a.css", b"short"),
+            ]
+        };
+        let names_and_data = |entries: &[ArchiveEntry]| -> Vec<(String, Vec<u8>)> {
+            entries
+                .iter()
+                .map(|entry| (entry.name.clone(), entry.data.clone()))
+                .collect()
+        };
+
+        let mut last = entries();
+        resolve_duplicates(&mut last, DuplicateMode::Last);
+        drop_directory_entries(&mut last);
+        assert_eq!(
+            names_and_data(&last),
+            [
+                ("a.css".to_string(), b"short".to_vec()),
+                ("b.css".to_string(), b"b".to_vec())
+            ]
+        );
+
+        let mut largest = entries();
+        resolve_duplicates(&mut largest, DuplicateMode::Largest);
+        assert_eq!(largest.len(), 3);
+        assert_eq!(largest[0].data, b"long rule");
+    }
+
+    #[test]
+    fn verify_output_checks_entries_and_idempotence() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("book.epub");
+        let chapter: &[u8] =
+            b"<?xml version=\"1.0\" encoding=\"utf-8\"?><html><body><p>Hi</p></body></html>";
+        crate::archive::write_test_book(
+            &path,
+            &[("mimetype", b"application/epub+zip"), ("c1.xhtml", chapter)],
+        );
+        let book = BookContext::default();
+        let options = FixOptions::default();
+        let written = [
+            ("mimetype".to_string(), 20),
+            ("c1.xhtml".to_string(), chapter.len()),
+        ];
+        let verify = |written: &[(String, usize)]| {
+            verify_output(&path, written, "book.epub", &book, &options)
+        };
+        assert_eq!(verify(&written), Ok(()));
+
+        let truncated = [written[0].clone(), ("c1.xhtml".to_string(), 3)];
+        let err = verify(&truncated).unwrap_err();
+        assert!(err.contains("c1.xhtml"), "{err}");
+
+        crate::archive::write_test_book(
+            &path,
+            &[
+                ("mimetype", b"application/epub+zip"),
+                ("c1.xhtml", b"<html><body><p>Hi</p></body></html>"),
+            ],
+        );
+        let written = [written[0].clone(), ("c1.xhtml".to_string(), 35)];
+        assert_eq!(
+            verify(&written),
+            Err("fixing again changes c1.xhtml".to_string())
+        );
+    }
+
+    #[test]
+    fn fix_rewrites_stylesheets_missing_from_the_manifest() {
+        let temp = tempfile::tempdir().unwrap();
+        let input = temp.path().join("book.epub");
+        let output = temp.path().join("book_fixed.epub");
+        crate::archive::write_test_book(
+            &input,
+            &[
+                ("mimetype", b"application/epub+zip"),
+                (
+                    "META-INF/container.xml",
+                    b"<container><rootfiles><rootfile full-path='OEBPS/content.opf'/></rootfiles></container>",
+                ),
+                (
+                    "OEBPS/content.opf",
+                    b"<package xmlns='http://www.idpf.org/2007/opf' version='2.0' unique-identifier='id'>\
+                      <metadata xmlns:dc='http://purl.org/dc/elements/1.1/'>\
+                      <dc:identifier id='id'>urn:isbn:1</dc:identifier><dc:title>T</dc:title>\
+                      <dc:language>en</dc:language></metadata><manifest>\
+                      <item id='c1' href='Text/c1.xhtml' media-type='application/xhtml+xml'/>\
+                      </manifest><spine><itemref idref='c1'/></spine></package>",
+                ),
+                (
+                    "OEBPS/Text/c1.xhtml",
+                    b"<html><head><link rel='stylesheet' href='../Styles/my%20style.css'/></head>\
+                      <body><h1>One</h1></body></html>",
+                ),
+                ("OEBPS/Styles/my style.css", b".a { position: fixed; }"),
+            ],
+        );
+        let options = FixOptions {
+            css: CssMode::Rewrite,
+            ..FixOptions::default()
+        };
+        output::muted(|| fix(&input.to_string_lossy(), &output, &options)).unwrap();
+
+        let mut archive = ZipArchive::new(File::open(&output).unwrap()).unwrap();
+        let mut css = String::new();
+        archive
+            .by_name("OEBPS/Styles/my_style.css")
+            .unwrap()
+            .read_to_string(&mut css)
+            .unwrap();
+        assert_eq!(css, ".a { position: static; }");
+    }
+
+    #[test]
+    fn fix_validates_the_output() {
+        let temp = tempfile::tempdir().unwrap();
+        let input = temp.path().join("book.epub");
+        let output = temp.path().join("book_fixed.epub");
+        let book = |link: &str| {
+            let chapter = format!("<html><body><h1>One</h1><a href='{link}'>Two</a></body></html>");
+            crate::archive::write_test_book(
+                &input,
+                &[
+                    ("mimetype", b"application/epub+zip"),
+                    (
+                        "META-INF/container.xml",
+                        b"<container><rootfiles><rootfile full-path='content.opf'/></rootfiles></container>",
+                    ),
+                    (
+                        "content.opf",
+                        b"<package xmlns='http://www.idpf.org/2007/opf' version='2.0' unique-identifier='id'>\
+                          <metadata xmlns:dc='http://purl.org/dc/elements/1.1/'>\
+                          <dc:identifier id='id'>urn:isbn:1</dc:identifier><dc:title>T</dc:title>\
+                          <dc:language>en</dc:language></metadata>\
+                          <manifest><item id='c1' href='c1.xhtml' media-type='application/xhtml+xml'/>\
+                          </manifest><spine><itemref idref='c1'/></spine></package>",
+                    ),
+                    ("c1.xhtml", chapter.as_bytes()),
+                ],
+            );
+        };
+        let options = FixOptions {
+            validate: true,
+            ..FixOptions::default()
+        };
+
+        book("c1.xhtml");
+        output::muted(|| fix(&input.to_string_lossy(), &output, &options)).unwrap();
+
+        book("gone.xhtml");
+        let err = output::muted(|| fix(&input.to_string_lossy(), &output, &options)).unwrap_err();
+        assert!(
+            matches!(&err, FixError::ValidationFailed(name, 1) if name.ends_with("book_fixed.epub")),
+            "{err}"
+        );
+        assert!(output.exists());
+    }
+
+    #[test]
+    fn fix_repairs_documents_the_manifest_repair_declares() {
+        let temp = tempfile::tempdir().unwrap();
+        let input = temp.path().join("book.epub");
+        let output = temp.path().join("book_fixed.epub");
+        crate::archive::write_test_book(
+            &input,
+            &[
+                ("mimetype", b"application/epub+zip"),
+                (
+                    "META-INF/container.xml",
+                    b"<container><rootfiles><rootfile full-path='content.opf'/></rootfiles></container>",
+                ),
+                (
+                    "content.opf",
+                    b"<package xmlns='http://www.idpf.org/2007/opf' version='2.0'><metadata/>\
+                      <manifest><item id='c1' href='c1.xhtml' media-type='application/xhtml+xml'/>\
+                      </manifest><spine><itemref idref='c1'/></spine></package>",
+                ),
+                (
+                    "c1.xhtml",
+                    b"<html><body><h1>One</h1><a href='c2.xhtml'>Two</a></body></html>",
+                ),
+                // Only declared once the manifest is repaired.
+                ("c2.xhtml", b"<html><body><h1>Two</h1><br></body></html>"),
+            ],
+        );
+        output::muted(|| fix(&input.to_string_lossy(), &output, &FixOptions::default())).unwrap();
+
+        let mut archive = ZipArchive::new(File::open(&output).unwrap()).unwrap();
+        let mut opf = String::new();
+        archive
+            .by_name("content.opf")
+            .unwrap()
+            .read_to_string(&mut opf)
+            .unwrap();
+        assert!(opf.contains("href=\"c2.xhtml\""), "{opf}");
+        let mut chapter = Vec::new();
+        archive
+            .by_name("c2.xhtml")
+            .unwrap()
+            .read_to_end(&mut chapter)
+            .unwrap();
+        assert!(xhtml::is_well_formed(&chapter, true));
+    }
+
+    #[test]
+    fn fix_keeps_epub2_without_a_table_of_contents_to_upgrade() {
+        let temp = tempfile::tempdir().unwrap();
+        let input = temp.path().join("book.epub");
+        let output = temp.path().join("book_fixed.epub");
+        crate::archive::write_test_book(
+            &input,
+            &[
+                ("mimetype", b"application/epub+zip"),
+                (
+                    "META-INF/container.xml",
+                    b"<container><rootfiles><rootfile full-path='content.opf'/></rootfiles></container>",
+                ),
+                (
+                    "content.opf",
+                    b"<package xmlns='http://www.idpf.org/2007/opf' version='2.0'><metadata/>\
+                      <manifest><item id='c1' href='c1.xhtml' media-type='application/xhtml+xml'/>\
+                      </manifest><spine><itemref idref='c1'/></spine></package>",
+                ),
+                ("c1.xhtml", b"<html><body><p>No headings</p></body></html>"),
+            ],
+        );
+        let options = FixOptions {
+            upgrade: true,
+            ..FixOptions::default()
+        };
+        output::muted(|| fix(&input.to_string_lossy(), &output, &options)).unwrap();
+
+        let package = Package::from_epub(&output).unwrap();
+        assert!(!package.is_epub3());
+        assert_eq!(package.items_with_property("nav").count(), 0);
+    }
+
+    #[test]
+    fn fix_upgrades_from_the_repaired_ncx_and_manifest() {
+        let temp = tempfile::tempdir().unwrap();
+        let input = temp.path().join("book.epub");
+        let output = temp.path().join("book_fixed.epub");
+        crate::archive::write_test_book(
+            &input,
+            &[
+                ("mimetype", b"application/epub+zip"),
+                (
+                    "META-INF/container.xml",
+                    b"<container><rootfiles><rootfile full-path='content.opf'/></rootfiles></container>",
+                ),
+                (
+                    "content.opf",
+                    b"<package xmlns='http://www.idpf.org/2007/opf' version='2.0' unique-identifier='id'>\
+                      <metadata xmlns:dc='http://purl.org/dc/elements/1.1/'>\
+                      <dc:identifier id='id'>urn:isbn:1</dc:identifier><dc:title>T</dc:title>\
+                      <dc:language>english</dc:language></metadata>\
+                      <manifest><item id='c1' href='c1.xhtml' media-type='application/xhtml+xml'/>\
+                      <item id='ncx' href='toc.ncx' media-type='application/x-dtbncx+xml'/>\
+                      </manifest><spine toc='ncx'><itemref idref='c1'/></spine></package>",
+                ),
+                (
+                    "toc.ncx",
+                    b"<ncx xmlns='http://www.daisy.org/z3986/2005/ncx/' version='2005-1'>\
+                      <head><meta name='dtb:uid' content='urn:isbn:1'/></head>\
+                      <docTitle><text>T</text></docTitle><navMap>\
+                      <navPoint id='n1' playOrder='1'><navLabel><text>One</text></navLabel>\
+                      <content src='c1.xhtml'/></navPoint>\
+                      <navPoint id='n2' playOrder='2'><navLabel><text>Gone</text></navLabel>\
+                      <content src='Text/missing.xhtml'/></navPoint></navMap></ncx>",
+                ),
+                (
+                    "c1.xhtml",
+                    b"<html xmlns='http://www.w3.org/1999/xhtml'><head><title>One</title></head>\
+                      <body><p><a href='c2.xhtml'>Two</a></p></body></html>",
+                ),
+                (
+                    "c2.xhtml",
+                    b"<html xmlns='http://www.w3.org/1999/xhtml'><head><title>Two</title></head>\
+                      <body><svg xmlns='http://www.w3.org/2000/svg'><rect width='1' height='1'/></svg>\
+                      </body></html>",
+                ),
+            ],
+        );
+        let options = FixOptions {
+            upgrade: true,
+            validate: true,
+            ..FixOptions::default()
+        };
+        output::muted(|| fix(&input.to_string_lossy(), &output, &options)).unwrap();
+
+        let package = Package::from_epub(&output).unwrap();
+        let item = package.item_by_path("content.opf", "c2.xhtml").unwrap();
+        assert!(item.has_property("svg"));
+        let nav = package.items_with_property("nav").next().unwrap();
+        let mut archive = zip::ZipArchive::new(std::fs::File::open(&output).unwrap()).unwrap();
+        let mut nav = archive.by_name(&nav.href).unwrap();
+        let mut content = String::new();
+        nav.read_to_string(&mut content).unwrap();
+        assert!(content.contains(" lang=\"en\""), "{content}");
+        assert!(content.contains("c1.xhtml"));
+        assert!(!content.contains("missing.xhtml"));
+    }
+
+    #[test]
+    fn fix_keeps_chapters_declaring_only_the_xml_version() {
+        let temp = tempfile::tempdir().unwrap();
+        let input = temp.path().join("book.epub");
+        let output = temp.path().join("book_fixed.epub");
+        crate::archive::write_test_book(
+            &input,
+            &[
+                ("mimetype", b"application/epub+zip"),
+                (
+                    "META-INF/container.xml",
+                    b"<container><rootfiles><rootfile full-path='OEBPS/content.opf'/></rootfiles></container>",
+                ),
+                (
+                    "OEBPS/content.opf",
+                    b"<package xmlns='http://www.idpf.org/2007/opf' version='2.0'><metadata/>\
+                      <manifest><item id='c1' href='c1.xhtml' media-type='application/xhtml+xml'/>\
+                      </manifest><spine><itemref idref='c1'/></spine></package>",
+                ),
+                (
+                    "OEBPS/c1.xhtml",
+                    b"<?xml version=\"1.0\"?>\n<html><body><h1>One</h1></body></html>",
+                ),
+            ],
+        );
+        output::muted(|| fix(&input.to_string_lossy(), &output, &FixOptions::default())).unwrap();
+
+        let mut archive = ZipArchive::new(File::open(&output).unwrap()).unwrap();
+        let mut chapter = String::new();
+        archive
+            .by_name("OEBPS/c1.xhtml")
+            .unwrap()
+            .read_to_string(&mut chapter)
+            .unwrap();
+        assert_eq!(chapter.matches("<?xml").count(), 1, "{chapter}");
+    }
+
+    #[test]
+    fn fix_verifies_the_whole_pipeline_is_idempotent() {
+        let temp = tempfile::tempdir().unwrap();
+        let input = temp.path().join("book.epub");
+        let output = temp.path().join("book_fixed.epub");
+        let cover = temp.path().join("cover.png");
+        std::fs::write(&cover, b"\x89PNG\r\n\x1a\n").unwrap();
+        crate::archive::write_test_book(
+            &input,
+            &[
+                ("mimetype", b"application/epub+zip"),
+                (
+                    "META-INF/container.xml",
+                    b"<container><rootfiles><rootfile full-path='content.opf'/></rootfiles></container>",
+                ),
+                (
+                    "content.opf",
+                    b"<package xmlns='http://www.idpf.org/2007/opf' version='2.0'><metadata/>\
+                      <manifest><item id='c1' href='chapter one.xhtml' media-type='application/xhtml+xml'/>\
+                      <item id='c2' href='c2.htm' media-type='application/xhtml+xml'/>\
+                      </manifest><spine><itemref idref='c1'/><itemref idref='c2'/></spine></package>",
+                ),
+                (
+                    "chapter one.xhtml",
+                    b"<html><body><h1>One</h1><p>A<br>B</p><a href='c2.htm#two'>Two</a>\
+                      <img src='data:image/png;base64,iVBORw0KGgo='/></body></html>",
+                ),
+                (
+                    "c2.htm",
+                    b"<html><body><h1 id='two'>Two</h1><a href='chapter%20one.xhtml'>One</a></body></html>",
+                ),
+            ],
+        );
+        let options = FixOptions {
+            cover: Some(cover),
+            upgrade: true,
+            extract_data_images: true,
+            ..FixOptions::default()
+        };
+        output::muted(|| fix(&input.to_string_lossy(), &output, &options)).unwrap();
+
+        let package = Package::from_epub(&output).unwrap();
+        assert!(package.is_epub3());
+        assert_eq!(package.items_with_property("nav").count(), 1);
+    }
+
+    #[test]
+    fn fix_points_the_cover_page_at_the_new_cover() {
+        let temp = tempfile::tempdir().unwrap();
+        let input = temp.path().join("book.epub");
+        let output = temp.path().join("book_fixed.epub");
+        let cover = temp.path().join("front.png");
+        std::fs::write(&cover, b"\x89PNG\r\n\x1a\n").unwrap();
+        crate::archive::write_test_book(
+            &input,
+            &[
+                ("mimetype", b"application/epub+zip"),
+                (
+                    "META-INF/container.xml",
+                    b"<container><rootfiles><rootfile full-path='OEBPS/content.opf'/></rootfiles></container>",
+                ),
+                (
+                    "OEBPS/content.opf",
+                    b"<package xmlns='http://www.idpf.org/2007/opf' version='2.0'>\
+                      <metadata><meta name='cover' content='img'/></metadata>\
+                      <manifest><item id='img' href='Images/cover.jpg' media-type='image/jpeg'/>\
+                      <item id='p' href='Text/cover.xhtml' media-type='application/xhtml+xml'/>\
+                      <item id='c1' href='Text/c1.xhtml' media-type='application/xhtml+xml'/>\
+                      </manifest><spine><itemref idref='p'/><itemref idref='c1'/></spine>\
+                      <guide><reference type='cover' href='Text/cover.xhtml'/></guide></package>",
+                ),
+                ("OEBPS/Images/cover.jpg", b"\xFF\xD8\xFF"),
+                (
+                    "OEBPS/Text/cover.xhtml",
+                    b"<html xmlns='http://www.w3.org/1999/xhtml'><head><title>Cover</title></head>\
+                      <body><img src='../Images/cover.jpg' alt=''/></body></html>",
+                ),
+                (
+                    "OEBPS/Text/c1.xhtml",
+                    b"<html xmlns='http://www.w3.org/1999/xhtml'><head><title>One</title></head>\
+                      <body><h1>One</h1></body></html>",
+                ),
+            ],
+        );
+        let options = FixOptions {
+            cover: Some(cover),
+            ..FixOptions::default()
+        };
+        output::muted(|| fix(&input.to_string_lossy(), &output, &options)).unwrap();
+
+        let mut archive = zip::ZipArchive::new(std::fs::File::open(&output).unwrap()).unwrap();
+        let mut page = String::new();
+        archive
+            .by_name("OEBPS/Text/cover.xhtml")
+            .unwrap()
+            .read_to_string(&mut page)
+            .unwrap();
+        assert!(page.contains("src='../cover.png'"), "{page}");
+    }
+
+    #[test]
+    fn add_cover_places_image_next_to_opf() {
+        let temp = tempfile::tempdir().unwrap();
+        let image = temp.path().join("front.img");
+        std::fs::write(&image, b"\x89PNG\r\n\x1a\n").unwrap();
+        let mut entries = vec![
+            ArchiveEntry {
+                name: "META-INF/container.xml".to_string(),
+                data: b"<container><rootfiles><rootfile full-path='OEBPS/content.opf'/></rootfiles></container>".to_vec(),
+                options: SimpleFileOptions::default(),
+            },
+            ArchiveEntry {
+                name: "OEBPS/cover.png".to_string(),
+                data: Vec::new(),
+                options: SimpleFileOptions::default(),
+            },
+        ];
+        assert_eq!(
+            add_cover(&mut entries, &image).unwrap(),
+            "OEBPS/cover-2.png"
+        );
+        assert_eq!(entries.len(), 3);
+
+        std::fs::write(&image, b"not an image").unwrap();
+        assert!(matches!(
+            add_cover(&mut entries, &image),
+            Err(FixError::InvalidCover(_))
+        ));
+    }
+
+    #[test]
+    fn extract_data_images_only_reads_content_documents() {
+        let entry = |name: &str, data: &[u8]| ArchiveEntry {
+            name: name.to_string(),
+            data: data.to_vec(),
+            options: SimpleFileOptions::default(),
+        };
+        let page: &[u8] =
+            b"<html><body><img src='data:image/png;base64,iVBORw0KGgo='/></body></html>";
+        let mut entries = vec![
+            entry(
+                "META-INF/container.xml",
+                b"<container><rootfiles><rootfile full-path='OEBPS/content.opf'/></rootfiles></container>",
+            ),
+            entry(
+                "OEBPS/content.opf",
+                b"<package><metadata/><manifest>\
+                  <item id='c1' href='Text/c1.htm' media-type='application/xhtml+xml'/>\
+                  <item id='ad' href='ad.html' media-type='application/octet-stream'/>\
+                  </manifest></package>",
+            ),
+            entry("OEBPS/Text/c1.htm", page),
+            entry("OEBPS/ad.html", page),
+        ];
+        let opf = PackageDocument::read(&entries).ok();
+        output::muted(|| extract_data_images(&mut entries, opf.as_ref()));
+        assert_eq!(entries.len(), 5);
+        assert_eq!(entries[4].name, "OEBPS/Text/c1-image.png");
+        assert_eq!(
+            entries[2].data,
+            b"<html><body><img src='c1-image.png'/></body></html>"
+        );
+        assert_eq!(entries[3].data, page);
+    }
+
+    #[test]
+    fn sanitize_file_names_rewrites_links_in_declared_content_documents() {
+        let entry = |name: &str, data: &[u8]| ArchiveEntry {
+            name: name.to_string(),
+            data: data.to_vec(),
+            options: SimpleFileOptions::default(),
+        };
+        let page: &[u8] = b"<html><body><img src='caf%C3%A9.png'/></body></html>";
+        let mut entries = vec![
+            entry(
+                "META-INF/container.xml",
+                b"<container><rootfiles><rootfile full-path='content.opf'/></rootfiles></container>",
+            ),
+            entry(
+                "content.opf",
+                b"<package><metadata/><manifest>\
+                  <item id='c1' href='c1.xml' media-type='application/xhtml+xml'/>\
+                  <item id='c2' href='c2.HTM' media-type='application/xhtml+xml'/>\
+                  <item id='ad' href='ad.html' media-type='application/octet-stream'/>\
+                  <item id='img' href='caf%C3%A9.png' media-type='image/png'/>\
+                  </manifest></package>",
+            ),
+            entry("c1.xml", page),
+            entry("c2.HTM", page),
+            entry("ad.html", page),
+            entry("caf\u{e9}.png", b"\x89PNG"),
+        ];
+        let mut opf = PackageDocument::read(&entries).ok();
+        output::muted(|| sanitize_file_names(&mut entries, opf.as_mut()));
+        assert_eq!(entries[5].name, "cafe.png");
+        let fixed: &[u8] = b"<html><body><img src='cafe.png'/></body></html>";
+        assert_eq!(entries[2].data, fixed);
+        assert_eq!(entries[3].data, fixed);
+        assert_eq!(entries[4].data, page);
+    }
+
+    #[test]
+    fn book_context_falls_back_to_extension_without_opf() {
+        let book = BookContext::default();
+        assert!(book.is_xhtml("Text/ch1.HTM"));
+        assert!(book.is_xhtml("Text/ch1.xhtml"));
+        assert!(!book.is_xhtml("Text/style.css"));
+    }
+
+    #[test]
+    fn fix_malformed_xhtml_repairs_tag_soup() {
+        let content = b"<html><body><p>One<br>Two&nbsp;Three</body></html>";
+        let result = fix_malformed_xhtml("file.xhtml", &BookContext::default(), content);
+        assert_eq!(
+            String::from_utf8_lossy(&result),
+            "<html xmlns=\"http://www.w3.org/1999/xhtml\"><head></head><body><p>One<br/>Two&#160;Three</p></body></html>"
+        );
+    }
+
+    #[test]
+    fn fix_malformed_xhtml_keeps_well_formed_documents() {
+        let content = b"<html><body><p>One<br/>Two</p></body></html>";
+        let result = fix_malformed_xhtml("file.xhtml", &BookContext::default(), content);
+        assert_eq!(result, content);
+    }
+
     #[test]
     fn get_opf_filename_extracts_correct_path() {
         let content =
@@ -348,80 +2594,89 @@ mod tests {
         assert_eq!(result, None);
     }
 
+    /// Runs `fix_book_language` on a package document, serializing it only
+    /// when it changes, like `fix_package` does.
+    fn fix_book_language_in(content: &[u8]) -> Vec<u8> {
+        let Ok(mut opf) = XmlDocument::parse(content) else {
+            return content.to_vec();
+        };
+        if !fix_book_language(&mut opf.root) {
+            return content.to_vec();
+        }
+        opf.to_bytes()
+    }
+
     #[test]
     fn fix_book_language_updates_language() {
         let content = b"<package xmlns=\"http://www.idpf.org/2007/opf\"><metadata xmlns:dc=\"http://purl.org/dc/elements/1.1/\"><dc:language>invalid</dc:language></metadata></package>";
-        let opf_path = "content.opf".to_string();
-        let result = fix_book_language("content.opf", content, &opf_path);
+        let result = fix_book_language_in(content);
         assert!(String::from_utf8_lossy(&result).contains("<dc:language>en</dc:language>"));
     }
 
     #[test]
     fn fix_book_language_adds_language_tag() {
         let content = b"<package><metadata></metadata></package>";
-        let opf_path = "content.opf".to_string();
-        let result = fix_book_language("content.opf", content, &opf_path);
+        let result = fix_book_language_in(content);
         assert!(String::from_utf8_lossy(&result).contains("<dc:language>en</dc:language>"));
     }
 
     #[test]
     fn fix_book_language_returns_original_on_invalid_xml() {
         let content = b"<package><metadata>";
-        let opf_path = "content.opf".to_string();
-        let result = fix_book_language("content.opf", content, &opf_path);
+        let result = fix_book_language_in(content);
         assert_eq!(result, content);
     }
 
     #[test]
     fn fix_book_language_returns_original_without_metadata() {
         let content = b"<package></package>";
-        let opf_path = "content.opf".to_string();
-        let result = fix_book_language("content.opf", content, &opf_path);
+        let result = fix_book_language_in(content);
         assert_eq!(result, content);
     }
 
+    #[test]
+    fn fix_book_language_preserves_formatting() {
+        let content = b"<?xml version=\"1.0\"?>\n<!-- keep -->\n<package xmlns=\"http://www.idpf.org/2007/opf\">\n  <metadata xmlns:dc=\"http://purl.org/dc/elements/1.1/\">\n    <dc:title>T</dc:title>\n    <dc:language>xx-INVALID</dc:language>\n  </metadata>\n</package>\n";
+        let result = fix_book_language_in(content);
+        let expected = String::from_utf8_lossy(content).replace("xx-INVALID", "en");
+        assert_eq!(String::from_utf8_lossy(&result), expected);
+    }
+
+    #[test]
+    fn fix_book_language_adds_language_with_existing_prefix() {
+        let content = b"<package xmlns:opf=\"http://www.idpf.org/2007/opf\">\n  <opf:metadata xmlns:d=\"http://purl.org/dc/elements/1.1/\">\n    <d:title>T</d:title>\n  </opf:metadata>\n</package>";
+        let result = fix_book_language_in(content);
+        assert!(String::from_utf8_lossy(&result)
+            .contains("<d:title>T</d:title>\n    <d:language>en</d:language>\n  </opf:metadata>"));
+    }
+
     #[test]
     fn fix_language_updates_invalid_language() {
-        let mut metadata = Element::new("metadata");
-        let mut lang_tag = Element::new("language");
-        lang_tag.children.push(XMLNode::Text("invalid".to_string()));
-        metadata.children.push(XMLNode::Element(lang_tag));
+        let mut metadata = XmlElement::new("metadata");
+        metadata.append_child(XmlElement::new("language").with_text("invalid"));
 
         let changed = fix_language(&mut metadata);
         assert!(changed);
-        assert_eq!(
-            metadata.get_child("language").unwrap().get_text().unwrap(),
-            "en"
-        );
+        assert_eq!(metadata.child("language").unwrap().text(), "en");
     }
 
     #[test]
     fn fix_language_does_not_change_valid_language() {
-        let mut metadata = Element::new("metadata");
-        let mut lang_tag = Element::new("language");
-        lang_tag.children.push(XMLNode::Text("en".to_string()));
-        metadata.children.push(XMLNode::Element(lang_tag));
+        let mut metadata = XmlElement::new("metadata");
+        metadata.append_child(XmlElement::new("language").with_text("en"));
 
         let changed = fix_language(&mut metadata);
         assert!(!changed);
-        assert_eq!(
-            metadata.get_child("language").unwrap().get_text().unwrap(),
-            "en"
-        );
+        assert_eq!(metadata.child("language").unwrap().text(), "en");
     }
 
     #[test]
     fn fix_language_keeps_valid_bcp47_tag() {
-        let mut metadata = Element::new("metadata");
-        let mut lang_tag = Element::new("language");
-        lang_tag.children.push(XMLNode::Text("en-US".to_string()));
-        metadata.children.push(XMLNode::Element(lang_tag));
+        let mut metadata = XmlElement::new("metadata");
+        metadata.append_child(XmlElement::new("language").with_text("en-US"));
 
         let changed = fix_language(&mut metadata);
         assert!(!changed);
-        assert_eq!(
-            metadata.get_child("language").unwrap().get_text().unwrap(),
-            "en-US"
-        );
+        assert_eq!(metadata.child("language").unwrap().text(), "en-US");
     }
 }
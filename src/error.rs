@@ -10,6 +10,22 @@ pub enum FixError {
     ProgressTemplate(indicatif::style::TemplateError),
     #[error("invalid input filename: {0}")]
     InvalidFileName(String),
+    #[error("invalid OPF package: {0}")]
+    InvalidPackage(String),
+    #[error("malformed XML: {0}")]
+    MalformedXml(String),
+    #[error("invalid cover image: {0}")]
+    InvalidCover(String),
+    #[error("DRM-protected book: {0}")]
+    DrmProtected(String),
+    #[error("{0} failed {1} checks")]
+    CheckFailed(String, usize),
+    #[error("{0} is not valid: {1} errors")]
+    ValidationFailed(String, usize),
+    #[error("verification of {0} failed: {1}")]
+    VerificationFailed(String, String),
+    #[error("invalid SOURCE_DATE_EPOCH: {0}")]
+    InvalidSourceDateEpoch(String),
 }
 
 impl From<std::io::Error> for FixError {
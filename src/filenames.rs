@@ -0,0 +1,159 @@
+//! Archive entry names that Kindle and other reading systems choke on:
+//! spaces, non-ASCII characters, `#` and `%`, backslashes, and names that
+//! differ from another only in case.
+
+use std::collections::{BTreeMap, HashSet};
+
+/// Picks safe names for the entries in `names` that need one and returns
+/// them as a map from old to new name. Entries for which `keep` is true are
+/// never renamed. Names that are already safe keep priority over renamed
+/// ones when two would collide.
+pub(crate) fn safe_names<'a>(
+    names: impl IntoIterator<Item = &'a str>,
+    keep: impl Fn(&str) -> bool,
+) -> BTreeMap<String, String> {
+    let names: Vec<&str> = names.into_iter().collect();
+    let mut taken: HashSet<String> = HashSet::new();
+    let mut unsafe_names = Vec::new();
+    for name in names {
+        let lower = name.to_ascii_lowercase();
+        if keep(name) || (is_safe(name) && !taken.contains(&lower)) {
+            taken.insert(lower);
+        } else {
+            unsafe_names.push(name);
+        }
+    }
+
+    let mut renamed = BTreeMap::new();
+    for name in unsafe_names {
+        let safe = sanitize(name);
+        let (stem, extension) = match safe.rfind('.') {
+            Some(dot) if dot > safe.rfind('/').map_or(0, |slash| slash + 1) => safe.split_at(dot),
+            _ => (safe.as_str(), ""),
+        };
+        let mut candidate = safe.clone();
+        let mut n = 2;
+        while !taken.insert(candidate.to_ascii_lowercase()) {
+            candidate = format!("{stem}-{n}{extension}");
+            n += 1;
+        }
+        renamed.insert(name.to_string(), candidate);
+    }
+    renamed
+}
+
+/// Whether every path segment of `name` uses only safe characters.
+fn is_safe(name: &str) -> bool {
+    name.split('/')
+        .all(|segment| !segment.is_empty() && segment != "." && segment.chars().all(is_safe_char))
+}
+
+fn is_safe_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-')
+}
+
+/// Rewrites `name` with forward slashes, without empty or `.` segments, and
+/// with accented Latin letters folded to ASCII and every other unsafe run of
+/// characters replaced by `_`.
+fn sanitize(name: &str) -> String {
+    name.split(['/', '\\'])
+        .filter(|segment| !segment.is_empty() && *segment != ".")
+        .map(|segment| {
+            let mut safe = String::with_capacity(segment.len());
+            for c in segment.chars() {
+                match fold(c) {
+                    Some(folded) => safe.push_str(folded),
+                    None if is_safe_char(c) => safe.push(c),
+                    None if !safe.ends_with('_') => safe.push('_'),
+                    None => {}
+                }
+            }
+            safe
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// The ASCII spelling of common accented Latin letters.
+fn fold(c: char) -> Option<&'static str> {
+    Some(match c {
+        'À'..='Å' => "A",
+        'à'..='å' => "a",
+        'Æ' => "AE",
+        'æ' => "ae",
+        'Ç' => "C",
+        'ç' => "c",
+        'È'..='Ë' => "E",
+        'è'..='ë' => "e",
+        'Ì'..='Ï' => "I",
+        'ì'..='ï' => "i",
+        'Ñ' => "N",
+        'ñ' => "n",
+        'Ò'..='Ö' | 'Ø' => "O",
+        'ò'..='ö' | 'ø' => "o",
+        'Œ' => "OE",
+        'œ' => "oe",
+        'Ù'..='Ü' => "U",
+        'ù'..='ü' => "u",
+        'Ý' => "Y",
+        'ý' | 'ÿ' => "y",
+        'ß' => "ss",
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn safe_names_renames_unsafe_entries() {
+        let names = [
+            "mimetype",
+            "META-INF/container.xml",
+            "OEBPS/content.opf",
+            "OEBPS/Text/Chapter 1.xhtml",
+            "OEBPS\\Images\\café #1.jpg",
+            "OEBPS/Images/100%.png",
+            "OEBPS/ok.css",
+        ];
+        let renamed = safe_names(names, |name| {
+            name == "mimetype" || name.starts_with("META-INF/") || name == "OEBPS/content.opf"
+        });
+        assert_eq!(
+            renamed,
+            BTreeMap::from([
+                (
+                    "OEBPS/Text/Chapter 1.xhtml".to_string(),
+                    "OEBPS/Text/Chapter_1.xhtml".to_string()
+                ),
+                (
+                    "OEBPS\\Images\\café #1.jpg".to_string(),
+                    "OEBPS/Images/cafe_1.jpg".to_string()
+                ),
+                (
+                    "OEBPS/Images/100%.png".to_string(),
+                    "OEBPS/Images/100_.png".to_string()
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn safe_names_separates_case_only_collisions() {
+        let names = [
+            "Text/a b.xhtml",
+            "Text/Cover.jpg",
+            "Text/cover.jpg",
+            "Text/a_b.xhtml",
+        ];
+        let renamed = safe_names(names, |_| false);
+        assert_eq!(
+            renamed,
+            BTreeMap::from([
+                ("Text/a b.xhtml".to_string(), "Text/a_b-2.xhtml".to_string()),
+                ("Text/cover.jpg".to_string(), "Text/cover-2.jpg".to_string()),
+            ])
+        );
+    }
+}
@@ -0,0 +1,223 @@
+use crate::toc::TocEntry;
+use crate::xml_doc::{XmlDocument, XmlElement};
+use crate::{href, manifest, media_type, metadata, xhtml};
+use std::collections::HashSet;
+
+/// A heading of a content document.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Heading {
+    /// 1 for `h1` through 6 for `h6`.
+    pub(crate) level: usize,
+    pub(crate) label: String,
+    /// Archive path of the document with the heading's fragment.
+    pub(crate) href: String,
+}
+
+/// Collects the `h1` to `h<depth>` headings of the content document at
+/// `path`, in document order, giving the ones without an `id` a new one.
+///
+/// Returns the headings and, when ids were added, the rewritten document.
+/// Malformed documents are repaired first; headings without text are skipped.
+pub(crate) fn collect(path: &str, content: &[u8], depth: usize) -> (Vec<Heading>, Option<Vec<u8>>) {
+    let document = XmlDocument::parse(content).or_else(|_| {
+        XmlDocument::parse(xhtml::repair(path, &String::from_utf8_lossy(content)).as_bytes())
+    });
+    let Ok(mut document) = document else {
+        return (Vec::new(), None);
+    };
+
+    let mut ids = metadata::element_ids(&document.root);
+    let mut found = Vec::new();
+    let mut added = 0;
+    visit(&mut document.root, depth, &mut ids, &mut found, &mut added);
+
+    let headings = found
+        .into_iter()
+        .map(|(level, label, id)| Heading {
+            level,
+            label,
+            href: format!("{path}#{id}"),
+        })
+        .collect();
+    if added == 0 {
+        return (headings, None);
+    }
+    report!("TOC: adding missing heading ids in {path} ({added})");
+    (headings, Some(document.to_bytes()))
+}
+
+fn visit(
+    element: &mut XmlElement,
+    depth: usize,
+    ids: &mut HashSet<String>,
+    found: &mut Vec<(usize, String, String)>,
+    added: &mut usize,
+) {
+    for child in element.elements_mut() {
+        let Some(level) = heading_level(child.local_name()).filter(|level| *level <= depth) else {
+            visit(child, depth, ids, found, added);
+            continue;
+        };
+        let label = child
+            .text()
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ");
+        if label.is_empty() {
+            continue;
+        }
+        let id = match child.attr("id") {
+            Some(id) => id.to_string(),
+            None => {
+                let id = (1..)
+                    .map(|n| format!("heading-{n}"))
+                    .find(|id| !ids.contains(id))
+                    .unwrap_or_default();
+                ids.insert(id.clone());
+                child.set_attr("id", &id);
+                *added += 1;
+                id
+            }
+        };
+        found.push((level, label, id));
+    }
+}
+
+fn heading_level(name: &str) -> Option<usize> {
+    let level = name.strip_prefix('h')?.parse().ok()?;
+    (1..=6).contains(&level).then_some(level)
+}
+
+/// Nests `headings` into a table of contents: each heading takes the
+/// following deeper headings as its children.
+pub(crate) fn nest(headings: &[Heading]) -> Vec<TocEntry> {
+    let mut entries = Vec::new();
+    let mut rest = headings;
+    while let Some((first, tail)) = rest.split_first() {
+        let end = tail
+            .iter()
+            .position(|heading| heading.level <= first.level)
+            .unwrap_or(tail.len());
+        entries.push(TocEntry {
+            label: first.label.clone(),
+            href: first.href.clone(),
+            children: nest(&tail[..end]),
+        });
+        rest = &tail[end..];
+    }
+    entries
+}
+
+/// Declares the navigation document at `nav_path` in the manifest with the
+/// `nav` property. Returns true if the package was modified.
+pub(crate) fn link_nav(package: &mut XmlElement, opf_path: &str, nav_path: &str) -> bool {
+    let id = manifest::unique_id("nav", &metadata::element_ids(package));
+    let Some(manifest) = package.child_mut("manifest") else {
+        return false;
+    };
+    let is_declared = manifest
+        .elements()
+        .filter(|item| item.local_name() == "item")
+        .any(|item| href::resolve(opf_path, item.attr("href").unwrap_or_default()) == nav_path);
+    if is_declared {
+        return false;
+    }
+
+    report!("TOC: adding navigation document {nav_path} to the manifest as {id}");
+    let name = match manifest.elements().find(|e| e.local_name() == "item") {
+        Some(item) => item.name.clone(),
+        None => "item".to_string(),
+    };
+    manifest.append_child(
+        XmlElement::new(&name)
+            .with_attr("id", &id)
+            .with_attr("href", &href::relative(opf_path, nav_path))
+            .with_attr("media-type", media_type::XHTML)
+            .with_attr("properties", "nav"),
+    );
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collect_adds_missing_ids() {
+        let content = br#"<html xmlns="http://www.w3.org/1999/xhtml"><body>
+<h1 id="part">Part  One</h1>
+<section><h2>Chapter 1</h2><p>Text</p><h4>Too deep</h4></section>
+<h2 id="heading-1">Chapter 2</h2>
+<h3></h3>
+</body></html>"#;
+        let (headings, rewritten) = collect("OEBPS/ch1.xhtml", content, 3);
+        let found: Vec<_> = headings
+            .iter()
+            .map(|h| (h.level, h.label.as_str(), h.href.as_str()))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (1, "Part One", "OEBPS/ch1.xhtml#part"),
+                (2, "Chapter 1", "OEBPS/ch1.xhtml#heading-2"),
+                (2, "Chapter 2", "OEBPS/ch1.xhtml#heading-1"),
+            ]
+        );
+        let rewritten = String::from_utf8(rewritten.unwrap()).unwrap();
+        assert!(rewritten.contains(r#"<h2 id="heading-2">Chapter 1</h2>"#));
+        assert!(rewritten.contains(r#"<h1 id="part">Part  One</h1>"#));
+
+        let (_, rewritten) = collect("OEBPS/ch1.xhtml", rewritten.as_bytes(), 3);
+        assert_eq!(rewritten, None);
+    }
+
+    #[test]
+    fn nest_follows_heading_levels() {
+        let heading = |level, label: &str| Heading {
+            level,
+            label: label.into(),
+            href: format!("{label}.xhtml"),
+        };
+        let toc = nest(&[
+            heading(2, "a"),
+            heading(1, "b"),
+            heading(2, "c"),
+            heading(3, "d"),
+            heading(2, "e"),
+        ]);
+        let shape: Vec<_> = toc
+            .iter()
+            .map(|entry| (entry.label.as_str(), entry.children.len()))
+            .collect();
+        assert_eq!(shape, [("a", 0), ("b", 2)]);
+        assert_eq!(toc[1].children[0].children[0].label, "d");
+    }
+
+    #[test]
+    fn link_nav_declares_nav_once() {
+        let mut package = XmlDocument::parse(
+            br#"<package><manifest><item id="nav" href="a.xhtml" media-type="application/xhtml+xml"/></manifest></package>"#
+                .as_slice(),
+        )
+        .unwrap()
+        .root;
+        assert!(link_nav(
+            &mut package,
+            "OEBPS/content.opf",
+            "OEBPS/nav.xhtml"
+        ));
+        assert!(!link_nav(
+            &mut package,
+            "OEBPS/content.opf",
+            "OEBPS/nav.xhtml"
+        ));
+        let item = package
+            .child("manifest")
+            .unwrap()
+            .elements()
+            .nth(1)
+            .unwrap();
+        assert_eq!(item.attr("id"), Some("nav-2"));
+        assert_eq!(item.attr("properties"), Some("nav"));
+    }
+}
@@ -0,0 +1,139 @@
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+
+/// Characters escaped when writing an archive path back out as an href.
+const HREF_ESCAPE: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'[')
+    .add(b'\\')
+    .add(b']')
+    .add(b'^')
+    .add(b'`')
+    .add(b'{')
+    .add(b'|')
+    .add(b'}');
+
+/// Resolves `href`, relative to the archive entry `base`, to an archive entry
+/// name. Fragments and queries are dropped and percent-encoding is decoded.
+pub(crate) fn resolve(base: &str, href: &str) -> String {
+    let href = href.split(['#', '?']).next().unwrap_or_default();
+    if href.is_empty() {
+        return base.to_string();
+    }
+    let href = percent_decode_str(href).decode_utf8_lossy();
+
+    let mut parts: Vec<&str> = match href.strip_prefix('/') {
+        Some(_) => Vec::new(),
+        None => base.split('/').collect(),
+    };
+    parts.pop();
+
+    for segment in href.split('/') {
+        match segment {
+            "" | "." => (),
+            ".." => {
+                parts.pop();
+            }
+            segment => parts.push(segment),
+        }
+    }
+    parts.join("/")
+}
+
+/// Builds an href from the archive entry `base` to the archive entry `target`.
+pub(crate) fn relative(base: &str, target: &str) -> String {
+    let mut base_dir: Vec<&str> = base.split('/').collect();
+    base_dir.pop();
+    let target: Vec<&str> = target.split('/').collect();
+
+    let common = base_dir
+        .iter()
+        .zip(&target)
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut parts: Vec<String> = vec!["..".to_string(); base_dir.len() - common];
+    parts.extend(
+        target[common..]
+            .iter()
+            .map(|segment| utf8_percent_encode(segment, HREF_ESCAPE).to_string()),
+    );
+    parts.join("/")
+}
+
+/// Rewrites `href`, relative to the archive entry `from`, so that it points at
+/// the same target from the entry `to`. Fragments are kept.
+pub(crate) fn rebase(from: &str, to: &str, href: &str) -> String {
+    if href.is_empty() || is_external(href) {
+        return href.to_string();
+    }
+    let fragment = href.find('#').map(|i| &href[i..]).unwrap_or_default();
+    format!("{}{fragment}", relative(to, &resolve(from, href)))
+}
+
+/// Returns true for hrefs with a URL scheme (`http:`, `mailto:`, `data:`, ...).
+pub(crate) fn is_external(href: &str) -> bool {
+    href.split_once(':').is_some_and(|(scheme, _)| {
+        scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+            && scheme
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_joins_relative_to_base_directory() {
+        assert_eq!(
+            resolve("OEBPS/content.opf", "Text/ch1.xhtml"),
+            "OEBPS/Text/ch1.xhtml"
+        );
+        assert_eq!(resolve("content.opf", "ch1.xhtml#top"), "ch1.xhtml");
+        assert_eq!(resolve("Text/ch1.xhtml", "#note"), "Text/ch1.xhtml");
+        assert_eq!(
+            resolve("OEBPS/Text/ch1.xhtml", "../Images/a%20b.jpg"),
+            "OEBPS/Images/a b.jpg"
+        );
+    }
+
+    #[test]
+    fn relative_walks_up_and_encodes() {
+        assert_eq!(
+            relative("OEBPS/content.opf", "OEBPS/Text/ch1.xhtml"),
+            "Text/ch1.xhtml"
+        );
+        assert_eq!(
+            relative("OEBPS/Text/ch1.xhtml", "OEBPS/Images/a b.jpg"),
+            "../Images/a%20b.jpg"
+        );
+        assert_eq!(relative("content.opf", "cover.jpg"), "cover.jpg");
+    }
+
+    #[test]
+    fn rebase_keeps_fragments() {
+        assert_eq!(
+            rebase("OEBPS/toc.ncx", "OEBPS/Text/nav.xhtml", "Text/ch1.xhtml#s1"),
+            "ch1.xhtml#s1"
+        );
+        assert_eq!(
+            rebase("OEBPS/Text/ch1.xhtml", "OEBPS/nav.xhtml", "#top"),
+            "Text/ch1.xhtml#top"
+        );
+        assert_eq!(rebase("a.xhtml", "b/c.xhtml", "http://x/"), "http://x/");
+    }
+
+    #[test]
+    fn is_external_detects_schemes() {
+        assert!(is_external("http://example.com/a.css"));
+        assert!(is_external("data:image/png;base64,AAAA"));
+        assert!(!is_external("Text/ch1.xhtml"));
+        assert!(!is_external("ch1.xhtml#a:b"));
+    }
+}
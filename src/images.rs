@@ -0,0 +1,105 @@
+//! Detection of `<img>` references that break Kindle conversion, and
+//! extraction of `data:` URI images into archive files.
+
+use crate::{href, media_type};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use percent_encoding::percent_decode_str;
+use std::collections::BTreeMap;
+
+/// What to do with images that are missing, remote, embedded as `data:`
+/// URIs or in a format Kindle does not support.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum ImageMode {
+    /// Only report the images.
+    #[default]
+    Report,
+    /// Remove the `<img>` elements.
+    Remove,
+    /// Replace the `<img>` elements with their alt text in brackets.
+    Placeholder,
+}
+
+/// Image formats Kindle cannot display.
+pub(crate) const UNSUPPORTED: &[&str] = &["image/webp", "image/avif", "image/tiff"];
+
+/// Why the `<img>` with `src` in the document at `path` will not display,
+/// or `None` if it is fine. `archive` maps entry names to their media type.
+pub(crate) fn problem(
+    path: &str,
+    src: &str,
+    archive: &BTreeMap<String, Option<&'static str>>,
+) -> Option<String> {
+    if src.trim_start().starts_with("data:") {
+        return Some("is embedded as a data: URI".to_string());
+    }
+    if href::is_external(src) {
+        return Some("is a remote image".to_string());
+    }
+    let target = href::resolve(path, src);
+    match archive.get(&target) {
+        None => Some("is not in the archive".to_string()),
+        Some(Some(media_type)) if UNSUPPORTED.contains(media_type) => {
+            Some(format!("is {media_type}, which Kindle does not support"))
+        }
+        Some(_) => None,
+    }
+}
+
+/// Decodes a `data:` URI into its bytes and the file extension for its
+/// media type. Returns `None` for malformed URIs and non-image data.
+pub(crate) fn decode_data_uri(uri: &str) -> Option<(Vec<u8>, &'static str)> {
+    let (header, payload) = uri.trim().strip_prefix("data:")?.split_once(',')?;
+    let data = if header.ends_with(";base64") {
+        let payload: String = payload.split_whitespace().collect();
+        STANDARD.decode(payload).ok()?
+    } else {
+        percent_decode_str(payload).collect()
+    };
+    let declared = header.split(';').next().unwrap_or_default();
+    let media_type = media_type::sniff("", &data).unwrap_or(declared);
+    let extension = match media_type.to_ascii_lowercase().as_str() {
+        "image/jpeg" => "jpg",
+        "image/png" => "png",
+        "image/gif" => "gif",
+        "image/svg+xml" => "svg",
+        "image/webp" => "webp",
+        "image/avif" => "avif",
+        "image/tiff" => "tif",
+        "image/bmp" => "bmp",
+        _ => return None,
+    };
+    Some((data, extension))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn problem_detects_broken_references() {
+        let archive = BTreeMap::from([
+            ("OEBPS/Images/a.jpg".to_string(), Some("image/jpeg")),
+            ("OEBPS/Images/b.webp".to_string(), Some("image/webp")),
+        ]);
+        let check = |src| problem("OEBPS/Text/ch1.xhtml", src, &archive);
+        assert_eq!(check("../Images/a.jpg"), None);
+        assert!(check("../Images/b.webp").unwrap().contains("image/webp"));
+        assert!(check("../Images/c.png").is_some());
+        assert!(check("https://example.com/a.png").is_some());
+        assert!(check("data:image/png;base64,AAAA").is_some());
+    }
+
+    #[test]
+    fn decode_data_uri_handles_base64_and_percent_encoding() {
+        let (data, extension) = decode_data_uri("data:image/png;base64,iVBORw0K GgoAAAA=").unwrap();
+        assert_eq!(extension, "png");
+        assert!(data.starts_with(b"\x89PNG"));
+
+        let (data, extension) = decode_data_uri("data:image/svg+xml,%3Csvg%2F%3E").unwrap();
+        assert_eq!((data.as_slice(), extension), (b"<svg/>".as_slice(), "svg"));
+
+        assert_eq!(decode_data_uri("data:text/plain,hello"), None);
+        assert_eq!(decode_data_uri("data:image/png;base64,!!!"), None);
+    }
+}
@@ -1,26 +1,162 @@
+// Declared first so `report!` is available to every other module.
+#[macro_use]
+mod output;
+
+pub mod archive;
+pub mod check;
 pub mod cli;
+mod cover;
+pub mod css;
 pub mod encoding_matcher;
+mod encryption;
 pub mod epub;
 pub mod error;
+mod filenames;
+mod headings;
+mod href;
+pub mod images;
+mod links;
+mod manifest;
+mod media_type;
+pub mod metadata;
+mod ncx;
+pub mod opf;
+mod salvage;
+mod spine;
+mod svg;
+mod toc;
+#[cfg(feature = "transcode")]
+pub mod transcode;
+mod upgrade;
+pub mod validate;
+mod xhtml;
+mod xml_doc;
 
-pub use cli::Args;
+pub use cli::{Args, CheckArgs, Command, MetaArgs, ValidateArgs};
 pub use error::FixError;
+pub use metadata::{BookMetadata, Creator, MetadataUpdate, Series};
+pub use opf::Package;
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 pub fn run(args: Args) -> Result<(), FixError> {
-    for filename in args.filenames {
-        let path = Path::new(&filename);
-        let stem = path
-            .file_stem()
-            .and_then(|s| s.to_str())
-            .ok_or_else(|| FixError::InvalidFileName(filename.clone()))?;
-        let new_stem = format!("{stem}-fixed");
-        let new_path = epub::change_file_stem(path, &new_stem);
-        let output_path = new_path.as_path();
+    match args.command {
+        Some(Command::Meta(meta)) => return run_meta(*meta),
+        Some(Command::Check(check)) => return run_check(check),
+        Some(Command::Validate(validate)) => return run_validate(validate),
+        None => {}
+    }
 
+    let reproducible = if args.reproducible {
+        Some(source_date_epoch()?)
+    } else {
+        None
+    };
+    let options = epub::FixOptions {
+        remove_missing_items: args.remove_missing_items,
+        missing_chapters_nonlinear: args.missing_chapters_nonlinear,
+        generate_identifier: !args.no_identifier,
+        fill_title: !args.no_title,
+        modified: args.modified,
+        cover: args.cover,
+        guess_cover: args.guess_cover,
+        unwrap_svg_cover: args.unwrap_svg_cover,
+        upgrade: args.upgrade,
+        generate_ncx: !args.no_ncx,
+        css: args.css,
+        images: args.images,
+        extract_data_images: args.extract_data_images,
+        #[cfg(feature = "transcode")]
+        transcode: args.transcode.limits(),
+        sanitize_file_names: !args.keep_file_names,
+        deobfuscate_fonts: args.deobfuscate_fonts,
+        generate_toc: !args.no_toc,
+        toc_depth: args.toc_depth.into(),
+        duplicates: args.duplicates,
+        salvage: args.salvage,
+        reproducible,
+        verify: !args.no_verify,
+        validate: args.validate,
+    };
+    for filename in args.filenames {
+        let output_path = fixed_path(&filename)?;
         println!("{} ⟶ {}", filename, output_path.to_string_lossy());
-        epub::fix(&filename, output_path)?;
+        epub::fix(&filename, &output_path, &options)?;
     }
     Ok(())
 }
+
+/// The time to date reproducible output at: `SOURCE_DATE_EPOCH` when set,
+/// otherwise the earliest time a ZIP entry can hold, 1980-01-01.
+fn source_date_epoch() -> Result<u64, FixError> {
+    match std::env::var("SOURCE_DATE_EPOCH") {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .map_err(|_| FixError::InvalidSourceDateEpoch(value)),
+        Err(_) => Ok(315_532_800),
+    }
+}
+
+fn run_meta(args: MetaArgs) -> Result<(), FixError> {
+    let update = args.update();
+    if update.is_empty() {
+        print!("{}", BookMetadata::from_epub(&args.filename)?);
+        return Ok(());
+    }
+
+    let output_path = match args.output {
+        Some(path) => path,
+        None => fixed_path(&args.filename)?,
+    };
+    println!("{} ⟶ {}", args.filename, output_path.to_string_lossy());
+    epub::update_metadata(&args.filename, &output_path, &update)
+}
+
+fn run_check(args: CheckArgs) -> Result<(), FixError> {
+    let mut failed = Vec::new();
+    for filename in &args.filenames {
+        let report = check::check(filename, args.target)?;
+        println!("{filename}");
+        print!("{report}");
+        if report.failures() > 0 {
+            failed.push((filename, report.failures()));
+        }
+    }
+    match failed.first() {
+        Some((filename, failures)) => Err(FixError::CheckFailed(filename.to_string(), *failures)),
+        None => Ok(()),
+    }
+}
+
+fn run_validate(args: ValidateArgs) -> Result<(), FixError> {
+    let mut failed = Vec::new();
+    for filename in &args.filenames {
+        let messages = validate::validate(filename)?;
+        println!("{filename}");
+        for message in &messages {
+            println!("{message}");
+        }
+        if messages.is_empty() {
+            println!("No problems found");
+        }
+        let errors = validate::error_count(&messages);
+        if errors > 0 {
+            failed.push((filename, errors));
+        }
+    }
+    match failed.first() {
+        Some((filename, errors)) => Err(FixError::ValidationFailed(filename.to_string(), *errors)),
+        None => Ok(()),
+    }
+}
+
+/// The default output path for `filename`: `<stem>-fixed.<ext>`.
+fn fixed_path(filename: &str) -> Result<PathBuf, FixError> {
+    let path = Path::new(filename);
+    let stem = path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .ok_or_else(|| FixError::InvalidFileName(filename.to_string()))?;
+    Ok(epub::change_file_stem(path, &format!("{stem}-fixed")))
+}
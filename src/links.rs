@@ -0,0 +1,443 @@
+use crate::{href, xml_doc};
+use scraper::{Html, Selector};
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+/// Attributes of content documents that point at other resources.
+const LINK_ATTRIBUTES: &[&str] = &["href", "src", "poster", "data"];
+
+/// Returns the raw link targets of an XHTML document: element `href`/`src`
+/// attributes (including `xlink:href`) and `url()`s in inline CSS.
+pub(crate) fn xhtml_links(content: &[u8]) -> Vec<String> {
+    let html = String::from_utf8_lossy(content);
+    let document = Html::parse_document(&html);
+    let mut links = Vec::new();
+
+    for node in document.tree.nodes() {
+        let Some(element) = node.value().as_element() else {
+            continue;
+        };
+        for (name, value) in element.attrs() {
+            if LINK_ATTRIBUTES.contains(&name) {
+                links.push(value.trim().to_string());
+            } else if name == "style" {
+                links.extend(css_urls(value));
+            }
+        }
+    }
+
+    let style = Selector::parse("style").unwrap();
+    for element in document.select(&style) {
+        links.extend(css_urls(&element.text().collect::<String>()));
+    }
+    links
+}
+
+/// Returns the targets of `url(...)` and `@import "..."` in a stylesheet.
+pub(crate) fn css_urls(css: &str) -> Vec<String> {
+    let mut urls = Vec::new();
+    let mut rest = css;
+
+    while let Some(start) = find_url_start(rest) {
+        rest = &rest[start..];
+        if let Some(after) = rest.strip_prefix("url(") {
+            let end = after.find(')').unwrap_or(after.len());
+            urls.push(unquote(&after[..end]).to_string());
+            rest = &after[end..];
+        } else {
+            let after = rest["@import".len()..].trim_start();
+            if after.starts_with(['"', '\'']) {
+                let quote = &after[..1];
+                let body = &after[1..];
+                let end = body.find(quote).unwrap_or(body.len());
+                urls.push(body[..end].to_string());
+                rest = &body[end..];
+            } else {
+                rest = after;
+            }
+        }
+    }
+    urls.retain(|url| !url.is_empty());
+    urls
+}
+
+/// Points the links of an XHTML document or NCX at the new names in
+/// `renamed`: link attributes, and `url()`s in `style` attributes and
+/// `<style>` elements. See [`rewrite`].
+pub(crate) fn rewrite_xhtml(
+    content: &str,
+    old_base: &str,
+    new_base: &str,
+    renamed: &BTreeMap<String, String>,
+) -> Option<String> {
+    rewrite(content, xhtml_slots(content), old_base, new_base, renamed)
+}
+
+/// Points the `url()`s and `@import`s of a stylesheet at the new names in
+/// `renamed`. See [`rewrite`].
+pub(crate) fn rewrite_css(
+    content: &str,
+    old_base: &str,
+    new_base: &str,
+    renamed: &BTreeMap<String, String>,
+) -> Option<String> {
+    rewrite(
+        content,
+        css_slots(content, 0, false),
+        old_base,
+        new_base,
+        renamed,
+    )
+}
+
+/// Where a link is written in a document.
+struct Slot {
+    /// The byte range of the link text.
+    range: Range<usize>,
+    /// Whether the text is XML-escaped, as in attribute values.
+    escaped: bool,
+}
+
+/// Points the links written at `slots` at the new names in `renamed`,
+/// keeping fragments. The document was at the archive entry `old_base` and is
+/// now at `new_base`, so links to entries that were not renamed are updated
+/// too when it moved. Each link is replaced where it is written, once.
+/// Returns `None` if nothing changed.
+fn rewrite(
+    content: &str,
+    slots: Vec<Slot>,
+    old_base: &str,
+    new_base: &str,
+    renamed: &BTreeMap<String, String>,
+) -> Option<String> {
+    let mut out = String::with_capacity(content.len());
+    let mut copied = 0;
+    let mut changed = false;
+    for slot in slots {
+        let written = &content[slot.range.clone()];
+        let link = if slot.escaped {
+            xml_doc::unescape(written)
+        } else {
+            written.to_string()
+        };
+        let Some(new_link) = new_link(link.trim(), old_base, new_base, renamed) else {
+            continue;
+        };
+        out.push_str(&content[copied..slot.range.start]);
+        if slot.escaped {
+            out.push_str(&xml_doc::escape(&new_link, true));
+        } else {
+            out.push_str(&new_link);
+        }
+        copied = slot.range.end;
+        changed = true;
+    }
+    out.push_str(&content[copied..]);
+    changed.then_some(out)
+}
+
+/// The link that replaces `link`, or `None` when it stays the same.
+fn new_link(
+    link: &str,
+    old_base: &str,
+    new_base: &str,
+    renamed: &BTreeMap<String, String>,
+) -> Option<String> {
+    if link.is_empty() || link.starts_with('#') || href::is_external(link) {
+        return None;
+    }
+    let target = href::resolve(old_base, link);
+    let new_path = match renamed.get(&target) {
+        Some(new_path) => new_path,
+        None if old_base != new_base => &target,
+        None => return None,
+    };
+    let suffix = link
+        .find(['#', '?'])
+        .map(|i| &link[i..])
+        .unwrap_or_default();
+    let new_link = format!("{}{suffix}", href::relative(new_base, new_path));
+    (new_link != link).then_some(new_link)
+}
+
+/// The links of a markup document in source order: the values of link
+/// attributes and the `url()`s of `style` attributes and `<style>` elements.
+/// Comments, CDATA sections, processing instructions, doctypes and scripts
+/// are skipped.
+fn xhtml_slots(content: &str) -> Vec<Slot> {
+    let mut slots = Vec::new();
+    let mut pos = 0;
+    while let Some(offset) = content[pos..].find('<') {
+        let start = pos + offset;
+        let rest = &content[start..];
+        let skip_past = |terminator: &str| {
+            rest.find(terminator)
+                .map_or(content.len(), |end| start + end + terminator.len())
+        };
+        pos = if rest.starts_with("<!--") {
+            skip_past("-->")
+        } else if rest.starts_with("<![CDATA[") {
+            skip_past("]]>")
+        } else if rest.starts_with("<?") {
+            skip_past("?>")
+        } else if rest.starts_with("<!") || rest.starts_with("</") {
+            skip_past(">")
+        } else {
+            start_tag_slots(content, start + 1, &mut slots)
+        };
+    }
+    slots
+}
+
+/// Collects the slots of the start tag whose name begins at `pos`, and of the
+/// `<style>` element it opens, and returns where scanning continues.
+fn start_tag_slots(content: &str, mut pos: usize, slots: &mut Vec<Slot>) -> usize {
+    let name_end = |pos: usize| {
+        content[pos..]
+            .find(|c: char| c.is_whitespace() || matches!(c, '=' | '>' | '/' | '<'))
+            .map_or(content.len(), |end| pos + end)
+    };
+    let end = name_end(pos);
+    let element = &content[pos..end];
+    pos = end;
+
+    loop {
+        pos += content[pos..].len() - content[pos..].trim_start().len();
+        let rest = &content[pos..];
+        if rest.is_empty() || rest.starts_with('<') {
+            return pos;
+        }
+        if rest.starts_with('>') {
+            pos += 1;
+            break;
+        }
+        if rest.starts_with('/') {
+            pos += 1;
+            continue;
+        }
+        let end = name_end(pos).max(pos + 1);
+        let attribute = &content[pos..end];
+        pos = end;
+        pos += content[pos..].len() - content[pos..].trim_start().len();
+        if !content[pos..].starts_with('=') {
+            continue;
+        }
+        pos += 1;
+        pos += content[pos..].len() - content[pos..].trim_start().len();
+        let value = match content[pos..].chars().next() {
+            Some(quote @ ('"' | '\'')) => {
+                let start = pos + 1;
+                let end = content[start..]
+                    .find(quote)
+                    .map_or(content.len(), |end| start + end);
+                pos = (end + 1).min(content.len());
+                start..end
+            }
+            _ => {
+                let end = content[pos..]
+                    .find(|c: char| c.is_whitespace() || c == '>')
+                    .map_or(content.len(), |end| pos + end);
+                let value = pos..end;
+                pos = end;
+                value
+            }
+        };
+        let local = attribute.rsplit(':').next().unwrap_or(attribute);
+        if LINK_ATTRIBUTES
+            .iter()
+            .any(|name| name.eq_ignore_ascii_case(local))
+        {
+            slots.push(Slot {
+                range: value,
+                escaped: true,
+            });
+        } else if attribute.eq_ignore_ascii_case("style") {
+            slots.extend(css_slots(&content[value.clone()], value.start, true));
+        }
+    }
+
+    let raw_text_end =
+        |tag: &str| find_ignore_case(&content[pos..], tag).map_or(content.len(), |end| pos + end);
+    if content[..pos - 1].ends_with('/') {
+        pos
+    } else if element.eq_ignore_ascii_case("style") {
+        let end = raw_text_end("</style");
+        slots.extend(css_slots(&content[pos..end], pos, true));
+        end
+    } else if element.eq_ignore_ascii_case("script") {
+        raw_text_end("</script")
+    } else {
+        pos
+    }
+}
+
+/// The links of the stylesheet `css`, which starts at byte `offset` of the
+/// document, in source order: the targets of `url(...)` and `@import "..."`.
+fn css_slots(css: &str, offset: usize, escaped: bool) -> Vec<Slot> {
+    let mut slots = Vec::new();
+    let mut pos = 0;
+    while let Some(start) = find_url_start(&css[pos..]) {
+        pos += start;
+        let rest = &css[pos..];
+        let (body, end) = if let Some(after) = rest.strip_prefix("url(") {
+            let body_start = pos + "url(".len();
+            let end = after.find(')').map_or(css.len(), |end| body_start + end);
+            let inner = &css[body_start..end];
+            let link = unquote(inner);
+            let link_start = body_start + inner.find(link).unwrap_or(0);
+            (link_start..link_start + link.len(), end)
+        } else {
+            let after = rest["@import".len()..].trim_start();
+            let quote_pos = css.len() - after.len();
+            match after.chars().next() {
+                Some(quote @ ('"' | '\'')) => {
+                    let body_start = quote_pos + 1;
+                    let end = css[body_start..]
+                        .find(quote)
+                        .map_or(css.len(), |end| body_start + end);
+                    (body_start..end, end)
+                }
+                _ => (quote_pos..quote_pos, quote_pos),
+            }
+        };
+        if !body.is_empty() {
+            slots.push(Slot {
+                range: offset + body.start..offset + body.end,
+                escaped,
+            });
+        }
+        pos = end.max(pos + 1);
+    }
+    slots
+}
+
+/// The byte position of the first match of the ASCII `needle` in `haystack`,
+/// in any case.
+fn find_ignore_case(haystack: &str, needle: &str) -> Option<usize> {
+    haystack
+        .as_bytes()
+        .windows(needle.len())
+        .position(|window| window.eq_ignore_ascii_case(needle.as_bytes()))
+}
+
+fn find_url_start(css: &str) -> Option<usize> {
+    match (css.find("url("), css.find("@import")) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+fn unquote(value: &str) -> &str {
+    value.trim().trim_matches(['"', '\'']).trim()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn css_urls_finds_urls_and_imports() {
+        let css = r#"@import "base.css"; @import url('print.css');
+            body { background: url( "../Images/bg.png" ) }
+            @font-face { src: url(../Fonts/a.ttf) format("truetype") }"#;
+        assert_eq!(
+            css_urls(css),
+            [
+                "base.css",
+                "print.css",
+                "../Images/bg.png",
+                "../Fonts/a.ttf"
+            ]
+        );
+    }
+
+    #[test]
+    fn rewrite_replaces_renamed_targets() {
+        let html =
+            r#"<img src="../Images/a.webp"/><div style="background: url(../Images/a.webp#x)"/>"#;
+        let renamed = BTreeMap::from([(
+            "OEBPS/Images/a.webp".to_string(),
+            "OEBPS/Images/a.jpg".to_string(),
+        )]);
+        let html = rewrite_xhtml(
+            html,
+            "OEBPS/Text/ch1.xhtml",
+            "OEBPS/Text/ch1.xhtml",
+            &renamed,
+        )
+        .unwrap();
+        assert_eq!(
+            html,
+            r#"<img src="../Images/a.jpg"/><div style="background: url(../Images/a.jpg#x)"/>"#
+        );
+    }
+
+    #[test]
+    fn rewrite_follows_a_moved_document() {
+        let html = r##"<a href="Caf%C3%A9.xhtml#n">x</a><img src="../Images/a&amp;b.jpg"/><a href="#top">t</a>"##;
+        let renamed = BTreeMap::from([
+            (
+                "OEBPS/Text Files/Caf\u{e9}.xhtml".to_string(),
+                "OEBPS/Text/Cafe.xhtml".to_string(),
+            ),
+            (
+                "OEBPS/Images/a&b.jpg".to_string(),
+                "OEBPS/Images/a_b.jpg".to_string(),
+            ),
+        ]);
+        let html = rewrite_xhtml(
+            html,
+            "OEBPS/Text Files/ch1.xhtml",
+            "OEBPS/ch1.xhtml",
+            &renamed,
+        )
+        .unwrap();
+        assert_eq!(
+            html,
+            r##"<a href="Text/Cafe.xhtml#n">x</a><img src="Images/a_b.jpg"/><a href="#top">t</a>"##
+        );
+    }
+
+    #[test]
+    fn rewrite_replaces_each_link_where_it_is_written() {
+        let html = r#"<html><head><!-- <a href="../Images/a.jpg"> --><style>
+p { background: url( '../Images/a.jpg' ) }</style></head>
+<body><p>"../Images/a.jpg"</p><img SRC=../Images/a.jpg /><a href="Images/a.jpg">b</a>
+<script>let s = "../Images/a.jpg";</script></body></html>"#;
+        let renamed = BTreeMap::new();
+        let html = rewrite_xhtml(html, "OEBPS/Text/ch1.xhtml", "OEBPS/ch1.xhtml", &renamed);
+        assert_eq!(
+            html.unwrap(),
+            r#"<html><head><!-- <a href="../Images/a.jpg"> --><style>
+p { background: url( 'Images/a.jpg' ) }</style></head>
+<body><p>"../Images/a.jpg"</p><img SRC=Images/a.jpg /><a href="Text/Images/a.jpg">b</a>
+<script>let s = "../Images/a.jpg";</script></body></html>"#
+        );
+    }
+
+    #[test]
+    fn rewrite_css_replaces_urls_and_imports() {
+        let css = r#"@import "a b.css"; @import url(a%20b.css); p { background: url("a b.css") }"#;
+        let renamed = BTreeMap::from([("S/a b.css".to_string(), "S/a_b.css".to_string())]);
+        assert_eq!(
+            rewrite_css(css, "S/main.css", "S/main.css", &renamed).unwrap(),
+            r#"@import "a_b.css"; @import url(a_b.css); p { background: url("a_b.css") }"#
+        );
+        assert_eq!(
+            rewrite_css("p {}", "S/main.css", "S/main.css", &renamed),
+            None
+        );
+    }
+
+    #[test]
+    fn xhtml_links_collects_attributes_and_styles() {
+        let html = br#"<html><head><link href="style.css"/><style>p { background: url(bg.png) }</style></head>
+            <body><img src="a.jpg"/><svg><image xlink:href="cover.jpg"/></svg><a href="ch2.xhtml#x">2</a></body></html>"#;
+        let mut links = xhtml_links(html);
+        links.sort();
+        assert_eq!(
+            links,
+            ["a.jpg", "bg.png", "ch2.xhtml#x", "cover.jpg", "style.css"]
+        );
+    }
+}
@@ -0,0 +1,391 @@
+use crate::xml_doc::XmlElement;
+use crate::{href, media_type};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+/// Entries that belong to the container rather than the publication.
+pub(crate) fn is_container_file(path: &str, opf_path: &str) -> bool {
+    path == "mimetype" || path.starts_with("META-INF/") || path == opf_path || path.ends_with('/')
+}
+
+/// Brings the `<manifest>` of `package` in line with the archive.
+///
+/// `archive` maps every entry name to its sniffed media type and `referenced`
+/// holds the entries linked from content documents and stylesheets. Returns
+/// true if the package was modified.
+pub(crate) fn reconcile(
+    package: &mut XmlElement,
+    opf_path: &str,
+    archive: &BTreeMap<String, Option<&'static str>>,
+    referenced: &BTreeSet<String>,
+    remove_missing: bool,
+) -> bool {
+    let Some(manifest) = package.child_mut("manifest") else {
+        return false;
+    };
+
+    let mut changed = false;
+    let mut ids: HashSet<String> = HashSet::new();
+    let mut paths: HashMap<String, String> = HashMap::new();
+    let mut replaced_ids: HashMap<String, String> = HashMap::new();
+
+    manifest.retain_elements(|item| {
+        if item.local_name() != "item" {
+            return true;
+        }
+        let id = item.attr("id").unwrap_or_default().to_string();
+        let item_href = item.attr("href").unwrap_or_default().to_string();
+        let mut path = href::resolve(opf_path, &item_href);
+
+        if !href::is_external(&item_href) && !archive.contains_key(&path) {
+            let case_match = archive
+                .keys()
+                .find(|name| name.eq_ignore_ascii_case(&path))
+                .cloned();
+            if let Some(actual) = case_match {
+                report!("Manifest item {id}: fixing href {item_href} to match {actual}");
+                item.set_attr("href", &href::relative(opf_path, &actual));
+                path = actual;
+                changed = true;
+            } else if remove_missing {
+                report!("Manifest item {id}: removing, {item_href} is not in the archive");
+                changed = true;
+                return false;
+            } else {
+                report!("Manifest item {id}: {item_href} is not in the archive");
+            }
+        }
+
+        if let Some(kept) = paths.get(&path) {
+            report!("Manifest item {id}: removing duplicate of {kept} for {item_href}");
+            if *kept != id {
+                replaced_ids.insert(id, kept.clone());
+            }
+            changed = true;
+            return false;
+        }
+
+        let mut id = id;
+        if id.is_empty() || ids.contains(&id) {
+            let new_id = unique_id(&path, &ids);
+            report!("Manifest item {item_href}: renaming duplicate id {id:?} to {new_id}");
+            item.set_attr("id", &new_id);
+            id = new_id;
+            changed = true;
+        }
+
+        if let Some(Some(sniffed)) = archive.get(&path) {
+            let declared = item.attr("media-type").unwrap_or_default();
+            if !media_type::matches(declared, sniffed) {
+                report!("Manifest item {id}: media-type {declared:?} should be {sniffed}");
+                item.set_attr("media-type", sniffed);
+                changed = true;
+            }
+        }
+
+        ids.insert(id.clone());
+        paths.insert(path, id);
+        true
+    });
+
+    for path in referenced {
+        if paths.contains_key(path)
+            || !archive.contains_key(path)
+            || is_container_file(path, opf_path)
+        {
+            continue;
+        }
+        let id = unique_id(path, &ids);
+        let media_type = archive
+            .get(path)
+            .copied()
+            .flatten()
+            .unwrap_or("application/octet-stream");
+        report!("Manifest: adding undeclared {path} as {id} ({media_type})");
+
+        manifest.append_child(
+            XmlElement::new("item")
+                .with_attr("id", &id)
+                .with_attr("href", &href::relative(opf_path, path))
+                .with_attr("media-type", media_type),
+        );
+
+        ids.insert(id.clone());
+        paths.insert(path.clone(), id);
+        changed = true;
+    }
+
+    if !replaced_ids.is_empty() {
+        remap_ids(package, &replaced_ids);
+    }
+    changed
+}
+
+/// Points every id reference in `element` and its descendants that names a
+/// removed item at the item that was kept instead: `idref`, `toc`,
+/// `fallback`, `media-overlay`, `refines="#id"` and `<meta name="cover">`.
+fn remap_ids(element: &mut XmlElement, replaced_ids: &HashMap<String, String>) {
+    for attribute in ["idref", "toc", "fallback", "media-overlay"] {
+        if let Some(kept) = element
+            .attr(attribute)
+            .and_then(|idref| replaced_ids.get(idref))
+        {
+            element.set_attr(attribute, &kept.clone());
+        }
+    }
+    if let Some(kept) = element
+        .attr("refines")
+        .and_then(|refines| refines.strip_prefix('#'))
+        .and_then(|idref| replaced_ids.get(idref))
+    {
+        element.set_attr("refines", &format!("#{kept}"));
+    }
+    if element.local_name() == "meta" && element.attr("name") == Some("cover") {
+        if let Some(kept) = element
+            .attr("content")
+            .and_then(|idref| replaced_ids.get(idref))
+        {
+            element.set_attr("content", &kept.clone());
+        }
+    }
+    for child in element.elements_mut() {
+        remap_ids(child, replaced_ids);
+    }
+}
+
+/// Points the manifest items and guide references to the archive entries
+/// renamed in `renamed` at their new names, updating the media types of items
+/// whose extension changed. Returns true if the package was modified.
+pub(crate) fn rename(
+    package: &mut XmlElement,
+    opf_path: &str,
+    renamed: &BTreeMap<String, String>,
+) -> bool {
+    let mut changed = false;
+    for section in ["manifest", "guide"] {
+        let Some(section) = package.child_mut(section) else {
+            continue;
+        };
+        for element in section.elements_mut() {
+            let old_href = element.attr("href").unwrap_or_default();
+            let Some(new_path) = renamed.get(&href::resolve(opf_path, old_href)) else {
+                continue;
+            };
+            let fragment = old_href
+                .find('#')
+                .map(|i| &old_href[i..])
+                .unwrap_or_default();
+            let new_href = format!("{}{fragment}", href::relative(opf_path, new_path));
+            let extension_changed =
+                media_type::from_extension(new_path) != media_type::from_extension(old_href);
+            element.set_attr("href", &new_href);
+            if element.local_name() == "item" && extension_changed {
+                if let Some(media_type) = media_type::from_extension(new_path) {
+                    element.set_attr("media-type", media_type);
+                }
+            }
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// Removes `property` from the `properties` of the manifest item for the
+/// archive entry `path`. Returns true if the package was modified.
+pub(crate) fn remove_property(
+    package: &mut XmlElement,
+    opf_path: &str,
+    path: &str,
+    property: &str,
+) -> bool {
+    let Some(item) = package.child_mut("manifest").and_then(|manifest| {
+        manifest.elements_mut().find(|item| {
+            item.local_name() == "item"
+                && href::resolve(opf_path, item.attr("href").unwrap_or_default()) == path
+        })
+    }) else {
+        return false;
+    };
+    let properties = item.attr("properties").unwrap_or_default();
+    if !properties.split_whitespace().any(|p| p == property) {
+        return false;
+    }
+    let rest: Vec<&str> = properties
+        .split_whitespace()
+        .filter(|p| *p != property)
+        .collect();
+    let rest = rest.join(" ");
+    let id = item.attr("id").unwrap_or_default().to_string();
+    report!("Manifest item {id}: removing the {property} property");
+    if rest.is_empty() {
+        item.remove_attr("properties");
+    } else {
+        item.set_attr("properties", &rest);
+    }
+    true
+}
+
+/// Derives an XML id from the file name of `path` that is not yet in `ids`.
+pub(crate) fn unique_id(path: &str, ids: &HashSet<String>) -> String {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let mut base: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if !base.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        base.insert_str(0, "id_");
+    }
+
+    let mut id = base.clone();
+    let mut n = 2;
+    while ids.contains(&id) {
+        id = format!("{base}-{n}");
+        n += 1;
+    }
+    id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::xml_doc::XmlDocument;
+
+    fn archive(entries: &[(&str, Option<&'static str>)]) -> BTreeMap<String, Option<&'static str>> {
+        entries
+            .iter()
+            .map(|(name, media_type)| (name.to_string(), *media_type))
+            .collect()
+    }
+
+    fn items(package: &XmlElement) -> Vec<(String, String, String)> {
+        package
+            .child("manifest")
+            .unwrap()
+            .elements()
+            .map(|item| {
+                let attr = |name| item.attr(name).unwrap_or_default().to_string();
+                (attr("id"), attr("href"), attr("media-type"))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reconcile_adds_removes_and_fixes_items() {
+        let mut package = XmlDocument::parse(
+            br#"<package><manifest>
+                <item id="ch1" href="Text/ch1.xhtml" media-type="text/html"/>
+                <item id="ch1" href="Text/ch2.xhtml" media-type="application/xhtml+xml"/>
+                <item id="dup" href="Text/ch1.xhtml" media-type="application/xhtml+xml"/>
+                <item id="gone" href="Text/gone.xhtml" media-type="application/xhtml+xml"/>
+                <item id="cover" href="images/Cover.JPG" media-type="image/jpg"/>
+            </manifest><spine><itemref idref="dup"/></spine></package>"#
+                .as_slice(),
+        )
+        .unwrap()
+        .root;
+        let archive = archive(&[
+            ("OEBPS/Text/ch1.xhtml", Some(media_type::XHTML)),
+            ("OEBPS/Text/ch2.xhtml", Some(media_type::XHTML)),
+            ("OEBPS/Images/cover.jpg", Some("image/jpeg")),
+            ("OEBPS/Styles/main.css", Some(media_type::CSS)),
+        ]);
+        let referenced = BTreeSet::from(["OEBPS/Styles/main.css".to_string()]);
+
+        assert!(reconcile(
+            &mut package,
+            "OEBPS/content.opf",
+            &archive,
+            &referenced,
+            true
+        ));
+        let expected = [
+            ("ch1", "Text/ch1.xhtml", media_type::XHTML),
+            ("ch2.xhtml", "Text/ch2.xhtml", media_type::XHTML),
+            ("cover", "Images/cover.jpg", "image/jpeg"),
+            ("main.css", "Styles/main.css", media_type::CSS),
+        ];
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|(a, b, c)| (a.to_string(), b.to_string(), c.to_string()))
+            .collect();
+        assert_eq!(items(&package), expected);
+
+        let itemref = package.child("spine").unwrap().child("itemref");
+        assert_eq!(itemref.unwrap().attr("idref"), Some("ch1"));
+    }
+
+    #[test]
+    fn reconcile_remaps_references_to_removed_duplicates() {
+        let mut package = XmlDocument::parse(
+            br##"<package><metadata>
+                <meta name="cover" content="cover-dup"/>
+                <meta refines="#cover-dup" property="file-as">Cover</meta>
+            </metadata><manifest>
+                <item id="cover" href="cover.jpg" media-type="image/jpeg"/>
+                <item id="cover-dup" href="cover.jpg" media-type="image/jpeg"/>
+                <item id="ch1" href="ch1.xhtml" media-type="application/xhtml+xml"/>
+                <item id="toc" href="toc.ncx" media-type="application/x-dtbncx+xml"/>
+                <item id="toc-dup" href="toc.ncx" media-type="application/x-dtbncx+xml"/>
+                <item id="page" href="page.xhtml" media-type="application/xhtml+xml" fallback="cover-dup"/>
+            </manifest><spine toc="toc-dup"><itemref idref="ch1"/></spine></package>"##
+                .as_slice(),
+        )
+        .unwrap()
+        .root;
+        let archive = archive(&[
+            ("cover.jpg", Some("image/jpeg")),
+            ("ch1.xhtml", Some(media_type::XHTML)),
+            ("page.xhtml", Some(media_type::XHTML)),
+            ("toc.ncx", Some(media_type::NCX)),
+        ]);
+
+        assert!(reconcile(
+            &mut package,
+            "content.opf",
+            &archive,
+            &BTreeSet::new(),
+            true
+        ));
+        let metadata = package.child("metadata").unwrap();
+        let metas: Vec<_> = metadata.elements().collect();
+        assert_eq!(metas[0].attr("content"), Some("cover"));
+        assert_eq!(metas[1].attr("refines"), Some("#cover"));
+        assert_eq!(package.child("spine").unwrap().attr("toc"), Some("toc"));
+        let manifest = package.child("manifest").unwrap();
+        let page = manifest
+            .elements()
+            .find(|item| item.attr("id") == Some("page"));
+        assert_eq!(page.unwrap().attr("fallback"), Some("cover"));
+    }
+
+    #[test]
+    fn reconcile_reports_missing_items_without_removing() {
+        let mut package = XmlDocument::parse(
+            br#"<package><manifest><item id="gone" href="gone.xhtml" media-type="application/xhtml+xml"/></manifest></package>"#
+                .as_slice(),
+        )
+        .unwrap()
+        .root;
+        let changed = reconcile(
+            &mut package,
+            "content.opf",
+            &archive(&[]),
+            &BTreeSet::new(),
+            false,
+        );
+        assert!(!changed);
+        assert_eq!(items(&package).len(), 1);
+    }
+
+    #[test]
+    fn unique_id_avoids_collisions() {
+        let ids = HashSet::from(["cover.jpg".to_string()]);
+        assert_eq!(unique_id("Images/cover.jpg", &ids), "cover.jpg-2");
+        assert_eq!(unique_id("Text/1 intro.xhtml", &ids), "id_1_intro.xhtml");
+    }
+}
@@ -0,0 +1,113 @@
+use std::path::Path;
+
+pub(crate) const XHTML: &str = "application/xhtml+xml";
+pub(crate) const CSS: &str = "text/css";
+pub(crate) const NCX: &str = "application/x-dtbncx+xml";
+
+/// Media types that are interchangeable for the same kind of resource.
+const EQUIVALENTS: &[&[&str]] = &[
+    &[
+        "font/ttf",
+        "font/otf",
+        "font/sfnt",
+        "application/font-sfnt",
+        "application/x-font-ttf",
+        "application/x-font-truetype",
+        "application/x-font-opentype",
+        "application/vnd.ms-opentype",
+    ],
+    &["font/woff", "application/font-woff"],
+    &[
+        "text/javascript",
+        "application/javascript",
+        "application/ecmascript",
+    ],
+];
+
+/// Infers the media type of an archive entry, preferring the content's magic
+/// bytes over its file extension.
+pub(crate) fn sniff(path: &str, data: &[u8]) -> Option<&'static str> {
+    sniff_content(data).or_else(|| from_extension(path))
+}
+
+fn sniff_content(data: &[u8]) -> Option<&'static str> {
+    let media_type = match data {
+        [0xFF, 0xD8, 0xFF, ..] => "image/jpeg",
+        [0x89, b'P', b'N', b'G', ..] => "image/png",
+        [b'G', b'I', b'F', b'8', ..] => "image/gif",
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => "image/webp",
+        [_, _, _, _, b'f', b't', b'y', b'p', b'a', b'v', b'i', b'f', ..] => "image/avif",
+        [b'I', b'I', b'*', 0, ..] | [b'M', b'M', 0, b'*', ..] => "image/tiff",
+        [b'B', b'M', ..] => "image/bmp",
+        [0, 1, 0, 0, ..] | [b't', b'r', b'u', b'e', ..] => "font/ttf",
+        [b'O', b'T', b'T', b'O', ..] => "font/otf",
+        [b'w', b'O', b'F', b'F', ..] => "font/woff",
+        [b'w', b'O', b'F', b'2', ..] => "font/woff2",
+        [b'I', b'D', b'3', ..] | [0xFF, 0xFB, ..] => "audio/mpeg",
+        _ => return None,
+    };
+    Some(media_type)
+}
+
+pub(crate) fn from_extension(path: &str) -> Option<&'static str> {
+    let extension = Path::new(path)
+        .extension()
+        .and_then(|s| s.to_str())?
+        .to_ascii_lowercase();
+    let media_type = match extension.as_str() {
+        "xhtml" | "html" | "htm" => XHTML,
+        "css" => CSS,
+        "ncx" => NCX,
+        "opf" => "application/oebps-package+xml",
+        "svg" => "image/svg+xml",
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "tif" | "tiff" => "image/tiff",
+        "bmp" => "image/bmp",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "js" => "application/javascript",
+        "smil" => "application/smil+xml",
+        "pls" => "application/pls+xml",
+        "mp3" => "audio/mpeg",
+        "m4a" | "mp4" => "audio/mp4",
+        "xpgt" => "application/adobe-page-template+xml",
+        _ => return None,
+    };
+    Some(media_type)
+}
+
+/// Returns true when `declared` is an acceptable media type for content
+/// sniffed as `sniffed`.
+pub(crate) fn matches(declared: &str, sniffed: &str) -> bool {
+    let declared = declared.trim().to_ascii_lowercase();
+    declared == sniffed
+        || EQUIVALENTS
+            .iter()
+            .any(|group| group.contains(&declared.as_str()) && group.contains(&sniffed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniff_prefers_magic_bytes() {
+        assert_eq!(sniff("cover.jpg", b"\x89PNG\r\n\x1a\n"), Some("image/png"));
+        assert_eq!(sniff("style.CSS", b"body {}"), Some(CSS));
+        assert_eq!(sniff("README", b"hello"), None);
+    }
+
+    #[test]
+    fn matches_accepts_equivalent_font_types() {
+        assert!(matches("application/vnd.ms-opentype", "font/otf"));
+        assert!(matches("Image/JPEG", "image/jpeg"));
+        assert!(!matches("image/jpg", "image/jpeg"));
+        assert!(!matches("text/html", XHTML));
+    }
+}
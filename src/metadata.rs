@@ -0,0 +1,887 @@
+use crate::error::FixError;
+use crate::opf::{self, Package};
+use crate::{manifest, xml_doc::XmlElement};
+use std::collections::HashSet;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+/// When to write `meta property="dcterms:modified"` on EPUB 3 books.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum ModifiedMode {
+    /// Leave the modification date alone.
+    Off,
+    /// Add the date only if it is missing or malformed.
+    #[default]
+    Missing,
+    /// Always set the date to the time of the fix.
+    Always,
+}
+
+/// A `dc:creator` with its optional sort name and MARC relator role.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Creator {
+    pub name: String,
+    pub file_as: Option<String>,
+    pub role: Option<String>,
+}
+
+impl FromStr for Creator {
+    type Err = String;
+
+    /// Parses `NAME[|FILE-AS[|ROLE]]`, e.g. `Jane Doe|Doe, Jane|aut`.
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let mut parts = value.split('|').map(str::trim);
+        let name = parts.next().unwrap_or_default().to_string();
+        if name.is_empty() {
+            return Err("the creator name is empty".to_string());
+        }
+        let mut next = || {
+            parts
+                .next()
+                .filter(|part| !part.is_empty())
+                .map(String::from)
+        };
+        let creator = Creator {
+            name,
+            file_as: next(),
+            role: next(),
+        };
+        if next().is_some() {
+            return Err(format!("expected NAME|FILE-AS|ROLE, got {value:?}"));
+        }
+        Ok(creator)
+    }
+}
+
+/// The series a book belongs to and its position in it.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Series {
+    pub name: String,
+    pub index: Option<String>,
+}
+
+/// The descriptive metadata shown and edited by `fixepub meta`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct BookMetadata {
+    pub title: Option<String>,
+    pub creators: Vec<Creator>,
+    pub series: Option<Series>,
+    pub publisher: Option<String>,
+    pub date: Option<String>,
+    pub subjects: Vec<String>,
+    pub description: Option<String>,
+}
+
+impl BookMetadata {
+    pub fn from_package(package: &Package) -> Self {
+        let metadata = &package.metadata;
+        let first = |name: &str| {
+            metadata
+                .first(name)
+                .filter(|value| !value.is_empty())
+                .map(String::from)
+        };
+        let refinement = |id: Option<&str>, property: &str| {
+            metadata
+                .refinements(id?)
+                .find(|meta| meta.property.as_deref() == Some(property))
+                .map(|meta| meta.value.clone())
+        };
+
+        let collection = metadata.meta.iter().find(|meta| {
+            meta.refines.is_none()
+                && meta.property.as_deref() == Some("belongs-to-collection")
+                && refinement(meta.id.as_deref(), "collection-type")
+                    .is_none_or(|kind| kind == "series")
+        });
+        let series = match collection {
+            Some(meta) => Some(Series {
+                name: meta.value.clone(),
+                index: refinement(meta.id.as_deref(), "group-position"),
+            }),
+            None => metadata.meta_content("calibre:series").map(|name| Series {
+                name: name.to_string(),
+                index: metadata
+                    .meta_content("calibre:series_index")
+                    .map(String::from),
+            }),
+        };
+
+        BookMetadata {
+            title: first("title"),
+            creators: metadata
+                .dc("creator")
+                .map(|creator| Creator {
+                    name: creator.value.clone(),
+                    file_as: metadata.refined(creator, "file-as").map(String::from),
+                    role: metadata.refined(creator, "role").map(String::from),
+                })
+                .collect(),
+            series,
+            publisher: first("publisher"),
+            date: first("date"),
+            subjects: metadata
+                .dc("subject")
+                .map(|subject| subject.value.clone())
+                .collect(),
+            description: first("description"),
+        }
+    }
+
+    /// Reads the metadata of the EPUB at `path`.
+    pub fn from_epub<P: AsRef<Path>>(path: P) -> Result<Self, FixError> {
+        Package::from_epub(path).map(|package| BookMetadata::from_package(&package))
+    }
+}
+
+impl fmt::Display for BookMetadata {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut field = |label: &str, value: &str| writeln!(f, "{label:<12} {value}");
+        if let Some(title) = &self.title {
+            field("Title:", title)?;
+        }
+        for creator in &self.creators {
+            let mut value = creator.name.clone();
+            if let Some(file_as) = &creator.file_as {
+                value.push_str(&format!(" [{file_as}]"));
+            }
+            if let Some(role) = &creator.role {
+                value.push_str(&format!(" ({role})"));
+            }
+            field("Creator:", &value)?;
+        }
+        if let Some(series) = &self.series {
+            match &series.index {
+                Some(index) => field("Series:", &format!("{} #{index}", series.name))?,
+                None => field("Series:", &series.name)?,
+            }
+        }
+        if let Some(publisher) = &self.publisher {
+            field("Publisher:", publisher)?;
+        }
+        if let Some(date) = &self.date {
+            field("Date:", date)?;
+        }
+        for subject in &self.subjects {
+            field("Subject:", subject)?;
+        }
+        if let Some(description) = &self.description {
+            field("Description:", description)?;
+        }
+        Ok(())
+    }
+}
+
+/// Changes to the descriptive metadata of a book. `None` leaves a field
+/// alone and an empty value removes it.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct MetadataUpdate {
+    pub title: Option<String>,
+    pub creators: Option<Vec<Creator>>,
+    pub series: Option<Series>,
+    pub publisher: Option<String>,
+    pub date: Option<String>,
+    pub subjects: Option<Vec<String>>,
+    pub description: Option<String>,
+}
+
+impl MetadataUpdate {
+    pub fn is_empty(&self) -> bool {
+        *self == MetadataUpdate::default()
+    }
+
+    /// Writes the changed fields into `package`, whose metadata currently
+    /// reads as `current`. Returns true if the package was modified.
+    pub(crate) fn apply(&self, package: &mut XmlElement, current: &BookMetadata) -> bool {
+        let is_epub3 = opf::is_epub3(package);
+        let mut changed = false;
+
+        let single = [
+            ("title", &self.title, &current.title),
+            ("publisher", &self.publisher, &current.publisher),
+            ("date", &self.date, &current.date),
+            ("description", &self.description, &current.description),
+        ];
+        for (name, new, old) in single {
+            let Some(new) = new.as_deref().map(str::trim) else {
+                continue;
+            };
+            if new != old.as_deref().unwrap_or_default() {
+                let values: Vec<&str> = Some(new).filter(|v| !v.is_empty()).into_iter().collect();
+                changed |= set_dc(package, name, &values);
+            }
+        }
+
+        if let Some(subjects) = &self.subjects {
+            let values: Vec<&str> = subjects
+                .iter()
+                .map(|subject| subject.trim())
+                .filter(|subject| !subject.is_empty())
+                .collect();
+            if values != current.subjects {
+                changed |= set_dc(package, "subject", &values);
+            }
+        }
+
+        if let Some(creators) = &self.creators {
+            if *creators != current.creators {
+                changed |= set_creators(package, creators, is_epub3);
+            }
+        }
+
+        if let Some(series) = &self.series {
+            let series = Some(series).filter(|series| !series.name.trim().is_empty());
+            if series != current.series.as_ref() {
+                changed |= set_series(package, series, is_epub3);
+            }
+        }
+        changed
+    }
+}
+
+/// Replaces every Dublin Core element named `local` with one per value.
+fn set_dc(package: &mut XmlElement, local: &str, values: &[&str]) -> bool {
+    let Some(metadata) = package.child_mut("metadata") else {
+        return false;
+    };
+    match values {
+        [] => report!("Metadata: removing {local}"),
+        [value] => report!("Metadata: setting {local} to {value:?}"),
+        values => report!("Metadata: setting {local} to {values:?}"),
+    }
+    let name = opf::dc_element_name(metadata, local);
+    let elements = values
+        .iter()
+        .map(|value| XmlElement::new(&name).with_text(value))
+        .collect();
+    replace_elements(metadata, |e| e.local_name() == local, elements);
+    true
+}
+
+fn set_creators(package: &mut XmlElement, creators: &[Creator], is_epub3: bool) -> bool {
+    // The ids of the creators being replaced are free to reuse.
+    let mut ids = element_ids(package);
+    if let Some(metadata) = package.child("metadata") {
+        for creator in metadata.elements().filter(|e| e.local_name() == "creator") {
+            if let Some(id) = creator.attr("id") {
+                ids.remove(id);
+            }
+        }
+    }
+    let attribute_names = (!is_epub3).then(|| {
+        (
+            opf::opf_attribute_name(package, "file-as"),
+            opf::opf_attribute_name(package, "role"),
+        )
+    });
+    let Some(metadata) = package.child_mut("metadata") else {
+        return false;
+    };
+
+    let names: Vec<&str> = creators.iter().map(|c| c.name.as_str()).collect();
+    report!("Metadata: setting creators to {names:?}");
+    let name = opf::dc_element_name(metadata, "creator");
+    let meta = meta_element_name(metadata);
+    let mut elements = Vec::new();
+    for creator in creators {
+        let mut element = XmlElement::new(&name).with_text(&creator.name);
+        match &attribute_names {
+            Some((file_as_name, role_name)) => {
+                if let Some(file_as) = &creator.file_as {
+                    element.set_attr(file_as_name, file_as);
+                }
+                if let Some(role) = &creator.role {
+                    element.set_attr(role_name, role);
+                }
+                elements.push(element);
+            }
+            None => {
+                let id = manifest::unique_id("creator", &ids);
+                ids.insert(id.clone());
+                element.set_attr("id", &id);
+                elements.push(element);
+                let refines = format!("#{id}");
+                if let Some(file_as) = &creator.file_as {
+                    elements.push(
+                        XmlElement::new(&meta)
+                            .with_attr("refines", &refines)
+                            .with_attr("property", "file-as")
+                            .with_text(file_as),
+                    );
+                }
+                if let Some(role) = &creator.role {
+                    elements.push(
+                        XmlElement::new(&meta)
+                            .with_attr("refines", &refines)
+                            .with_attr("property", "role")
+                            .with_attr("scheme", "marc:relators")
+                            .with_text(role),
+                    );
+                }
+            }
+        }
+    }
+    replace_elements(metadata, |e| e.local_name() == "creator", elements);
+    true
+}
+
+/// Writes `series` as an EPUB 3 `belongs-to-collection` and as the
+/// `calibre:series` metas most reading systems understand.
+fn set_series(package: &mut XmlElement, series: Option<&Series>, is_epub3: bool) -> bool {
+    let mut ids = element_ids(package);
+    let Some(metadata) = package.child_mut("metadata") else {
+        return false;
+    };
+
+    let other_collections: HashSet<String> = metadata
+        .elements()
+        .filter(|e| e.attr("property") == Some("collection-type") && e.text().trim() != "series")
+        .filter_map(|e| e.attr("refines")?.strip_prefix('#').map(String::from))
+        .collect();
+    let is_series = |e: &XmlElement| {
+        e.local_name() == "meta"
+            && match e.attr("property") {
+                Some("belongs-to-collection") => {
+                    e.attr("refines").is_none()
+                        && !e
+                            .attr("id")
+                            .is_some_and(|id| other_collections.contains(id))
+                }
+                _ => matches!(
+                    e.attr("name"),
+                    Some("calibre:series" | "calibre:series_index")
+                ),
+            }
+    };
+
+    for id in metadata
+        .elements()
+        .filter(|e| is_series(e))
+        .filter_map(|e| e.attr("id"))
+    {
+        ids.remove(id);
+    }
+
+    let meta = meta_element_name(metadata);
+    let mut elements = Vec::new();
+    match series {
+        None => report!("Metadata: removing series"),
+        Some(series) => {
+            let name = series.name.trim();
+            let index = series.index.as_deref().map(str::trim);
+            match index {
+                Some(index) => report!("Metadata: setting series to {name:?} #{index}"),
+                None => report!("Metadata: setting series to {name:?}"),
+            }
+            if is_epub3 {
+                let id = manifest::unique_id("series", &ids);
+                ids.insert(id.clone());
+                let refines = format!("#{id}");
+                elements.push(
+                    XmlElement::new(&meta)
+                        .with_attr("property", "belongs-to-collection")
+                        .with_attr("id", &id)
+                        .with_text(name),
+                );
+                elements.push(
+                    XmlElement::new(&meta)
+                        .with_attr("refines", &refines)
+                        .with_attr("property", "collection-type")
+                        .with_text("series"),
+                );
+                if let Some(index) = index {
+                    elements.push(
+                        XmlElement::new(&meta)
+                            .with_attr("refines", &refines)
+                            .with_attr("property", "group-position")
+                            .with_text(index),
+                    );
+                }
+            }
+            elements.push(
+                XmlElement::new(&meta)
+                    .with_attr("name", "calibre:series")
+                    .with_attr("content", name),
+            );
+            if let Some(index) = index {
+                elements.push(
+                    XmlElement::new(&meta)
+                        .with_attr("name", "calibre:series_index")
+                        .with_attr("content", index),
+                );
+            }
+        }
+    }
+    replace_elements(metadata, is_series, elements);
+    true
+}
+
+/// Removes the children of `metadata` matching `remove`, together with the
+/// `meta` elements refining them, and inserts `elements` where the first
+/// removed child was.
+fn replace_elements(
+    metadata: &mut XmlElement,
+    remove: impl Fn(&XmlElement) -> bool,
+    elements: Vec<XmlElement>,
+) {
+    let removed_ids: HashSet<String> = metadata
+        .elements()
+        .filter(|e| remove(e))
+        .filter_map(|e| e.attr("id").map(|id| format!("#{id}")))
+        .collect();
+    let matches =
+        |e: &XmlElement| remove(e) || e.attr("refines").is_some_and(|r| removed_ids.contains(r));
+
+    let position = metadata.elements().position(&matches);
+    let index = match position {
+        Some(position) => metadata
+            .elements()
+            .take(position)
+            .filter(|e| !matches(e))
+            .count(),
+        None => metadata.elements().count(),
+    };
+    metadata.retain_elements(|e| !matches(e));
+    for (offset, element) in elements.into_iter().enumerate() {
+        metadata.insert_child(index + offset, element);
+    }
+}
+
+/// Every `id` attribute in `element` and its descendants.
+pub(crate) fn element_ids(element: &XmlElement) -> HashSet<String> {
+    let mut ids: HashSet<String> = element.attr("id").map(String::from).into_iter().collect();
+    for child in element.elements() {
+        ids.extend(element_ids(child));
+    }
+    ids
+}
+
+/// The qualified name for a new `meta` element, matching its siblings.
+pub(crate) fn meta_element_name(metadata: &XmlElement) -> String {
+    match metadata.elements().find(|e| e.local_name() == "meta") {
+        Some(meta) => meta.name.clone(),
+        None => "meta".to_string(),
+    }
+}
+
+/// Makes sure the package has a `dc:identifier` named by its
+/// `unique-identifier` attribute, generating a UUID if there is none: a
+/// random one, or one derived from `seed` so that the same book always gets
+/// the same identifier.
+pub(crate) fn ensure_identifier(package: &mut XmlElement, seed: Option<&[u8]>) -> bool {
+    let unique_id = package_attr(package, "unique-identifier");
+    let Some(metadata) = package.child_mut("metadata") else {
+        return false;
+    };
+
+    let identifiers: Vec<Option<String>> = metadata
+        .elements()
+        .filter(|e| e.local_name() == "identifier" && !e.text().trim().is_empty())
+        .map(|e| e.attr("id").map(String::from))
+        .collect();
+    if unique_id
+        .as_ref()
+        .is_some_and(|uid| identifiers.iter().flatten().any(|id| id == uid))
+    {
+        return false;
+    }
+
+    let id = match identifiers.first() {
+        Some(Some(id)) => {
+            report!("Metadata: pointing unique-identifier at identifier {id}");
+            id.clone()
+        }
+        Some(None) => {
+            let id = unique_id.unwrap_or_else(|| "uid".to_string());
+            metadata.retain_elements(|e| {
+                let empty = e.local_name() == "identifier" && e.attr("id") == Some(id.as_str());
+                if empty {
+                    report!("Metadata: removing the empty identifier {id}");
+                }
+                !empty
+            });
+            if let Some(identifier) = metadata
+                .elements_mut()
+                .find(|e| e.local_name() == "identifier" && !e.text().trim().is_empty())
+            {
+                identifier.set_attr("id", &id);
+            }
+            report!("Metadata: naming the first identifier {id}");
+            id
+        }
+        None => {
+            let id = unique_id.unwrap_or_else(|| "uid".to_string());
+            let uuid = match seed {
+                Some(seed) => {
+                    let digest = sha1_smol::Sha1::from(seed).digest().bytes();
+                    uuid::Builder::from_sha1_bytes(digest[..16].try_into().unwrap()).into_uuid()
+                }
+                None => Uuid::new_v4(),
+            };
+            let value = format!("urn:uuid:{uuid}");
+            // An empty identifier can already carry the id.
+            let is_empty_identifier = |e: &XmlElement| {
+                e.local_name() == "identifier" && e.attr("id") == Some(id.as_str())
+            };
+            if metadata.elements().any(is_empty_identifier) {
+                report!("Metadata: filling the empty identifier {id} with {value}");
+                if let Some(empty) = metadata.elements_mut().find(|e| is_empty_identifier(e)) {
+                    empty.set_text(&value);
+                }
+            } else {
+                report!("Metadata: adding identifier {value}");
+                let name = opf::dc_element_name(metadata, "identifier");
+                metadata.insert_child(
+                    0,
+                    XmlElement::new(&name)
+                        .with_attr("id", &id)
+                        .with_text(&value),
+                );
+            }
+            id
+        }
+    };
+    package.set_attr("unique-identifier", &id);
+    true
+}
+
+/// Adds a `dc:title` from `fallback` when the package has none.
+pub(crate) fn ensure_title(package: &mut XmlElement, fallback: &str) -> bool {
+    let Some(metadata) = package.child_mut("metadata") else {
+        return false;
+    };
+    let has_title = metadata
+        .elements()
+        .any(|e| e.local_name() == "title" && !e.text().trim().is_empty());
+    if has_title || fallback.trim().is_empty() {
+        return false;
+    }
+
+    report!("Metadata: title is missing, using {fallback:?}");
+    metadata.retain_elements(|e| e.local_name() != "title");
+    let name = opf::dc_element_name(metadata, "title");
+    metadata.append_child(XmlElement::new(&name).with_text(fallback.trim()));
+    true
+}
+
+/// Adds or updates `dcterms:modified` on EPUB 3 packages.
+pub(crate) fn ensure_modified(
+    package: &mut XmlElement,
+    timestamp: &str,
+    mode: ModifiedMode,
+) -> bool {
+    if mode == ModifiedMode::Off || !opf::is_epub3(package) {
+        return false;
+    }
+    let Some(metadata) = package.child_mut("metadata") else {
+        return false;
+    };
+
+    let existing = metadata.elements_mut().find(|e| {
+        e.local_name() == "meta"
+            && e.attr("property") == Some("dcterms:modified")
+            && e.attr("refines").is_none()
+    });
+    match existing {
+        Some(meta) if mode == ModifiedMode::Missing && is_timestamp(meta.text().trim()) => false,
+        Some(meta) if meta.text().trim() == timestamp => false,
+        Some(meta) => {
+            report!("Metadata: setting dcterms:modified to {timestamp}");
+            meta.set_text(timestamp);
+            true
+        }
+        None => {
+            report!("Metadata: adding dcterms:modified {timestamp}");
+            let name = meta_element_name(metadata);
+            metadata.append_child(
+                XmlElement::new(&name)
+                    .with_attr("property", "dcterms:modified")
+                    .with_text(timestamp),
+            );
+            true
+        }
+    }
+}
+
+fn package_attr(package: &XmlElement, name: &str) -> Option<String> {
+    package
+        .attr(name)
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(String::from)
+}
+
+/// Checks for the `CCYY-MM-DDThh:mm:ssZ` form EPUB 3 requires.
+fn is_timestamp(value: &str) -> bool {
+    let bytes = value.as_bytes();
+    bytes.len() == 20
+        && bytes.iter().enumerate().all(|(i, b)| match i {
+            4 | 7 => *b == b'-',
+            10 => *b == b'T',
+            13 | 16 => *b == b':',
+            19 => *b == b'Z',
+            _ => b.is_ascii_digit(),
+        })
+}
+
+/// The current time as an EPUB 3 `dcterms:modified` timestamp.
+pub(crate) fn now_timestamp() -> String {
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    format_timestamp(seconds)
+}
+
+/// Formats seconds since the Unix epoch as `CCYY-MM-DDThh:mm:ssZ`.
+pub(crate) fn format_timestamp(seconds: u64) -> String {
+    let (year, month, day) = civil_from_days((seconds / 86_400) as i64);
+    let time = seconds % 86_400;
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+/// Converts days since 1970-01-01 to a proleptic Gregorian date.
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::xml_doc::XmlDocument;
+
+    fn package(opf: &str) -> XmlElement {
+        XmlDocument::parse(opf.as_bytes()).unwrap().root
+    }
+
+    #[test]
+    fn ensure_identifier_generates_uuid() {
+        let mut package = package(
+            r#"<package version="3.0"><metadata xmlns:dc="http://purl.org/dc/elements/1.1/"><dc:title>T</dc:title></metadata></package>"#,
+        );
+        assert!(ensure_identifier(&mut package, None));
+        assert_eq!(package.attr("unique-identifier"), Some("uid"));
+        let identifier = package
+            .child("metadata")
+            .unwrap()
+            .child("identifier")
+            .unwrap();
+        assert_eq!(identifier.name, "dc:identifier");
+        assert_eq!(identifier.attr("id"), Some("uid"));
+        assert!(identifier.text().starts_with("urn:uuid:"));
+        assert!(!ensure_identifier(&mut package, None));
+    }
+
+    #[test]
+    fn ensure_identifier_derives_uuid_from_seed() {
+        let source = r#"<package version="3.0"><metadata xmlns:dc="http://purl.org/dc/elements/1.1/"><dc:title>T</dc:title></metadata></package>"#;
+        let identifier = |seed: &[u8]| {
+            let mut package = package(source);
+            assert!(ensure_identifier(&mut package, Some(seed)));
+            package
+                .child("metadata")
+                .unwrap()
+                .child("identifier")
+                .unwrap()
+                .text()
+        };
+        assert_eq!(identifier(b"book"), identifier(b"book"));
+        assert_ne!(identifier(b"book"), identifier(b"other book"));
+    }
+
+    #[test]
+    fn ensure_identifier_points_at_existing_identifier() {
+        let mut package = package(
+            r#"<package unique-identifier="missing"><metadata><dc:identifier id="isbn">978</dc:identifier></metadata></package>"#,
+        );
+        assert!(ensure_identifier(&mut package, None));
+        assert_eq!(package.attr("unique-identifier"), Some("isbn"));
+    }
+
+    #[test]
+    fn ensure_identifier_reuses_empty_identifiers() {
+        let mut empty = package(
+            r#"<package unique-identifier="uid"><metadata><dc:identifier id="uid"/></metadata></package>"#,
+        );
+        assert!(ensure_identifier(&mut empty, None));
+        let metadata = empty.child("metadata").unwrap();
+        assert_eq!(metadata.elements().count(), 1);
+        let identifier = metadata.child("identifier").unwrap();
+        assert_eq!(identifier.attr("id"), Some("uid"));
+        assert!(identifier.text().starts_with("urn:uuid:"));
+
+        let mut named = package(
+            r#"<package unique-identifier="uid"><metadata><dc:identifier id="uid"> </dc:identifier><dc:identifier>978</dc:identifier></metadata></package>"#,
+        );
+        assert!(ensure_identifier(&mut named, None));
+        let metadata = named.child("metadata").unwrap();
+        assert_eq!(metadata.elements().count(), 1);
+        assert_eq!(
+            metadata.child("identifier").unwrap().attr("id"),
+            Some("uid")
+        );
+        assert_eq!(metadata.child("identifier").unwrap().text(), "978");
+    }
+
+    #[test]
+    fn ensure_title_uses_fallback() {
+        let mut package = package(
+            r#"<package><metadata xmlns:dc="http://purl.org/dc/elements/1.1/"><dc:title/></metadata></package>"#,
+        );
+        assert!(ensure_title(&mut package, "Chapter One"));
+        let metadata = package.child("metadata").unwrap();
+        assert_eq!(metadata.elements().count(), 1);
+        assert_eq!(metadata.child("title").unwrap().text(), "Chapter One");
+        assert!(!ensure_title(&mut package, "Other"));
+    }
+
+    #[test]
+    fn ensure_modified_respects_mode_and_version() {
+        let opf = r#"<package version="3.0"><metadata><meta property="dcterms:modified">2020-01-01T00:00:00Z</meta></metadata></package>"#;
+        let mut package3 = package(opf);
+        assert!(!ensure_modified(
+            &mut package3,
+            "2024-05-06T07:08:09Z",
+            ModifiedMode::Missing
+        ));
+        assert!(ensure_modified(
+            &mut package3,
+            "2024-05-06T07:08:09Z",
+            ModifiedMode::Always
+        ));
+        let meta = package3.child("metadata").unwrap().child("meta").unwrap();
+        assert_eq!(meta.text(), "2024-05-06T07:08:09Z");
+
+        let mut package2 = package(&opf.replace("3.0", "2.0"));
+        assert!(!ensure_modified(
+            &mut package2,
+            "2024-05-06T07:08:09Z",
+            ModifiedMode::Always
+        ));
+
+        let mut missing = package(r#"<package version="3.0"><metadata></metadata></package>"#);
+        assert!(ensure_modified(
+            &mut missing,
+            "2024-05-06T07:08:09Z",
+            ModifiedMode::Missing
+        ));
+    }
+
+    #[test]
+    fn creator_parses_optional_fields() {
+        assert_eq!(
+            "Jane Doe | Doe, Jane | aut".parse::<Creator>(),
+            Ok(Creator {
+                name: "Jane Doe".into(),
+                file_as: Some("Doe, Jane".into()),
+                role: Some("aut".into()),
+            })
+        );
+        assert_eq!(
+            "Bob||edt".parse::<Creator>().unwrap().role.as_deref(),
+            Some("edt")
+        );
+        assert!("".parse::<Creator>().is_err());
+        assert!("a|b|c|d".parse::<Creator>().is_err());
+    }
+
+    #[test]
+    fn update_writes_epub2_attributes_and_calibre_series() {
+        let opf = br#"<package version="2.0"><metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:title>T</dc:title>
+    <dc:creator>Old</dc:creator>
+    <meta name="calibre:series" content="Old Saga"/>
+  </metadata><manifest/></package>"#;
+        let mut package = XmlDocument::parse(opf).unwrap().root;
+        let current = BookMetadata::from_package(&Package::parse(opf).unwrap());
+        assert_eq!(current.series.as_ref().unwrap().name, "Old Saga");
+
+        let update = MetadataUpdate {
+            creators: Some(vec!["Jane Doe|Doe, Jane|aut".parse().unwrap()]),
+            series: Some(Series {
+                name: "Saga".into(),
+                index: Some("3".into()),
+            }),
+            title: Some("T".into()),
+            ..Default::default()
+        };
+        assert!(update.apply(&mut package, &current));
+
+        let metadata = package.child("metadata").unwrap();
+        assert_eq!(metadata.attr("xmlns:opf"), Some(opf::OPF_NS));
+        let creator = metadata.child("creator").unwrap();
+        assert_eq!(creator.text(), "Jane Doe");
+        assert_eq!(creator.attr("opf:file-as"), Some("Doe, Jane"));
+        assert_eq!(creator.attr("opf:role"), Some("aut"));
+        let metas: Vec<_> = metadata
+            .elements()
+            .filter(|e| e.local_name() == "meta")
+            .map(|e| (e.attr("name").unwrap(), e.attr("content").unwrap()))
+            .collect();
+        assert_eq!(
+            metas,
+            [("calibre:series", "Saga"), ("calibre:series_index", "3")]
+        );
+    }
+
+    #[test]
+    fn update_replaces_epub3_refinements() {
+        let opf =
+            br##"<package version="3.0"><metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:creator id="creator">Old</dc:creator>
+    <meta refines="#creator" property="role">aut</meta>
+    <dc:subject>x</dc:subject>
+  </metadata><manifest/></package>"##;
+        let mut document = XmlDocument::parse(opf).unwrap();
+        let current = BookMetadata::from_package(&Package::parse(opf).unwrap());
+        let update = MetadataUpdate {
+            creators: Some(vec!["New||edt".parse().unwrap()]),
+            subjects: Some(Vec::new()),
+            ..Default::default()
+        };
+        assert!(update.apply(&mut document.root, &current));
+
+        let metadata = document.root.child("metadata").unwrap();
+        let names: Vec<_> = metadata
+            .elements()
+            .map(|e| (e.name.as_str(), e.text()))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                ("dc:creator", "New".to_string()),
+                ("meta", "edt".to_string())
+            ]
+        );
+        assert_eq!(
+            metadata.child("meta").unwrap().attr("refines"),
+            Some("#creator")
+        );
+
+        let current = BookMetadata::from_package(&Package::parse(&document.to_bytes()).unwrap());
+        assert!(!update.apply(&mut document.root, &current));
+    }
+
+    #[test]
+    fn format_timestamp_converts_epoch_seconds() {
+        assert_eq!(format_timestamp(0), "1970-01-01T00:00:00Z");
+        assert_eq!(format_timestamp(1_709_251_199), "2024-02-29T23:59:59Z");
+    }
+}
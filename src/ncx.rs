@@ -0,0 +1,262 @@
+use crate::xml_doc::{XmlElement, XmlNode};
+use crate::{href, manifest, media_type};
+use std::collections::{BTreeMap, HashSet};
+
+/// Elements of an NCX that carry a `playOrder`.
+const ORDERED: &[&str] = &["navPoint", "pageTarget", "navTarget"];
+
+/// Checks an NCX against the archive: removes `navPoint`s whose target is
+/// missing (keeping their children), renumbers `playOrder` in document order
+/// and updates `dtb:uid` and `dtb:depth`.
+///
+/// `exists` tells whether an archive entry exists and `uid` is the package's
+/// unique identifier, if known. Returns true if the NCX was modified.
+pub(crate) fn repair(
+    ncx: &mut XmlElement,
+    ncx_path: &str,
+    exists: impl Fn(&str) -> bool,
+    uid: Option<&str>,
+) -> bool {
+    let mut changed = false;
+    if let Some(nav_map) = ncx.child_mut("navMap") {
+        changed |= prune(nav_map, ncx_path, &exists);
+    }
+
+    let mut orders = BTreeMap::new();
+    changed |= renumber(ncx, &mut orders);
+
+    let depth = ncx.child("navMap").map(nav_depth).unwrap_or(0).max(1);
+    if let Some(head) = ncx.child_mut("head") {
+        for meta in head.elements_mut().filter(|e| e.local_name() == "meta") {
+            let expected = match meta.attr("name") {
+                Some("dtb:uid") => match uid {
+                    Some(uid) => uid.to_string(),
+                    None => continue,
+                },
+                Some("dtb:depth") => depth.to_string(),
+                _ => continue,
+            };
+            if meta.attr("content") != Some(expected.as_str()) {
+                let name = meta.attr("name").unwrap_or_default();
+                report!("NCX: setting {name} to {expected}");
+                meta.set_attr("content", &expected);
+                changed = true;
+            }
+        }
+    }
+    changed
+}
+
+/// True when the NCX has at least one `navPoint` left.
+pub(crate) fn has_nav_points(ncx: &XmlElement) -> bool {
+    ncx.child("navMap")
+        .is_some_and(|nav_map| nav_map.child("navPoint").is_some())
+}
+
+fn prune(parent: &mut XmlElement, ncx_path: &str, exists: &impl Fn(&str) -> bool) -> bool {
+    let mut changed = false;
+    let mut children = Vec::with_capacity(parent.children.len());
+    for node in std::mem::take(&mut parent.children) {
+        let XmlNode::Element(mut element) = node else {
+            children.push(node);
+            continue;
+        };
+        if element.local_name() != "navPoint" {
+            children.push(XmlNode::Element(element));
+            continue;
+        }
+
+        changed |= prune(&mut element, ncx_path, exists);
+        let src = element
+            .child("content")
+            .and_then(|content| content.attr("src"))
+            .unwrap_or_default()
+            .to_string();
+        if href::is_external(&src) || (!src.is_empty() && exists(&href::resolve(ncx_path, &src))) {
+            children.push(XmlNode::Element(element));
+            continue;
+        }
+
+        let label = element
+            .child("navLabel")
+            .map(|label| label.text().trim().to_string())
+            .unwrap_or_default();
+        report!("NCX: removing navPoint {label:?}, {src:?} is not in the archive");
+        if children.last().is_some_and(is_whitespace) {
+            children.pop();
+        }
+        children.extend(element.children.into_iter().filter(
+            |node| matches!(node, XmlNode::Element(child) if child.local_name() == "navPoint"),
+        ));
+        changed = true;
+    }
+    parent.children = children;
+    changed
+}
+
+fn is_whitespace(node: &XmlNode) -> bool {
+    matches!(node, XmlNode::Text(text) if text.trim().is_empty())
+}
+
+/// Gives every ordered element a `playOrder` following document order, with
+/// elements pointing at the same target sharing one.
+fn renumber(element: &mut XmlElement, orders: &mut BTreeMap<String, usize>) -> bool {
+    let mut changed = false;
+    for child in element.elements_mut() {
+        if ORDERED.contains(&child.local_name()) {
+            let src = child
+                .child("content")
+                .and_then(|content| content.attr("src"))
+                .unwrap_or_default()
+                .to_string();
+            let next = orders.len() + 1;
+            let order = *orders.entry(src).or_insert(next);
+            if child.attr("playOrder") != Some(order.to_string().as_str()) {
+                let id = child.attr("id").unwrap_or_default();
+                report!("NCX: setting playOrder of {id} to {order}");
+                child.set_attr("playOrder", &order.to_string());
+                changed = true;
+            }
+        }
+        changed |= renumber(child, orders);
+    }
+    changed
+}
+
+fn nav_depth(element: &XmlElement) -> usize {
+    element
+        .elements()
+        .filter(|e| e.local_name() == "navPoint")
+        .map(|point| 1 + nav_depth(point))
+        .max()
+        .unwrap_or(0)
+}
+
+/// Declares the NCX at `ncx_path` in the manifest and names it in the spine
+/// `toc` attribute. Returns true if the package was modified.
+pub(crate) fn link(package: &mut XmlElement, opf_path: &str, ncx_path: &str) -> bool {
+    let Some(manifest) = package.child_mut("manifest") else {
+        return false;
+    };
+
+    let mut changed = false;
+    let existing = manifest
+        .elements()
+        .filter(|item| item.local_name() == "item")
+        .find(|item| href::resolve(opf_path, item.attr("href").unwrap_or_default()) == ncx_path)
+        .and_then(|item| item.attr("id"))
+        .map(String::from);
+    let id = match existing {
+        Some(id) => id,
+        None => {
+            let ids: HashSet<String> = manifest
+                .elements()
+                .filter_map(|item| item.attr("id"))
+                .map(String::from)
+                .collect();
+            let id = manifest::unique_id("ncx", &ids);
+            report!("NCX: adding {ncx_path} to the manifest as {id}");
+            let name = match manifest.elements().find(|e| e.local_name() == "item") {
+                Some(item) => item.name.clone(),
+                None => "item".to_string(),
+            };
+            manifest.append_child(
+                XmlElement::new(&name)
+                    .with_attr("id", &id)
+                    .with_attr("href", &href::relative(opf_path, ncx_path))
+                    .with_attr("media-type", media_type::NCX),
+            );
+            changed = true;
+            id
+        }
+    };
+
+    if let Some(spine) = package.child_mut("spine") {
+        if spine.attr("toc") != Some(id.as_str()) {
+            report!("NCX: setting the spine toc to {id}");
+            spine.set_attr("toc", &id);
+            changed = true;
+        }
+    }
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::xml_doc::XmlDocument;
+
+    const NCX: &[u8] = br#"<ncx>
+  <head>
+    <meta name="dtb:uid" content="old"/>
+    <meta name="dtb:depth" content="3"/>
+  </head>
+  <navMap>
+    <navPoint id="a" playOrder="5">
+      <navLabel><text>One</text></navLabel>
+      <content src="Text/ch1.xhtml"/>
+    </navPoint>
+    <navPoint id="b" playOrder="1">
+      <navLabel><text>Gone</text></navLabel>
+      <content src="Text/gone.xhtml"/>
+      <navPoint id="c" playOrder="7">
+        <navLabel><text>Two</text></navLabel>
+        <content src="Text/ch2.xhtml"/>
+      </navPoint>
+    </navPoint>
+  </navMap>
+</ncx>"#;
+
+    #[test]
+    fn repair_removes_missing_targets_and_renumbers() {
+        let mut document = XmlDocument::parse(NCX).unwrap();
+        let exists = |path: &str| path != "OEBPS/Text/gone.xhtml";
+        assert!(repair(
+            &mut document.root,
+            "OEBPS/toc.ncx",
+            exists,
+            Some("urn:uuid:1")
+        ));
+        assert!(!repair(
+            &mut document.root,
+            "OEBPS/toc.ncx",
+            exists,
+            Some("urn:uuid:1")
+        ));
+
+        let nav_map = document.root.child("navMap").unwrap();
+        let points: Vec<_> = nav_map
+            .elements()
+            .map(|p| (p.attr("id").unwrap(), p.attr("playOrder").unwrap()))
+            .collect();
+        assert_eq!(points, [("a", "1"), ("c", "2")]);
+        let head = document.root.child("head").unwrap();
+        let metas: Vec<_> = head
+            .elements()
+            .map(|m| m.attr("content").unwrap())
+            .collect();
+        assert_eq!(metas, ["urn:uuid:1", "1"]);
+        assert!(has_nav_points(&document.root));
+    }
+
+    #[test]
+    fn link_declares_ncx_and_sets_spine_toc() {
+        let mut package = XmlDocument::parse(
+            br#"<package><manifest><item id="ncx" href="a.xhtml" media-type="application/xhtml+xml"/></manifest><spine/></package>"#
+                .as_slice(),
+        )
+        .unwrap()
+        .root;
+        assert!(link(&mut package, "OEBPS/content.opf", "OEBPS/toc.ncx"));
+        let item = package
+            .child("manifest")
+            .unwrap()
+            .elements()
+            .nth(1)
+            .unwrap();
+        assert_eq!(item.attr("id"), Some("ncx-2"));
+        assert_eq!(item.attr("href"), Some("toc.ncx"));
+        assert_eq!(package.child("spine").unwrap().attr("toc"), Some("ncx-2"));
+        assert!(!link(&mut package, "OEBPS/content.opf", "OEBPS/toc.ncx"));
+    }
+}
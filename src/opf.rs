@@ -0,0 +1,780 @@
+//! Typed model of the OPF package document.
+
+use crate::{
+    epub,
+    error::FixError,
+    href, media_type,
+    xml_doc::{XmlDocument, XmlElement},
+};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use zip::ZipArchive;
+
+pub const OPF_NS: &str = "http://www.idpf.org/2007/opf";
+pub const DC_NS: &str = "http://purl.org/dc/elements/1.1/";
+
+/// The Dublin Core element set.
+pub(crate) const DC_ELEMENTS: &[&str] = &[
+    "contributor",
+    "coverage",
+    "creator",
+    "date",
+    "description",
+    "format",
+    "identifier",
+    "language",
+    "publisher",
+    "relation",
+    "rights",
+    "source",
+    "subject",
+    "title",
+    "type",
+];
+
+/// EPUB 2 attributes that live in the `opf:` namespace on Dublin Core elements.
+const OPF_ATTRIBUTES: &[&str] = &["role", "file-as", "scheme", "event"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EpubVersion {
+    #[default]
+    Epub2,
+    Epub3,
+}
+
+impl EpubVersion {
+    /// Reads the `version` attribute of a package. Only versions starting
+    /// with 3 are EPUB 3; a missing, empty or unknown version is treated as
+    /// EPUB 2, so that no EPUB 3-only markup is written into a book that does
+    /// not declare itself EPUB 3.
+    pub(crate) fn parse(version: &str) -> Self {
+        if version.trim().starts_with('3') {
+            EpubVersion::Epub3
+        } else {
+            EpubVersion::Epub2
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EpubVersion::Epub2 => "2.0",
+            EpubVersion::Epub3 => "3.0",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Package {
+    pub version: EpubVersion,
+    pub unique_identifier: Option<String>,
+    pub prefix: Option<String>,
+    pub metadata: Metadata,
+    pub manifest: Vec<ManifestItem>,
+    pub spine: Spine,
+    pub guide: Vec<GuideReference>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Metadata {
+    pub dc: Vec<DcElement>,
+    pub meta: Vec<Meta>,
+    pub links: Vec<Link>,
+}
+
+/// A Dublin Core element such as `dc:title`. `name` is the local name.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct DcElement {
+    pub name: String,
+    pub id: Option<String>,
+    pub value: String,
+    /// Remaining attributes keyed by local name, e.g. `role`, `file-as` or `lang`.
+    pub attributes: BTreeMap<String, String>,
+}
+
+/// A `meta` element: either an EPUB 3 `property` (possibly refining another
+/// element) or an EPUB 2 `name`/`content` pair.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Meta {
+    pub property: Option<String>,
+    pub refines: Option<String>,
+    pub id: Option<String>,
+    pub scheme: Option<String>,
+    pub value: String,
+    pub name: Option<String>,
+    pub content: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Link {
+    pub rel: String,
+    pub href: String,
+    pub media_type: Option<String>,
+    pub refines: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ManifestItem {
+    pub id: String,
+    pub href: String,
+    pub media_type: String,
+    pub properties: Vec<String>,
+    pub fallback: Option<String>,
+    pub media_overlay: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Spine {
+    pub toc: Option<String>,
+    pub page_progression_direction: Option<String>,
+    pub itemrefs: Vec<ItemRef>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ItemRef {
+    pub idref: String,
+    pub id: Option<String>,
+    pub linear: bool,
+    pub properties: Vec<String>,
+}
+
+impl Default for ItemRef {
+    fn default() -> Self {
+        ItemRef {
+            idref: String::new(),
+            id: None,
+            linear: true,
+            properties: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct GuideReference {
+    pub kind: String,
+    pub title: Option<String>,
+    pub href: String,
+}
+
+impl Package {
+    /// Whether the package is EPUB 3; see [`EpubVersion::parse`].
+    pub fn is_epub3(&self) -> bool {
+        self.version == EpubVersion::Epub3
+    }
+
+    pub fn parse(content: &[u8]) -> Result<Package, FixError> {
+        Package::from_element(&XmlDocument::parse(content)?.root)
+    }
+
+    /// Reads the package from `root`, the `<package>` element of an already
+    /// parsed package document.
+    pub(crate) fn from_element(root: &XmlElement) -> Result<Package, FixError> {
+        if root.local_name() != "package" {
+            return Err(FixError::InvalidPackage(format!(
+                "unexpected root element <{}>",
+                root.name
+            )));
+        }
+        let metadata = root
+            .child("metadata")
+            .ok_or_else(|| FixError::InvalidPackage("missing <metadata>".to_string()))?;
+        let manifest = root
+            .child("manifest")
+            .ok_or_else(|| FixError::InvalidPackage("missing <manifest>".to_string()))?;
+
+        let mut dc_prefixes = vec![Some("dc")];
+        dc_prefixes.extend(root.declared_prefix(DC_NS));
+        Ok(Package {
+            version: EpubVersion::parse(root.attr("version").unwrap_or_default()),
+            unique_identifier: root.attr("unique-identifier").map(String::from),
+            prefix: root.attr("prefix").map(String::from),
+            metadata: Metadata::parse(metadata, &dc_prefixes),
+            manifest: child_elements(manifest, "item")
+                .map(ManifestItem::parse)
+                .collect(),
+            spine: root.child("spine").map(Spine::parse).unwrap_or_default(),
+            guide: root
+                .child("guide")
+                .map(|guide| {
+                    child_elements(guide, "reference")
+                        .map(GuideReference::parse)
+                        .collect()
+                })
+                .unwrap_or_default(),
+        })
+    }
+
+    /// Reads the package document of the EPUB at `path`.
+    pub fn from_epub<P: AsRef<Path>>(path: P) -> Result<Package, FixError> {
+        let mut archive = ZipArchive::new(File::open(path)?)?;
+        let mut container = Vec::new();
+        archive
+            .by_name("META-INF/container.xml")?
+            .read_to_end(&mut container)?;
+        let opf_path = epub::get_opf_filename(&container)
+            .ok_or_else(|| FixError::InvalidPackage("no rootfile in container.xml".to_string()))?;
+        let mut content = Vec::new();
+        archive.by_name(&opf_path)?.read_to_end(&mut content)?;
+        Package::parse(&content)
+    }
+
+    pub fn item(&self, id: &str) -> Option<&ManifestItem> {
+        self.manifest.iter().find(|item| item.id == id)
+    }
+
+    pub fn item_mut(&mut self, id: &str) -> Option<&mut ManifestItem> {
+        self.manifest.iter_mut().find(|item| item.id == id)
+    }
+
+    /// Finds the manifest item for the archive entry `path`, resolving hrefs
+    /// relative to the package document at `opf_path`.
+    pub fn item_by_path(&self, opf_path: &str, path: &str) -> Option<&ManifestItem> {
+        self.manifest
+            .iter()
+            .find(|item| href::resolve(opf_path, &item.href) == path)
+    }
+
+    pub fn items_with_property<'a>(
+        &'a self,
+        property: &'a str,
+    ) -> impl Iterator<Item = &'a ManifestItem> + 'a {
+        self.manifest
+            .iter()
+            .filter(move |item| item.has_property(property))
+    }
+
+    /// The NCX named by the spine `toc` attribute, or else the first NCX in
+    /// the manifest.
+    pub fn ncx(&self) -> Option<&ManifestItem> {
+        self.spine
+            .toc
+            .as_deref()
+            .and_then(|id| self.item(id))
+            .or_else(|| {
+                self.manifest
+                    .iter()
+                    .find(|item| item.media_type == media_type::NCX)
+            })
+    }
+
+    /// Manifest items referenced by the spine, in reading order.
+    pub fn spine_items(&self) -> impl Iterator<Item = &ManifestItem> + '_ {
+        self.spine
+            .itemrefs
+            .iter()
+            .filter_map(|itemref| self.item(&itemref.idref))
+    }
+
+    /// The `dc:identifier` named by the package `unique-identifier` attribute.
+    pub fn unique_identifier_value(&self) -> Option<&str> {
+        let id = self.unique_identifier.as_deref()?;
+        self.metadata
+            .dc("identifier")
+            .find(|element| element.id.as_deref() == Some(id))
+            .map(|element| element.value.as_str())
+    }
+
+    /// Serializes the package as a new OPF document. It is written without
+    /// indentation; fixes edit existing package documents in place instead,
+    /// keeping their formatting.
+    pub fn to_xml(&self) -> Vec<u8> {
+        let mut root = XmlElement::new("package")
+            .with_attr("xmlns", OPF_NS)
+            .with_attr("version", self.version.as_str());
+        set_opt(&mut root, "unique-identifier", &self.unique_identifier);
+        set_opt(&mut root, "prefix", &self.prefix);
+
+        root.append_child(self.metadata.to_element(self.version));
+        let mut manifest = XmlElement::new("manifest");
+        for item in &self.manifest {
+            manifest.append_child(item.to_element());
+        }
+        root.append_child(manifest);
+        root.append_child(self.spine.to_element());
+
+        if !self.guide.is_empty() {
+            let mut guide = XmlElement::new("guide");
+            for reference in &self.guide {
+                guide.append_child(reference.to_element());
+            }
+            root.append_child(guide);
+        }
+        XmlDocument::new(root).to_bytes()
+    }
+}
+
+impl Metadata {
+    /// Reads `element`, in which Dublin Core elements use one of the
+    /// `dc_prefixes` declared by its ancestors, `None` being the default
+    /// namespace.
+    fn parse(element: &XmlElement, dc_prefixes: &[Option<&str>]) -> Self {
+        let mut dc_prefixes = dc_prefixes.to_vec();
+        dc_prefixes.extend(element.declared_prefix(DC_NS));
+        let mut metadata = Metadata::default();
+        for child in element.elements() {
+            match child.local_name() {
+                // OPF 2.0 allows the legacy `dc-metadata`/`x-metadata` grouping.
+                "dc-metadata" | "x-metadata" => {
+                    let nested = Metadata::parse(child, &dc_prefixes);
+                    metadata.dc.extend(nested.dc);
+                    metadata.meta.extend(nested.meta);
+                    metadata.links.extend(nested.links);
+                }
+                "meta" => metadata.meta.push(Meta::parse(child)),
+                "link" => metadata.links.push(Link {
+                    rel: child.attr("rel").unwrap_or_default().to_string(),
+                    href: child.attr("href").unwrap_or_default().to_string(),
+                    media_type: child.attr("media-type").map(String::from),
+                    refines: child.attr("refines").map(String::from),
+                }),
+                _ if dc_prefixes.contains(&child.prefix())
+                    || child.declared_prefix(DC_NS) == Some(child.prefix()) =>
+                {
+                    metadata.dc.push(DcElement::parse(child))
+                }
+                _ => (),
+            }
+        }
+        metadata
+    }
+
+    /// Dublin Core elements with the local name `name`, in document order.
+    pub fn dc<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a DcElement> + 'a {
+        self.dc.iter().filter(move |element| element.name == name)
+    }
+
+    /// The value of the first Dublin Core element named `name`.
+    pub fn first(&self, name: &str) -> Option<&str> {
+        self.dc
+            .iter()
+            .find(|element| element.name == name)
+            .map(|element| element.value.as_str())
+    }
+
+    /// EPUB 3 `meta` elements refining the element with the given `id`.
+    pub fn refinements<'a>(&'a self, id: &'a str) -> impl Iterator<Item = &'a Meta> + 'a {
+        self.meta.iter().filter(move |meta| {
+            meta.refines.as_deref().and_then(|r| r.strip_prefix('#')) == Some(id)
+        })
+    }
+
+    /// Looks up `property` (e.g. `role` or `file-as`) for `element`, from an
+    /// EPUB 3 refinement or from the equivalent EPUB 2 `opf:` attribute.
+    pub fn refined<'a>(&'a self, element: &'a DcElement, property: &str) -> Option<&'a str> {
+        element
+            .id
+            .as_deref()
+            .and_then(|id| {
+                self.refinements(id)
+                    .find(|meta| meta.property.as_deref() == Some(property))
+                    .map(|meta| meta.value.as_str())
+            })
+            .or_else(|| element.attributes.get(property).map(String::as_str))
+    }
+
+    /// The `content` of the EPUB 2 `meta` element named `name`.
+    pub fn meta_content(&self, name: &str) -> Option<&str> {
+        self.meta
+            .iter()
+            .find(|meta| meta.name.as_deref() == Some(name))
+            .and_then(|meta| meta.content.as_deref())
+    }
+
+    /// The value of the first non-refining EPUB 3 `meta` with `property`.
+    pub fn property(&self, property: &str) -> Option<&str> {
+        self.meta
+            .iter()
+            .find(|meta| meta.refines.is_none() && meta.property.as_deref() == Some(property))
+            .map(|meta| meta.value.as_str())
+    }
+
+    fn to_element(&self, version: EpubVersion) -> XmlElement {
+        let mut metadata = XmlElement::new("metadata").with_attr("xmlns:dc", DC_NS);
+        if version == EpubVersion::Epub2 {
+            metadata.set_attr("xmlns:opf", OPF_NS);
+        }
+
+        for dc in &self.dc {
+            let mut element = XmlElement::new(&format!("dc:{}", dc.name));
+            set_opt(&mut element, "id", &dc.id);
+            for (name, value) in &dc.attributes {
+                let name = match name.as_str() {
+                    "lang" => "xml:lang".to_string(),
+                    name if OPF_ATTRIBUTES.contains(&name) => format!("opf:{name}"),
+                    name => name.to_string(),
+                };
+                element.set_attr(&name, value);
+            }
+            metadata.append_child(element.with_text(&dc.value));
+        }
+
+        for meta in &self.meta {
+            let mut element = XmlElement::new("meta");
+            set_opt(&mut element, "property", &meta.property);
+            set_opt(&mut element, "refines", &meta.refines);
+            set_opt(&mut element, "id", &meta.id);
+            set_opt(&mut element, "scheme", &meta.scheme);
+            set_opt(&mut element, "name", &meta.name);
+            set_opt(&mut element, "content", &meta.content);
+            if !meta.value.is_empty() {
+                element.set_text(&meta.value);
+            }
+            metadata.append_child(element);
+        }
+
+        for link in &self.links {
+            let mut element = XmlElement::new("link")
+                .with_attr("rel", &link.rel)
+                .with_attr("href", &link.href);
+            set_opt(&mut element, "media-type", &link.media_type);
+            set_opt(&mut element, "refines", &link.refines);
+            metadata.append_child(element);
+        }
+        metadata
+    }
+}
+
+impl DcElement {
+    pub fn new(name: &str, value: &str) -> Self {
+        DcElement {
+            name: name.to_string(),
+            value: value.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn parse(element: &XmlElement) -> Self {
+        let mut attributes: BTreeMap<String, String> = element
+            .attributes()
+            .filter(|(name, _)| *name != "xmlns" && !name.starts_with("xmlns:"))
+            .map(|(name, value)| {
+                let local = name.rsplit_once(':').map_or(name, |(_, local)| local);
+                (local.to_string(), value.to_string())
+            })
+            .collect();
+        DcElement {
+            name: element.local_name().to_string(),
+            id: attributes.remove("id"),
+            value: text(element),
+            attributes,
+        }
+    }
+}
+
+impl Meta {
+    fn parse(element: &XmlElement) -> Self {
+        Meta {
+            property: element.attr("property").map(String::from),
+            refines: element.attr("refines").map(String::from),
+            id: element.attr("id").map(String::from),
+            scheme: element.attr("scheme").map(String::from),
+            value: text(element),
+            name: element.attr("name").map(String::from),
+            content: element.attr("content").map(String::from),
+        }
+    }
+}
+
+impl ManifestItem {
+    pub fn has_property(&self, property: &str) -> bool {
+        self.properties.iter().any(|p| p == property)
+    }
+
+    pub fn is_xhtml(&self) -> bool {
+        matches!(
+            self.media_type.as_str(),
+            "application/xhtml+xml" | "text/html"
+        )
+    }
+
+    fn parse(element: &XmlElement) -> Self {
+        ManifestItem {
+            id: element.attr("id").unwrap_or_default().to_string(),
+            href: element.attr("href").unwrap_or_default().to_string(),
+            media_type: element.attr("media-type").unwrap_or_default().to_string(),
+            properties: split_properties(element.attr("properties")),
+            fallback: element.attr("fallback").map(String::from),
+            media_overlay: element.attr("media-overlay").map(String::from),
+        }
+    }
+
+    fn to_element(&self) -> XmlElement {
+        let mut element = XmlElement::new("item")
+            .with_attr("id", &self.id)
+            .with_attr("href", &self.href)
+            .with_attr("media-type", &self.media_type);
+        if !self.properties.is_empty() {
+            element.set_attr("properties", &self.properties.join(" "));
+        }
+        set_opt(&mut element, "fallback", &self.fallback);
+        set_opt(&mut element, "media-overlay", &self.media_overlay);
+        element
+    }
+}
+
+impl Spine {
+    fn parse(element: &XmlElement) -> Self {
+        Spine {
+            toc: element.attr("toc").map(String::from),
+            page_progression_direction: element
+                .attr("page-progression-direction")
+                .map(String::from),
+            itemrefs: child_elements(element, "itemref")
+                .map(|itemref| ItemRef {
+                    idref: itemref.attr("idref").unwrap_or_default().to_string(),
+                    id: itemref.attr("id").map(String::from),
+                    linear: itemref.attr("linear") != Some("no"),
+                    properties: split_properties(itemref.attr("properties")),
+                })
+                .collect(),
+        }
+    }
+
+    fn to_element(&self) -> XmlElement {
+        let mut spine = XmlElement::new("spine");
+        set_opt(&mut spine, "toc", &self.toc);
+        set_opt(
+            &mut spine,
+            "page-progression-direction",
+            &self.page_progression_direction,
+        );
+        for itemref in &self.itemrefs {
+            let mut element = XmlElement::new("itemref").with_attr("idref", &itemref.idref);
+            set_opt(&mut element, "id", &itemref.id);
+            if !itemref.linear {
+                element.set_attr("linear", "no");
+            }
+            if !itemref.properties.is_empty() {
+                element.set_attr("properties", &itemref.properties.join(" "));
+            }
+            spine.append_child(element);
+        }
+        spine
+    }
+}
+
+impl GuideReference {
+    fn parse(element: &XmlElement) -> Self {
+        GuideReference {
+            kind: element.attr("type").unwrap_or_default().to_string(),
+            title: element.attr("title").map(String::from),
+            href: element.attr("href").unwrap_or_default().to_string(),
+        }
+    }
+
+    fn to_element(&self) -> XmlElement {
+        let mut element = XmlElement::new("reference").with_attr("type", &self.kind);
+        set_opt(&mut element, "title", &self.title);
+        element.with_attr("href", &self.href)
+    }
+}
+
+/// Whether the `<package>` element `package` is EPUB 3, by the rule of
+/// [`EpubVersion::parse`].
+pub(crate) fn is_epub3(package: &XmlElement) -> bool {
+    EpubVersion::parse(package.attr("version").unwrap_or_default()) == EpubVersion::Epub3
+}
+
+/// The qualified name to use for a new Dublin Core element in `metadata`,
+/// declaring the `dc` prefix on `metadata` if no prefix for it is in use.
+pub(crate) fn dc_element_name(metadata: &mut XmlElement, local: &str) -> String {
+    let prefix = match metadata.declared_prefix(DC_NS) {
+        Some(prefix) => prefix.map(String::from),
+        None => {
+            let sibling = metadata
+                .elements()
+                .find(|e| DC_ELEMENTS.contains(&e.local_name()) && e.prefix().is_some())
+                .and_then(|e| e.prefix().map(String::from));
+            if sibling.is_none() {
+                metadata.set_attr("xmlns:dc", DC_NS);
+            }
+            Some(sibling.unwrap_or_else(|| "dc".to_string()))
+        }
+    };
+    match prefix {
+        Some(prefix) => format!("{prefix}:{local}"),
+        None => local.to_string(),
+    }
+}
+
+/// The qualified name of an EPUB 2 `opf:` attribute such as `opf:file-as`,
+/// declaring the `opf` prefix on the package's metadata if needed.
+pub(crate) fn opf_attribute_name(package: &mut XmlElement, local: &str) -> String {
+    let declared = package
+        .declared_prefix(OPF_NS)
+        .flatten()
+        .or_else(|| package.child("metadata")?.declared_prefix(OPF_NS).flatten())
+        .map(String::from);
+    let prefix = match declared {
+        Some(prefix) => prefix,
+        None => {
+            if let Some(metadata) = package.child_mut("metadata") {
+                metadata.set_attr("xmlns:opf", OPF_NS);
+            }
+            "opf".to_string()
+        }
+    };
+    format!("{prefix}:{local}")
+}
+
+fn text(element: &XmlElement) -> String {
+    element.text().trim().to_string()
+}
+
+fn child_elements<'a>(
+    element: &'a XmlElement,
+    name: &'a str,
+) -> impl Iterator<Item = &'a XmlElement> {
+    element
+        .elements()
+        .filter(move |child| child.local_name() == name)
+}
+
+fn split_properties(properties: Option<&str>) -> Vec<String> {
+    properties
+        .map(|p| p.split_whitespace().map(String::from).collect())
+        .unwrap_or_default()
+}
+
+fn set_opt(element: &mut XmlElement, name: &str, value: &Option<String>) {
+    if let Some(value) = value {
+        element.set_attr(name, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::xml_doc::XmlDocument;
+
+    const EPUB3_OPF: &[u8] = br##"<?xml version="1.0" encoding="utf-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0" unique-identifier="uid">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:identifier id="uid">urn:uuid:1234</dc:identifier>
+    <dc:title>A Book</dc:title>
+    <dc:creator id="author">Jane Doe</dc:creator>
+    <meta refines="#author" property="role" scheme="marc:relators">aut</meta>
+    <meta refines="#author" property="file-as">Doe, Jane</meta>
+    <meta property="dcterms:modified">2024-01-01T00:00:00Z</meta>
+    <dc:language>en</dc:language>
+  </metadata>
+  <manifest>
+    <item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/>
+    <item id="ch1" href="Text/ch1.xhtml" media-type="application/xhtml+xml"/>
+    <item id="cover" href="Images/cover.jpg" media-type="image/jpeg" properties="cover-image"/>
+  </manifest>
+  <spine>
+    <itemref idref="ch1"/>
+    <itemref idref="nav" linear="no"/>
+  </spine>
+</package>"##;
+
+    const EPUB2_OPF: &[u8] = br##"<package xmlns="http://www.idpf.org/2007/opf" xmlns:opf="http://www.idpf.org/2007/opf" version="2.0" unique-identifier="BookId">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:identifier id="BookId" opf:scheme="ISBN">9780000000000</dc:identifier>
+    <dc:creator opf:role="aut" opf:file-as="Doe, Jane">Jane Doe</dc:creator>
+    <meta name="cover" content="cover"/>
+  </metadata>
+  <manifest>
+    <item id="ncx" href="toc.ncx" media-type="application/x-dtbncx+xml"/>
+  </manifest>
+  <spine toc="ncx"/>
+  <guide>
+    <reference type="cover" title="Cover" href="cover.xhtml"/>
+  </guide>
+</package>"##;
+
+    #[test]
+    fn parses_epub3_package() {
+        let package = Package::parse(EPUB3_OPF).unwrap();
+        assert_eq!(package.version, EpubVersion::Epub3);
+        assert_eq!(package.unique_identifier_value(), Some("urn:uuid:1234"));
+        assert_eq!(package.metadata.first("title"), Some("A Book"));
+        assert_eq!(
+            package.metadata.property("dcterms:modified"),
+            Some("2024-01-01T00:00:00Z")
+        );
+
+        let creator = package.metadata.dc("creator").next().unwrap();
+        assert_eq!(package.metadata.refined(creator, "role"), Some("aut"));
+        assert_eq!(
+            package.metadata.refined(creator, "file-as"),
+            Some("Doe, Jane")
+        );
+
+        assert_eq!(package.items_with_property("cover-image").count(), 1);
+        assert_eq!(
+            package.item_by_path("OEBPS/content.opf", "OEBPS/Text/ch1.xhtml"),
+            package.item("ch1")
+        );
+        let spine: Vec<_> = package.spine_items().map(|item| item.id.as_str()).collect();
+        assert_eq!(spine, ["ch1", "nav"]);
+        assert!(!package.spine.itemrefs[1].linear);
+    }
+
+    #[test]
+    fn missing_version_is_epub2() {
+        let source = "<package><metadata/><manifest/><spine/></package>";
+        assert!(!Package::parse(source.as_bytes()).unwrap().is_epub3());
+        assert!(!is_epub3(
+            &XmlDocument::parse(source.as_bytes()).unwrap().root
+        ));
+        let source = "<package version=\"3.0\"><metadata/><manifest/><spine/></package>";
+        assert!(Package::parse(source.as_bytes()).unwrap().is_epub3());
+        assert!(is_epub3(
+            &XmlDocument::parse(source.as_bytes()).unwrap().root
+        ));
+    }
+
+    #[test]
+    fn parses_epub2_package() {
+        let package = Package::parse(EPUB2_OPF).unwrap();
+        assert_eq!(package.version, EpubVersion::Epub2);
+        assert_eq!(package.unique_identifier_value(), Some("9780000000000"));
+        assert_eq!(package.metadata.meta_content("cover"), Some("cover"));
+        assert_eq!(package.spine.toc.as_deref(), Some("ncx"));
+        assert_eq!(package.guide[0].kind, "cover");
+
+        let creator = package.metadata.dc("creator").next().unwrap();
+        assert_eq!(package.metadata.refined(creator, "role"), Some("aut"));
+        assert_eq!(
+            package.metadata.refined(creator, "file-as"),
+            Some("Doe, Jane")
+        );
+    }
+
+    #[test]
+    fn default_package_is_epub2() {
+        assert_eq!(Package::default().version, EpubVersion::Epub2);
+    }
+
+    #[test]
+    fn reads_prefixed_dublin_core_elements() {
+        let opf = br#"<opf:package xmlns:opf="http://www.idpf.org/2007/opf" xmlns:d="http://purl.org/dc/elements/1.1/" version="2.0">
+  <opf:metadata><d:title xml:lang="en">T</d:title><title>Not DC</title></opf:metadata>
+  <opf:manifest><opf:item id="a" href="a.xhtml" media-type="application/xhtml+xml"/></opf:manifest>
+</opf:package>"#;
+        let package = Package::parse(opf).unwrap();
+        assert_eq!(package.metadata.dc.len(), 1);
+        assert_eq!(package.metadata.first("title"), Some("T"));
+        assert_eq!(package.metadata.dc[0].attributes["lang"], "en");
+        assert_eq!(package.manifest[0].id, "a");
+    }
+
+    #[test]
+    fn rejects_package_without_manifest() {
+        let result = Package::parse(b"<package><metadata/></package>");
+        assert!(matches!(result, Err(FixError::InvalidPackage(_))));
+    }
+
+    #[test]
+    fn to_xml_round_trips() {
+        for opf in [EPUB3_OPF, EPUB2_OPF] {
+            let package = Package::parse(opf).unwrap();
+            let xml = package.to_xml();
+            assert!(!String::from_utf8_lossy(&xml).contains("\n  "));
+            assert_eq!(Package::parse(&xml).unwrap(), package);
+        }
+    }
+}
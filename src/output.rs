@@ -0,0 +1,29 @@
+//! The messages the fixes print, which can be muted while the pipeline is
+//! re-run to verify its output.
+
+use std::cell::Cell;
+
+thread_local! {
+    static MUTED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Prints a line to stdout like `println!`, unless messages are muted.
+macro_rules! report {
+    ($($arg:tt)*) => {{
+        if !$crate::output::is_muted() {
+            println!($($arg)*);
+        }
+    }};
+}
+
+pub(crate) fn is_muted() -> bool {
+    MUTED.with(Cell::get)
+}
+
+/// Runs `f` without printing its messages.
+pub(crate) fn muted<T>(f: impl FnOnce() -> T) -> T {
+    let previous = MUTED.with(|muted| muted.replace(true));
+    let result = f();
+    MUTED.with(|muted| muted.set(previous));
+    result
+}
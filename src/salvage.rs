@@ -0,0 +1,311 @@
+//! Recovers the entries of damaged ZIP archives — truncated downloads, broken
+//! central directories, corrupt entries — by scanning for local file headers
+//! instead of trusting the central directory.
+
+use flate2::bufread::DeflateDecoder;
+use std::collections::BTreeSet;
+use std::io::Read;
+use zip::CompressionMethod;
+
+const LOCAL_HEADER: &[u8; 4] = b"PK\x03\x04";
+const CENTRAL_HEADER: &[u8; 4] = b"PK\x01\x02";
+const DATA_DESCRIPTOR: &[u8; 4] = b"PK\x07\x08";
+/// The size of a local file header before the file name.
+const LOCAL_HEADER_SIZE: usize = 30;
+/// The size of a central directory header before the file name.
+const CENTRAL_HEADER_SIZE: usize = 46;
+
+/// An entry recovered intact from a damaged archive.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Recovered {
+    pub(crate) name: String,
+    pub(crate) data: Vec<u8>,
+    pub(crate) compression: CompressionMethod,
+}
+
+/// What could be recovered from a damaged archive.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Salvaged {
+    pub(crate) entries: Vec<Recovered>,
+    /// Entries that were found but could not be recovered, with the reason.
+    pub(crate) lost: Vec<(String, String)>,
+}
+
+/// Scans `data` for local file headers and recovers every entry whose
+/// content decompresses and matches its CRC. Entries listed in a readable
+/// part of the central directory but never found are reported as lost too.
+pub(crate) fn salvage(data: &[u8]) -> Salvaged {
+    let mut salvaged = Salvaged::default();
+    let mut position = 0;
+    while let Some(offset) = find(data, LOCAL_HEADER, position) {
+        match read_local_entry(data, offset) {
+            Ok((entry, end)) => {
+                salvaged.entries.push(entry);
+                position = end;
+            }
+            Err((name, reason)) => {
+                salvaged.lost.push((name, reason));
+                position = offset + LOCAL_HEADER.len();
+            }
+        }
+    }
+
+    let found: BTreeSet<&str> = salvaged
+        .entries
+        .iter()
+        .map(|entry| entry.name.as_str())
+        .chain(salvaged.lost.iter().map(|(name, _)| name.as_str()))
+        .collect();
+    let missing: Vec<String> = central_directory_names(data)
+        .into_iter()
+        .filter(|name| !found.contains(name.as_str()))
+        .collect();
+    salvaged.lost.extend(
+        missing
+            .into_iter()
+            .map(|name| (name, "its data is missing from the archive".to_string())),
+    );
+    salvaged
+}
+
+/// Reads the entry whose local header starts at `offset`, returning it and
+/// the offset just past its data, or its name and why it is lost.
+fn read_local_entry(data: &[u8], offset: usize) -> Result<(Recovered, usize), (String, String)> {
+    let header = &data[offset..];
+    let lost = |name: &str, reason: &str| (name.to_string(), reason.to_string());
+    if header.len() < LOCAL_HEADER_SIZE {
+        return Err(lost("?", "the local header is truncated"));
+    }
+    let flags = u16_at(header, 6);
+    let method = u16_at(header, 8);
+    let crc = u32_at(header, 14);
+    let compressed_size = u32_at(header, 18) as usize;
+    let name_length = u16_at(header, 26) as usize;
+    let extra_length = u16_at(header, 28) as usize;
+    let data_start = LOCAL_HEADER_SIZE + name_length + extra_length;
+    let Some(name) = header.get(LOCAL_HEADER_SIZE..LOCAL_HEADER_SIZE + name_length) else {
+        return Err(lost("?", "the local header is truncated"));
+    };
+    let name = String::from_utf8_lossy(name).to_string();
+    if flags & 1 != 0 {
+        return Err(lost(&name, "it is encrypted"));
+    }
+    let Some(rest) = header.get(data_start..) else {
+        return Err(lost(&name, "the archive ends in its header"));
+    };
+    let has_descriptor = flags & 0x08 != 0;
+
+    let (content, used) = match method {
+        0 => {
+            let size = if has_descriptor {
+                stored_size(rest).ok_or_else(|| lost(&name, "its size is unknown"))?
+            } else {
+                compressed_size
+            };
+            let content = rest
+                .get(..size)
+                .ok_or_else(|| lost(&name, "the archive ends in its data"))?;
+            (content.to_vec(), size)
+        }
+        8 => {
+            let input = if has_descriptor {
+                rest
+            } else {
+                rest.get(..compressed_size)
+                    .ok_or_else(|| lost(&name, "the archive ends in its data"))?
+            };
+            let mut decoder = DeflateDecoder::new(input);
+            let mut content = Vec::new();
+            decoder
+                .read_to_end(&mut content)
+                .map_err(|err| lost(&name, &format!("its data does not inflate: {err}")))?;
+            (content, decoder.total_in() as usize)
+        }
+        method => {
+            return Err(lost(
+                &name,
+                &format!("compression method {method} is not supported"),
+            ))
+        }
+    };
+
+    let mut end = offset + data_start + used;
+    let crc = if has_descriptor {
+        let descriptor = &data[end..];
+        let descriptor = descriptor
+            .strip_prefix(DATA_DESCRIPTOR.as_slice())
+            .unwrap_or(descriptor);
+        if descriptor.len() < 12 {
+            return Err(lost(&name, "the archive ends before its data descriptor"));
+        }
+        end = data.len() - descriptor.len() + 12;
+        u32_at(descriptor, 0)
+    } else {
+        crc
+    };
+    if crc32fast::hash(&content) != crc {
+        return Err(lost(&name, "its CRC does not match"));
+    }
+    let compression = match method {
+        0 => CompressionMethod::Stored,
+        _ => CompressionMethod::Deflated,
+    };
+    Ok((
+        Recovered {
+            name,
+            data: content,
+            compression,
+        },
+        end,
+    ))
+}
+
+/// The size of stored data followed by a data descriptor: the distance to
+/// the first descriptor signature whose compressed size matches it.
+fn stored_size(rest: &[u8]) -> Option<usize> {
+    let mut position = 0;
+    while let Some(offset) = find(rest, DATA_DESCRIPTOR, position) {
+        if rest.len() >= offset + 16 && u32_at(rest, offset + 8) as usize == offset {
+            return Some(offset);
+        }
+        position = offset + 1;
+    }
+    None
+}
+
+/// The names the central directory lists more than once. `ZipArchive` only
+/// gives access to one entry of each name.
+pub(crate) fn duplicate_names(data: &[u8]) -> BTreeSet<String> {
+    let mut seen = BTreeSet::new();
+    central_directory_names(data)
+        .into_iter()
+        .filter(|name| !seen.insert(name.clone()))
+        .collect()
+}
+
+/// The names in the readable headers of the central directory.
+fn central_directory_names(data: &[u8]) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut position = 0;
+    while let Some(offset) = find(data, CENTRAL_HEADER, position) {
+        let header = &data[offset..];
+        position = offset + CENTRAL_HEADER.len();
+        if header.len() < CENTRAL_HEADER_SIZE {
+            break;
+        }
+        let name_length = u16_at(header, 28) as usize;
+        if let Some(name) = header.get(CENTRAL_HEADER_SIZE..CENTRAL_HEADER_SIZE + name_length) {
+            names.push(String::from_utf8_lossy(name).to_string());
+        }
+    }
+    names
+}
+
+fn find(data: &[u8], signature: &[u8], from: usize) -> Option<usize> {
+    data.get(from..)?
+        .windows(signature.len())
+        .position(|window| window == signature)
+        .map(|offset| from + offset)
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+    use zip::write::SimpleFileOptions;
+
+    fn archive(files: &[(&str, &[u8], CompressionMethod)]) -> Vec<u8> {
+        let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, data, method) in files {
+            let options = SimpleFileOptions::default().compression_method(*method);
+            zip.start_file(*name, options).unwrap();
+            zip.write_all(data).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn salvage_recovers_entries_of_a_truncated_archive() {
+        let chapter = "<p>Chapter</p>".repeat(100);
+        let data = archive(&[
+            (
+                "mimetype",
+                b"application/epub+zip",
+                CompressionMethod::Stored,
+            ),
+            ("a.xhtml", chapter.as_bytes(), CompressionMethod::Deflated),
+            ("b.xhtml", chapter.as_bytes(), CompressionMethod::Deflated),
+        ]);
+        let b = find(&data, b"b.xhtml", 0).unwrap();
+        let salvaged = salvage(&data[..b + 20]);
+        let names: Vec<&str> = salvaged
+            .entries
+            .iter()
+            .map(|entry| entry.name.as_str())
+            .collect();
+        assert_eq!(names, ["mimetype", "a.xhtml"]);
+        assert_eq!(salvaged.entries[1].data, chapter.as_bytes());
+        assert_eq!(salvaged.entries[0].compression, CompressionMethod::Stored);
+        assert_eq!(salvaged.lost.len(), 1);
+        assert_eq!(salvaged.lost[0].0, "b.xhtml");
+    }
+
+    #[test]
+    fn duplicate_names_reads_the_central_directory() {
+        let data = archive(&[
+            ("a.txt", b"one", CompressionMethod::Stored),
+            ("b.txt", b"two", CompressionMethod::Deflated),
+        ]);
+        assert!(duplicate_names(&data).is_empty());
+
+        // Rename b.txt to a.txt in both its local and central headers.
+        let mut data = data;
+        while let Some(b) = find(&data, b"b.txt", 0) {
+            data[b] = b'a';
+        }
+        assert_eq!(
+            duplicate_names(&data),
+            BTreeSet::from(["a.txt".to_string()])
+        );
+        let salvaged = salvage(&data);
+        assert_eq!(salvaged.entries.len(), 2);
+        assert_eq!(salvaged.entries[1].data, b"two");
+    }
+
+    #[test]
+    fn salvage_reports_crc_mismatches_and_unfound_entries() {
+        let mut data = archive(&[
+            ("a.txt", b"hello", CompressionMethod::Stored),
+            ("b.txt", b"world", CompressionMethod::Stored),
+        ]);
+        let hello = find(&data, b"hello", 0).unwrap();
+        data[hello] = b'j';
+        let b = find(&data, LOCAL_HEADER, hello).unwrap();
+        data[b] = b'X';
+        let salvaged = salvage(&data);
+        assert!(salvaged.entries.is_empty());
+        assert_eq!(
+            salvaged.lost,
+            [
+                ("a.txt".to_string(), "its CRC does not match".to_string()),
+                (
+                    "b.txt".to_string(),
+                    "its data is missing from the archive".to_string()
+                ),
+            ]
+        );
+    }
+}
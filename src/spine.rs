@@ -0,0 +1,199 @@
+use crate::xml_doc::XmlElement;
+use crate::{href, media_type};
+use std::collections::{HashMap, HashSet};
+
+/// Validates the `<spine>` of `package` against its manifest and inserts
+/// content documents that the table of contents links to but the spine
+/// lacks.
+///
+/// `toc_order` lists the archive entries linked from the TOC in reading
+/// order. Missing chapters are placed after their TOC predecessor, or
+/// appended with `linear="no"` when `missing_nonlinear` is set. Returns true
+/// if the package was modified.
+pub(crate) fn repair(
+    package: &mut XmlElement,
+    opf_path: &str,
+    toc_order: &[String],
+    missing_nonlinear: bool,
+) -> bool {
+    let Some(manifest) = package.child("manifest") else {
+        return false;
+    };
+    let items: HashMap<String, (String, String, bool)> = manifest
+        .elements()
+        .filter(|item| item.local_name() == "item")
+        .filter_map(|item| {
+            let id = item.attr("id")?.to_string();
+            let path = href::resolve(opf_path, item.attr("href")?);
+            let media_type = item.attr("media-type").unwrap_or_default().to_string();
+            let has_fallback = item.attr("fallback").is_some();
+            Some((id, (path, media_type, has_fallback)))
+        })
+        .collect();
+
+    let Some(spine) = package.child_mut("spine") else {
+        return false;
+    };
+
+    let mut changed = false;
+    let mut seen: HashSet<String> = HashSet::new();
+    spine.retain_elements(|itemref| {
+        if itemref.local_name() != "itemref" {
+            return true;
+        }
+        let idref = itemref.attr("idref").unwrap_or_default().to_string();
+        let keep = match items.get(&idref) {
+            None => {
+                report!("Spine: removing itemref {idref:?}, no such manifest item");
+                false
+            }
+            Some(_) if seen.contains(&idref) => {
+                report!("Spine: removing duplicate itemref {idref}");
+                false
+            }
+            Some((path, media_type, has_fallback)) if !is_spine_type(media_type, *has_fallback) => {
+                report!("Spine: removing {path}, {media_type} is not a content document");
+                false
+            }
+            Some(_) => true,
+        };
+        seen.insert(idref);
+        changed |= !keep;
+        keep
+    });
+
+    let ids_by_path: HashMap<&str, &str> = items
+        .iter()
+        .filter(|(_, (_, media_type, _))| is_spine_type(media_type, false))
+        .map(|(id, (path, _, _))| (path.as_str(), id.as_str()))
+        .collect();
+    let mut spine_ids: Vec<String> = spine_idrefs(spine);
+
+    for (position, path) in toc_order.iter().enumerate() {
+        let Some(&id) = ids_by_path.get(path.as_str()) else {
+            continue;
+        };
+        if spine_ids.iter().any(|spine_id| spine_id == id) {
+            continue;
+        }
+
+        let mut itemref = XmlElement::new("itemref").with_attr("idref", id);
+
+        let insert_at = if missing_nonlinear {
+            itemref.set_attr("linear", "no");
+            report!("Spine: appending {path} from the table of contents as non-linear");
+            None
+        } else {
+            report!("Spine: inserting {path} from the table of contents");
+            toc_order[..position]
+                .iter()
+                .rev()
+                .filter_map(|previous| ids_by_path.get(previous.as_str()))
+                .find_map(|previous| spine_ids.iter().position(|id| id == previous))
+                .map(|index| index + 1)
+                .or_else(|| {
+                    toc_order[position + 1..]
+                        .iter()
+                        .filter_map(|next| ids_by_path.get(next.as_str()))
+                        .find_map(|next| spine_ids.iter().position(|id| id == next))
+                })
+        };
+
+        match insert_at {
+            Some(index) => spine.insert_child(index, itemref),
+            None => spine.append_child(itemref),
+        }
+        spine_ids = spine_idrefs(spine);
+        changed = true;
+    }
+    changed
+}
+
+fn is_spine_type(media_type: &str, has_fallback: bool) -> bool {
+    has_fallback || matches!(media_type, media_type::XHTML | "image/svg+xml")
+}
+
+fn spine_idrefs(spine: &XmlElement) -> Vec<String> {
+    spine
+        .elements()
+        .filter(|e| e.local_name() == "itemref")
+        .map(|e| e.attr("idref").unwrap_or_default().to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::xml_doc::XmlDocument;
+
+    const OPF: &[u8] = br#"<package><manifest>
+        <item id="ch1" href="ch1.xhtml" media-type="application/xhtml+xml"/>
+        <item id="ch2" href="ch2.xhtml" media-type="application/xhtml+xml"/>
+        <item id="ch3" href="ch3.xhtml" media-type="application/xhtml+xml"/>
+        <item id="img" href="a.jpg" media-type="image/jpeg"/>
+    </manifest><spine>
+        <itemref idref="ch1"/><itemref idref="ghost"/><itemref idref="img"/>
+        <itemref idref="ch1"/><itemref idref="ch3"/>
+    </spine></package>"#;
+
+    fn spine(package: &XmlElement) -> Vec<(String, bool)> {
+        package
+            .child("spine")
+            .unwrap()
+            .elements()
+            .map(|e| {
+                (
+                    e.attr("idref").unwrap().to_string(),
+                    e.attr("linear").is_none(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn repair_removes_invalid_itemrefs_and_inserts_in_toc_order() {
+        let mut package = XmlDocument::parse(OPF).unwrap().root;
+        let toc = ["ch1.xhtml", "ch2.xhtml", "ch3.xhtml"].map(String::from);
+        assert!(repair(&mut package, "content.opf", &toc, false));
+        assert_eq!(
+            spine(&package),
+            [
+                ("ch1".into(), true),
+                ("ch2".into(), true),
+                ("ch3".into(), true)
+            ]
+        );
+    }
+
+    #[test]
+    fn repair_can_append_missing_chapters_as_non_linear() {
+        let mut package = XmlDocument::parse(OPF).unwrap().root;
+        let toc = ["ch1.xhtml", "ch2.xhtml", "ch3.xhtml"].map(String::from);
+        assert!(repair(&mut package, "content.opf", &toc, true));
+        assert_eq!(
+            spine(&package),
+            [
+                ("ch1".into(), true),
+                ("ch3".into(), true),
+                ("ch2".into(), false)
+            ]
+        );
+    }
+
+    #[test]
+    fn repair_leaves_valid_spine_alone() {
+        let mut package = XmlDocument::parse(
+            br#"<package><manifest><item id="ch1" href="ch1.xhtml" media-type="application/xhtml+xml"/></manifest>
+            <spine><itemref idref="ch1"/></spine></package>"#
+                .as_slice(),
+        )
+        .unwrap()
+        .root;
+        assert!(!repair(
+            &mut package,
+            "content.opf",
+            &["ch1.xhtml".to_string()],
+            false
+        ));
+    }
+}
@@ -0,0 +1,204 @@
+//! Pages that wrap a raster image in an `<svg>` element, the way many
+//! publishers ship their cover page.
+
+use crate::xml_doc;
+use scraper::node::Element;
+use scraper::{ElementRef, Html, Selector};
+use std::ops::Range;
+
+/// Elements that may surround the `<svg>` of a wrapper page.
+const CONTAINERS: &[&str] = &["body", "div", "p", "span", "section", "a", "center"];
+
+/// An `<svg>` element whose only content is a single `<image>`.
+#[derive(Debug, PartialEq)]
+pub(crate) struct WrappedImage {
+    /// The link of the image as written in the document.
+    pub(crate) href: String,
+    /// The text of the `<title>` of the `<svg>`, if any.
+    pub(crate) title: Option<String>,
+    /// The byte range of the `<svg>` element in the document.
+    pub(crate) range: Range<usize>,
+}
+
+/// The link of an SVG `<image>`: `xlink:href`, or the SVG 2 `href`.
+pub(crate) fn image_href(image: &Element) -> Option<&str> {
+    image
+        .attrs()
+        .find(|(name, _)| *name == "href")
+        .map(|(_, value)| value)
+}
+
+/// The `<image>` of `svg` when it holds nothing else, ignoring `<title>`,
+/// `<desc>` and grouping `<g>` elements.
+pub(crate) fn only_image(svg: ElementRef) -> Option<ElementRef> {
+    let mut image = None;
+    for element in svg.descendants().skip(1).filter_map(ElementRef::wrap) {
+        match element.value().name() {
+            "image" if image.is_none() => image = Some(element),
+            "title" | "desc" | "g" => {}
+            _ => return None,
+        }
+    }
+    image
+}
+
+/// The `<svg>` around an SVG `<image>` when it holds nothing but that image.
+pub(crate) fn wrapper(image: ElementRef) -> Option<ElementRef> {
+    let svg = image
+        .ancestors()
+        .filter_map(ElementRef::wrap)
+        .find(|element| element.value().name() == "svg")?;
+    (only_image(svg)?.id() == image.id()).then_some(svg)
+}
+
+/// Finds the `<svg>` of a page whose only content is an `<svg>` wrapping a
+/// single `<image>`, e.g. a cover page. Returns `None` for any other page.
+pub(crate) fn wrapped_image(content: &str) -> Option<WrappedImage> {
+    if !content.contains("<svg") {
+        return None;
+    }
+    let document = Html::parse_document(content);
+    let body = document.select(&Selector::parse("body").unwrap()).next()?;
+    let svgs: Vec<ElementRef> = body.select(&Selector::parse("svg").unwrap()).collect();
+    let [svg] = svgs.as_slice() else {
+        return None;
+    };
+    let image = only_image(*svg)?;
+
+    for node in body.descendants() {
+        if node.ancestors().any(|ancestor| ancestor.id() == svg.id()) || node.id() == svg.id() {
+            continue;
+        }
+        if let Some(text) = node.value().as_text() {
+            if !text.trim().is_empty() {
+                return None;
+            }
+        } else if let Some(element) = node.value().as_element() {
+            if !CONTAINERS.contains(&element.name()) {
+                return None;
+            }
+        }
+    }
+
+    let title = svg
+        .children()
+        .filter_map(ElementRef::wrap)
+        .find(|child| child.value().name() == "title")
+        .map(|title| title.text().collect::<String>().trim().to_string())
+        .filter(|title| !title.is_empty());
+    Some(WrappedImage {
+        href: image_href(image.value())?.trim().to_string(),
+        title,
+        range: svg_range(content)?,
+    })
+}
+
+/// The byte range of the `<svg>` element in `content`, which must be the only
+/// one outside comments, CDATA sections, scripts and style sheets.
+fn svg_range(content: &str) -> Option<Range<usize>> {
+    let mut starts = Vec::new();
+    let mut ends = Vec::new();
+    let mut pos = 0;
+    while let Some(offset) = content[pos..].find('<') {
+        let i = pos + offset;
+        let rest = &content[i..];
+        let skip_past = |terminator: &str| Some(i + rest.find(terminator)? + terminator.len());
+        pos = if rest.starts_with("<!--") {
+            skip_past("-->")?
+        } else if rest.starts_with("<![CDATA[") {
+            skip_past("]]>")?
+        } else if is_tag(rest, "<script") {
+            skip_past("</script")?
+        } else if is_tag(rest, "<style") {
+            skip_past("</style")?
+        } else {
+            if is_tag(rest, "<svg") {
+                starts.push(i);
+            } else if is_tag(rest, "</svg") {
+                ends.push(skip_past(">")?);
+            }
+            i + 1
+        };
+    }
+    match (starts.as_slice(), ends.as_slice()) {
+        ([start], [end]) if start < end => Some(*start..*end),
+        _ => None,
+    }
+}
+
+/// Whether `rest` starts with the tag opened by `open`, e.g. `<svg`, in any
+/// case.
+fn is_tag(rest: &str, open: &str) -> bool {
+    rest.get(..open.len())
+        .is_some_and(|prefix| prefix.eq_ignore_ascii_case(open))
+        && rest[open.len()..].starts_with(|c: char| c.is_ascii_whitespace() || c == '>' || c == '/')
+}
+
+/// Replaces the `<svg>` wrapper in `content` with a plain `<img>` of the same
+/// image, using the SVG title as alt text.
+pub(crate) fn unwrap(content: &str, wrapped: &WrappedImage) -> String {
+    let alt = wrapped.title.as_deref().unwrap_or("Cover");
+    format!(
+        "{}<img src=\"{}\" alt=\"{}\"/>{}",
+        &content[..wrapped.range.start],
+        xml_doc::escape(&wrapped.href, true),
+        xml_doc::escape(alt, true),
+        &content[wrapped.range.end..]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COVER: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml"><head><title>Cover</title></head>
+<body><div class="cover">
+<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" viewBox="0 0 600 800">
+<title>The Book</title><image width="600" height="800" xlink:href="../Images/cover.jpg"/></svg>
+</div></body></html>"#;
+
+    #[test]
+    fn wrapped_image_finds_svg_cover_pages() {
+        let wrapped = wrapped_image(COVER).unwrap();
+        assert_eq!(wrapped.href, "../Images/cover.jpg");
+        assert_eq!(wrapped.title.as_deref(), Some("The Book"));
+        assert!(COVER[wrapped.range.clone()].starts_with("<svg "));
+        assert!(COVER[wrapped.range.clone()].ends_with("</svg>"));
+
+        let with_text = COVER.replace("</div>", "</div><p>Chapter one</p>");
+        assert_eq!(wrapped_image(&with_text), None);
+        let drawing = COVER.replace("<title>", "<rect width=\"1\"/><title>");
+        assert_eq!(wrapped_image(&drawing), None);
+        assert_eq!(wrapped_image("<html><body><p>Hi</p></body></html>"), None);
+    }
+
+    #[test]
+    fn unwrap_replaces_svg_with_img() {
+        let wrapped = wrapped_image(COVER).unwrap();
+        let page = unwrap(COVER, &wrapped);
+        assert!(page.contains(
+            "<div class=\"cover\">\n<img src=\"../Images/cover.jpg\" alt=\"The Book\"/>\n</div>"
+        ));
+        assert!(!page.contains("svg"));
+    }
+
+    #[test]
+    fn wrapped_image_ignores_svg_in_comments_and_scripts() {
+        let page = COVER
+            .replace(
+                "<title>Cover</title>",
+                "<title>Cover</title><!-- <svg> --><script>let s = '</svg>';</script>",
+            )
+            .replace("</html>", "</html><!-- </svg> -->");
+        let wrapped = wrapped_image(&page).unwrap();
+        let unwrapped = unwrap(&page, &wrapped);
+        assert!(unwrapped.contains(
+            "<div class=\"cover\">\n<img src=\"../Images/cover.jpg\" alt=\"The Book\"/>\n</div>"
+        ));
+        assert!(unwrapped.contains("<!-- <svg> --><script>let s = '</svg>';</script>"));
+
+        let two = COVER.replace("</html>", "</html>\n<svg></svg>");
+        assert_eq!(wrapped_image(&two), None);
+    }
+}
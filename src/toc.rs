@@ -0,0 +1,383 @@
+use crate::href;
+use crate::xml_doc::escape;
+use scraper::{ElementRef, Html, Selector};
+use xmltree::{Element, XMLNode};
+
+/// One entry of a table of contents, with `href` relative to the document
+/// the TOC was read from.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub(crate) struct TocEntry {
+    pub(crate) label: String,
+    pub(crate) href: String,
+    pub(crate) children: Vec<TocEntry>,
+}
+
+/// Reads the `toc` navigation of an EPUB 3 navigation document.
+pub(crate) fn parse_nav(content: &[u8]) -> Option<Vec<TocEntry>> {
+    let html = String::from_utf8_lossy(content);
+    let document = Html::parse_document(&html);
+    let nav_selector = Selector::parse("nav").unwrap();
+
+    let navs: Vec<_> = document.select(&nav_selector).collect();
+    let nav = navs
+        .iter()
+        .find(|nav| {
+            nav.value()
+                .attr("epub:type")
+                .is_some_and(|t| t.split_whitespace().any(|t| t == "toc"))
+        })
+        .or(navs.first())?;
+    let list = child_elements(*nav, "ol").next()?;
+    Some(nav_list(list))
+}
+
+fn nav_list(list: ElementRef<'_>) -> Vec<TocEntry> {
+    child_elements(list, "li")
+        .filter_map(|li| {
+            let link = child_elements(li, "a")
+                .next()
+                .or_else(|| child_elements(li, "span").next())?;
+            Some(TocEntry {
+                label: collapse_whitespace(&link.text().collect::<String>()),
+                href: link.value().attr("href").unwrap_or_default().to_string(),
+                children: child_elements(li, "ol")
+                    .next()
+                    .map(nav_list)
+                    .unwrap_or_default(),
+            })
+        })
+        .collect()
+}
+
+fn child_elements<'a>(
+    element: ElementRef<'a>,
+    name: &'a str,
+) -> impl Iterator<Item = ElementRef<'a>> + 'a {
+    element
+        .children()
+        .filter_map(ElementRef::wrap)
+        .filter(move |child| child.value().name() == name)
+}
+
+/// Reads the `navMap` of an NCX document.
+pub(crate) fn parse_ncx(content: &[u8]) -> Option<Vec<TocEntry>> {
+    let ncx = Element::parse(content).ok()?;
+    let nav_map = ncx.get_child("navMap")?;
+    Some(nav_points(nav_map))
+}
+
+fn nav_points(parent: &Element) -> Vec<TocEntry> {
+    parent
+        .children
+        .iter()
+        .filter_map(XMLNode::as_element)
+        .filter(|child| child.name == "navPoint")
+        .map(|point| TocEntry {
+            label: point
+                .get_child("navLabel")
+                .and_then(|label| label.get_child("text"))
+                .and_then(|text| text.get_text())
+                .map(|text| collapse_whitespace(&text))
+                .unwrap_or_default(),
+            href: point
+                .get_child("content")
+                .and_then(|content| content.attributes.get("src"))
+                .cloned()
+                .unwrap_or_default(),
+            children: nav_points(point),
+        })
+        .collect()
+}
+
+/// All entries in document order, parents before their children.
+pub(crate) fn flatten(entries: &[TocEntry]) -> Vec<&TocEntry> {
+    let mut flat = Vec::new();
+    for entry in entries {
+        flat.push(entry);
+        flat.extend(flatten(&entry.children));
+    }
+    flat
+}
+
+/// Rewrites the hrefs of `entries`, relative to the archive entry `from`, to
+/// be relative to the entry `to`.
+pub(crate) fn rebase(entries: &[TocEntry], from: &str, to: &str) -> Vec<TocEntry> {
+    entries
+        .iter()
+        .map(|entry| TocEntry {
+            label: entry.label.clone(),
+            href: href::rebase(from, to, &entry.href),
+            children: rebase(&entry.children, from, to),
+        })
+        .collect()
+}
+
+/// A landmark of an EPUB 3 navigation document, e.g. the cover or the start
+/// of the body matter.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub(crate) struct Landmark {
+    /// The `epub:type`, e.g. `cover` or `bodymatter`.
+    pub(crate) kind: String,
+    pub(crate) label: String,
+    pub(crate) href: String,
+}
+
+/// Builds an EPUB 3 navigation document with a `toc` nav for `entries` and,
+/// when there are any, a hidden `landmarks` nav.
+pub(crate) fn nav_document(
+    title: &str,
+    language: Option<&str>,
+    entries: &[TocEntry],
+    landmarks: &[Landmark],
+) -> String {
+    let title = escape(title, false);
+    let language = match language {
+        Some(language) => {
+            let language = escape(language, true);
+            format!(" lang=\"{language}\" xml:lang=\"{language}\"")
+        }
+        None => String::new(),
+    };
+    let mut nav = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <!DOCTYPE html>\n\
+         <html xmlns=\"http://www.w3.org/1999/xhtml\" \
+         xmlns:epub=\"http://www.idpf.org/2007/ops\"{language}>\n\
+         <head>\n  <title>{title}</title>\n</head>\n<body>\n  \
+         <nav epub:type=\"toc\" id=\"toc\">\n    <h1>{title}</h1>\n"
+    );
+    write_nav_list(&mut nav, entries, 2);
+    nav.push_str("  </nav>\n");
+
+    if !landmarks.is_empty() {
+        nav.push_str(
+            "  <nav epub:type=\"landmarks\" id=\"landmarks\" hidden=\"hidden\">\n    <ol>\n",
+        );
+        for landmark in landmarks {
+            nav.push_str(&format!(
+                "      <li><a epub:type=\"{}\" href=\"{}\">{}</a></li>\n",
+                escape(&landmark.kind, true),
+                escape(&landmark.href, true),
+                escape(&landmark.label, false)
+            ));
+        }
+        nav.push_str("    </ol>\n  </nav>\n");
+    }
+    nav.push_str("</body>\n</html>\n");
+    nav
+}
+
+fn write_nav_list(out: &mut String, entries: &[TocEntry], depth: usize) {
+    let indent = "  ".repeat(depth);
+    out.push_str(&format!("{indent}<ol>\n"));
+    for entry in entries {
+        let label = escape(&entry.label, false);
+        let link = if entry.href.is_empty() {
+            format!("<span>{label}</span>")
+        } else {
+            format!("<a href=\"{}\">{label}</a>", escape(&entry.href, true))
+        };
+        if entry.children.is_empty() {
+            out.push_str(&format!("{indent}  <li>{link}</li>\n"));
+        } else {
+            out.push_str(&format!("{indent}  <li>{link}\n"));
+            write_nav_list(out, &entry.children, depth + 2);
+            out.push_str(&format!("{indent}  </li>\n"));
+        }
+    }
+    out.push_str(&format!("{indent}</ol>\n"));
+}
+
+/// Builds an NCX document for `entries`. Entries without an href are left
+/// out and their children moved up; `playOrder` follows document order.
+pub(crate) fn ncx_document(uid: &str, title: &str, entries: &[TocEntry]) -> String {
+    let entries = linked(entries);
+    let mut nav_map = String::new();
+    let mut orders: Vec<&str> = Vec::new();
+    let mut count = 0;
+    write_nav_points(&mut nav_map, &entries, 2, &mut orders, &mut count);
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <ncx xmlns=\"http://www.daisy.org/z3986/2005/ncx/\" version=\"2005-1\">\n\
+         \x20 <head>\n\
+         \x20   <meta name=\"dtb:uid\" content=\"{}\"/>\n\
+         \x20   <meta name=\"dtb:depth\" content=\"{}\"/>\n\
+         \x20   <meta name=\"dtb:totalPageCount\" content=\"0\"/>\n\
+         \x20   <meta name=\"dtb:maxPageNumber\" content=\"0\"/>\n\
+         \x20 </head>\n\
+         \x20 <docTitle><text>{}</text></docTitle>\n\
+         \x20 <navMap>\n{nav_map}  </navMap>\n\
+         </ncx>\n",
+        escape(uid, true),
+        depth(&entries).max(1),
+        escape(title, false),
+    )
+}
+
+/// `entries` with unlinked entries replaced by their children.
+fn linked(entries: &[TocEntry]) -> Vec<TocEntry> {
+    let mut result = Vec::new();
+    for entry in entries {
+        let children = linked(&entry.children);
+        if entry.href.is_empty() {
+            result.extend(children);
+        } else {
+            result.push(TocEntry {
+                children,
+                ..entry.clone()
+            });
+        }
+    }
+    result
+}
+
+fn write_nav_points<'a>(
+    out: &mut String,
+    entries: &'a [TocEntry],
+    depth: usize,
+    orders: &mut Vec<&'a str>,
+    count: &mut usize,
+) {
+    let indent = "  ".repeat(depth);
+    for entry in entries {
+        *count += 1;
+        let order = match orders.iter().position(|href| *href == entry.href) {
+            Some(index) => index + 1,
+            None => {
+                orders.push(&entry.href);
+                orders.len()
+            }
+        };
+        out.push_str(&format!(
+            "{indent}<navPoint id=\"navPoint-{count}\" playOrder=\"{order}\">\n\
+             {indent}  <navLabel><text>{}</text></navLabel>\n\
+             {indent}  <content src=\"{}\"/>\n",
+            escape(&entry.label, false),
+            escape(&entry.href, true)
+        ));
+        write_nav_points(out, &entry.children, depth + 1, orders, count);
+        out.push_str(&format!("{indent}</navPoint>\n"));
+    }
+}
+
+/// The nesting depth of `entries`.
+pub(crate) fn depth(entries: &[TocEntry]) -> usize {
+    entries
+        .iter()
+        .map(|entry| 1 + depth(&entry.children))
+        .max()
+        .unwrap_or(0)
+}
+
+fn collapse_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_nav_reads_nested_toc() {
+        let nav = br#"<html xmlns:epub="http://www.idpf.org/2007/ops"><body>
+            <nav epub:type="landmarks"><ol><li><a href="cover.xhtml">Cover</a></li></ol></nav>
+            <nav epub:type="toc"><ol>
+              <li><a href="ch1.xhtml">Chapter
+                  One</a>
+                <ol><li><a href="ch1.xhtml#s1">Section</a></li></ol></li>
+              <li><span>Part</span><ol><li><a href="ch2.xhtml">Two</a></li></ol></li>
+            </ol></nav></body></html>"#;
+        let toc = parse_nav(nav).unwrap();
+        let flat: Vec<_> = flatten(&toc)
+            .iter()
+            .map(|e| (e.label.as_str(), e.href.as_str()))
+            .collect();
+        assert_eq!(
+            flat,
+            [
+                ("Chapter One", "ch1.xhtml"),
+                ("Section", "ch1.xhtml#s1"),
+                ("Part", ""),
+                ("Two", "ch2.xhtml")
+            ]
+        );
+    }
+
+    #[test]
+    fn nav_document_round_trips_through_parse_nav() {
+        let toc = vec![
+            TocEntry {
+                label: "One & Two".into(),
+                href: "Text/ch1.xhtml".into(),
+                children: vec![TocEntry {
+                    label: "Section".into(),
+                    href: "Text/ch1.xhtml#s".into(),
+                    children: Vec::new(),
+                }],
+            },
+            TocEntry {
+                label: "Part".into(),
+                href: String::new(),
+                children: Vec::new(),
+            },
+        ];
+        let landmarks = [Landmark {
+            kind: "cover".into(),
+            label: "Cover".into(),
+            href: "Text/cover.xhtml".into(),
+        }];
+        let nav = nav_document("Contents", Some("en"), &toc, &landmarks);
+        assert!(crate::xhtml::is_well_formed(nav.as_bytes(), true));
+        assert!(nav.contains(r#"<a epub:type="cover" href="Text/cover.xhtml">Cover</a>"#));
+        assert_eq!(parse_nav(nav.as_bytes()).unwrap(), toc);
+    }
+
+    #[test]
+    fn ncx_document_skips_unlinked_entries() {
+        let toc = vec![TocEntry {
+            label: "Part".into(),
+            href: String::new(),
+            children: vec![
+                TocEntry {
+                    label: "One".into(),
+                    href: "ch1.xhtml".into(),
+                    children: vec![TocEntry {
+                        label: "1.1".into(),
+                        href: "ch1.xhtml#s".into(),
+                        children: Vec::new(),
+                    }],
+                },
+                TocEntry {
+                    label: "Again".into(),
+                    href: "ch1.xhtml".into(),
+                    children: Vec::new(),
+                },
+            ],
+        }];
+        let ncx = ncx_document("urn:uuid:1", "Book", &toc);
+        assert!(crate::xhtml::is_well_formed(ncx.as_bytes(), true));
+        assert!(ncx.contains(r#"<meta name="dtb:depth" content="2"/>"#));
+        let orders: Vec<_> = ncx
+            .match_indices("playOrder=\"")
+            .map(|(i, _)| &ncx[i + 11..i + 12])
+            .collect();
+        assert_eq!(orders, ["1", "2", "1"]);
+        let parsed = parse_ncx(ncx.as_bytes()).unwrap();
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[0].children[0].href, "ch1.xhtml#s");
+    }
+
+    #[test]
+    fn parse_ncx_reads_nav_points() {
+        let ncx = br#"<ncx><navMap>
+            <navPoint id="a" playOrder="1"><navLabel><text>One</text></navLabel><content src="ch1.xhtml"/>
+              <navPoint id="b" playOrder="2"><navLabel><text>1.1</text></navLabel><content src="ch1.xhtml#s"/></navPoint>
+            </navPoint>
+            <navPoint id="c" playOrder="3"><navLabel><text>Two</text></navLabel><content src="ch2.xhtml"/></navPoint>
+        </navMap></ncx>"#;
+        let toc = parse_ncx(ncx).unwrap();
+        assert_eq!(toc.len(), 2);
+        assert_eq!(toc[0].children[0].href, "ch1.xhtml#s");
+        assert_eq!(toc[1].label, "Two");
+    }
+}
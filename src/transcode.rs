@@ -0,0 +1,179 @@
+//! Conversion of images Kindle rejects: formats it does not support and
+//! images beyond a pixel or byte limit.
+
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::imageops::FilterType;
+use image::DynamicImage;
+
+/// Images are re-encoded at this JPEG quality.
+const JPEG_QUALITY: u8 = 85;
+
+/// How many times an image still over the byte limit is shrunk further.
+const MAX_ATTEMPTS: usize = 4;
+
+/// Limits above which images are downscaled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// The largest number of pixels, width times height.
+    pub max_pixels: u64,
+    /// The largest encoded size in bytes.
+    pub max_bytes: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_pixels: 4_000_000,
+            max_bytes: 1024 * 1024,
+        }
+    }
+}
+
+/// A converted image.
+#[derive(Debug)]
+pub(crate) struct Transcoded {
+    pub(crate) data: Vec<u8>,
+    pub(crate) media_type: &'static str,
+    pub(crate) extension: &'static str,
+    /// What was done, for the report.
+    pub(crate) reason: String,
+}
+
+/// Converts `data`, an image of `media_type`, when Kindle cannot use it:
+/// WebP, TIFF and BMP become PNG when they have transparency and JPEG
+/// otherwise, and images beyond `limits` are downscaled. GIFs are left alone
+/// to keep their animation. Returns `None` when nothing needs to change or
+/// the image cannot be decoded.
+pub(crate) fn transcode(data: &[u8], media_type: &str, limits: &Limits) -> Option<Transcoded> {
+    let convert = matches!(media_type, "image/webp" | "image/tiff" | "image/bmp");
+    if !convert && !matches!(media_type, "image/jpeg" | "image/png") {
+        return None;
+    }
+    let image = image::load_from_memory(data).ok()?;
+    let pixels = u64::from(image.width()) * u64::from(image.height());
+    let too_many_pixels = pixels > limits.max_pixels;
+    let too_large = data.len() > limits.max_bytes;
+    if !convert && !too_many_pixels && !too_large {
+        return None;
+    }
+
+    let (media_type, extension) = if !convert {
+        match media_type {
+            "image/png" => ("image/png", "png"),
+            _ => ("image/jpeg", "jpg"),
+        }
+    } else if image.color().has_alpha() {
+        ("image/png", "png")
+    } else {
+        ("image/jpeg", "jpg")
+    };
+
+    let mut scale = if too_many_pixels {
+        (limits.max_pixels as f64 / pixels as f64).sqrt()
+    } else {
+        1.0
+    };
+    let mut encoded = encode(&resize(&image, scale), media_type)?;
+    for _ in 0..MAX_ATTEMPTS {
+        if encoded.len() <= limits.max_bytes {
+            break;
+        }
+        scale *= (limits.max_bytes as f64 / encoded.len() as f64).sqrt() * 0.95;
+        encoded = encode(&resize(&image, scale), media_type)?;
+    }
+    if !convert && encoded.len() >= data.len() && !too_many_pixels {
+        return None;
+    }
+
+    let mut reasons = Vec::new();
+    if convert {
+        reasons.push(format!("converting to {extension}"));
+    }
+    if scale < 1.0 {
+        let (width, height) = scaled_size(&image, scale);
+        reasons.push(format!(
+            "downscaling {}x{} to {width}x{height}",
+            image.width(),
+            image.height()
+        ));
+    }
+    if reasons.is_empty() {
+        reasons.push("re-encoding".to_string());
+    }
+    reasons.push(format!("{} to {} bytes", data.len(), encoded.len()));
+    Some(Transcoded {
+        data: encoded,
+        media_type,
+        extension,
+        reason: reasons.join(", "),
+    })
+}
+
+fn scaled_size(image: &DynamicImage, scale: f64) -> (u32, u32) {
+    let size = |length: u32| ((f64::from(length) * scale).round() as u32).max(1);
+    (size(image.width()), size(image.height()))
+}
+
+fn resize(image: &DynamicImage, scale: f64) -> DynamicImage {
+    if scale >= 1.0 {
+        return image.clone();
+    }
+    let (width, height) = scaled_size(image, scale);
+    image.resize_exact(width, height, FilterType::Lanczos3)
+}
+
+fn encode(image: &DynamicImage, media_type: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let result = if media_type == "image/png" {
+        image.write_with_encoder(PngEncoder::new(&mut out))
+    } else {
+        DynamicImage::ImageRgb8(image.to_rgb8())
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut out, JPEG_QUALITY))
+    };
+    result.ok()?;
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageFormat, Rgb, RgbImage, Rgba, RgbaImage};
+    use std::io::Cursor;
+
+    fn encoded(image: DynamicImage, format: ImageFormat) -> Vec<u8> {
+        let mut out = Cursor::new(Vec::new());
+        image.write_to(&mut out, format).unwrap();
+        out.into_inner()
+    }
+
+    #[test]
+    fn transcode_converts_unsupported_formats() {
+        let opaque = RgbImage::from_pixel(8, 8, Rgb([200, 10, 10]));
+        let bmp = encoded(DynamicImage::ImageRgb8(opaque), ImageFormat::Bmp);
+        let jpeg = transcode(&bmp, "image/bmp", &Limits::default()).unwrap();
+        assert_eq!((jpeg.media_type, jpeg.extension), ("image/jpeg", "jpg"));
+        assert!(jpeg.data.starts_with(&[0xFF, 0xD8, 0xFF]));
+
+        let clear = RgbaImage::from_pixel(8, 8, Rgba([0, 0, 0, 0]));
+        let tiff = encoded(DynamicImage::ImageRgba8(clear), ImageFormat::Tiff);
+        let png = transcode(&tiff, "image/tiff", &Limits::default()).unwrap();
+        assert_eq!(png.extension, "png");
+    }
+
+    #[test]
+    fn transcode_downscales_large_images_only() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(100, 50, Rgb([1, 2, 3])));
+        let png = encoded(image, ImageFormat::Png);
+        let limits = Limits {
+            max_pixels: 1250,
+            ..Limits::default()
+        };
+        let small = transcode(&png, "image/png", &limits).unwrap();
+        let decoded = image::load_from_memory(&small.data).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (50, 25));
+
+        assert!(transcode(&png, "image/png", &Limits::default()).is_none());
+        assert!(transcode(&png, "image/gif", &limits).is_none());
+    }
+}
@@ -0,0 +1,379 @@
+use crate::metadata::{self, ModifiedMode};
+use crate::opf::{self, GuideReference, OPF_NS};
+use crate::toc::Landmark;
+use crate::xml_doc::XmlElement;
+use crate::{href, manifest, media_type};
+use scraper::Html;
+use std::collections::{BTreeMap, HashSet};
+
+/// What the EPUB 3 upgrade of a book needs besides the package document.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Upgrade {
+    /// Archive path of the navigation document generated from the NCX.
+    pub(crate) nav_path: Option<String>,
+    /// The manifest properties each content document requires, by path.
+    pub(crate) properties: BTreeMap<String, Vec<&'static str>>,
+}
+
+/// The EPUB 3 manifest properties a content document requires: `scripted`,
+/// `svg` and `mathml`.
+pub(crate) fn content_properties(content: &[u8]) -> Vec<&'static str> {
+    let document = Html::parse_document(&String::from_utf8_lossy(content));
+    let has = |name: &str| {
+        document
+            .tree
+            .nodes()
+            .any(|node| node.value().as_element().is_some_and(|e| e.name() == name))
+    };
+    [("script", "scripted"), ("svg", "svg"), ("math", "mathml")]
+        .into_iter()
+        .filter(|(element, _)| has(element))
+        .map(|(_, property)| property)
+        .collect()
+}
+
+/// Converts EPUB 2 guide references to EPUB 3 landmarks with hrefs relative
+/// to `nav_path`.
+pub(crate) fn landmarks(guide: &[GuideReference], opf_path: &str, nav_path: &str) -> Vec<Landmark> {
+    guide
+        .iter()
+        .filter_map(|reference| {
+            let kind = match reference.kind.to_ascii_lowercase().as_str() {
+                "cover" => "cover",
+                "title-page" => "titlepage",
+                "toc" => "toc",
+                "text" | "start" => "bodymatter",
+                "copyright-page" => "copyright-page",
+                "acknowledgements" => "acknowledgments",
+                "dedication" => "dedication",
+                "epigraph" => "epigraph",
+                "foreword" => "foreword",
+                "preface" => "preface",
+                "colophon" => "colophon",
+                "bibliography" => "bibliography",
+                "glossary" => "glossary",
+                "index" => "index",
+                "loi" => "loi",
+                "lot" => "lot",
+                _ => return None,
+            };
+            Some(Landmark {
+                kind: kind.to_string(),
+                label: reference.title.clone().unwrap_or_else(|| kind.to_string()),
+                href: href::rebase(opf_path, nav_path, &reference.href),
+            })
+        })
+        .collect()
+}
+
+/// Upgrades an EPUB 2 `package` to EPUB 3: sets the version, moves `opf:`
+/// attributes to refinements, adds the navigation document and
+/// `dcterms:modified`. Manifest properties are added by `add_properties`. The NCX and guide are kept.
+/// Returns true if the package was modified.
+pub(crate) fn repair(
+    package: &mut XmlElement,
+    opf_path: &str,
+    upgrade: &Upgrade,
+    timestamp: &str,
+) -> bool {
+    if opf::is_epub3(package) {
+        return false;
+    }
+    let version = package.attr("version").unwrap_or("with no version");
+
+    report!("Upgrade: converting the package from EPUB {version} to EPUB 3.0");
+    package.set_attr("version", "3.0");
+    if package.prefix().is_none() && package.declared_prefix(OPF_NS).is_none() {
+        package.set_attr("xmlns", OPF_NS);
+    }
+
+    let opf_prefix = package
+        .declared_prefix(OPF_NS)
+        .flatten()
+        .or_else(|| package.child("metadata")?.declared_prefix(OPF_NS).flatten())
+        .unwrap_or("opf")
+        .to_string();
+    let mut ids = metadata::element_ids(package);
+    if let Some(metadata) = package.child_mut("metadata") {
+        convert_opf_attributes(metadata, &opf_prefix, &mut ids);
+    }
+
+    if let Some(manifest) = package.child_mut("manifest") {
+        if let Some(nav_path) = &upgrade.nav_path {
+            let id = manifest::unique_id("nav", &ids);
+            report!("Upgrade: adding navigation document {nav_path}");
+            let name = match manifest.elements().find(|e| e.local_name() == "item") {
+                Some(item) => item.name.clone(),
+                None => "item".to_string(),
+            };
+            manifest.append_child(
+                XmlElement::new(&name)
+                    .with_attr("id", &id)
+                    .with_attr("href", &href::relative(opf_path, nav_path))
+                    .with_attr("media-type", media_type::XHTML)
+                    .with_attr("properties", "nav"),
+            );
+        }
+    }
+
+    metadata::ensure_modified(package, timestamp, ModifiedMode::Missing);
+    true
+}
+
+/// Adds the properties `upgrade` requires to the manifest items of the
+/// content documents, once the manifest is repaired so that items it adds
+/// get them too. Returns true if the package was modified.
+pub(crate) fn add_properties(package: &mut XmlElement, opf_path: &str, upgrade: &Upgrade) -> bool {
+    let Some(manifest) = package.child_mut("manifest") else {
+        return false;
+    };
+    let mut changed = false;
+    for item in manifest
+        .elements_mut()
+        .filter(|item| item.local_name() == "item")
+    {
+        let path = href::resolve(opf_path, item.attr("href").unwrap_or_default());
+        let Some(required) = upgrade.properties.get(&path) else {
+            continue;
+        };
+        let mut properties: Vec<String> = item
+            .attr("properties")
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(String::from)
+            .collect();
+        let missing: Vec<&str> = required
+            .iter()
+            .copied()
+            .filter(|property| !properties.iter().any(|p| p == property))
+            .collect();
+        if missing.is_empty() {
+            continue;
+        }
+        let id = item.attr("id").unwrap_or_default();
+        report!("Upgrade: adding properties {missing:?} to {id}");
+        properties.extend(missing.into_iter().map(String::from));
+        item.set_attr("properties", &properties.join(" "));
+        changed = true;
+    }
+    changed
+}
+
+/// Replaces the EPUB 2 `opf:role`, `opf:file-as`, `opf:scheme` and
+/// `opf:event` attributes of Dublin Core elements with EPUB 3 refinements.
+/// EPUB 3 allows a single `dc:date`, the publication date, so the first
+/// publication date is kept and the other dates become `dcterms:` metas.
+fn convert_opf_attributes(metadata: &mut XmlElement, prefix: &str, ids: &mut HashSet<String>) {
+    let meta_name = metadata::meta_element_name(metadata);
+    let attribute = |local: &str| format!("{prefix}:{local}");
+
+    let mut has_date = false;
+    for element in metadata
+        .elements_mut()
+        .filter(|element| element.local_name() == "date")
+    {
+        let event = element
+            .attr(&attribute("event"))
+            .unwrap_or_default()
+            .to_string();
+        if !has_date && matches!(event.as_str(), "" | "publication" | "original-publication") {
+            has_date = true;
+            continue;
+        }
+        let property = date_property(&event);
+        let value = element.text();
+        report!("Upgrade: keeping dc:date {value:?} ({event}) as {property}");
+        let mut meta = XmlElement::new(&meta_name).with_attr("property", property);
+        if let Some(id) = element.attr("id") {
+            meta.set_attr("id", id);
+        }
+        *element = meta.with_text(value.trim());
+    }
+
+    let mut index = 0;
+    loop {
+        let Some(element) = metadata.elements_mut().nth(index) else {
+            break;
+        };
+        index += 1;
+        let local = element.local_name().to_string();
+        if !opf::DC_ELEMENTS.contains(&local.as_str()) {
+            continue;
+        }
+
+        let mut take = |name: &str| {
+            let name = attribute(name);
+            let value = element.attr(&name).map(String::from);
+            element.remove_attr(&name);
+            value.filter(|value| !value.trim().is_empty())
+        };
+        let role = take("role");
+        let file_as = take("file-as");
+        let scheme = take("scheme");
+        take("event");
+
+        let mut refinements = Vec::new();
+        if let Some(role) = role {
+            refinements.push(("role", role, Some("marc:relators")));
+        }
+        if let Some(file_as) = file_as {
+            refinements.push(("file-as", file_as, None));
+        }
+        if let Some(scheme) = scheme.filter(|_| local == "identifier") {
+            refinements.push(("identifier-type", scheme, None));
+        }
+        if refinements.is_empty() {
+            continue;
+        }
+
+        let id = match element.attr("id") {
+            Some(id) => id.to_string(),
+            None => {
+                let id = manifest::unique_id(&local, ids);
+                ids.insert(id.clone());
+                element.set_attr("id", &id);
+                id
+            }
+        };
+        report!("Upgrade: moving opf: attributes of {id} to refinements");
+        for (property, value, scheme) in refinements {
+            let mut meta = XmlElement::new(&meta_name)
+                .with_attr("refines", &format!("#{id}"))
+                .with_attr("property", property);
+            if let Some(scheme) = scheme {
+                meta.set_attr("scheme", scheme);
+            }
+            metadata.insert_child(index, meta.with_text(&value));
+            index += 1;
+        }
+    }
+}
+
+/// The `dcterms:` property for a `dc:date` with the EPUB 2 `event` that is
+/// not the publication date EPUB 3 keeps as `dc:date`.
+fn date_property(event: &str) -> &'static str {
+    match event.to_ascii_lowercase().as_str() {
+        "creation" | "created" => "dcterms:created",
+        "copyright" => "dcterms:dateCopyrighted",
+        "publication" | "original-publication" | "issue" | "issued" => "dcterms:issued",
+        _ => "dcterms:date",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::xml_doc::XmlDocument;
+
+    const OPF: &[u8] =
+        br#"<package xmlns="http://www.idpf.org/2007/opf" version="2.0" unique-identifier="uid">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:opf="http://www.idpf.org/2007/opf">
+    <dc:identifier id="uid" opf:scheme="ISBN">9780000000000</dc:identifier>
+    <dc:creator opf:role="aut" opf:file-as="Doe, Jane">Jane Doe</dc:creator>
+    <dc:date opf:event="modification">2020-01-01</dc:date>
+    <dc:date opf:event="publication">2019-01-01</dc:date>
+    <dc:date opf:event="creation">2018-06-01</dc:date>
+    <meta name="cover" content="cover"/>
+  </metadata>
+  <manifest>
+    <item id="ch1" href="Text/ch1.xhtml" media-type="application/xhtml+xml"/>
+    <item id="ncx" href="toc.ncx" media-type="application/x-dtbncx+xml"/>
+  </manifest>
+  <spine toc="ncx"><itemref idref="ch1"/></spine>
+</package>"#;
+
+    #[test]
+    fn repair_upgrades_epub2_package() {
+        let mut document = XmlDocument::parse(OPF).unwrap();
+        let upgrade = Upgrade {
+            nav_path: Some("OEBPS/nav.xhtml".into()),
+            properties: BTreeMap::from([("OEBPS/Text/ch1.xhtml".into(), vec!["svg"])]),
+        };
+        assert!(repair(
+            &mut document.root,
+            "OEBPS/content.opf",
+            &upgrade,
+            "2024-01-01T00:00:00Z"
+        ));
+        assert!(!repair(
+            &mut document.root,
+            "OEBPS/content.opf",
+            &upgrade,
+            "2024-01-01T00:00:00Z"
+        ));
+        assert!(add_properties(
+            &mut document.root,
+            "OEBPS/content.opf",
+            &upgrade
+        ));
+        assert!(!add_properties(
+            &mut document.root,
+            "OEBPS/content.opf",
+            &upgrade
+        ));
+
+        let package = opf::Package::parse(&document.to_bytes()).unwrap();
+        assert!(package.is_epub3());
+        let metadata = &package.metadata;
+        let creator = metadata.dc("creator").next().unwrap();
+        assert!(creator.attributes.is_empty());
+        assert_eq!(metadata.refined(creator, "role"), Some("aut"));
+        assert_eq!(metadata.refined(creator, "file-as"), Some("Doe, Jane"));
+        let identifier = metadata.dc("identifier").next().unwrap();
+        assert_eq!(
+            metadata.refined(identifier, "identifier-type"),
+            Some("ISBN")
+        );
+        let dates: Vec<_> = metadata.dc("date").map(|d| d.value.as_str()).collect();
+        assert_eq!(dates, ["2019-01-01"]);
+        assert_eq!(metadata.property("dcterms:date"), Some("2020-01-01"));
+        assert_eq!(metadata.property("dcterms:created"), Some("2018-06-01"));
+        assert_eq!(
+            metadata.property("dcterms:modified"),
+            Some("2024-01-01T00:00:00Z")
+        );
+        assert_eq!(metadata.meta_content("cover"), Some("cover"));
+
+        assert!(package.item("ch1").unwrap().has_property("svg"));
+        let nav = package.items_with_property("nav").next().unwrap();
+        assert_eq!(nav.href, "nav.xhtml");
+        assert_eq!(package.spine.toc.as_deref(), Some("ncx"));
+    }
+
+    #[test]
+    fn content_properties_detects_scripts_svg_and_mathml() {
+        let content = br#"<html><body><script>x()</script><svg><image/></svg></body></html>"#;
+        assert_eq!(content_properties(content), ["scripted", "svg"]);
+        assert!(content_properties(b"<html><body><p>Hi</p></body></html>").is_empty());
+    }
+
+    #[test]
+    fn landmarks_map_guide_types() {
+        let guide = [
+            GuideReference {
+                kind: "cover".into(),
+                title: Some("Cover".into()),
+                href: "Text/cover.xhtml".into(),
+            },
+            GuideReference {
+                kind: "other.ms-thumbimage".into(),
+                title: None,
+                href: "thumb.jpg".into(),
+            },
+            GuideReference {
+                kind: "text".into(),
+                title: None,
+                href: "Text/ch1.xhtml#start".into(),
+            },
+        ];
+        let landmarks = landmarks(&guide, "OEBPS/content.opf", "OEBPS/Text/nav.xhtml");
+        let kinds: Vec<_> = landmarks
+            .iter()
+            .map(|l| (l.kind.as_str(), l.href.as_str()))
+            .collect();
+        assert_eq!(
+            kinds,
+            [("cover", "cover.xhtml"), ("bodymatter", "ch1.xhtml#start")]
+        );
+    }
+}
@@ -0,0 +1,719 @@
+//! A structural EPUB validator covering the OCF container, the package
+//! document, XHTML well-formedness and reference integrity. Messages carry
+//! the IDs and severities epubcheck uses for the same problems.
+
+use crate::archive::Book;
+use crate::epub::is_valid_language;
+use crate::error::FixError;
+use crate::metadata::element_ids;
+use crate::opf::{EpubVersion, Package};
+use crate::xml_doc::XmlDocument;
+use crate::{href, links, manifest, media_type, upgrade, xhtml};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt;
+use zip::CompressionMethod;
+
+/// Characters OCF forbids in file names.
+const FORBIDDEN_NAME_CHARS: &[char] = &['"', '*', ':', '<', '>', '?', '\\', '|', '\u{7f}'];
+
+/// Manifest properties that must match what a content document contains.
+const CONTENT_PROPERTIES: &[&str] = &["scripted", "svg", "mathml"];
+
+/// How serious a message is, from least to most.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Usage,
+    Warning,
+    Error,
+    Fatal,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Severity::Usage => "USAGE",
+            Severity::Warning => "WARNING",
+            Severity::Error => "ERROR",
+            Severity::Fatal => "FATAL",
+        };
+        f.write_str(name)
+    }
+}
+
+/// One validation message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    /// The epubcheck message ID, e.g. `RSC-005`.
+    pub id: &'static str,
+    pub severity: Severity,
+    /// The archive entry the message is about, if any.
+    pub path: Option<String>,
+    pub text: String,
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}({})", self.severity, self.id)?;
+        if let Some(path) = &self.path {
+            write!(f, ": {path}")?;
+        }
+        write!(f, ": {}", self.text)
+    }
+}
+
+/// The number of messages that are errors or worse.
+pub fn error_count(messages: &[Message]) -> usize {
+    messages
+        .iter()
+        .filter(|message| message.severity >= Severity::Error)
+        .count()
+}
+
+/// Validates the EPUB `filename`.
+pub fn validate(filename: &str) -> Result<Vec<Message>, FixError> {
+    let book = Book::read(filename)?;
+    let mut validator = Validator {
+        book: &book,
+        messages: Vec::new(),
+    };
+    validator.check_container();
+    if let Some(package) = validator.package() {
+        validator.check_metadata(package);
+        validator.check_manifest(package);
+        validator.check_spine(package);
+        validator.check_content_documents(package);
+        validator.check_references(package);
+    }
+    Ok(validator.messages)
+}
+
+struct Validator<'a> {
+    book: &'a Book,
+    messages: Vec<Message>,
+}
+
+impl<'a> Validator<'a> {
+    fn report(&mut self, id: &'static str, severity: Severity, path: Option<&str>, text: String) {
+        self.messages.push(Message {
+            id,
+            severity,
+            path: path.map(String::from),
+            text,
+        });
+    }
+
+    fn opf_path(&self) -> &'a str {
+        self.book.opf_path()
+    }
+
+    fn check_container(&mut self) {
+        match self.book.entries.first() {
+            Some(first) if first.name == "mimetype" => {
+                if first.data != b"application/epub+zip"
+                    || first.compression != CompressionMethod::Stored
+                {
+                    self.report(
+                        "PKG-007",
+                        Severity::Error,
+                        Some("mimetype"),
+                        "Mimetype file should only contain the string \"application/epub+zip\" \
+                         and should not be compressed."
+                            .to_string(),
+                    );
+                }
+            }
+            _ => self.report(
+                "PKG-006",
+                Severity::Error,
+                None,
+                "Mimetype file entry is missing or is not the first file in the archive."
+                    .to_string(),
+            ),
+        }
+
+        if !self.book.contains("META-INF/container.xml") {
+            self.report(
+                "RSC-002",
+                Severity::Fatal,
+                None,
+                "Required META-INF/container.xml resource could not be found.".to_string(),
+            );
+        } else if self.book.opf_path.is_none() {
+            self.report(
+                "RSC-003",
+                Severity::Error,
+                Some("META-INF/container.xml"),
+                "No rootfile with a full-path was found in the container.".to_string(),
+            );
+        } else if !self.book.contains(self.opf_path()) {
+            let opf_path = self.opf_path();
+            self.report(
+                "OPF-002",
+                Severity::Fatal,
+                None,
+                format!("The OPF file {opf_path} was not found in the EPUB."),
+            );
+        }
+
+        for entry in &self.book.entries {
+            let name = entry.name.trim_end_matches('/');
+            if name.contains(FORBIDDEN_NAME_CHARS)
+                || name.chars().any(|c| c.is_control())
+                || name.ends_with('.')
+            {
+                self.report(
+                    "PKG-009",
+                    Severity::Error,
+                    Some(&entry.name),
+                    "File name contains characters that are not allowed in OCF file names."
+                        .to_string(),
+                );
+            }
+            if name.contains(' ') {
+                self.report(
+                    "PKG-010",
+                    Severity::Warning,
+                    Some(&entry.name),
+                    "File name contains spaces, which should be avoided.".to_string(),
+                );
+            }
+            if !name.is_ascii() {
+                self.report(
+                    "PKG-012",
+                    Severity::Usage,
+                    Some(&entry.name),
+                    "File name contains non-ASCII characters, which might cause \
+                     interoperability issues."
+                        .to_string(),
+                );
+            }
+        }
+        for name in &self.book.duplicates {
+            self.report(
+                "OPF-060",
+                Severity::Error,
+                Some(name),
+                "Duplicate entry in the ZIP file.".to_string(),
+            );
+        }
+    }
+
+    /// The parsed package document, reporting why it cannot be read.
+    fn package(&mut self) -> Option<&'a Package> {
+        let opf_path = self.opf_path();
+        if !self.book.contains(opf_path) {
+            return None;
+        }
+        match &self.book.package {
+            Ok(package) => Some(package),
+            Err(FixError::MalformedXml(err)) => {
+                self.report(
+                    "RSC-016",
+                    Severity::Fatal,
+                    Some(opf_path),
+                    format!("Fatal Error while parsing file: {err}"),
+                );
+                None
+            }
+            Err(err) => {
+                self.report(
+                    "RSC-005",
+                    Severity::Error,
+                    Some(opf_path),
+                    format!("Error while parsing file: {err}"),
+                );
+                None
+            }
+        }
+    }
+
+    fn check_metadata(&mut self, package: &Package) {
+        let opf_path = Some(self.opf_path());
+        for name in ["identifier", "title", "language"] {
+            if package.metadata.first(name).is_none() {
+                self.report(
+                    "RSC-005",
+                    Severity::Error,
+                    opf_path,
+                    format!("Error while parsing file: metadata is missing dc:{name}"),
+                );
+            }
+        }
+        for language in package.metadata.dc("language") {
+            if !is_valid_language(&language.value) {
+                self.report(
+                    "OPF-092",
+                    Severity::Error,
+                    opf_path,
+                    format!("Language tag \"{}\" is not well-formed.", language.value),
+                );
+            }
+        }
+
+        match &package.unique_identifier {
+            None => self.report(
+                "OPF-048",
+                Severity::Error,
+                opf_path,
+                "Package tag is missing its required unique-identifier attribute and value."
+                    .to_string(),
+            ),
+            Some(uid) if package.unique_identifier_value().is_none() => self.report(
+                "OPF-030",
+                Severity::Error,
+                opf_path,
+                format!("The unique-identifier \"{uid}\" was not found."),
+            ),
+            Some(_) => {}
+        }
+
+        if package.is_epub3() && package.metadata.property("dcterms:modified").is_none() {
+            self.report(
+                "RSC-005",
+                Severity::Error,
+                opf_path,
+                "Error while parsing file: package dcterms:modified meta element must occur \
+                 exactly once"
+                    .to_string(),
+            );
+        }
+    }
+
+    fn check_manifest(&mut self, package: &Package) {
+        let opf_path = self.opf_path();
+        let mut ids = HashSet::new();
+        let mut paths = HashSet::new();
+        for item in &package.manifest {
+            if !ids.insert(item.id.as_str()) {
+                self.report(
+                    "RSC-005",
+                    Severity::Error,
+                    Some(opf_path),
+                    format!("Error while parsing file: Duplicate ID \"{}\"", item.id),
+                );
+            }
+            if href::is_external(&item.href) {
+                continue;
+            }
+            let path = href::resolve(opf_path, &item.href);
+            if !paths.insert(path.clone()) {
+                self.report(
+                    "OPF-074",
+                    Severity::Error,
+                    Some(opf_path),
+                    format!(
+                        "Package resource {} is declared in several manifest items.",
+                        item.href
+                    ),
+                );
+            }
+            if !self.book.contains(&path) {
+                self.report(
+                    "RSC-001",
+                    Severity::Error,
+                    Some(opf_path),
+                    format!("File {} could not be found.", item.href),
+                );
+            }
+        }
+
+        let undeclared: Vec<&str> = self
+            .book
+            .entries
+            .iter()
+            .map(|entry| entry.name.as_str())
+            .filter(|name| !manifest::is_container_file(name, opf_path) && !paths.contains(*name))
+            .collect();
+        for name in undeclared {
+            self.report(
+                "OPF-003",
+                Severity::Usage,
+                Some(name),
+                "Item exists in the EPUB, but is not declared in the OPF manifest.".to_string(),
+            );
+        }
+    }
+
+    fn check_spine(&mut self, package: &Package) {
+        let opf_path = Some(self.opf_path());
+        let mut seen = HashSet::new();
+        for itemref in &package.spine.itemrefs {
+            let Some(item) = package.item(&itemref.idref) else {
+                self.report(
+                    "OPF-049",
+                    Severity::Error,
+                    opf_path,
+                    format!(
+                        "Item id \"{}\" was not found in the manifest.",
+                        itemref.idref
+                    ),
+                );
+                continue;
+            };
+            if !seen.insert(itemref.idref.as_str()) {
+                self.report(
+                    "OPF-034",
+                    Severity::Error,
+                    opf_path,
+                    format!(
+                        "The spine contains multiple references to the manifest item with id \
+                         \"{}\".",
+                        itemref.idref
+                    ),
+                );
+            }
+            let standard = match package.version {
+                EpubVersion::Epub3 => item.is_xhtml() || item.media_type == "image/svg+xml",
+                _ => {
+                    item.is_xhtml()
+                        || matches!(
+                            item.media_type.as_str(),
+                            "application/x-dtbook+xml" | "text/x-oeb1-document"
+                        )
+                }
+            };
+            if !standard && item.fallback.is_none() {
+                self.report(
+                    "OPF-043",
+                    Severity::Error,
+                    opf_path,
+                    format!(
+                        "Spine item {} with non-standard media-type {} has no fallback.",
+                        item.href, item.media_type
+                    ),
+                );
+            }
+        }
+        let linear = package
+            .spine
+            .itemrefs
+            .iter()
+            .any(|itemref| itemref.linear && package.item(&itemref.idref).is_some());
+        if !linear {
+            self.report(
+                "OPF-033",
+                Severity::Error,
+                opf_path,
+                "The spine contains no linear resources.".to_string(),
+            );
+        }
+
+        match &package.spine.toc {
+            Some(toc) => match package.item(toc) {
+                None => self.report(
+                    "OPF-049",
+                    Severity::Error,
+                    opf_path,
+                    format!("Item id \"{toc}\" was not found in the manifest."),
+                ),
+                Some(item) if item.media_type != media_type::NCX => self.report(
+                    "OPF-050",
+                    Severity::Error,
+                    opf_path,
+                    "TOC attribute references resource with non-NCX mime type; \
+                     \"application/x-dtbncx+xml\" is expected."
+                        .to_string(),
+                ),
+                Some(_) => {}
+            },
+            None if !package.is_epub3() => self.report(
+                "RSC-005",
+                Severity::Error,
+                opf_path,
+                "Error while parsing file: spine is missing the required toc attribute".to_string(),
+            ),
+            None => {}
+        }
+
+        if package.is_epub3() {
+            let navs = package.items_with_property("nav").count();
+            if navs != 1 {
+                self.report(
+                    "RSC-005",
+                    Severity::Error,
+                    opf_path,
+                    format!(
+                        "Error while parsing file: exactly one manifest item must declare the \
+                         \"nav\" property (found {navs})"
+                    ),
+                );
+            }
+        }
+    }
+
+    fn check_content_documents(&mut self, package: &Package) {
+        let opf_path = self.opf_path();
+        for item in package.manifest.iter().filter(|item| item.is_xhtml()) {
+            let path = href::resolve(opf_path, &item.href);
+            let Some(entry) = self.book.entry(&path) else {
+                continue;
+            };
+            if let Err(err) = xhtml::parse(&entry.data, package.is_epub3()) {
+                self.report(
+                    "RSC-016",
+                    Severity::Fatal,
+                    Some(&path),
+                    format!("Fatal Error while parsing file: {err}"),
+                );
+            }
+            if !package.is_epub3() {
+                continue;
+            }
+
+            let required = upgrade::content_properties(&entry.data);
+            for property in CONTENT_PROPERTIES {
+                let declared = item.has_property(property);
+                if required.contains(property) && !declared {
+                    self.report(
+                        "OPF-014",
+                        Severity::Error,
+                        Some(opf_path),
+                        format!(
+                            "The property \"{property}\" should be declared in the OPF file \
+                             for {}.",
+                            item.href
+                        ),
+                    );
+                } else if declared && !required.contains(property) {
+                    self.report(
+                        "OPF-015",
+                        Severity::Error,
+                        Some(opf_path),
+                        format!(
+                            "The property \"{property}\" should not be declared in the OPF \
+                             file for {}.",
+                            item.href
+                        ),
+                    );
+                }
+            }
+        }
+    }
+
+    fn check_references(&mut self, package: &Package) {
+        let opf_path = self.opf_path();
+        let declared: BTreeMap<String, &str> = package
+            .manifest
+            .iter()
+            .map(|item| {
+                (
+                    href::resolve(opf_path, &item.href),
+                    item.media_type.as_str(),
+                )
+            })
+            .collect();
+        let mut ids: BTreeMap<&str, Option<HashSet<String>>> = BTreeMap::new();
+
+        for (path, media_type) in &declared {
+            let Some(entry) = self.book.entry(path) else {
+                continue;
+            };
+            let entry_links = if *media_type == media_type::XHTML {
+                links::xhtml_links(&entry.data)
+            } else if *media_type == media_type::CSS {
+                links::css_urls(&String::from_utf8_lossy(&entry.data))
+            } else {
+                continue;
+            };
+
+            let mut reported = BTreeSet::new();
+            for link in entry_links {
+                if link.is_empty() || href::is_external(&link) || !reported.insert(link.clone()) {
+                    continue;
+                }
+                let target = href::resolve(path, &link);
+                let Some(target_entry) = self.book.entry(&target) else {
+                    self.report(
+                        "RSC-007",
+                        Severity::Error,
+                        Some(path),
+                        format!("Referenced resource {link} could not be found in the EPUB."),
+                    );
+                    continue;
+                };
+                if !declared.contains_key(&target)
+                    && !manifest::is_container_file(&target, opf_path)
+                {
+                    self.report(
+                        "RSC-008",
+                        Severity::Error,
+                        Some(path),
+                        format!("Referenced resource {link} is not declared in the OPF manifest."),
+                    );
+                    continue;
+                }
+
+                let fragment = link.split_once('#').map(|(_, fragment)| fragment);
+                let Some(fragment) = fragment.filter(|fragment| !fragment.is_empty()) else {
+                    continue;
+                };
+                if declared.get(&target) != Some(&media_type::XHTML) {
+                    continue;
+                }
+                let target_ids = ids.entry(&target_entry.name).or_insert_with(|| {
+                    XmlDocument::parse(&target_entry.data)
+                        .ok()
+                        .map(|document| element_ids(&document.root))
+                });
+                if target_ids
+                    .as_ref()
+                    .is_some_and(|target_ids| !target_ids.contains(fragment))
+                {
+                    self.report(
+                        "RSC-012",
+                        Severity::Error,
+                        Some(path),
+                        format!("Fragment identifier is not defined in {link}."),
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archive::write_test_book;
+
+    const CONTAINER: &[u8] =
+        b"<container><rootfiles><rootfile full-path='OEBPS/content.opf'/></rootfiles></container>";
+
+    const OPF: &str = r#"<package version="3.0" unique-identifier="uid">
+<metadata xmlns:dc="http://purl.org/dc/elements/1.1/"><dc:identifier id="uid">urn:x</dc:identifier>
+<dc:title>T</dc:title><dc:language>en</dc:language>
+<meta property="dcterms:modified">2024-01-01T00:00:00Z</meta></metadata>
+<manifest><item id="nav" href="nav.xhtml" media-type="application/xhtml+xml" properties="nav"/>
+<item id="c1" href="c1.xhtml" media-type="application/xhtml+xml"/></manifest>
+<spine><itemref idref="c1"/></spine></package>"#;
+
+    const NAV: &[u8] = br#"<html xmlns="http://www.w3.org/1999/xhtml" xmlns:epub="http://www.idpf.org/2007/ops"><head><title>N</title></head>
+<body><nav epub:type="toc"><ol><li><a href="c1.xhtml#start">One</a></li></ol></nav></body></html>"#;
+
+    const CHAPTER: &[u8] =
+        br#"<html xmlns="http://www.w3.org/1999/xhtml"><head><title>1</title></head>
+<body><h1 id="start">One</h1></body></html>"#;
+
+    fn validate_files(files: &[(&str, &[u8])]) -> Vec<Message> {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("book.epub");
+        write_test_book(&path, files);
+        validate(path.to_str().unwrap()).unwrap()
+    }
+
+    fn ids(messages: &[Message]) -> Vec<&str> {
+        messages.iter().map(|message| message.id).collect()
+    }
+
+    #[test]
+    fn validate_accepts_a_valid_book() {
+        let messages = validate_files(&[
+            ("mimetype", b"application/epub+zip"),
+            ("META-INF/container.xml", CONTAINER),
+            ("OEBPS/content.opf", OPF.as_bytes()),
+            ("OEBPS/nav.xhtml", NAV),
+            ("OEBPS/c1.xhtml", CHAPTER),
+        ]);
+        assert!(messages.is_empty(), "{messages:?}");
+    }
+
+    #[test]
+    fn validate_reports_a_malformed_package_as_fatal() {
+        let opf = OPF.replace("</package>", "</packag>");
+        let messages = validate_files(&[
+            ("mimetype", b"application/epub+zip"),
+            ("META-INF/container.xml", CONTAINER),
+            ("OEBPS/content.opf", opf.as_bytes()),
+        ]);
+        assert_eq!(ids(&messages), ["RSC-016"]);
+        assert_eq!(messages[0].severity, Severity::Fatal);
+    }
+
+    #[test]
+    fn validate_reports_structural_problems() {
+        let opf = OPF
+            .replace("<dc:language>en</dc:language>", "")
+            .replace(
+                r#"<itemref idref="c1"/>"#,
+                r#"<itemref idref="c1"/><itemref idref="c9"/>"#,
+            )
+            .replace(
+                "</manifest>",
+                r#"<item id="img" href="a.png" media-type="image/png"/>
+<item id="c2" href="c2.xhtml" media-type="application/xhtml+xml"/></manifest>"#,
+            );
+        let chapter = String::from_utf8_lossy(CHAPTER).replace("id=\"start\"", "id=\"begin\"");
+        let chapter = chapter.replace("</h1>", "</h1><img src='b.png'/>");
+        let messages = validate_files(&[
+            ("META-INF/container.xml", CONTAINER),
+            ("OEBPS/content.opf", opf.as_bytes()),
+            ("OEBPS/nav.xhtml", NAV),
+            ("OEBPS/c1.xhtml", chapter.as_bytes()),
+            (
+                "OEBPS/c2.xhtml",
+                b"<html><body><p>Two<br></p></body></html>",
+            ),
+            ("OEBPS/b.png", b"\x89PNG"),
+        ]);
+        assert_eq!(
+            ids(&messages),
+            [
+                "PKG-006", "RSC-005", "RSC-001", "OPF-003", "OPF-049", "RSC-016", "RSC-008",
+                "RSC-012"
+            ]
+        );
+        assert!(error_count(&messages) > 0);
+        assert_eq!(
+            messages[0].to_string(),
+            "ERROR(PKG-006): Mimetype file entry is missing or is not the first file in the archive."
+        );
+    }
+
+    #[test]
+    fn validate_checks_content_properties() {
+        let opf = OPF.replace(
+            r#"href="c1.xhtml" media-type="application/xhtml+xml""#,
+            r#"href="c1.xhtml" media-type="application/xhtml+xml" properties="scripted""#,
+        );
+        let chapter = String::from_utf8_lossy(CHAPTER)
+            .replace("</h1>", "</h1><svg xmlns=\"http://www.w3.org/2000/svg\"/>");
+        let messages = validate_files(&[
+            ("mimetype", b"application/epub+zip"),
+            ("META-INF/container.xml", CONTAINER),
+            ("OEBPS/content.opf", opf.as_bytes()),
+            ("OEBPS/nav.xhtml", NAV),
+            ("OEBPS/c1.xhtml", chapter.as_bytes()),
+        ]);
+        assert_eq!(ids(&messages), ["OPF-015", "OPF-014"]);
+    }
+
+    #[test]
+    fn validate_accepts_xhtml_entities_in_epub2_documents() {
+        let chapter = String::from_utf8_lossy(CHAPTER).replace(
+            "<html",
+            "<!DOCTYPE html PUBLIC \"-//W3C//DTD XHTML 1.1//EN\" \
+             \"http://www.w3.org/TR/xhtml11/DTD/xhtml11.dtd\">\n<html",
+        );
+        let chapter = chapter.replace(">One</h1>", ">One&nbsp;&mdash;</h1>");
+        let fatal = |version: &str| {
+            let opf = OPF.replace(r#"version="3.0""#, version);
+            let messages = validate_files(&[
+                ("mimetype", b"application/epub+zip"),
+                ("META-INF/container.xml", CONTAINER),
+                ("OEBPS/content.opf", opf.as_bytes()),
+                ("OEBPS/nav.xhtml", NAV),
+                ("OEBPS/c1.xhtml", chapter.as_bytes()),
+            ]);
+            messages
+                .into_iter()
+                .filter(|message| message.id == "RSC-016")
+                .map(|message| message.text)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(fatal(r#"version="2.0""#), Vec::<String>::new());
+        assert_eq!(
+            fatal(r#"version="3.0""#),
+            ["Fatal Error while parsing file: The entity \"mdash\" was referenced, but not declared."]
+        );
+    }
+}
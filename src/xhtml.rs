@@ -0,0 +1,435 @@
+use crate::xml_doc::XmlDocument;
+use ego_tree::NodeRef;
+use scraper::{Html, Node};
+use std::collections::BTreeMap;
+
+const XHTML_NS: &str = "http://www.w3.org/1999/xhtml";
+const SVG_NS: &str = "http://www.w3.org/2000/svg";
+const MATHML_NS: &str = "http://www.w3.org/1998/Math/MathML";
+const XLINK_NS: &str = "http://www.w3.org/1999/xlink";
+const XML_NS: &str = "http://www.w3.org/XML/1998/namespace";
+const XMLNS_NS: &str = "http://www.w3.org/2000/xmlns/";
+const EPUB_NS: &str = "http://www.idpf.org/2007/ops";
+
+/// Namespaces of attribute prefixes found in content documents, declared
+/// when the document itself does not declare them.
+const KNOWN_PREFIXES: &[(&str, &str)] = &[
+    ("epub", EPUB_NS),
+    ("xlink", XLINK_NS),
+    ("opf", "http://www.idpf.org/2007/opf"),
+    (
+        "ibooks",
+        "http://vocabulary.itunes.apple.com/rdf/ibooks/vocabulary-extensions-1.0",
+    ),
+];
+
+/// The entities the XHTML 1.0 and 1.1 DTDs declare, beyond the five XML
+/// defines.
+pub(crate) const DTD_ENTITIES: &[&str] = &[
+    // Latin-1
+    "nbsp", "iexcl", "cent", "pound", "curren", "yen", "brvbar", "sect", "uml", "copy", "ordf",
+    "laquo", "not", "shy", "reg", "macr", "deg", "plusmn", "sup2", "sup3", "acute", "micro",
+    "para", "middot", "cedil", "sup1", "ordm", "raquo", "frac14", "frac12", "frac34", "iquest",
+    "Agrave", "Aacute", "Acirc", "Atilde", "Auml", "Aring", "AElig", "Ccedil", "Egrave", "Eacute",
+    "Ecirc", "Euml", "Igrave", "Iacute", "Icirc", "Iuml", "ETH", "Ntilde", "Ograve", "Oacute",
+    "Ocirc", "Otilde", "Ouml", "times", "Oslash", "Ugrave", "Uacute", "Ucirc", "Uuml", "Yacute",
+    "THORN", "szlig", "agrave", "aacute", "acirc", "atilde", "auml", "aring", "aelig", "ccedil",
+    "egrave", "eacute", "ecirc", "euml", "igrave", "iacute", "icirc", "iuml", "eth", "ntilde",
+    "ograve", "oacute", "ocirc", "otilde", "ouml", "divide", "oslash", "ugrave", "uacute", "ucirc",
+    "uuml", "yacute", "thorn", "yuml", // Special
+    "OElig", "oelig", "Scaron", "scaron", "Yuml", "circ", "tilde", "ensp", "emsp", "thinsp",
+    "zwnj", "zwj", "lrm", "rlm", "ndash", "mdash", "lsquo", "rsquo", "sbquo", "ldquo", "rdquo",
+    "bdquo", "dagger", "Dagger", "permil", "lsaquo", "rsaquo", "euro", // Symbols
+    "fnof", "Alpha", "Beta", "Gamma", "Delta", "Epsilon", "Zeta", "Eta", "Theta", "Iota", "Kappa",
+    "Lambda", "Mu", "Nu", "Xi", "Omicron", "Pi", "Rho", "Sigma", "Tau", "Upsilon", "Phi", "Chi",
+    "Psi", "Omega", "alpha", "beta", "gamma", "delta", "epsilon", "zeta", "eta", "theta", "iota",
+    "kappa", "lambda", "mu", "nu", "xi", "omicron", "pi", "rho", "sigmaf", "sigma", "tau",
+    "upsilon", "phi", "chi", "psi", "omega", "thetasym", "upsih", "piv", "bull", "hellip", "prime",
+    "Prime", "oline", "frasl", "weierp", "image", "real", "trade", "alefsym", "larr", "uarr",
+    "rarr", "darr", "harr", "crarr", "lArr", "uArr", "rArr", "dArr", "hArr", "forall", "part",
+    "exist", "empty", "nabla", "isin", "notin", "ni", "prod", "sum", "minus", "lowast", "radic",
+    "prop", "infin", "ang", "and", "or", "cap", "cup", "int", "there4", "sim", "cong", "asymp",
+    "ne", "equiv", "le", "ge", "sub", "sup", "nsub", "sube", "supe", "oplus", "otimes", "perp",
+    "sdot", "lceil", "rceil", "lfloor", "rfloor", "lang", "rang", "loz", "spades", "clubs",
+    "hearts", "diams",
+];
+
+const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "param", "source",
+    "track", "wbr",
+];
+
+/// Parses the content document `content` and fails, like epubcheck, on
+/// references to entities that are not declared. Only EPUB 2 documents with
+/// an XHTML doctype declare the XHTML entities, e.g. `&nbsp;`.
+pub(crate) fn parse(content: &[u8], epub3: bool) -> Result<XmlDocument, String> {
+    let document = XmlDocument::parse(content).map_err(|err| err.to_string())?;
+    let xhtml_dtd = !epub3
+        && document
+            .doctype()
+            .is_some_and(|doctype| doctype.contains("//DTD XHTML"));
+    let undeclared = document
+        .entity_references()?
+        .into_iter()
+        .find(|name| !(xhtml_dtd && DTD_ENTITIES.contains(&name.as_str())));
+    match undeclared {
+        Some(name) => Err(format!(
+            "The entity \"{name}\" was referenced, but not declared."
+        )),
+        None => Ok(document),
+    }
+}
+
+pub(crate) fn is_well_formed(content: &[u8], epub3: bool) -> bool {
+    parse(content, epub3).is_ok()
+}
+
+/// Parses `html`, the content document at `path`, leniently and serializes it
+/// back as well-formed XHTML.
+///
+/// Named entities are resolved by the HTML parser; characters that are easy
+/// to lose (non-breaking and zero-width spaces, soft hyphens) are written back
+/// as numeric character references. Prefixed elements and attributes keep
+/// the namespace the document declares for their prefix, or a well-known one;
+/// elements whose prefix has neither are replaced by their content, such
+/// attributes are dropped, and both are reported. The XML declaration is left
+/// to `fix_encoding`.
+pub(crate) fn repair(path: &str, html: &str) -> String {
+    write(path, &Html::parse_document(html))
+}
+
+/// Serializes `document`, the parsed content document at `path`, as XHTML,
+/// like [`repair`] does.
+pub(crate) fn write(path: &str, document: &Html) -> String {
+    let prefixes = Prefixes {
+        path,
+        namespaces: declared_namespaces(document.tree.root()),
+    };
+    let mut out = String::new();
+    for child in document.tree.root().children() {
+        write_node(child, None, &prefixes, &mut out);
+    }
+    out
+}
+
+/// The namespaces attribute prefixes are bound to while repairing the
+/// content document at `path`.
+struct Prefixes<'a> {
+    path: &'a str,
+    namespaces: BTreeMap<String, String>,
+}
+
+/// The `xmlns:` declarations anywhere in the document, the first one winning,
+/// on top of [`KNOWN_PREFIXES`].
+fn declared_namespaces(root: NodeRef<'_, Node>) -> BTreeMap<String, String> {
+    let mut namespaces: BTreeMap<String, String> = KNOWN_PREFIXES
+        .iter()
+        .map(|(prefix, uri)| (prefix.to_string(), uri.to_string()))
+        .collect();
+    let mut declared = BTreeMap::new();
+    for element in root
+        .descendants()
+        .filter_map(|node| node.value().as_element())
+    {
+        for (name, value) in element.attrs.iter() {
+            // The HTML parser only splits `xmlns:` off on SVG and MathML
+            // elements.
+            let prefix = match &*name.ns {
+                XMLNS_NS => Some(&*name.local),
+                "" => name.local.strip_prefix("xmlns:"),
+                _ => None,
+            };
+            if let Some(prefix) = prefix.filter(|prefix| is_xml_name(prefix) && !value.is_empty()) {
+                declared
+                    .entry(prefix.to_string())
+                    .or_insert_with(|| value.to_string());
+            }
+        }
+    }
+    namespaces.extend(declared);
+    namespaces.remove("xml");
+    namespaces
+}
+
+fn write_node(
+    node: NodeRef<'_, Node>,
+    parent_ns: Option<&str>,
+    prefixes: &Prefixes,
+    out: &mut String,
+) {
+    match node.value() {
+        Node::Doctype(doctype) => {
+            out.push_str("<!DOCTYPE ");
+            out.push_str(doctype.name());
+            if !doctype.public_id().is_empty() {
+                out.push_str(&format!(r#" PUBLIC "{}""#, doctype.public_id()));
+                if !doctype.system_id().is_empty() {
+                    out.push_str(&format!(r#" "{}""#, doctype.system_id()));
+                }
+            } else if !doctype.system_id().is_empty() {
+                out.push_str(&format!(r#" SYSTEM "{}""#, doctype.system_id()));
+            }
+            out.push_str(">\n");
+        }
+        Node::Comment(comment) => {
+            // The HTML parser turns `<?xml ...?>` into a bogus comment.
+            if comment.starts_with('?') {
+                return;
+            }
+            out.push_str("<!--");
+            comment_into(comment, out);
+            out.push_str("-->");
+        }
+        Node::Text(text) => escape_into(text, false, out),
+        Node::Element(element) => {
+            let ns = &*element.name.ns;
+            let local = &*element.name.local;
+            if local.contains(':') && !prefixes.binds(local) {
+                report!(
+                    "XHTML: {}: unwrapping <{local}>, its namespace prefix is not declared",
+                    prefixes.path
+                );
+                for child in node.children() {
+                    write_node(child, parent_ns, prefixes, out);
+                }
+                return;
+            }
+
+            out.push('<');
+            out.push_str(local);
+            if parent_ns != Some(ns) && matches!(ns, XHTML_NS | SVG_NS | MATHML_NS) {
+                out.push_str(&format!(r#" xmlns="{ns}""#));
+            }
+            if parent_ns.is_none() {
+                for (prefix, uri) in &prefixes.namespaces {
+                    if uses_prefix(node, prefix, prefixes) {
+                        out.push_str(&format!(r#" xmlns:{prefix}=""#));
+                        escape_into(uri, true, out);
+                        out.push('"');
+                    }
+                }
+            }
+            for (name, value) in element.attrs.iter() {
+                let Some(name) = attribute_name(&name.ns, &name.local) else {
+                    continue;
+                };
+                if !prefixes.binds(&name) {
+                    report!(
+                        "XHTML: {}: dropping attribute {name} of <{local}>, \
+                         its namespace prefix is not declared",
+                        prefixes.path
+                    );
+                    continue;
+                }
+                out.push(' ');
+                out.push_str(&name);
+                out.push_str("=\"");
+                escape_into(value, true, out);
+                out.push('"');
+            }
+
+            if node.first_child().is_none() {
+                if ns != XHTML_NS || VOID_ELEMENTS.contains(&local) {
+                    out.push_str("/>");
+                } else {
+                    out.push_str(&format!("></{local}>"));
+                }
+                return;
+            }
+
+            out.push('>');
+            if !(ns == XHTML_NS && VOID_ELEMENTS.contains(&local)) {
+                for child in node.children() {
+                    write_node(child, Some(ns), prefixes, out);
+                }
+            }
+            out.push_str(&format!("</{local}>"));
+        }
+        Node::Document | Node::Fragment | Node::ProcessingInstruction(_) => {
+            for child in node.children() {
+                write_node(child, parent_ns, prefixes, out);
+            }
+        }
+    }
+}
+
+/// Returns the serialized attribute name, or `None` for namespace
+/// declarations, which are written on the root element instead, and for
+/// names that are not XML names.
+fn attribute_name(ns: &str, local: &str) -> Option<String> {
+    let name = match ns {
+        XLINK_NS => format!("xlink:{local}"),
+        XML_NS => format!("xml:{local}"),
+        "" => local.to_string(),
+        _ => return None,
+    };
+
+    if name == "xmlns" || name.starts_with("xmlns:") || !is_xml_name(&name) {
+        return None;
+    }
+    Some(name)
+}
+
+impl Prefixes<'_> {
+    /// Whether the attribute `name` can be written: it has no prefix, or one
+    /// bound to a namespace.
+    fn binds(&self, name: &str) -> bool {
+        match name.split_once(':') {
+            None => true,
+            Some((prefix, rest)) if !rest.contains(':') => {
+                prefix == "xml" || self.namespaces.contains_key(prefix)
+            }
+            Some(_) => false,
+        }
+    }
+}
+
+fn is_xml_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    let Some(first) = chars.next() else {
+        return false;
+    };
+    (first.is_alphabetic() || first == '_')
+        && chars.all(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+}
+
+fn uses_prefix(node: NodeRef<'_, Node>, prefix: &str, prefixes: &Prefixes) -> bool {
+    let has_prefix =
+        |name: &str| prefixes.binds(name) && name.split_once(':').is_some_and(|(p, _)| p == prefix);
+    node.descendants().any(|n| {
+        n.value().as_element().is_some_and(|element| {
+            has_prefix(&element.name.local)
+                || element.attrs.iter().any(|(name, _)| {
+                    attribute_name(&name.ns, &name.local).is_some_and(|n| has_prefix(&n))
+                })
+        })
+    })
+}
+
+/// Writes comment text that XML allows: no `--` inside and no `-` at the
+/// end, which would run into the closing `-->`.
+fn comment_into(text: &str, out: &mut String) {
+    for c in text.chars() {
+        if c == '-' && out.ends_with('-') {
+            out.push(' ');
+        }
+        out.push(c);
+    }
+    if text.ends_with('-') {
+        out.push(' ');
+    }
+}
+
+fn escape_into(text: &str, attribute: bool, out: &mut String) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' if attribute => out.push_str("&quot;"),
+            '\t' | '\n' | '\r' => out.push(c),
+            c if c.is_control() && c.is_ascii() => (),
+            c if needs_char_ref(c) => out.push_str(&format!("&#{};", c as u32)),
+            c => out.push(c),
+        }
+    }
+}
+
+fn needs_char_ref(c: char) -> bool {
+    !c.is_ascii()
+        && (c.is_whitespace()
+            || c.is_control()
+            || matches!(
+                c,
+                '\u{ad}' | '\u{200b}'..='\u{200d}' | '\u{2060}' | '\u{feff}'
+            ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_well_formed_rejects_tag_soup() {
+        assert!(is_well_formed(
+            b"<html><body><p>Text</p></body></html>",
+            true
+        ));
+        assert!(!is_well_formed(
+            b"<html><body><p>Text<br></body></html>",
+            true
+        ));
+        assert!(!is_well_formed(
+            b"<html><body><p>A&nbsp;B</p></body></html>",
+            false
+        ));
+        let declared = b"<!DOCTYPE html PUBLIC \"-//W3C//DTD XHTML 1.1//EN\" \"\">\
+            <html><body><p>A&nbsp;B</p></body></html>";
+        assert!(is_well_formed(declared, false));
+        assert!(!is_well_formed(declared, true));
+    }
+
+    #[test]
+    fn repair_closes_tags_and_quotes_attributes() {
+        let result = repair(
+            "a.xhtml",
+            "<html><body><p class=intro>One<br>Two<p>Three</body></html>",
+        );
+        assert_eq!(
+            result,
+            r#"<html xmlns="http://www.w3.org/1999/xhtml"><head></head><body><p class="intro">One<br/>Two</p><p>Three</p></body></html>"#
+        );
+        assert!(is_well_formed(result.as_bytes(), true));
+    }
+
+    #[test]
+    fn repair_writes_well_formed_comments() {
+        let result = repair("a.xhtml", "<p>One<!-- a -- b ---><br></p>");
+        assert!(result.contains("<!-- a - - b - -->"), "{result}");
+        assert!(is_well_formed(result.as_bytes(), true), "{result}");
+    }
+
+    #[test]
+    fn repair_writes_numeric_entities() {
+        let result = repair("a.xhtml", "<p>A&nbsp;B &amp; C&hellip;</p>");
+        assert!(result.contains("<p>A&#160;B &amp; C…</p>"), "{result}");
+        assert!(is_well_formed(result.as_bytes(), true));
+    }
+
+    #[test]
+    fn repair_declares_namespaces() {
+        let html = r#"<?xml version="1.0" encoding="utf-8"?>
+<html><body epub:type=bodymatter><svg><image xlink:href="cover.jpg"></svg></body></html>"#;
+        let result = repair("a.xhtml", html);
+        assert!(!result.contains("?xml"), "{result}");
+        assert!(result.contains(r#"xmlns:epub="http://www.idpf.org/2007/ops""#));
+        assert!(result.contains(r#"xmlns:xlink="http://www.w3.org/1999/xlink""#));
+        assert!(result.contains(
+            r#"<svg xmlns="http://www.w3.org/2000/svg"><image xlink:href="cover.jpg"/></svg>"#
+        ));
+        assert!(is_well_formed(result.as_bytes(), true));
+    }
+
+    #[test]
+    fn repair_unwraps_elements_with_undeclared_prefixes() {
+        let result = crate::output::muted(|| repair("a.xhtml", "<p>x<o:p></o:p><w:b>y</w:b></p>"));
+        assert!(result.contains("<p>xy</p>"), "{result}");
+        assert!(is_well_formed(result.as_bytes(), true), "{result}");
+
+        let html = r#"<html xmlns:o="urn:schemas-microsoft-com:office:office"><body><p>x<o:p></o:p></p></body></html>"#;
+        let result = repair("a.xhtml", html);
+        assert!(result.contains("<o:p></o:p>"), "{result}");
+        assert!(is_well_formed(result.as_bytes(), true), "{result}");
+    }
+
+    #[test]
+    fn repair_keeps_attributes_with_other_prefixes() {
+        let html = r#"<html xmlns:m="urn:example:m"><body ibooks:version="1" m:x="y" q:z="w"><p>A<br></p></body></html>"#;
+        let result = crate::output::muted(|| repair("a.xhtml", html));
+        assert!(result.contains(r#"xmlns:m="urn:example:m""#), "{result}");
+        assert!(result.contains(r#"ibooks:version="1""#), "{result}");
+        assert!(result.contains(r#"m:x="y""#), "{result}");
+        assert!(!result.contains("q:z"), "{result}");
+        assert!(is_well_formed(result.as_bytes(), true), "{result}");
+    }
+}
@@ -0,0 +1,863 @@
+//! A minimal XML tree that round-trips its source byte for byte.
+//!
+//! Unmodified tags, text, comments, processing instructions and whitespace
+//! are written back verbatim, so editing one element of an OPF leaves the
+//! rest of the document — prefixes, namespace declarations, formatting —
+//! exactly as the publisher wrote it.
+
+use crate::error::FixError;
+use std::collections::BTreeSet;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct XmlDocument {
+    prolog: Vec<XmlNode>,
+    pub(crate) root: XmlElement,
+    epilog: Vec<XmlNode>,
+    /// The encoding of the source, which the document is written back in.
+    encoding: Encoding,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+enum Encoding {
+    #[default]
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum XmlNode {
+    Element(XmlElement),
+    /// Character data as written in the source, entities still escaped.
+    Text(String),
+    /// Comments, processing instructions, CDATA sections and doctypes.
+    Markup(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct XmlElement {
+    /// The qualified name as written, e.g. `dc:language`.
+    pub(crate) name: String,
+    attributes: Vec<(String, String)>,
+    pub(crate) children: Vec<XmlNode>,
+    /// The original start tag, dropped once the attributes change.
+    start_tag: Option<String>,
+    /// The original end tag; `None` for self-closing and new elements.
+    end_tag: Option<String>,
+}
+
+impl XmlDocument {
+    /// Parses `content`, which is UTF-8 or, going by its byte order mark or
+    /// the bytes of its XML declaration, UTF-16. Documents declared in any
+    /// other encoding are only read when they are plain ASCII.
+    pub(crate) fn parse(content: &[u8]) -> Result<XmlDocument, FixError> {
+        let (source, encoding) = decode(content).map_err(FixError::MalformedXml)?;
+        let mut parser = Parser {
+            source: &source,
+            pos: 0,
+        };
+        let mut document = parser.document().map_err(|message| {
+            FixError::MalformedXml(format!("{message} at character {}", parser.pos))
+        })?;
+        document.encoding = encoding;
+        Ok(document)
+    }
+
+    /// A new document holding `root`, with an XML declaration.
+    pub(crate) fn new(root: XmlElement) -> Self {
+        XmlDocument {
+            prolog: vec![
+                XmlNode::Markup(r#"<?xml version="1.0" encoding="UTF-8"?>"#.to_string()),
+                XmlNode::Text("\n".to_string()),
+            ],
+            root,
+            epilog: vec![XmlNode::Text("\n".to_string())],
+            encoding: Encoding::Utf8,
+        }
+    }
+
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut out = String::new();
+        for node in self.prolog.iter() {
+            node.write(&mut out);
+        }
+        self.root.write(&mut out);
+        for node in self.epilog.iter() {
+            node.write(&mut out);
+        }
+        match self.encoding {
+            Encoding::Utf8 => out.into_bytes(),
+            Encoding::Utf16Le => out.encode_utf16().flat_map(u16::to_le_bytes).collect(),
+            Encoding::Utf16Be => out.encode_utf16().flat_map(u16::to_be_bytes).collect(),
+        }
+    }
+
+    /// The doctype declaration, if the document has one.
+    pub(crate) fn doctype(&self) -> Option<&str> {
+        self.prolog.iter().find_map(|node| match node {
+            XmlNode::Markup(markup) if markup.starts_with("<!DOCTYPE") => Some(markup.as_str()),
+            _ => None,
+        })
+    }
+
+    /// The names of the entities referenced in the text and attribute values
+    /// of the document, other than the five XML defines. An `&` that starts
+    /// no entity or character reference is an error.
+    pub(crate) fn entity_references(&self) -> Result<BTreeSet<String>, String> {
+        let mut names = BTreeSet::new();
+        self.root.entity_references(&mut names)?;
+        Ok(names)
+    }
+}
+
+/// Decodes `content` as UTF-16 when it starts with a UTF-16 byte order mark
+/// or `<?` in UTF-16, and as UTF-8 otherwise. A byte order mark is kept.
+fn decode(content: &[u8]) -> Result<(String, Encoding), String> {
+    let encoding = match content {
+        [0xff, 0xfe, ..] | [b'<', 0, b'?', 0, ..] => Encoding::Utf16Le,
+        [0xfe, 0xff, ..] | [0, b'<', 0, b'?', ..] => Encoding::Utf16Be,
+        _ => Encoding::Utf8,
+    };
+    let source = match encoding {
+        Encoding::Utf8 => String::from_utf8(content.to_vec())
+            .map_err(|err| format!("not valid UTF-8 at byte {}", err.utf8_error().valid_up_to()))?,
+        Encoding::Utf16Le | Encoding::Utf16Be => {
+            if !content.len().is_multiple_of(2) {
+                return Err("UTF-16 content with an odd number of bytes".to_string());
+            }
+            let units = content.chunks_exact(2).map(|pair| match encoding {
+                Encoding::Utf16Le => u16::from_le_bytes([pair[0], pair[1]]),
+                _ => u16::from_be_bytes([pair[0], pair[1]]),
+            });
+            char::decode_utf16(units)
+                .collect::<Result<String, _>>()
+                .map_err(|err| format!("not valid UTF-16: {err}"))?
+        }
+    };
+
+    if let Some(declared) = declared_encoding(&source) {
+        let known = ["utf-8", "utf8", "utf-16", "utf16", "us-ascii", "ascii"]
+            .iter()
+            .any(|known| declared.eq_ignore_ascii_case(known));
+        if !known && !source.is_ascii() {
+            return Err(format!("unsupported encoding {declared}"));
+        }
+    }
+    Ok((source, encoding))
+}
+
+/// The `encoding` of the XML declaration at the start of `source`.
+fn declared_encoding(source: &str) -> Option<&str> {
+    let declaration = source
+        .trim_start_matches('\u{feff}')
+        .strip_prefix("<?xml")?;
+    let declaration = &declaration[..declaration.find("?>")?];
+    let (_, rest) = declaration.split_once("encoding")?;
+    let rest = rest.trim_start().strip_prefix('=')?.trim_start();
+    let quote = rest.chars().next().filter(|c| matches!(c, '"' | '\''))?;
+    let rest = &rest[1..];
+    Some(&rest[..rest.find(quote)?])
+}
+
+impl XmlNode {
+    pub(crate) fn as_element(&self) -> Option<&XmlElement> {
+        match self {
+            XmlNode::Element(element) => Some(element),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_mut_element(&mut self) -> Option<&mut XmlElement> {
+        match self {
+            XmlNode::Element(element) => Some(element),
+            _ => None,
+        }
+    }
+
+    fn is_whitespace(&self) -> bool {
+        matches!(self, XmlNode::Text(text) if text.trim().is_empty())
+    }
+
+    fn write(&self, out: &mut String) {
+        match self {
+            XmlNode::Element(element) => element.write(out),
+            XmlNode::Text(text) | XmlNode::Markup(text) => out.push_str(text),
+        }
+    }
+}
+
+impl XmlElement {
+    pub(crate) fn new(name: &str) -> Self {
+        XmlElement {
+            name: name.to_string(),
+            attributes: Vec::new(),
+            children: Vec::new(),
+            start_tag: None,
+            end_tag: None,
+        }
+    }
+
+    pub(crate) fn with_attr(mut self, name: &str, value: &str) -> Self {
+        self.set_attr(name, value);
+        self
+    }
+
+    pub(crate) fn with_text(mut self, text: &str) -> Self {
+        self.set_text(text);
+        self
+    }
+
+    /// The name without its namespace prefix.
+    pub(crate) fn local_name(&self) -> &str {
+        local_name(&self.name)
+    }
+
+    pub(crate) fn prefix(&self) -> Option<&str> {
+        self.name.split_once(':').map(|(prefix, _)| prefix)
+    }
+
+    pub(crate) fn attr(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The attributes as written, namespace declarations included.
+    pub(crate) fn attributes(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.attributes
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    pub(crate) fn set_attr(&mut self, name: &str, value: &str) {
+        match self.attributes.iter_mut().find(|(n, _)| n == name) {
+            Some((_, v)) if v == value => return,
+            Some((_, v)) => *v = value.to_string(),
+            None => self.attributes.push((name.to_string(), value.to_string())),
+        }
+        self.start_tag = None;
+    }
+
+    pub(crate) fn remove_attr(&mut self, name: &str) {
+        let before = self.attributes.len();
+        self.attributes.retain(|(n, _)| n != name);
+        if self.attributes.len() != before {
+            self.start_tag = None;
+        }
+    }
+
+    /// The namespace prefix this element declares for `uri`: `Some(None)`
+    /// for a default namespace declaration, `Some(Some(prefix))` otherwise.
+    pub(crate) fn declared_prefix(&self, uri: &str) -> Option<Option<&str>> {
+        self.attributes
+            .iter()
+            .filter(|(_, value)| value == uri)
+            .find_map(|(name, _)| match name.as_str() {
+                "xmlns" => Some(None),
+                name => name.strip_prefix("xmlns:").map(Some),
+            })
+    }
+
+    pub(crate) fn elements(&self) -> impl Iterator<Item = &XmlElement> {
+        self.children.iter().filter_map(XmlNode::as_element)
+    }
+
+    pub(crate) fn elements_mut(&mut self) -> impl Iterator<Item = &mut XmlElement> {
+        self.children.iter_mut().filter_map(XmlNode::as_mut_element)
+    }
+
+    /// The first child element with the local name `name`.
+    pub(crate) fn child(&self, name: &str) -> Option<&XmlElement> {
+        self.elements().find(|e| e.local_name() == name)
+    }
+
+    pub(crate) fn child_mut(&mut self, name: &str) -> Option<&mut XmlElement> {
+        self.elements_mut().find(|e| e.local_name() == name)
+    }
+
+    /// The unescaped character data of this element and its descendants.
+    pub(crate) fn text(&self) -> String {
+        let mut text = String::new();
+        for child in &self.children {
+            match child {
+                XmlNode::Text(raw) => text.push_str(&unescape(raw)),
+                XmlNode::Element(element) => text.push_str(&element.text()),
+                XmlNode::Markup(raw) => {
+                    if let Some(cdata) = raw
+                        .strip_prefix("<![CDATA[")
+                        .and_then(|raw| raw.strip_suffix("]]>"))
+                    {
+                        text.push_str(cdata);
+                    }
+                }
+            }
+        }
+        text
+    }
+
+    pub(crate) fn set_text(&mut self, text: &str) {
+        self.children = vec![XmlNode::Text(escape(text, false))];
+        self.open();
+    }
+
+    /// Appends `element` after the last child element, copying the
+    /// indentation used by its siblings.
+    pub(crate) fn append_child(&mut self, element: XmlElement) {
+        let count = self.elements().count();
+        self.insert_child(count, element);
+    }
+
+    /// Inserts `element` before the `index`-th child element, copying the
+    /// indentation used by its siblings.
+    pub(crate) fn insert_child(&mut self, index: usize, element: XmlElement) {
+        let indent = self.child_indent();
+        let node_index = self
+            .children
+            .iter()
+            .enumerate()
+            .filter(|(_, node)| node.as_element().is_some())
+            .nth(index)
+            .map(|(i, _)| {
+                // Insert before the whitespace that indents the element.
+                if i > 0 && self.children[i - 1].is_whitespace() {
+                    i - 1
+                } else {
+                    i
+                }
+            })
+            .unwrap_or_else(|| match self.children.last() {
+                Some(last) if last.is_whitespace() => self.children.len() - 1,
+                _ => self.children.len(),
+            });
+
+        let mut nodes = Vec::with_capacity(2);
+        if let Some(indent) = indent {
+            nodes.push(XmlNode::Text(indent));
+        }
+        nodes.push(XmlNode::Element(element));
+        self.children.splice(node_index..node_index, nodes);
+        self.open();
+    }
+
+    /// Keeps only the child elements for which `keep` returns true, removing
+    /// the whitespace that indented the dropped ones.
+    pub(crate) fn retain_elements<F>(&mut self, mut keep: F)
+    where
+        F: FnMut(&mut XmlElement) -> bool,
+    {
+        let mut retained: Vec<XmlNode> = Vec::with_capacity(self.children.len());
+        for mut node in self.children.drain(..) {
+            if let XmlNode::Element(element) = &mut node {
+                if !keep(element) {
+                    if retained.last().is_some_and(XmlNode::is_whitespace) {
+                        retained.pop();
+                    }
+                    continue;
+                }
+            }
+            retained.push(node);
+        }
+        self.children = retained;
+    }
+
+    fn entity_references(&self, names: &mut BTreeSet<String>) -> Result<(), String> {
+        if let Some(start_tag) = &self.start_tag {
+            references_in(start_tag, names)?;
+        }
+        for child in &self.children {
+            match child {
+                XmlNode::Element(element) => element.entity_references(names)?,
+                XmlNode::Text(text) => references_in(text, names)?,
+                XmlNode::Markup(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// The whitespace preceding the first child element, if any.
+    fn child_indent(&self) -> Option<String> {
+        let first = self
+            .children
+            .iter()
+            .position(|n| n.as_element().is_some())?;
+        match first.checked_sub(1).map(|i| &self.children[i]) {
+            Some(XmlNode::Text(text)) if text.trim().is_empty() => Some(text.clone()),
+            _ => None,
+        }
+    }
+
+    /// Makes sure a self-closing element gets a separate end tag.
+    fn open(&mut self) {
+        if self.start_tag.as_deref().is_some_and(|t| t.ends_with("/>")) {
+            self.start_tag = None;
+        }
+    }
+
+    fn write(&self, out: &mut String) {
+        let self_closing = match &self.start_tag {
+            Some(start_tag) => {
+                out.push_str(start_tag);
+                start_tag.ends_with("/>")
+            }
+            None => {
+                out.push('<');
+                out.push_str(&self.name);
+                for (name, value) in &self.attributes {
+                    out.push(' ');
+                    out.push_str(name);
+                    out.push_str("=\"");
+                    out.push_str(&escape(value, true));
+                    out.push('"');
+                }
+                let self_closing = self.children.is_empty() && self.end_tag.is_none();
+                out.push_str(if self_closing { "/>" } else { ">" });
+                self_closing
+            }
+        };
+        if self_closing {
+            return;
+        }
+        for child in &self.children {
+            child.write(out);
+        }
+        match &self.end_tag {
+            Some(end_tag) => out.push_str(end_tag),
+            None => {
+                out.push_str("</");
+                out.push_str(&self.name);
+                out.push('>');
+            }
+        }
+    }
+}
+
+fn local_name(name: &str) -> &str {
+    name.rsplit_once(':').map_or(name, |(_, local)| local)
+}
+
+/// Adds the entity names `text` references to `names`.
+fn references_in(text: &str, names: &mut BTreeSet<String>) -> Result<(), String> {
+    for (start, _) in text.match_indices('&') {
+        let rest = &text[start + 1..];
+        let reference = rest
+            .find(';')
+            .map(|end| &rest[..end])
+            .filter(|reference| is_reference(reference))
+            .ok_or_else(|| {
+                let shown: String = rest.chars().take(10).collect();
+                format!("'&' starts no entity or character reference: &{shown}")
+            })?;
+        if !reference.starts_with('#')
+            && !matches!(reference, "lt" | "gt" | "amp" | "quot" | "apos")
+        {
+            names.insert(reference.to_string());
+        }
+    }
+    Ok(())
+}
+
+/// Whether `reference`, the text between `&` and `;`, is a character
+/// reference to a character or an entity name.
+fn is_reference(reference: &str) -> bool {
+    if let Some(number) = reference.strip_prefix('#') {
+        let (digits, radix) = match number.strip_prefix('x') {
+            Some(hex) => (hex, 16),
+            None => (number, 10),
+        };
+        return digits.chars().all(|c| c.is_digit(radix))
+            && u32::from_str_radix(digits, radix)
+                .ok()
+                .and_then(char::from_u32)
+                .is_some_and(|c| c >= ' ' || matches!(c, '\t' | '\n' | '\r'));
+    }
+    let mut chars = reference.chars();
+    chars
+        .next()
+        .is_some_and(|first| first.is_alphabetic() || matches!(first, '_' | ':'))
+        && chars.all(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+}
+
+pub(crate) fn escape(text: &str, attribute: bool) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' if attribute => out.push_str("&quot;"),
+            '\n' if attribute => out.push_str("&#10;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+pub(crate) fn unescape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let Some(end) = rest.find(';') else {
+            break;
+        };
+        let entity = &rest[1..end];
+        let decoded = match entity {
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "amp" => Some('&'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => entity
+                .strip_prefix("#x")
+                .or_else(|| entity.strip_prefix("#X"))
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .or_else(|| entity.strip_prefix('#').and_then(|dec| dec.parse().ok()))
+                .and_then(char::from_u32),
+        };
+        match decoded {
+            Some(c) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn rest(&self) -> &str {
+        &self.source[self.pos..]
+    }
+
+    fn document(&mut self) -> Result<XmlDocument, String> {
+        let mut prolog = Vec::new();
+        loop {
+            if self.rest().is_empty() {
+                return Err("missing root element".to_string());
+            }
+            if self.rest().starts_with("<!DOCTYPE") {
+                prolog.push(XmlNode::Markup(self.doctype()?));
+            } else if self.rest().starts_with("<?") || self.rest().starts_with("<!--") {
+                prolog.push(XmlNode::Markup(self.markup()?));
+            } else if self.rest().starts_with('<') {
+                break;
+            } else {
+                let text = self.text();
+                if !text.trim().trim_start_matches('\u{feff}').is_empty() {
+                    return Err("text before root element".to_string());
+                }
+                prolog.push(XmlNode::Text(text));
+            }
+        }
+
+        let root = self.element()?;
+
+        let mut epilog = Vec::new();
+        while !self.rest().is_empty() {
+            if self.rest().starts_with("<?") || self.rest().starts_with("<!--") {
+                epilog.push(XmlNode::Markup(self.markup()?));
+            } else {
+                let text = self.text();
+                if !text.trim().is_empty() {
+                    return Err("content after root element".to_string());
+                }
+                epilog.push(XmlNode::Text(text));
+            }
+        }
+        Ok(XmlDocument {
+            prolog,
+            root,
+            epilog,
+            encoding: Encoding::Utf8,
+        })
+    }
+
+    fn take_until(&mut self, terminator: &str) -> Result<String, String> {
+        let end = self
+            .rest()
+            .find(terminator)
+            .ok_or_else(|| format!("missing {terminator:?}"))?;
+        let taken = self.rest()[..end + terminator.len()].to_string();
+        self.pos += taken.len();
+        Ok(taken)
+    }
+
+    fn markup(&mut self) -> Result<String, String> {
+        if self.rest().starts_with("<!--") {
+            self.take_until("-->")
+        } else if self.rest().starts_with("<![CDATA[") {
+            self.take_until("]]>")
+        } else {
+            self.take_until("?>")
+        }
+    }
+
+    fn doctype(&mut self) -> Result<String, String> {
+        let mut depth = 0;
+        for (i, c) in self.rest().char_indices() {
+            match c {
+                '[' => depth += 1,
+                ']' => depth -= 1,
+                '>' if depth == 0 => {
+                    let doctype = self.rest()[..=i].to_string();
+                    self.pos += i + 1;
+                    return Ok(doctype);
+                }
+                _ => (),
+            }
+        }
+        Err("unterminated doctype".to_string())
+    }
+
+    fn text(&mut self) -> String {
+        let end = self.rest().find('<').unwrap_or(self.rest().len());
+        let text = self.rest()[..end].to_string();
+        self.pos += end;
+        text
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        let end = self
+            .rest()
+            .find(|c: char| c.is_whitespace() || matches!(c, '=' | '>' | '/' | '<'))
+            .unwrap_or(self.rest().len());
+        if end == 0 {
+            return Err("expected a name".to_string());
+        }
+        let name = self.rest()[..end].to_string();
+        self.pos += end;
+        Ok(name)
+    }
+
+    fn skip_whitespace(&mut self) {
+        let trimmed = self.rest().trim_start();
+        self.pos = self.source.len() - trimmed.len();
+    }
+
+    fn element(&mut self) -> Result<XmlElement, String> {
+        let start = self.pos;
+        self.pos += 1;
+        let name = self.name()?;
+        let mut attributes = Vec::new();
+
+        let self_closing = loop {
+            self.skip_whitespace();
+            if self.rest().starts_with("/>") {
+                self.pos += 2;
+                break true;
+            }
+            if self.rest().starts_with('>') {
+                self.pos += 1;
+                break false;
+            }
+            let attribute = self.name()?;
+            self.skip_whitespace();
+            if !self.rest().starts_with('=') {
+                return Err(format!("expected '=' after attribute {attribute}"));
+            }
+            self.pos += 1;
+            self.skip_whitespace();
+            let quote = self
+                .rest()
+                .chars()
+                .next()
+                .filter(|c| matches!(c, '"' | '\''))
+                .ok_or_else(|| format!("unquoted value for attribute {attribute}"))?;
+            self.pos += 1;
+            let value = self.take_until(&quote.to_string())?;
+            attributes.push((attribute, unescape(&value[..value.len() - 1])));
+        };
+
+        let mut element = XmlElement {
+            name,
+            attributes,
+            children: Vec::new(),
+            start_tag: Some(self.source[start..self.pos].to_string()),
+            end_tag: None,
+        };
+        if self_closing {
+            return Ok(element);
+        }
+
+        loop {
+            let rest = self.rest();
+            if rest.is_empty() {
+                return Err(format!("unclosed element <{}>", element.name));
+            } else if rest.starts_with("</") {
+                let end_start = self.pos;
+                self.pos += 2;
+                let name = self.name()?;
+                if name != element.name {
+                    return Err(format!(
+                        "mismatched end tag </{name}> for <{}>",
+                        element.name
+                    ));
+                }
+                self.skip_whitespace();
+                if !self.rest().starts_with('>') {
+                    return Err(format!("malformed end tag </{name}>"));
+                }
+                self.pos += 1;
+                element.end_tag = Some(self.source[end_start..self.pos].to_string());
+                return Ok(element);
+            } else if rest.starts_with("<!--")
+                || rest.starts_with("<![CDATA[")
+                || rest.starts_with("<?")
+            {
+                element.children.push(XmlNode::Markup(self.markup()?));
+            } else if rest.starts_with('<') {
+                element.children.push(XmlNode::Element(self.element()?));
+            } else {
+                element.children.push(XmlNode::Text(self.text()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPF: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!-- generated by hand -->
+<package xmlns="http://www.idpf.org/2007/opf" version="3.0">
+    <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+        <dc:title   id='t'>A &amp; B</dc:title>
+        <?calibre keep?>
+        <dc:language>xx</dc:language>
+    </metadata>
+    <manifest>
+        <item id="a" href="a.xhtml" media-type="application/xhtml+xml"/>
+    </manifest>
+    <spine/>
+</package>
+"#;
+
+    #[test]
+    fn round_trips_unmodified_documents() {
+        let document = XmlDocument::parse(OPF.as_bytes()).unwrap();
+        assert_eq!(String::from_utf8(document.to_bytes()).unwrap(), OPF);
+    }
+
+    #[test]
+    fn edits_only_touch_targeted_nodes() {
+        let mut document = XmlDocument::parse(OPF.as_bytes()).unwrap();
+        let metadata = document.root.child_mut("metadata").unwrap();
+        assert_eq!(metadata.child("title").unwrap().text(), "A & B");
+        metadata.child_mut("language").unwrap().set_text("en");
+        metadata.append_child(XmlElement::new("dc:creator").with_text("Me"));
+
+        let manifest = document.root.child_mut("manifest").unwrap();
+        manifest.append_child(
+            XmlElement::new("item")
+                .with_attr("id", "b")
+                .with_attr("href", "b&c.xhtml"),
+        );
+        manifest.retain_elements(|item| item.attr("id") == Some("b"));
+        document
+            .root
+            .child_mut("spine")
+            .unwrap()
+            .append_child(XmlElement::new("itemref").with_attr("idref", "b"));
+
+        let expected = OPF
+            .replace(
+                "<dc:language>xx</dc:language>",
+                "<dc:language>en</dc:language>\n        <dc:creator>Me</dc:creator>",
+            )
+            .replace(
+                r#"<item id="a" href="a.xhtml" media-type="application/xhtml+xml"/>"#,
+                r#"<item id="b" href="b&amp;c.xhtml"/>"#,
+            )
+            .replace("<spine/>", r#"<spine><itemref idref="b"/></spine>"#);
+        assert_eq!(String::from_utf8(document.to_bytes()).unwrap(), expected);
+    }
+
+    #[test]
+    fn insert_child_keeps_indentation() {
+        let mut document = XmlDocument::parse(
+            b"<spine>\n  <itemref idref=\"a\"/>\n  <itemref idref=\"c\"/>\n</spine>",
+        )
+        .unwrap();
+        document
+            .root
+            .insert_child(1, XmlElement::new("itemref").with_attr("idref", "b"));
+        assert_eq!(
+            String::from_utf8(document.to_bytes()).unwrap(),
+            "<spine>\n  <itemref idref=\"a\"/>\n  <itemref idref=\"b\"/>\n  <itemref idref=\"c\"/>\n</spine>"
+        );
+    }
+
+    #[test]
+    fn entity_references_lists_named_entities() {
+        let document = XmlDocument::parse(
+            b"<!DOCTYPE html><html title='&eacute;&amp;'><p>A&nbsp;B &#160;&#xA0; &lt;</p>\
+              <!-- &bogus --></html>",
+        )
+        .unwrap();
+        assert_eq!(document.doctype(), Some("<!DOCTYPE html>"));
+        assert_eq!(
+            document.entity_references().unwrap(),
+            BTreeSet::from(["eacute".to_string(), "nbsp".to_string()])
+        );
+
+        for bad in [
+            "<p>A & B</p>",
+            "<p>&#xZZ;</p>",
+            "<p>&#0;</p>",
+            "<p>&nbsp</p>",
+        ] {
+            let document = XmlDocument::parse(bad.as_bytes()).unwrap();
+            assert!(document.entity_references().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn round_trips_utf16_documents() {
+        let source = "\u{feff}<?xml version=\"1.0\" encoding=\"UTF-16\"?>\n<package><metadata><title>Café</title><language>xx</language></metadata></package>";
+        let utf16: Vec<u8> = source.encode_utf16().flat_map(u16::to_le_bytes).collect();
+        let mut document = XmlDocument::parse(&utf16).unwrap();
+        assert_eq!(document.to_bytes(), utf16);
+
+        let metadata = document.root.child_mut("metadata").unwrap();
+        assert_eq!(metadata.child("title").unwrap().text(), "Café");
+        metadata.child_mut("language").unwrap().set_text("en");
+        let expected: Vec<u8> = source
+            .replace(">xx<", ">en<")
+            .encode_utf16()
+            .flat_map(u16::to_le_bytes)
+            .collect();
+        assert_eq!(document.to_bytes(), expected);
+    }
+
+    #[test]
+    fn parse_rejects_undecodable_documents() {
+        let latin1 = b"<?xml version=\"1.0\" encoding=\"ISO-8859-1\"?><p>Caf\xe9</p>";
+        assert!(matches!(
+            XmlDocument::parse(latin1),
+            Err(FixError::MalformedXml(_))
+        ));
+        assert!(
+            XmlDocument::parse(b"<?xml version='1.0' encoding='ISO-8859-1'?><p>Cafe</p>").is_ok()
+        );
+        assert!(XmlDocument::parse(b"<p>Caf\xe9</p>").is_err());
+    }
+
+    #[test]
+    fn parse_rejects_mismatched_tags() {
+        let result = XmlDocument::parse(b"<package><metadata></package>");
+        assert!(matches!(result, Err(FixError::MalformedXml(_))));
+    }
+}
@@ -54,6 +54,164 @@ fn fixes_epub_end_to_end() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+#[test]
+fn reproducible_output_is_byte_identical() -> Result<(), Box<dyn Error>> {
+    let temp = tempdir()?;
+    let input_path = temp.path().join("sample.epub");
+    build_sample_epub(&input_path)?;
+    let output_path = temp.path().join("sample-fixed.epub");
+
+    let mut outputs = Vec::new();
+    for _ in 0..2 {
+        let mut cmd = assert_cmd::Command::cargo_bin("fixepub")?;
+        cmd.env("SOURCE_DATE_EPOCH", "1700000000")
+            .args(["--reproducible", "--"])
+            .arg(&input_path);
+        cmd.assert().success();
+        outputs.push(std::fs::read(&output_path)?);
+    }
+    assert!(outputs[0] == outputs[1], "expected identical archives");
+
+    let mut archive = ZipArchive::new(File::open(&output_path)?)?;
+    let names = (0..archive.len())
+        .map(|i| Ok(archive.by_index(i)?.name().to_string()))
+        .collect::<Result<Vec<String>, zip::result::ZipError>>()?;
+    let mut sorted = names.clone();
+    sorted.sort();
+    assert_eq!(names, sorted);
+    for i in 0..archive.len() {
+        let entry = archive.by_index(i)?;
+        assert_eq!(entry.last_modified().map(|time| time.year()), Some(2023));
+        assert_eq!(entry.unix_mode(), Some(0o100644));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn meta_subcommand_edits_metadata() -> Result<(), Box<dyn Error>> {
+    let temp = tempdir()?;
+    let input_path = temp.path().join("book.epub");
+    let output_path = temp.path().join("edited.epub");
+    let file = File::create(&input_path)?;
+    let mut writer = ZipWriter::new(file);
+    let options = SimpleFileOptions::default();
+    writer.start_file("META-INF/container.xml", options)?;
+    writer.write_all(
+        br#"<container><rootfiles><rootfile full-path="content.opf"/></rootfiles></container>"#,
+    )?;
+    writer.start_file("content.opf", options)?;
+    writer.write_all(
+        br#"<package version="3.0">
+  <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+    <dc:title>Old Title</dc:title>
+  </metadata>
+  <manifest/>
+</package>"#,
+    )?;
+    writer.finish()?;
+
+    let mut cmd = assert_cmd::Command::cargo_bin("fixepub")?;
+    cmd.arg("meta")
+        .arg(&input_path)
+        .args([
+            "--title",
+            "New Title",
+            "--series",
+            "Saga",
+            "--series-index",
+            "2",
+        ])
+        .arg("--output")
+        .arg(&output_path);
+    cmd.assert().success();
+
+    let mut cmd = assert_cmd::Command::cargo_bin("fixepub")?;
+    cmd.arg("meta").arg(&output_path);
+    let output = cmd.assert().success().get_output().stdout.clone();
+    let shown = String::from_utf8(output)?;
+    assert!(shown.contains("Title:       New Title"), "{shown}");
+    assert!(shown.contains("Series:      Saga #2"), "{shown}");
+
+    Ok(())
+}
+
+#[test]
+fn refuses_drm_protected_books() -> Result<(), Box<dyn Error>> {
+    let temp = tempdir()?;
+    let input_path = temp.path().join("locked.epub");
+    build_sample_epub(&input_path)?;
+    let file = File::options().read(true).write(true).open(&input_path)?;
+    let mut writer = ZipWriter::new_append(file)?;
+    writer.start_file("META-INF/encryption.xml", SimpleFileOptions::default())?;
+    writer.write_all(
+        br#"<encryption xmlns="urn:oasis:names:tc:opendocument:xmlns:container"
+    xmlns:enc="http://www.w3.org/2001/04/xmlenc#">
+  <enc:EncryptedData>
+    <enc:EncryptionMethod Algorithm="http://www.w3.org/2001/04/xmlenc#aes128-cbc"/>
+    <enc:CipherData><enc:CipherReference URI="chapter1.xhtml"/></enc:CipherData>
+  </enc:EncryptedData>
+</encryption>"#,
+    )?;
+    writer.finish()?;
+
+    let mut cmd = assert_cmd::Command::cargo_bin("fixepub")?;
+    cmd.arg("--").arg(&input_path);
+    let output = cmd.assert().failure().get_output().stderr.clone();
+    let stderr = String::from_utf8(output)?;
+    assert!(stderr.contains("DRM-protected book"), "{stderr}");
+    assert!(!temp.path().join("locked-fixed.epub").exists());
+
+    Ok(())
+}
+
+#[test]
+fn check_subcommand_reports_failures_without_writing() -> Result<(), Box<dyn Error>> {
+    let temp = tempdir()?;
+    let input_path = temp.path().join("sample.epub");
+    build_sample_epub(&input_path)?;
+
+    let mut cmd = assert_cmd::Command::cargo_bin("fixepub")?;
+    cmd.args(["check", "--target", "kindle"]).arg(&input_path);
+    let output = cmd.assert().failure().get_output().clone();
+    let stdout = String::from_utf8(output.stdout)?;
+    assert!(
+        stdout.contains("FAIL  mimetype is the first, uncompressed entry"),
+        "{stdout}"
+    );
+    assert!(stdout.contains("FAIL  No links to <body> ids"), "{stdout}");
+    assert!(
+        stdout.contains("PASS  File names are short, ASCII and unique"),
+        "{stdout}"
+    );
+    let stderr = String::from_utf8(output.stderr)?;
+    assert!(stderr.contains("failed"), "{stderr}");
+    assert!(!temp.path().join("sample-fixed.epub").exists());
+
+    Ok(())
+}
+
+#[test]
+fn validate_subcommand_reports_message_ids() -> Result<(), Box<dyn Error>> {
+    let temp = tempdir()?;
+    let input_path = temp.path().join("sample.epub");
+    build_sample_epub(&input_path)?;
+
+    let mut cmd = assert_cmd::Command::cargo_bin("fixepub")?;
+    cmd.arg("validate").arg(&input_path);
+    let output = cmd.assert().failure().get_output().clone();
+    let stdout = String::from_utf8(output.stdout)?;
+    assert!(stdout.contains("ERROR(PKG-006)"), "{stdout}");
+    assert!(
+        stdout.contains("ERROR(RSC-005): content.opf: Error while parsing file"),
+        "{stdout}"
+    );
+    let stderr = String::from_utf8(output.stderr)?;
+    assert!(stderr.contains("is not valid"), "{stderr}");
+
+    Ok(())
+}
+
 fn build_sample_epub(path: &Path) -> Result<(), Box<dyn Error>> {
     let file = File::create(path)?;
     let mut writer = ZipWriter::new(file);